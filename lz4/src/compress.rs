@@ -4,7 +4,7 @@
 //! high performance. It has fixed memory usage, which contrary to other approachs, makes it less
 //! memory hungry.
 
-use byteorder::{NativeEndian, ByteOrder};
+use byteorder::{LittleEndian, ByteOrder};
 
 /// Duplication dictionary size.
 ///
@@ -98,11 +98,13 @@ impl<'a> Encoder<'a> {
 
     /// Read a 4-byte "batch" from some position.
     ///
-    /// This will read a native-endian 4-byte integer from some position.
+    /// This will read a little-endian 4-byte integer from some position. The endianness is fixed
+    /// (rather than native), so the batches hash the same on every target, making the compressed
+    /// output identical regardless of the target.
     fn get_batch(&self, n: usize) -> u32 {
         debug_assert!(self.remaining_batch(), "Reading a partial batch.");
 
-        NativeEndian::read_u32(&self.input[n..])
+        LittleEndian::read_u32(&self.input[n..])
     }
 
     /// Read the batch at the cursor.
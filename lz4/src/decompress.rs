@@ -14,6 +14,10 @@ quick_error! {
         OffsetOutOfBounds {
             description("The offset to copy is not contained in the decompressed buffer.")
         }
+        /// The decompressed data exceeds the limit given to `decompress_into_bounded()`.
+        LimitExceeded {
+            description("The decompressed data exceeds the size limit.")
+        }
     }
 }
 
@@ -32,6 +36,11 @@ struct Decoder<'a> {
     /// length and the back reference's length, respectively. LSIC is used if either are their
     /// maximal values.
     token: u8,
+    /// The maximal length of the output buffer.
+    ///
+    /// Compressed data can expand by a large factor, so when decompressing untrusted data, this
+    /// keeps a crafted input from exhausting the memory.
+    limit: usize,
 }
 
 impl<'a> Decoder<'a> {
@@ -68,6 +77,17 @@ impl<'a> Decoder<'a> {
         output.extend_from_slice(&buf[..buf.len()]);
     }
 
+    /// Check that `n` more bytes can be written to the output stream without exceeding the limit.
+    #[inline]
+    fn check_limit(&self, n: usize) -> Result<(), Error> {
+        // The output is never longer than the limit, so this can't underflow.
+        if n > self.limit - self.output.len() {
+            Err(Error::LimitExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Write an already decompressed match to the output stream.
     ///
     /// This is used for the essential part of the algorithm: deduplication. We start at some
@@ -145,6 +165,8 @@ impl<'a> Decoder<'a> {
         // Now we know the literal length. The number will be used to indicate how long the
         // following literal copied to the output buffer is.
 
+        // Make sure the literals fit, before copying anything.
+        self.check_limit(literal)?;
         // Read the literals segment and output them without processing.
         Self::output(&mut self.output, Self::take_imp(&mut self.input, literal)?);
 
@@ -187,6 +209,9 @@ impl<'a> Decoder<'a> {
         // overflow checks, which we will catch later.
         let start = self.output.len().wrapping_sub(offset as usize);
 
+        // Make sure the duplicate fits, before copying anything.
+        self.check_limit(match_length)?;
+
         // We'll do a bound check to avoid panicking.
         if start < self.output.len() {
             // Write the duplicate segment to the output buffer.
@@ -248,6 +273,27 @@ pub fn decompress_into(input: &[u8], output: &mut Vec<u8>) -> Result<(), Error>
         input: input,
         output: output,
         token: 0,
+        limit: ::std::usize::MAX,
+    }.complete()?;
+
+    Ok(())
+}
+
+/// Decompress all bytes of `input` into `output`, appending at most `limit` bytes.
+///
+/// If the decompressed data would exceed `limit` bytes, `Error::LimitExceeded` is returned, and
+/// `output` holds the data decompressed before the block crossing the limit. Use this for
+/// untrusted input, as a few bytes of compressed data can otherwise expand to a lot.
+pub fn decompress_into_bounded(input: &[u8], output: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+    // The limit is relative to the data already in the vector.
+    let limit = output.len().saturating_add(limit);
+
+    // Decode into our vector.
+    Decoder {
+        input: input,
+        output: output,
+        token: 0,
+        limit: limit,
     }.complete()?;
 
     Ok(())
@@ -287,4 +333,27 @@ mod test {
         decompress(&[0x10, b'a', 2, 0]).unwrap_err();
         decompress(&[0x40, b'a', 1, 0]).unwrap_err();
     }
+
+    #[test]
+    fn limit() {
+        let mut vec = b"xy".to_vec();
+        decompress_into_bounded(&[0x11, b'a', 1, 0], &mut vec, 6).unwrap();
+        assert_eq!(vec, b"xyaaaaaa");
+
+        // The literal fits, but the duplicate doesn't.
+        let mut vec = Vec::new();
+        match decompress_into_bounded(&[0x11, b'a', 1, 0], &mut vec, 5) {
+            Err(Error::LimitExceeded) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(vec, b"a");
+
+        // A long run of 0xFF lengths can't expand past the limit either.
+        let mut bomb = vec![0x1F, b'a', 1, 0];
+        bomb.extend_from_slice(&[0xFF; 64]);
+        bomb.push(0);
+        let mut vec = Vec::new();
+        decompress_into_bounded(&bomb, &mut vec, 1024).unwrap_err();
+        assert_eq!(vec.len(), 1);
+    }
 }
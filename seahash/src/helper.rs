@@ -5,68 +5,29 @@
 /// This assumes that `buf.len() < 8`. If this is not satisfied, the behavior is unspecified.
 #[inline(always)]
 pub fn read_int(buf: &[u8]) -> u64 {
-    // Because we want to make sure that it is register allocated, we fetch this into a variable.
-    // It will likely make no difference anyway, though.
-    let ptr = buf.as_ptr();
-
-    unsafe {
-        // Break it down to reads of integers with widths in total spanning the buffer. This minimizes
-        // the number of reads
-        match buf.len() {
-            // u8.
-            1 => *ptr as u64,
-            // u16.
-            2 => (*(ptr as *const u16)).to_le() as u64,
-            // u16 + u8.
-            3 => {
-                let a = (*(ptr as *const u16)).to_le() as u64;
-                let b = *ptr.offset(2) as u64;
-
-                a | (b << 16)
-            },
-            // u32.
-            4 => (*(ptr as *const u32)).to_le() as u64,
-            // u32 + u8.
-            5 => {
-                let a = (*(ptr as *const u32)).to_le() as u64;
-                let b = *ptr.offset(4) as u64;
-
-                a | (b << 32)
-            },
-            // u32 + u16.
-            6 => {
-                let a = (*(ptr as *const u32)).to_le() as u64;
-                let b = (*(ptr.offset(4) as *const u16)).to_le() as u64;
-
-                a | (b << 32)
-            },
-            // u32 + u16 + u8.
-            7 => {
-                let a = (*(ptr as *const u32)).to_le() as u64;
-                let b = (*(ptr.offset(4) as *const u16)).to_le() as u64;
-                let c = *ptr.offset(6) as u64;
-
-                a | (b << 32) | (c << 48)
-            },
-            _ => 0,
-        }
+    // Assemble the integer byte by byte, such that the result doesn't depend on the endianness of
+    // the target.
+    let mut x = 0;
+    for (i, &byte) in buf.iter().take(7).enumerate() {
+        x |= (byte as u64) << (8 * i);
     }
+
+    x
 }
 
 /// Read a little-endian 64-bit integer from some buffer.
+///
+/// The pointer doesn't need to be aligned.
 #[inline(always)]
 pub unsafe fn read_u64(ptr: *const u8) -> u64 {
-    #[cfg(target_pointer_width = "32")]
-    {
-        // We cannot be sure about the memory layout of a potentially emulated 64-bit integer, so
-        // we read it manually. If possible, the compiler should emit proper instructions.
-        (*(ptr as *const u32)).to_le() as u64 | ((*(ptr as *const u32)).to_le() as u64) << 32
+    // Assemble the integer byte by byte. This works regardless of the alignment of `ptr` and of
+    // the endianness of the target, and compilers turn it into a single load where possible.
+    let mut x = 0;
+    for i in 0..8 {
+        x |= (*ptr.offset(i) as u64) << (8 * i);
     }
 
-    #[cfg(target_pointer_width = "64")]
-    {
-        (*(ptr as *const u64)).to_le()
-    }
+    x
 }
 
 /// The diffusion function.
@@ -123,6 +84,9 @@ mod tests {
         unsafe {
             assert_eq!(read_u64([1, 0, 0, 0, 0, 0, 0, 0].as_ptr()), 1);
             assert_eq!(read_u64([2, 1, 0, 0, 0, 0, 0, 0].as_ptr()), 258);
+            assert_eq!(read_u64([1, 2, 3, 4, 5, 6, 7, 8].as_ptr()), 0x0807060504030201);
+            // Unaligned reads.
+            assert_eq!(read_u64([0, 1, 2, 3, 4, 5, 6, 7, 8].as_ptr().offset(1)), 0x0807060504030201);
         }
     }
 
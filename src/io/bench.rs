@@ -0,0 +1,427 @@
+//! Benchmark workloads.
+//!
+//! This module provides reusable workload generators, which can be run against any `Disk`
+//! through the cache, such that performance regressions in the I/O stack are measurable from
+//! within the crate (e.g. from `cargo bench` or an embedder's own harness).
+//!
+//! The workloads are deterministic: The same workload on the same disk issues the same
+//! operations in the same order. Besides the synthetic workloads, real workloads recorded by the
+//! page manager (see the `trace` module) can be replayed.
+
+use std::{cmp, thread};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The number of sectors of a "4K" operation.
+const SECTORS_PER_4K: usize = 4096 / disk::SECTOR_SIZE;
+
+quick_error! {
+    /// A trace replay error.
+    #[derive(Debug)]
+    pub enum Error {
+        /// A disk error.
+        Disk(err: disk::Error) {
+            from()
+            description("Disk I/O error")
+            display("Disk I/O error: {}", err)
+        }
+        /// A page manager error.
+        Manager(err: pages::Error) {
+            from()
+            description("Page manager error")
+            display("Page manager error: {}", err)
+        }
+        /// The replay speed isn't a positive, finite number.
+        InvalidSpeed {
+            description("Invalid replay speed.")
+        }
+    }
+}
+
+/// The kind of data written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Data {
+    /// Highly compressible data (text-like, with many repetitions).
+    Compressible,
+    /// Incompressible (pseudorandom) data.
+    Incompressible,
+}
+
+/// A workload.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Workload {
+    /// Write `sectors` sectors sequentially, committing every 4K.
+    SequentialWrite {
+        /// The number of sectors to write.
+        sectors: usize,
+    },
+    /// Read `ops` random 4K blocks.
+    RandomRead {
+        /// The number of reads.
+        ops: usize,
+    },
+    /// Read or write `ops` random 4K blocks, with `write_percent` percent being writes.
+    RandomReadWrite {
+        /// The number of operations.
+        ops: usize,
+        /// The percentage of writes.
+        write_percent: u8,
+    },
+    /// Rewrite single sectors of a small hot set `ops` times, committing after each.
+    ///
+    /// This mimics metadata-heavy loads (e.g. creating and deleting many small files), which
+    /// update the same few metadata sectors over and over.
+    MetadataChurn {
+        /// The number of operations.
+        ops: usize,
+        /// The number of sectors in the hot set.
+        hot_sectors: usize,
+    },
+}
+
+/// The result of a benchmark run.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Report {
+    /// The number of operations issued.
+    pub ops: usize,
+    /// The number of bytes read or written.
+    pub bytes: u64,
+    /// The time the run took, including the final flush.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Get the throughput in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / (self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 * 1e-9)
+    }
+}
+
+/// A xorshift pseudorandom number generator.
+///
+/// This is fast and deterministic, which is all we need for generating workloads.
+struct Rng(u64);
+
+impl Rng {
+    /// Get the next number.
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Get a number below `n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Fill a buffer with some kind of data.
+fn fill(data: Data, rng: &mut Rng, buf: &mut [u8]) {
+    match data {
+        Data::Compressible => {
+            // Repeat a short phrase, with a varying starting point.
+            const PHRASE: &[u8] = b"the quick brown fox jumps over the lazy dog. ";
+            let offset = rng.below(PHRASE.len());
+            for (i, x) in buf.iter_mut().enumerate() {
+                *x = PHRASE[(offset + i) % PHRASE.len()];
+            }
+        },
+        Data::Incompressible => for x in buf {
+            *x = rng.next() as u8;
+        },
+    }
+}
+
+/// Write or read the 4K block `block`, and commit.
+///
+/// The blocks start after the first sector.
+fn block_io<D: Disk>(
+    cache: &mut Cache<D>,
+    rng: &mut Rng,
+    data: Data,
+    buf: &mut [u8],
+    block: usize,
+    write: bool,
+) -> Result<(), disk::Error> {
+    for sector in 1 + block * SECTORS_PER_4K..1 + (block + 1) * SECTORS_PER_4K {
+        if write {
+            fill(data, rng, buf);
+            cache.queue(sector, buf.to_vec().into_boxed_slice())?;
+        } else {
+            cache.read(sector)?;
+        }
+    }
+    cache.commit();
+
+    Ok(())
+}
+
+/// Run a workload against a disk.
+///
+/// The disk is wrapped in a cache, and flushed at the end of the run, such that the report covers
+/// the whole I/O stack below the page manager. The contents of the disk are overwritten.
+pub fn run<D: Disk>(disk: D, workload: Workload, data: Data) -> Result<Report, disk::Error> {
+    let sectors = disk.number_of_sectors();
+    // Reserve the first sector, as the cache doesn't allow writing the null sector.
+    assert!(sectors > SECTORS_PER_4K + 1, "Disk too small for benchmarking.");
+    let blocks = (sectors - 1) / SECTORS_PER_4K;
+
+    let mut cache = Cache::new(disk);
+    let mut rng = Rng(0x2545F4914F6CDD1D);
+    let mut buf = vec![0; disk::SECTOR_SIZE];
+    let mut report = Report {
+        ops: 0,
+        bytes: 0,
+        elapsed: Duration::new(0, 0),
+    };
+    let start = Instant::now();
+
+    match workload {
+        Workload::SequentialWrite { sectors } => {
+            for block in 0..(sectors + SECTORS_PER_4K - 1) / SECTORS_PER_4K {
+                block_io(&mut cache, &mut rng, data, &mut buf, block % blocks, true)?;
+                report.ops += 1;
+            }
+        },
+        Workload::RandomRead { ops } => {
+            for _ in 0..ops {
+                let block = rng.below(blocks);
+                block_io(&mut cache, &mut rng, data, &mut buf, block, false)?;
+            }
+            report.ops = ops;
+        },
+        Workload::RandomReadWrite { ops, write_percent } => {
+            for _ in 0..ops {
+                let block = rng.below(blocks);
+                let write = rng.below(100) < write_percent as usize;
+                block_io(&mut cache, &mut rng, data, &mut buf, block, write)?;
+            }
+            report.ops = ops;
+        },
+        Workload::MetadataChurn { ops, hot_sectors } => {
+            let hot_sectors = cmp::max(1, cmp::min(hot_sectors, sectors - 1));
+            for _ in 0..ops {
+                fill(data, &mut rng, &mut buf);
+                cache.queue(1 + rng.below(hot_sectors), buf.clone().into_boxed_slice())?;
+                cache.commit();
+            }
+            report.ops = ops;
+            // Each operation touches a single sector rather than a 4K block.
+            report.bytes = (ops * disk::SECTOR_SIZE) as u64;
+        },
+    }
+
+    // Make sure everything reached the disk.
+    cache.flush_all()?;
+    report.elapsed = start.elapsed();
+    if report.bytes == 0 {
+        report.bytes = (report.ops * SECTORS_PER_4K * disk::SECTOR_SIZE) as u64;
+    }
+
+    Ok(report)
+}
+
+/// The options of a trace replay.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ReplayOptions {
+    /// The speedup of the replay.
+    ///
+    /// The operations are issued at the times they were recorded, divided by this factor (e.g. 2
+    /// replays twice as fast). It must be positive and finite. `None` issues them as fast as
+    /// possible.
+    pub speed: Option<f64>,
+    /// The number of times the trace is replayed.
+    ///
+    /// This scales short traces up to longer runs.
+    pub repeat: usize,
+    /// The kind of data written.
+    ///
+    /// Traces don't record the data, so it is generated.
+    pub data: Data,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions {
+            speed: None,
+            repeat: 1,
+            data: Data::Compressible,
+        }
+    }
+}
+
+/// Replay a recorded trace against a disk.
+///
+/// A fresh volume is formatted on `disk` (overwriting its contents), and the data page operations
+/// of the trace are re-executed through a page manager on it, so the replay goes through the same
+/// allocator, packing, and freelist as the recorded workload:
+///
+/// - Every write of a data cluster allocates a page, filled with generated data (traces don't
+///   record the data).
+/// - Every read of a data cluster reads the last page allocated for it. Clusters never written in
+///   the trace are skipped, as they were written before it started.
+/// - Every free of a data cluster releases the pages allocated for it.
+/// - Every commit commits.
+///
+/// The clusters of the replay are chosen by the allocator, and thus differ from the traced ones.
+/// Metadata I/O isn't replayed, as the page manager does its own, and allocations are implied by
+/// the writes. At the end of every round, the pages still allocated are released, so repeated
+/// rounds don't fill the volume.
+///
+/// This reproduces the workload recorded, such that performance bugs can be reproduced, and fixes
+/// regression-tested against real workloads. If the speed is invalid, `Error::InvalidSpeed` is
+/// returned, and if the disk is too small to hold a volume, the page manager's
+/// `Error::OutOfClusters`.
+pub fn replay<D: Disk>(disk: D, records: &[trace::Record], options: ReplayOptions) -> Result<Report, Error> {
+    if let Some(speed) = options.speed {
+        if !(speed > 0.0 && speed.is_finite()) {
+            return Err(Error::InvalidSpeed);
+        }
+    }
+
+    let mut manager = pages::Manager::format(header::Driver::init(disk)?)?;
+    let mut rng = Rng(0x2545F4914F6CDD1D);
+    let mut buf = vec![0; pages::PAGE_SIZE];
+    let mut report = Report {
+        ops: 0,
+        bytes: 0,
+        elapsed: Duration::new(0, 0),
+    };
+    let start = Instant::now();
+
+    for _ in 0..options.repeat {
+        // The pages allocated for every traced cluster.
+        let mut clusters: HashMap<u64, Vec<pages::Pointer>> = HashMap::new();
+
+        let round = Instant::now();
+        for record in records {
+            // The page manager does its own metadata I/O, so only data pages are replayed.
+            if record.subsystem != trace::Subsystem::Data && record.kind != trace::Kind::Commit {
+                continue;
+            }
+
+            // Wait until the (scaled) time of the operation.
+            if let Some(speed) = options.speed {
+                let due = record.time as f64 / speed;
+                let elapsed = round.elapsed();
+                let elapsed = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+                if due > elapsed {
+                    let wait = (due - elapsed) as u64;
+                    thread::sleep(Duration::new(wait / 1_000_000_000, (wait % 1_000_000_000) as u32));
+                }
+            }
+
+            match record.kind {
+                trace::Kind::Write => {
+                    fill(options.data, &mut rng, &mut buf);
+                    let ptr = manager.queue_alloc(&buf)?;
+                    clusters.entry(record.cluster).or_insert_with(Vec::new).push(ptr);
+                },
+                trace::Kind::Read => match clusters.get(&record.cluster).and_then(|pages| pages.last()) {
+                    Some(&ptr) => {
+                        manager.read(ptr)?;
+                    },
+                    None => continue,
+                },
+                trace::Kind::Free => {
+                    for ptr in clusters.remove(&record.cluster).unwrap_or_default() {
+                        manager.release_page(ptr)?;
+                    }
+                    continue;
+                },
+                trace::Kind::Commit => {
+                    manager.commit()?;
+                    continue;
+                },
+                // Allocations are implied by the writes.
+                trace::Kind::Alloc | trace::Kind::Unknown => continue,
+            }
+
+            report.ops += 1;
+            report.bytes += pages::PAGE_SIZE as u64;
+        }
+
+        // Release the pages left over, and commit the tail of the round.
+        for (_, pages) in clusters {
+            for ptr in pages {
+                manager.release_page(ptr)?;
+            }
+        }
+        manager.commit()?;
+    }
+
+    // Make sure everything reached the disk.
+    manager.flush()?;
+    report.elapsed = start.elapsed();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::StorageDisk;
+
+    /// Create a record of the trace.
+    fn record(kind: trace::Kind, subsystem: trace::Subsystem, cluster: u64) -> trace::Record {
+        trace::Record {
+            time: 0,
+            cluster: cluster,
+            len: 0,
+            kind: kind,
+            subsystem: subsystem,
+        }
+    }
+
+    /// Create a disk of `sectors` sectors.
+    fn disk(sectors: usize) -> StorageDisk<Vec<u8>> {
+        StorageDisk::new(vec![0; sectors * disk::SECTOR_SIZE])
+    }
+
+    #[test]
+    fn replay_trace() {
+        let records = [
+            record(trace::Kind::Alloc, trace::Subsystem::Data, 20),
+            record(trace::Kind::Write, trace::Subsystem::Data, 20),
+            record(trace::Kind::Write, trace::Subsystem::Data, 20),
+            record(trace::Kind::Write, trace::Subsystem::Data, 21),
+            // Metadata I/O is left to the page manager.
+            record(trace::Kind::Write, trace::Subsystem::Freelist, 30),
+            record(trace::Kind::Commit, trace::Subsystem::Data, 0),
+            record(trace::Kind::Read, trace::Subsystem::Data, 20),
+            // A cluster written before the trace started.
+            record(trace::Kind::Read, trace::Subsystem::Data, 22),
+            record(trace::Kind::Free, trace::Subsystem::Data, 21),
+            record(trace::Kind::Commit, trace::Subsystem::Data, 0),
+        ];
+
+        let report = replay(disk(256), &records, ReplayOptions {
+            speed: Some(1.0),
+            repeat: 3,
+            data: Data::Incompressible,
+        }).unwrap();
+        assert_eq!(report.ops, 3 * 4);
+        assert_eq!(report.bytes, 3 * 4 * pages::PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn invalid_options() {
+        for &speed in &[0.0, -1.0, ::std::f64::NAN, ::std::f64::INFINITY] {
+            match replay(disk(256), &[], ReplayOptions {
+                speed: Some(speed),
+                ..ReplayOptions::default()
+            }) {
+                Err(Error::InvalidSpeed) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+
+        // A disk without room for the volume.
+        match replay(disk(9), &[], ReplayOptions::default()) {
+            Err(Error::Manager(pages::Error::OutOfClusters)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
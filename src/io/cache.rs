@@ -1,3 +1,25 @@
+use std::mem;
+
+/// An I/O priority class.
+///
+/// Every write is tagged with the priority class of the operation issuing it. When flushing, the
+/// higher classes are written first, such that maintenance never starves user I/O.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    /// Scrubbing and other verification passes.
+    Scrub = 0,
+    /// Background maintenance, such as defragmentation and garbage collection.
+    Background = 1,
+    /// User I/O.
+    Foreground = 2,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Foreground
+    }
+}
+
 /// A cache block.
 ///
 /// This stores a single sector in memory, for more performant reads and writes.
@@ -17,6 +39,21 @@ struct Block {
     /// already matches the on-disk data. Whenever it is written in memory, the flag should be set
     /// so that we're sure that it gets flushed properly to the disk.
     dirty: bool,
+    /// Has the data been verified?
+    ///
+    /// This is set when the data has been checked against its checksum, or when it was written by
+    /// ourselves, and cleared when the block is fetched from the disk. The cache doesn't know about
+    /// checksums itself; this is merely a note left by the layers above.
+    verified: bool,
+    /// The mount generation the block was cached in.
+    ///
+    /// If this is older than the generation of the cache, the block is stale (see
+    /// `Cache::invalidate()`).
+    generation: u64,
+    /// The priority class of the last write to the block.
+    ///
+    /// This determines the order in which dirty blocks are flushed.
+    priority: Priority,
     /// Disk sectors that shall be flushed _before_ this block.
     ///
     /// This defines the flush dependencies and is crucial to the consistency of the cache. In
@@ -77,10 +114,96 @@ struct Cache<D> {
     ///
     /// These are not committed to the block map yet and will not be until `.commit()` is called.
     /// They are ensured to be written to the disk in the order of the pipeline.
-    pipeline: Vec<(disk::Sector, Box<[u8]>)>,
+    pipeline: Vec<(disk::Sector, Box<[u8]>, Priority)>,
+    /// The priority class of the writes currently being queued.
+    priority: Priority,
+    /// The number of dirty blocks.
+    dirty_blocks: usize,
+    /// The throttle of the background class.
+    background_throttle: throttle::Throttle,
+    /// The throttle of the scrub class.
+    scrub_throttle: throttle::Throttle,
+    /// Is the cache read-only?
+    ///
+    /// If this is set, every write queued to the cache is rejected. This is used for sealed
+    /// volumes.
+    read_only: bool,
+    /// The mount generation.
+    ///
+    /// Clean blocks cached in older generations are stale, and fetched again on access.
+    generation: u64,
 }
 
-impl<D: Disk> Cached<D> {
+impl<D: Disk> Cache<D> {
+    /// Create a new cache over some disk.
+    ///
+    /// The cache starts out empty and writable.
+    pub fn new(disk: D) -> Cache<D> {
+        Cache {
+            disk: disk,
+            cache_tracker: mlcr::Cache::new(),
+            blocks: HashMap::new(),
+            pipeline: Vec::new(),
+            priority: Priority::default(),
+            dirty_blocks: 0,
+            background_throttle: throttle::Throttle::unlimited(),
+            scrub_throttle: throttle::Throttle::unlimited(),
+            read_only: false,
+            generation: 0,
+        }
+    }
+
+    /// Get a reference to the inner disk.
+    pub fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    /// Get a mutable reference to the inner disk.
+    ///
+    /// Writes through this bypass the cache, so this must not be used to write sectors which
+    /// might be cached.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.disk
+    }
+
+    /// Set or unset the read-only flag.
+    ///
+    /// When the cache is read-only, `.queue()` fails with `disk::Error::ReadOnly`. Transactions
+    /// already in the pipeline are unaffected.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Is the cache read-only?
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Set the priority class of the writes queued from now on.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Set the throttle configuration of some priority class.
+    ///
+    /// Disk reads of this class will be limited accordingly, and so will the flushes paced through
+    /// `.flush_class()`. Flushes on behalf of other writes never wait. The foreground class
+    /// cannot be throttled, so this panics if `priority` is `Priority::Foreground`.
+    pub fn set_throttle(&mut self, priority: Priority, config: throttle::Config) {
+        *self.throttle(priority).expect("Cannot throttle foreground I/O.") = throttle::Throttle::new(config);
+    }
+
+    /// Get the throttle of some priority class.
+    ///
+    /// This returns `None` for the foreground class, which is never throttled.
+    fn throttle(&mut self, priority: Priority) -> Option<&mut throttle::Throttle> {
+        match priority {
+            Priority::Foreground => None,
+            Priority::Background => Some(&mut self.background_throttle),
+            Priority::Scrub => Some(&mut self.scrub_throttle),
+        }
+    }
+
     /// Flush a sector to the disk.
     ///
     /// This can potentially trigger outer flushes if the cache block has flush dependencies.
@@ -101,20 +224,73 @@ impl<D: Disk> Cached<D> {
         }
 
         // Check if the block is (still) dirty.
+        //
+        // The block is written regardless of its class: The flush might be on behalf of a
+        // dependent foreground write, which must never be held back. Lower classes are paced in
+        // `.flush_class()` instead.
         if block.dirty {
             // Write the block to the disk.
             self.disk.write(block.sector, &block.data)?;
             // Unset the dirty flag.
             block.dirty = false;
+            self.dirty_blocks -= 1;
         }
     }
 
     /// Flush all sectors to the disk.
+    ///
+    /// The blocks are flushed in the order of their priority class, highest first. This is not
+    /// throttled, as the caller waits for the flush to complete.
     pub fn flush_all(&mut self) -> Result<(), disk::Error> {
-        // Run over the block map and flush them.
-        for i in self.blocks.keys() {
-            self.flush(i);
+        for &priority in &[Priority::Foreground, Priority::Background, Priority::Scrub] {
+            // Find the dirty blocks of this class.
+            let sectors: Vec<_> = self.blocks.values()
+                .filter(|block| block.dirty && block.priority == priority)
+                .map(|block| block.sector)
+                .collect();
+
+            // Flush them.
+            for &sector in &sectors {
+                self.flush(sector)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush up to `max` dirty blocks of some priority class.
+    ///
+    /// This allows flushing of lower priority classes to be paced, such that they don't compete
+    /// with user I/O. Note that the dependencies of the flushed blocks are flushed as well,
+    /// regardless of their class, since the ordering must be upheld.
+    ///
+    /// The blocks are admitted by the throttle of the class, and the flush stops at the first
+    /// block which isn't, leaving the rest for a later call. This never blocks, so the cache isn't
+    /// held while waiting for the throttle.
+    ///
+    /// The number of blocks flushed (not counting dependencies) is returned.
+    pub fn flush_class(&mut self, priority: Priority, max: usize) -> Result<usize, disk::Error> {
+        // Find the dirty blocks of this class.
+        let sectors: Vec<_> = self.blocks.values()
+            .filter(|block| block.dirty && block.priority == priority)
+            .map(|block| block.sector)
+            .take(max)
+            .collect();
+
+        // Flush them, as far as the throttle admits.
+        let mut flushed = 0;
+        for &sector in &sectors {
+            if let Some(throttle) = self.throttle(priority) {
+                if !throttle.admit(disk::SECTOR_SIZE as u64) {
+                    break;
+                }
+            }
+
+            self.flush(sector)?;
+            flushed += 1;
         }
+
+        Ok(flushed)
     }
 
     /// Read a sector from the disk.
@@ -124,11 +300,114 @@ impl<D: Disk> Cached<D> {
         Ok(self.get(sector)?.data)
     }
 
+    /// Read a sector without caching it.
+    ///
+    /// If the sector is cached, it is copied from the cache (which might hold committed writes not
+    /// yet flushed). Otherwise, it is read from the disk into `buf`, without inserting it into the
+    /// cache, so one-off reads don't evict the working set.
+    pub fn read_uncached(&mut self, sector: disk::Sector, buf: &mut [u8]) -> Result<(), disk::Error> {
+        if let Some(block) = self.blocks.get(&sector) {
+            if !self.is_stale(block) {
+                buf.copy_from_slice(&block.data);
+                return Ok(());
+            }
+        }
+
+        // Wait for the throttle of the current class, if any.
+        let priority = self.priority;
+        if let Some(throttle) = self.throttle(priority) {
+            throttle.wait(disk::SECTOR_SIZE as u64);
+        }
+
+        self.disk.read(sector, buf)
+    }
+
+    /// Get the number of bytes of dirty data in the cache.
+    ///
+    /// This is the amount of data committed to the cache, but not yet flushed to the disk.
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_blocks * disk::SECTOR_SIZE
+    }
+
+    /// Prefetch a sector into the cache.
+    ///
+    /// If the sector isn't cached already, it is read from the disk as background I/O (i.e.
+    /// subject to the background throttle), so that a later read is served from memory.
+    pub fn prefetch(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+        if !self.blocks.contains_key(&sector) {
+            // Fetch the sector with background priority, and restore the priority afterwards.
+            let priority = mem::replace(&mut self.priority, Priority::Background);
+            let res = self.fetch_fresh(sector).map(|_| ());
+            self.priority = priority;
+
+            res?;
+        }
+
+        Ok(())
+    }
+
     /// Queue a write to the pipeline.
     ///
     /// This pushes a transaction to the pipeline, which can be committed through `.commit()`.
-    pub fn queue(&mut self, sector: disk::Sector, buf: Box<[u8]>) {
-        self.pipeline.push((sector, buf));
+    ///
+    /// If the cache is read-only, this fails with `disk::Error::ReadOnly`.
+    pub fn queue(&mut self, sector: disk::Sector, buf: Box<[u8]>) -> Result<(), disk::Error> {
+        // Reject the write if the cache is read-only.
+        if self.read_only {
+            return Err(disk::Error::ReadOnly);
+        }
+
+        self.pipeline.push((sector, buf, self.priority));
+
+        Ok(())
+    }
+
+    /// Is the cached data of some sector verified?
+    ///
+    /// This returns `false` if the sector isn't cached.
+    pub fn is_verified(&self, sector: disk::Sector) -> bool {
+        self.blocks.get(&sector).map_or(false, |block| block.verified && !self.is_stale(block))
+    }
+
+    /// Invalidate the cached data.
+    ///
+    /// This is used when the disk has been modified behind the back of the cache (e.g. by a repair
+    /// tool). It starts a new mount generation, which makes every clean block stale, so it is
+    /// fetched from the disk again on its next access. Dirty blocks are our own writes, and thus
+    /// stay valid; normally, the cache is flushed before invalidating.
+    ///
+    /// This takes constant time, as the stale blocks are only replaced when accessed (or dropped
+    /// when evicted).
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Is a block stale?
+    ///
+    /// This is the case if it is clean and was cached in an older mount generation.
+    fn is_stale(&self, block: &Block) -> bool {
+        !block.dirty && block.generation != self.generation
+    }
+
+    /// Mark the cached data of some sector as verified.
+    ///
+    /// This does nothing if the sector isn't cached.
+    pub fn mark_verified(&mut self, sector: disk::Sector) {
+        if let Some(block) = self.blocks.get_mut(&sector) {
+            block.verified = true;
+        }
+    }
+
+    /// Get the writes in the pipeline, in order.
+    pub fn pipeline(&self) -> Vec<(disk::Sector, Box<[u8]>)> {
+        self.pipeline.iter().map(|&(sector, ref buf, _)| (sector, buf.clone())).collect()
+    }
+
+    /// Is the pipeline empty?
+    ///
+    /// This is the case when no writes have been queued since the last commit or revert.
+    pub fn pipeline_is_empty(&self) -> bool {
+        self.pipeline.is_empty()
     }
 
     /// Revert the pipeline and drop the transactions.
@@ -139,6 +418,21 @@ impl<D: Disk> Cached<D> {
         self.pipeline.clear();
     }
 
+    /// Get the position of the end of the pipeline.
+    ///
+    /// The pipeline can be truncated back to this position through `.revert_to()`.
+    pub fn pipeline_len(&self) -> usize {
+        self.pipeline.len()
+    }
+
+    /// Revert the pipeline to some position.
+    ///
+    /// This drops the transactions queued after the position `len` (as returned by
+    /// `.pipeline_len()`), keeping the ones before it.
+    pub fn revert_to(&mut self, len: usize) {
+        self.pipeline.truncate(len);
+    }
+
     /// Commit the transactions in the pipeline to the cache.
     ///
     /// This commits the sectors and data given in the pipeline in the specified order enforcing
@@ -154,13 +448,13 @@ impl<D: Disk> Cached<D> {
     /// transactions preserving their order in the pipeline. If two transactions "collide" (are
     /// writing to the same sector), the newest one is picked and the old one is thrown away.
     pub fn commit(&mut self) {
-        if Some((first_sector, first_buf)) = writes.next() {
+        if Some((first_sector, first_buf, first_priority)) = writes.next() {
             // Write the first block which has no dependencies.
-            let mut block = self.commit_write(first_sector, first_buf, None);
+            let mut block = self.commit_write(first_sector, first_buf, first_priority, None);
 
             // Write the rest with the previous write as dependency.
-            for (sector, buf) in self.pipeline.drain() {
-                block = self.commit_write(sector, buf, Some(block.sector));
+            for (sector, buf, priority) in self.pipeline.drain() {
+                block = self.commit_write(sector, buf, priority, Some(block.sector));
             }
         }
     }
@@ -188,6 +482,37 @@ impl<D: Disk> Cached<D> {
         Ok(())
     }
 
+    /// Get the number of bytes of cached data.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.len() * disk::SECTOR_SIZE
+    }
+
+    /// Shrink the cache to (at most) `target` bytes.
+    ///
+    /// This evicts clean blocks, coldest first, until the cached data fits into `target` bytes.
+    /// Dirty blocks are never evicted, so the cache might stay above the target, in which case it
+    /// must be flushed first. Excess capacity of internal buffers is released as well.
+    pub fn shrink(&mut self, target: usize) {
+        // Find the clean blocks, from cold to hot.
+        let candidates: Vec<_> = self.cache_tracker.cold()
+            .filter(|sector| self.blocks.get(sector).map_or(false, |block| !block.dirty))
+            .collect();
+
+        // Evict them until we're below the target.
+        for sector in candidates {
+            if self.memory_usage() <= target {
+                break;
+            }
+
+            self.blocks.remove(&sector);
+            self.cache_tracker.remove(sector);
+        }
+
+        // Release the excess capacity.
+        self.blocks.shrink_to_fit();
+        self.pipeline.shrink_to_fit();
+    }
+
     /// Remove some sector from the trash.
     fn remove(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
         self.flush(block)?;
@@ -199,13 +524,23 @@ impl<D: Disk> Cached<D> {
     /// Commits a sector write with some dependency.
     ///
     /// This writes `buf` into sector `sector` in the cache, ensuring that the sector (if any)
-    /// `dependency` is flushed to the disk prior to `sector`.
-    fn commit_write(&mut self, sector: cluster::Pointer, buf: Box<[u8]>, dependency: Option<disk::Sector>) -> &mut Block {
+    /// `dependency` is flushed to the disk prior to `sector`. The block is tagged with the
+    /// priority class `priority`.
+    fn commit_write(
+        &mut self,
+        sector: cluster::Pointer,
+        buf: Box<[u8]>,
+        priority: Priority,
+        dependency: Option<disk::Sector>,
+    ) -> &mut Block {
         // Allocate a new cache block.
         let block = cache.alloc_block(sector);
 
-        // Put the data into the freshly allocated cache block.
+        // Put the data into the freshly allocated cache block. We've produced it ourselves, so it
+        // is trusted.
         block.data = buf;
+        block.verified = true;
+        block.priority = priority;
 
         // Add the potential dependency to the cache block.
         if let Some(dependency) = dependency {
@@ -213,6 +548,7 @@ impl<D: Disk> Cached<D> {
         }
         // Mark dirty.
         block.dirty = true;
+        self.dirty_blocks += 1;
 
         block
     }
@@ -225,11 +561,19 @@ impl<D: Disk> Cached<D> {
     fn alloc_block(&mut self, sector: disk::Sector) -> &mut Block {
         // Note that we simply insert letting the cache grow. We will incidentally "trim" the cache
         // to reduce memory usage.
-        self.blocks.insert(sector, Block {
+        let generation = self.generation;
+        let old = self.blocks.insert(sector, Block {
             data: vec![0; disk::SECTOR_SIZE],
             dirty: false,
+            verified: false,
+            generation: generation,
+            priority: Priority::default(),
             flush_dependencies: Vec::new(),
         });
+        // If we replaced a dirty block, it no longer counts as dirty.
+        if old.map_or(false, |old| old.dirty) {
+            self.dirty_blocks -= 1;
+        }
 
         // I wish there was a method to bypass this lookup, but there isn't, so we simply index.
         &mut self.blocks[sector]
@@ -239,6 +583,12 @@ impl<D: Disk> Cached<D> {
     ///
     /// This will fetch `sector` from the disk to store it in the in-memory cache structure.
     fn fetch_fresh(&mut self, sector: disk::Sector) -> Result<&mut Block, disk::Error> {
+        // Wait for the throttle of the current class, if any.
+        let priority = self.priority;
+        if let Some(throttle) = self.throttle(priority) {
+            throttle.wait(disk::SECTOR_SIZE as u64);
+        }
+
         // Allocate a new cache block.
         let block = self.alloc_block(sector);
 
@@ -253,8 +603,9 @@ impl<D: Disk> Cached<D> {
     ///
     /// This grabs the sector from the cache or from the disk, if necessary.
     fn get(&mut self, sector: disk::Sector) -> Result<&mut Block, disk::Error> {
-        // Check if the sector already exists in the cache.
-        if let Some(block) = self.blocks.get_mut(sector) {
+        // Check if the sector already exists in the cache, and isn't stale.
+        let fresh = self.blocks.get(&sector).map_or(false, |block| !self.is_stale(block));
+        if let Some(block) = self.blocks.get_mut(sector).filter(|_| fresh) {
             // It did!
 
             // Touch the cache block.
@@ -269,8 +620,61 @@ impl<D: Disk> Cached<D> {
     }
 }
 
-impl<D: Disk> Drop for Cached<D> {
+impl<D: Disk> Drop for Cache<D> {
     fn drop(&mut self) {
         self.flush_all();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A disk whose next `failures` writes are interrupted.
+    struct FlakyDisk {
+        /// The inner disk.
+        inner: storage::StorageDisk<Vec<u8>>,
+        /// The number of writes still to fail.
+        failures: usize,
+    }
+
+    impl Disk for FlakyDisk {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buffer: &[u8]) -> Result<(), disk::Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(disk::Error::Interrupted);
+            }
+
+            self.inner.write(sector, buffer)
+        }
+
+        fn read(&mut self, sector: disk::Sector, buffer: &mut [u8]) -> Result<(), disk::Error> {
+            self.inner.read(sector, buffer)
+        }
+    }
+
+    #[test]
+    fn retry_failed_flush() {
+        let mut cache = Cache::new(FlakyDisk {
+            inner: storage::StorageDisk::new(vec![0; 4 * disk::SECTOR_SIZE]),
+            failures: 1,
+        });
+
+        cache.queue(2, vec![7; disk::SECTOR_SIZE].into_boxed_slice()).unwrap();
+        cache.commit();
+
+        // The committed write stays dirty when the flush fails, so flushing again writes it.
+        assert_eq!(cache.flush_all(), Err(disk::Error::Interrupted));
+        assert_eq!(cache.dirty_bytes(), disk::SECTOR_SIZE);
+        cache.flush_all().unwrap();
+        assert_eq!(cache.dirty_bytes(), 0);
+
+        let mut buf = [0; disk::SECTOR_SIZE];
+        cache.inner_mut().inner.read(2, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[7; disk::SECTOR_SIZE][..]);
+    }
+}
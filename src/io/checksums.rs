@@ -0,0 +1,200 @@
+//! The checksum tree.
+//!
+//! The checksum in the header of a data cluster is only 14 bits wide (see
+//! `pages::decode_data_cluster()`), so one corruption in 16384 goes unnoticed. When the checksum
+//! tree is enabled (see `Manager::set_checksum_tree()`), the full-width checksum of every data
+//! cluster written is additionally recorded out of band, in a tree indexed by the cluster, and
+//! verified when the cluster is read or scrubbed. Volumes formatted with full checksum coverage
+//! (see `header::ChecksumCoverage`) have the tree from the start, and their data cluster headers
+//! carry no checksum, so the tree is the only one.
+//!
+//! The tree is a radix tree of fixed depth over the cluster numbers. Every node is a cluster,
+//! starting with an 8 byte checksum of the rest of the node, followed by `FANOUT` 64-bit entries:
+//!
+//! - The entries of a leaf are the checksums of `FANOUT` consecutive clusters.
+//! - The entries of an inner node are pointers to the nodes below it.
+//!
+//! Zero marks an absent entry, i.e. a cluster without a recorded checksum (e.g. one written before
+//! the tree was enabled) or an empty subtree. A new tree has the smallest depth covering every
+//! cluster of the disk (see `depth()`). The depth is stored next to the root, as it must not
+//! change with the size of the disk: If the disk grows past the clusters covered, the tree is
+//! deepened, with the old root becoming the first child of a new one.
+//!
+//! The root is linked from the state block. The page manager collects the checksums of a
+//! transaction, and applies them to the tree copy-on-write on commit, so the entries change along
+//! with the clusters they cover.
+
+use std::cmp;
+
+/// The size (in bytes) of the checksum header of a node.
+const NODE_HEADER: usize = 8;
+/// The number of entries of a node.
+pub const FANOUT: usize = (disk::SECTOR_SIZE - NODE_HEADER) / 8;
+
+quick_error! {
+    /// A checksum tree node decoding error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The node is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated checksum tree node.")
+        }
+        /// The checksum of the node doesn't match.
+        ChecksumMismatch {
+            /// The checksum stored in the node.
+            expected: u64,
+            /// The checksum of the node's entries.
+            found: u64,
+        } {
+            display("Mismatching checksum tree node checksum - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching checksum tree node checksum.")
+        }
+    }
+}
+
+/// A node of the checksum tree.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Node {
+    /// The entries of the node.
+    ///
+    /// There are always `FANOUT` of them.
+    pub entries: Vec<u64>,
+}
+
+impl Default for Node {
+    fn default() -> Node {
+        Node {
+            entries: vec![0; FANOUT],
+        }
+    }
+}
+
+impl Node {
+    /// Decode a node.
+    ///
+    /// This verifies the checksum of the node `buf`. It never panics, regardless of the content
+    /// and length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<Node, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum of the node matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(FANOUT * 8)?) & !header::CHECKSUM_FLAG;
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        let mut entries = Vec::with_capacity(FANOUT);
+        for _ in 0..FANOUT {
+            entries.push(reader.read_u64()?);
+        }
+
+        Ok(Node {
+            entries: entries,
+        })
+    }
+
+    /// Encode the node into a cluster-sized buffer.
+    pub fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> Box<[u8]> {
+        // Start with an all-null cluster buffer.
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            writer.seek(NODE_HEADER);
+            for &entry in &self.entries {
+                writer.write_u64(entry);
+            }
+        }
+
+        // Checksum the entries, and write it at the start of the buffer. The checksum flag is left
+        // to the caller.
+        let cksum = checksum_algorithm.hash(&buf[NODE_HEADER..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        buf
+    }
+
+    /// Is every entry of the node absent?
+    ///
+    /// Empty nodes are dropped from the tree rather than written.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|&entry| entry == 0)
+    }
+}
+
+/// Get the depth of the tree covering a disk of `clusters` clusters.
+///
+/// This is the number of levels, including the leaves, so it is at least one.
+pub fn depth(clusters: u64) -> u32 {
+    let mut depth = 1;
+    let mut covered = FANOUT as u64;
+    while covered < clusters {
+        depth += 1;
+        covered = covered.saturating_mul(FANOUT as u64);
+    }
+
+    depth
+}
+
+/// Get the index of the entry leading to `cluster` in a node of some level.
+///
+/// The leaves are at level zero, and the root at level `depth() - 1`.
+pub fn slot(cluster: u64, level: u32) -> usize {
+    (cluster / (FANOUT as u64).pow(level) % FANOUT as u64) as usize
+}
+
+/// Get the entry recording some checksum.
+///
+/// Zero marks an absent entry, so a checksum of zero is recorded as one.
+pub fn entry(checksum: u64) -> u64 {
+    cmp::max(checksum, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_round_trip() {
+        let mut node = Node::default();
+        assert!(node.is_empty());
+        node.entries[0] = 0xdeadbeef;
+        node.entries[FANOUT - 1] = entry(0);
+        assert!(!node.is_empty());
+
+        let mut buf = node.encode(header::ChecksumAlgorithm::SeaHash);
+        assert_eq!(Node::decode(&buf, header::ChecksumAlgorithm::SeaHash), Ok(node));
+
+        // Corrupt an entry.
+        buf[NODE_HEADER] ^= 1;
+        match Node::decode(&buf, header::ChecksumAlgorithm::SeaHash) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(Node::decode(&buf[..100], header::ChecksumAlgorithm::SeaHash), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn indexing() {
+        let fanout = FANOUT as u64;
+
+        assert_eq!(depth(0), 1);
+        assert_eq!(depth(fanout), 1);
+        assert_eq!(depth(fanout + 1), 2);
+        assert_eq!(depth(fanout * fanout), 2);
+        assert_eq!(depth(!0), 11);
+
+        // The entries of consecutive clusters are adjacent in a leaf.
+        assert_eq!(slot(fanout + 2, 0), 2);
+        assert_eq!(slot(fanout + 2, 1), 1);
+        assert_eq!(slot(fanout * fanout, 2), 1);
+    }
+}
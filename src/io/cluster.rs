@@ -1,25 +1,98 @@
 //! Cluster management.
 
 use std::NonZero;
+use std::{cmp, fmt};
 
 /// The size (in bytes) of a cluster pointer.
 const POINTER_SIZE: usize = 8;
 
 /// A pointer to some cluster.
+///
+/// This points to a physical cluster on the disk. Pages (which are what the layers above the page
+/// manager deal with) are pointed to by `pages::Pointer` instead.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Pointer(NonZero<u64>);
 
 impl Pointer {
-    /// Create a new `ClusterPointer` to the `x`'th cluster.
+    /// Create a new `Pointer` to the `x`'th cluster.
     ///
     /// This returns `None` if `x` is `0`.
-    pub fn new(x: u64) -> Option<ClusterPointer> {
+    pub fn new(x: u64) -> Option<Pointer> {
         if x == 0 {
             None
         } else {
             // This is safe due to the above conditional.
-            Some(ClusterPointer(unsafe {
+            Some(Pointer(unsafe {
                 NonZero::new(x)
             }))
         }
     }
 }
+
+impl From<Pointer> for u64 {
+    fn from(ptr: Pointer) -> u64 {
+        ptr.0.get()
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}", self.0.get())
+    }
+}
+
+/// The bounds of valid cluster pointers on some disk.
+///
+/// Not every cluster on the disk can be pointed to: Some are reserved (e.g. the disk header and
+/// the state block), and some might lie beyond the end of the disk. Pointers read from the disk
+/// are checked against these bounds before use, so corruption is caught at decode time rather than
+/// turning into wild reads.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Bounds {
+    /// The number of reserved clusters in the start of the disk.
+    ///
+    /// These hold the disk header.
+    reserved: u64,
+    /// The address of the state block.
+    state_block: u64,
+    /// The number of clusters on the disk.
+    size: u64,
+}
+
+impl Bounds {
+    /// Create the bounds of a disk with `size` clusters and the state block at `state_block`.
+    pub fn new(size: u64, state_block: u64) -> Bounds {
+        Bounds {
+            reserved: (header::DISK_HEADER_SIZE / disk::SECTOR_SIZE) as u64,
+            state_block: state_block,
+            size: size,
+        }
+    }
+
+    /// Get the number of clusters on the disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the number of reserved clusters on the disk.
+    ///
+    /// These are the clusters of the disk header and the state block.
+    pub fn reserved(&self) -> u64 {
+        let header = cmp::min(self.reserved, self.size);
+        let state_block = self.state_block >= self.reserved && self.state_block < self.size;
+
+        header + state_block as u64
+    }
+
+    /// Check if a cluster is valid and unreserved.
+    ///
+    /// The cluster is given as an integer (rather than `Pointer`), as it is usually taken right
+    /// from the disk. If it is valid, the pointer is returned, and otherwise `None`.
+    pub fn check(&self, cluster: u64) -> Option<Pointer> {
+        if cluster < self.reserved || cluster >= self.size || cluster == self.state_block {
+            None
+        } else {
+            Pointer::new(cluster)
+        }
+    }
+}
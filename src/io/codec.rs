@@ -0,0 +1,213 @@
+//! Binary encoding and decoding.
+//!
+//! This module provides cursors for reading and writing the (little-endian) on-disk structures.
+//! Rather than indexing the buffers directly, the decoders read through a `Reader`, which is
+//! bounds checked and returns `Error::Truncated` on short buffers instead of panicking. This is
+//! important, as the images we read might be corrupted or even attacker-controlled.
+//!
+//! Every integer is encoded byte by byte in a fixed byte order, so images are portable between
+//! targets of different endianness. In-memory structures must never be copied to the disk as is.
+
+quick_error! {
+    /// A decoding error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The buffer ended before the structure did.
+        Truncated {
+            description("Truncated buffer.")
+        }
+    }
+}
+
+/// A reading cursor.
+///
+/// This reads integers and byte strings from a buffer, advancing the position of the cursor.
+pub struct Reader<'a> {
+    /// The buffer to read from.
+    buf: &'a [u8],
+    /// The current position in the buffer.
+    pos: usize,
+}
+
+// The decoders rely on the reader never panicking, so it must not index the buffer directly.
+#[deny(clippy::indexing_slicing)]
+impl<'a> Reader<'a> {
+    /// Create a new reader starting at the beginning of `buf`.
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    /// Get the current position of the cursor.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Move the cursor to some absolute position.
+    ///
+    /// This fails if `pos` is past the end of the buffer.
+    pub fn seek(&mut self, pos: usize) -> Result<(), Error> {
+        if pos > self.buf.len() {
+            return Err(Error::Truncated);
+        }
+
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Look at the next `len` bytes without advancing the cursor.
+    pub fn peek(&self, len: usize) -> Result<&'a [u8], Error> {
+        // Be careful to avoid overflows, as `len` can come from the disk.
+        if len > self.buf.len() - self.pos {
+            return Err(Error::Truncated);
+        }
+
+        self.buf.get(self.pos..self.pos + len).ok_or(Error::Truncated)
+    }
+
+    /// Read the next `len` bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let ret = self.peek(len)?;
+        self.pos += len;
+
+        Ok(ret)
+    }
+
+    /// Read a byte.
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        self.bytes(1)?.first().cloned().ok_or(Error::Truncated)
+    }
+
+    /// Read a little-endian 16-bit integer.
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    /// Read a little-endian 32-bit integer.
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    /// Read a little-endian 64-bit integer.
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+}
+
+/// A writing cursor.
+///
+/// This writes integers and byte strings into a buffer, advancing the position of the cursor.
+///
+/// As opposed to `Reader`, this panics on short buffers, since the buffers we encode into are
+/// always allocated by ourselves, and hence a short buffer is a bug rather than bad input.
+pub struct Writer<'a> {
+    /// The buffer to write into.
+    buf: &'a mut [u8],
+    /// The current position in the buffer.
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Create a new writer starting at the beginning of `buf`.
+    pub fn new(buf: &'a mut [u8]) -> Writer<'a> {
+        Writer {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    /// Get the current position of the cursor.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Move the cursor to some absolute position.
+    pub fn seek(&mut self, pos: usize) {
+        assert!(pos <= self.buf.len(), "Seeking past the end of the buffer.");
+
+        self.pos = pos;
+    }
+
+    /// Write some bytes.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    /// Write a byte.
+    pub fn write_u8(&mut self, x: u8) {
+        self.bytes(&[x]);
+    }
+
+    /// Write a little-endian 16-bit integer.
+    pub fn write_u16(&mut self, x: u16) {
+        let mut buf = [0; 2];
+        LittleEndian::write_u16(&mut buf, x);
+        self.bytes(&buf);
+    }
+
+    /// Write a little-endian 32-bit integer.
+    pub fn write_u32(&mut self, x: u32) {
+        let mut buf = [0; 4];
+        LittleEndian::write_u32(&mut buf, x);
+        self.bytes(&buf);
+    }
+
+    /// Write a little-endian 64-bit integer.
+    pub fn write_u64(&mut self, x: u64) {
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, x);
+        self.bytes(&buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        let mut buf = [0; 15];
+
+        {
+            let mut writer = Writer::new(&mut buf);
+            writer.write_u8(0xAB);
+            writer.write_u16(0x1234);
+            writer.write_u32(0xDEADBEEF);
+            writer.write_u64(0x0102030405060708);
+            assert_eq!(writer.position(), 15);
+        }
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0xDEADBEEF));
+        assert_eq!(reader.read_u64(), Ok(0x0102030405060708));
+        assert_eq!(reader.read_u8(), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn little_endian() {
+        let mut buf = [0; 4];
+        Writer::new(&mut buf).write_u32(0x11223344);
+        assert_eq!(buf, [0x44, 0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = [1, 2, 3];
+        let mut reader = Reader::new(&buf);
+
+        assert_eq!(reader.read_u32(), Err(Error::Truncated));
+        // A failed read doesn't advance the cursor.
+        assert_eq!(reader.position(), 0);
+        assert_eq!(reader.read_u16(), Ok(0x0201));
+        assert_eq!(reader.read_u16(), Err(Error::Truncated));
+        assert_eq!(reader.bytes(usize::max_value()), Err(Error::Truncated));
+        assert_eq!(reader.seek(4), Err(Error::Truncated));
+        assert_eq!(reader.seek(3), Ok(()));
+        assert_eq!(reader.bytes(0), Ok(&[][..]));
+    }
+}
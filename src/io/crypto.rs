@@ -1,5 +1,91 @@
 //! Cryptography.
 
+use std::{env, fs};
+use std::path::PathBuf;
+
+quick_error! {
+    /// A key retrieval error.
+    #[derive(Debug)]
+    pub enum KeyError {
+        /// The key provider couldn't provide the secret.
+        Unavailable {
+            description("Key unavailable.")
+        }
+        /// The key provider provided the wrong kind of secret.
+        ///
+        /// For example, a passphrase was provided for a volume whose key is split into shares.
+        WrongKind {
+            description("Wrong kind of secret for this volume.")
+        }
+        /// The key couldn't be reconstructed from the shares.
+        Shares(err: shamir::Error) {
+            from()
+            description("Key share error")
+            display("Key share error: {}", err)
+        }
+    }
+}
+
+/// A secret from which the volume key is derived.
+pub enum Secret {
+    /// A passphrase.
+    ///
+    /// The key is derived from it by scrypt.
+    Passphrase(Vec<u8>),
+    /// Key shares.
+    ///
+    /// The key is reconstructed from them.
+    Shares(Vec<shamir::Share>),
+}
+
+/// A source of secrets.
+///
+/// The driver consults the key provider when the volume is opened, so the key management policy
+/// (prompting, key files, OS keyrings, KMS services, etc.) is up to the embedder. Providers which
+/// need user interaction or external services are implemented outside this crate.
+pub trait KeyProvider {
+    /// Provide the secret of a volume.
+    ///
+    /// `key_sharing` is the key sharing record of the volume, if its key is split into shares, in
+    /// which case `Secret::Shares` must be provided.
+    fn provide(&mut self, key_sharing: Option<header::KeySharing>) -> Result<Secret, KeyError>;
+}
+
+/// A fixed passphrase.
+impl KeyProvider for Vec<u8> {
+    fn provide(&mut self, _: Option<header::KeySharing>) -> Result<Secret, KeyError> {
+        Ok(Secret::Passphrase(self.clone()))
+    }
+}
+
+/// A key provider reading the passphrase from a file.
+///
+/// A single trailing newline is stripped, as most editors add one.
+pub struct KeyFile(pub PathBuf);
+
+impl KeyProvider for KeyFile {
+    fn provide(&mut self, _: Option<header::KeySharing>) -> Result<Secret, KeyError> {
+        let mut passphrase = fs::read(&self.0).map_err(|_| KeyError::Unavailable)?;
+        if passphrase.last() == Some(&b'\n') {
+            passphrase.pop();
+        }
+
+        Ok(Secret::Passphrase(passphrase))
+    }
+}
+
+/// A key provider reading the passphrase from an environment variable.
+pub struct EnvironmentKey(pub String);
+
+impl KeyProvider for EnvironmentKey {
+    fn provide(&mut self, _: Option<header::KeySharing>) -> Result<Secret, KeyError> {
+        env::var_os(&self.0)
+            .and_then(|var| var.into_string().ok())
+            .map(|var| Secret::Passphrase(var.into_bytes()))
+            .ok_or(KeyError::Unavailable)
+    }
+}
+
 /// A cipher.
 ///
 /// This represents the user's choice of cipher to encrypt the disk.
@@ -17,6 +103,70 @@ enum Cipher {
 }
 
 impl Cipher {
+    /// Create the cipher of a volume, consulting a key provider.
+    ///
+    /// The provider is only consulted if the volume is encrypted.
+    pub fn from_provider(header: &header::DiskHeader, provider: &mut KeyProvider) -> Result<Cipher, KeyError> {
+        if header.cipher == header::Cipher::Identity {
+            return Ok(Cipher::Identity);
+        }
+
+        match (header.key_sharing, provider.provide(header.key_sharing)?) {
+            (None, Secret::Passphrase(passphrase)) => Ok(Cipher::new(header.cipher, &passphrase)),
+            (Some(key_sharing), Secret::Shares(shares)) => {
+                Ok(Cipher::from_shares(header.cipher, key_sharing, &shares)?)
+            },
+            _ => Err(KeyError::WrongKind),
+        }
+    }
+
+    /// Generate a random volume key, and split it into shares.
+    ///
+    /// `random` is used to fill buffers with cryptographically secure random bytes. This returns
+    /// the cipher of the key, the key sharing record to store in the disk header, and the
+    /// `count` shares, of which any `threshold` reconstruct the key (see `shamir::split()`).
+    pub fn split_key<R: FnMut(&mut [u8])>(cipher: header::Cipher, threshold: u8, count: u8, mut random: R)
+        -> (Cipher, header::KeySharing, Vec<shamir::Share>) {
+        let mut key = [0; shamir::KEY_SIZE];
+        random(&mut key);
+
+        let key_sharing = header::KeySharing {
+            threshold: threshold,
+            shares: count,
+            key_check: shamir::key_check(&key),
+        };
+        let shares = shamir::split(&key, threshold, count, random);
+
+        (Cipher::from_key(cipher, &key), key_sharing, shares)
+    }
+
+    /// Create a cipher from key shares.
+    ///
+    /// This reconstructs the volume key from `shares` according to the key sharing record of the
+    /// disk header, and checks it against the stored key check value. `cipher` is the cipher of
+    /// the disk header.
+    pub fn from_shares(cipher: header::Cipher, key_sharing: header::KeySharing, shares: &[shamir::Share])
+        -> Result<Cipher, shamir::Error> {
+        let key = shamir::combine(shares, key_sharing.threshold)?;
+
+        // Reject the key if any of the shares were wrong.
+        if shamir::key_check(&key) != key_sharing.key_check {
+            return Err(shamir::Error::KeyMismatch);
+        }
+
+        Ok(Cipher::from_key(cipher, &key))
+    }
+
+    /// Create a cipher from a volume key.
+    fn from_key(cipher: header::Cipher, key: &[u8; shamir::KEY_SIZE]) -> Cipher {
+        match cipher {
+            header::Cipher::Identity => Cipher::Identity,
+            header::Cipher::Speck128 => Cipher::Speck128 {
+                key: LittleEndian::read_u128(key),
+            },
+        }
+    }
+
     pub fn new(cipher: header::Cipher, password: &[u8]) -> Cipher {
         match cipher {
             // The user has chosen not to encrypt his or her disk. Sad!
@@ -43,3 +193,40 @@ impl Cipher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_key() {
+        let mut state = 0u8;
+        let random = |buf: &mut [u8]| for x in buf {
+            state = state.wrapping_add(1);
+            *x = state;
+        };
+
+        let (cipher, key_sharing, shares) = Cipher::split_key(header::Cipher::Speck128, 2, 3, random);
+        let key = match cipher {
+            Cipher::Speck128 { key } => key,
+            Cipher::Identity => panic!("Key sharing without encryption."),
+        };
+        assert_eq!((key_sharing.threshold, key_sharing.shares), (2, 3));
+
+        // The shares reconstruct the key, for the cipher of the disk header.
+        match Cipher::from_shares(header::Cipher::Speck128, key_sharing, &shares[1..]) {
+            Ok(Cipher::Speck128 { key: found }) => assert_eq!(found, key),
+            _ => panic!("Unexpected cipher."),
+        }
+        match Cipher::from_shares(header::Cipher::Identity, key_sharing, &shares[1..]) {
+            Ok(Cipher::Identity) => (),
+            _ => panic!("Unexpected cipher."),
+        }
+
+        // Too few shares don't.
+        match Cipher::from_shares(header::Cipher::Speck128, key_sharing, &shares[..1]) {
+            Err(shamir::Error::TooFewShares) => (),
+            _ => panic!("Unexpected cipher."),
+        }
+    }
+}
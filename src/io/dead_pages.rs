@@ -0,0 +1,158 @@
+//! The dead page list.
+//!
+//! A cluster can hold several pages (see `pages::MAX_PAGES_PER_CLUSTER`), and is only freed once
+//! every page in it is released (see `Manager::release_page()`). The released pages of clusters
+//! which still hold live pages are the _dead pages_. They are stored on commit, so the clusters
+//! are still freed after remounting, rather than leaked.
+//!
+//! The list is stored as a chain of clusters linked from the state block. Every cluster starts
+//! with an 8 byte checksum of the rest of the cluster, followed by the pointer to the next cluster
+//! of the chain (zero ending it), and up to `ENTRIES` encoded page pointers (see
+//! `pages::Pointer::encode()`), ended by a zero pointer or the end of the cluster. The chain is
+//! rewritten copy-on-write when the list changes.
+
+/// The size (in bytes) of the header of a list cluster.
+///
+/// This is the checksum and the link to the next cluster.
+const CLUSTER_HEADER: usize = 16;
+/// The number of page pointers of a list cluster.
+pub const ENTRIES: usize = (disk::SECTOR_SIZE - CLUSTER_HEADER) / 8;
+
+quick_error! {
+    /// A dead page list decoding error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The list cluster is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated dead page list cluster.")
+        }
+        /// The checksum of the list cluster doesn't match.
+        ChecksumMismatch {
+            /// The checksum stored in the cluster.
+            expected: u64,
+            /// The checksum of the cluster's content.
+            found: u64,
+        } {
+            display("Mismatching dead page list checksum - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching dead page list checksum.")
+        }
+        /// The chain of list clusters links back to one of its clusters.
+        Cycle {
+            /// The cluster linked twice.
+            cluster: u64,
+        } {
+            display("Cycle in the dead page list chain at cluster {:x}.", cluster)
+            description("Cycle in the dead page list chain.")
+        }
+        /// A page pointer of the list is invalid.
+        InvalidPointer {
+            /// The encoded pointer.
+            ptr: u64,
+        } {
+            display("Invalid page pointer {:x} in the dead page list.", ptr)
+            description("Invalid page pointer in the dead page list.")
+        }
+    }
+}
+
+/// A cluster of the stored list.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Chunk {
+    /// The next cluster of the chain, or zero.
+    pub next: u64,
+    /// The dead pages of the cluster.
+    pub pages: Vec<pages::Pointer>,
+}
+
+impl Chunk {
+    /// Decode a list cluster.
+    ///
+    /// This verifies the checksum of the cluster `buf`, and the checksum nibbles of the pointers.
+    /// Their bounds are up to the caller. It never panics, regardless of the content and length of
+    /// `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<Chunk, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum of the cluster matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(disk::SECTOR_SIZE - 8)?) & !header::CHECKSUM_FLAG;
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        let next = reader.read_u64()?;
+        let mut pages = Vec::new();
+        for _ in 0..ENTRIES {
+            let ptr = reader.read_u64()?;
+            if ptr == 0 {
+                // A zero pointer ends the cluster.
+                break;
+            }
+
+            pages.push(pages::Pointer::decode(ptr).ok_or(Error::InvalidPointer { ptr: ptr })?);
+        }
+
+        Ok(Chunk {
+            next: next,
+            pages: pages,
+        })
+    }
+
+    /// Encode the list cluster into a cluster-sized buffer.
+    ///
+    /// At most `ENTRIES` pointers fit.
+    pub fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> Box<[u8]> {
+        assert!(self.pages.len() <= ENTRIES, "Too many pointers in a dead page list cluster.");
+
+        // Start with an all-null cluster buffer.
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            writer.seek(8);
+            writer.write_u64(self.next);
+            for &ptr in &self.pages {
+                writer.write_u64(ptr.encode());
+            }
+        }
+
+        // Checksum the rest of the cluster, and write it at the start of the buffer. The checksum
+        // flag is left to the caller.
+        let cksum = checksum_algorithm.hash(&buf[8..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_round_trip() {
+        let page = |cluster, index| pages::Pointer::new(cluster::Pointer::new(cluster).unwrap(), index);
+        let chunk = Chunk {
+            next: 40,
+            pages: vec![page(10, 0), page(10, 3), page(11, 1)],
+        };
+
+        let mut buf = chunk.encode(header::ChecksumAlgorithm::SeaHash);
+        assert_eq!(Chunk::decode(&buf, header::ChecksumAlgorithm::SeaHash), Ok(chunk));
+
+        // Corrupt a pointer.
+        buf[CLUSTER_HEADER] ^= 1;
+        match Chunk::decode(&buf, header::ChecksumAlgorithm::SeaHash) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(Chunk::decode(&buf[..100], header::ChecksumAlgorithm::SeaHash), Err(Error::Truncated));
+    }
+}
@@ -4,6 +4,8 @@
 //!
 //! We fix the sector size to 512, since it can be emulated by virtually any disk in use today.
 
+use std::cmp;
+
 /// A disk sector number.
 type Sector = usize;
 
@@ -26,9 +28,112 @@ quick_error! {
         SectorCorrupted {
             description("Corrupt disk sector.")
         }
+        /// The disk is read-only.
+        ///
+        /// This is returned when attempting to write to a disk (or cache) which doesn't accept
+        /// writes, e.g. because the volume is sealed.
+        ReadOnly {
+            description("Write to read-only disk.")
+        }
+        /// The backing disk of a thinly provisioned disk is full.
+        ///
+        /// The write needed a new extent, but the backing disk has none left (see the `thin`
+        /// module). Writes to the sectors already backed still succeed.
+        BackingExhausted {
+            description("Backing disk exhausted.")
+        }
+        /// The operation was interrupted, and might succeed if retried.
+        ///
+        /// This is returned for temporary conditions of the device or its transport, such as a
+        /// timeout, a bus reset, or a network disk being briefly unreachable.
+        Interrupted {
+            description("Disk operation interrupted.")
+        }
+    }
+}
+
+impl Error {
+    /// Is the error a failure of the device?
+    ///
+    /// This is the case for corrupt sectors and interrupted operations, but not for the errors
+    /// caused by the request itself (e.g. out of bounds), which say nothing about the health of
+    /// the device.
+    pub fn is_media_error(&self) -> bool {
+        match *self {
+            Error::SectorCorrupted | Error::Interrupted => true,
+            Error::OutOfBounds | Error::ReadOnly | Error::BackingExhausted => false,
+        }
+    }
+}
+
+/// The geometry of a disk.
+///
+/// Disks have preferred access patterns below the logical sector size: SSDs erase and program in
+/// units much larger than a sector, RAID arrays read-modify-write partial stripes, and SMR drives
+/// can only write zones sequentially. Writes respecting the geometry avoid these penalties.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Geometry {
+    /// The optimal I/O size (in sectors).
+    ///
+    /// This is e.g. the stripe width of a RAID array. Allocations of this size or larger should
+    /// start at a multiple of `alignment`.
+    pub optimal_io_size: Sector,
+    /// The preferred alignment (in sectors).
+    ///
+    /// This is e.g. the stripe unit, or 2048 sectors (1 MiB) for SSDs of unknown geometry.
+    pub alignment: Sector,
+    /// The erase block size (in sectors), if known.
+    pub erase_block_size: Option<Sector>,
+    /// Whether the disk is zoned (e.g. an SMR drive), and must be written sequentially per zone.
+    pub zoned: bool,
+}
+
+impl Geometry {
+    /// Round a sector up to the next aligned sector.
+    ///
+    /// `None` is returned on overflow.
+    pub fn align_up(&self, sector: Sector) -> Option<Sector> {
+        let alignment = cmp::max(self.alignment, 1);
+        match sector % alignment {
+            0 => Some(sector),
+            rem => sector.checked_add(alignment - rem),
+        }
+    }
+
+    /// Check if a sector is aligned.
+    pub fn is_aligned(&self, sector: Sector) -> bool {
+        sector % cmp::max(self.alignment, 1) == 0
+    }
+}
+
+impl Default for Geometry {
+    /// The geometry of a disk without any preferences.
+    fn default() -> Geometry {
+        Geometry {
+            optimal_io_size: 1,
+            alignment: 1,
+            erase_block_size: None,
+            zoned: false,
+        }
     }
 }
 
+/// A zone of a zoned disk.
+///
+/// Zoned disks (SMR drives, or ZNS SSDs) are split into zones, which can only be written
+/// sequentially at their write pointer, and must be reset as a whole before being rewritten.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Zone {
+    /// The first sector of the zone.
+    pub start: Sector,
+    /// The number of sectors in the zone.
+    pub len: Sector,
+    /// The sector the next write must go to.
+    ///
+    /// This is `start` for empty zones, and `start + len` for full ones.
+    pub write_pointer: Sector,
+}
+
 /// A storage device.
 ///
 /// This trait acts similarly to `std::io::{Read, Write}`, but is designed specifically for disks.
@@ -39,11 +144,41 @@ trait Disk {
     /// Write data to the disk.
     ///
     /// This writes `buffer` into sector `sector`.
-    fn write(sector: Sector, buffer: &[u8]) -> Result<(), Error>;
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), Error>;
     /// Read data from the disk.
     ///
     /// This reads `buffer.len()` bytes into `buffer` from sector `sector`.
-    fn read(sector: Sector, buffer: &mut [u8]) -> Result<(), Error>;
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), Error>;
+    /// Make the written data durable.
+    ///
+    /// This acts as a write barrier: When it returns, every previous write has reached stable
+    /// storage (e.g. through `fsync` or a device cache flush). The default does nothing, which is
+    /// right for disks without a volatile write cache.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Get the geometry of the disk.
+    ///
+    /// The geometry is a hint: Any access is still valid, but aligned ones might be faster. The
+    /// default has no preferences, which is right for disks that don't report their geometry.
+    fn geometry(&self) -> Geometry {
+        Geometry::default()
+    }
+    /// Report the zones of the disk.
+    ///
+    /// The zones are in ascending order, and cover the disk. Disks which aren't zoned (see
+    /// `Geometry::zoned`) report no zones, which is the default.
+    fn report_zones(&mut self) -> Result<Vec<Zone>, Error> {
+        Ok(Vec::new())
+    }
+    /// Reset the zone starting at sector `start`.
+    ///
+    /// This discards the content of the zone, and moves its write pointer back to its start. The
+    /// default does nothing, as disks which aren't zoned have nothing to reset.
+    fn reset_zone(&mut self, start: Sector) -> Result<(), Error> {
+        let _ = start;
+        Ok(())
+    }
 }
 
 /// For testing, we allow byte slices to act as disks.
@@ -53,7 +188,7 @@ impl Disk for &mut [u8] {
         self.len() as Sector / SECTOR_SIZE
     }
 
-    fn write(sector: Sector, buffer: &[u8]) -> Result<(), Error> {
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), Error> {
         // Check if the sector is within bounds.
         if sector as usize >= self.number_of_sectors() {
             Err(Error::OutOfBounds)
@@ -63,7 +198,7 @@ impl Disk for &mut [u8] {
         }
     }
 
-    fn read(sector: Sector, buffer: &mut [u8]) -> Result<(), Error> {
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), Error> {
         // Check if the sector is within bounds.
         if sector as usize >= self.number_of_sectors() {
             Err(Error::OutOfBounds)
@@ -72,3 +207,32 @@ impl Disk for &mut [u8] {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alignment() {
+        let geometry = Geometry {
+            alignment: 8,
+            ..Geometry::default()
+        };
+        assert_eq!(geometry.align_up(0), Some(0));
+        assert_eq!(geometry.align_up(1), Some(8));
+        assert_eq!(geometry.align_up(16), Some(16));
+        assert_eq!(geometry.align_up(::std::usize::MAX), None);
+        assert!(geometry.is_aligned(24));
+        assert!(!geometry.is_aligned(25));
+
+        // Disks without preferences have every sector aligned, including a zero alignment.
+        for &alignment in &[0, 1] {
+            let geometry = Geometry {
+                alignment: alignment,
+                ..Geometry::default()
+            };
+            assert_eq!(geometry.align_up(13), Some(13));
+            assert!(geometry.is_aligned(13));
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! The health event log.
+//!
+//! Intermittent corruption is hard to diagnose after the fact, so errors (checksum mismatches, I/O
+//! errors, repairs) are recorded in a small on-disk ring buffer, which can be inspected later.
+//!
+//! The log occupies a single cluster, which is laid out as follows:
+//!
+//! - 8 bytes: The checksum of the rest of the cluster.
+//! - 4 bytes: The index of the oldest event in the ring.
+//! - 4 bytes: The number of events in the ring.
+//! - The events, each 24 bytes: an 8 byte timestamp (seconds since the UNIX epoch), an 8 byte
+//!   cluster pointer, a 2 byte event kind, and 6 bytes of padding.
+//!
+//! When the ring is full, the oldest event is overwritten.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The size (in bytes) of the event log header.
+const HEADER_SIZE: usize = 16;
+/// The size (in bytes) of an event.
+const EVENT_SIZE: usize = 24;
+/// The number of events the log can hold.
+pub const CAPACITY: usize = (disk::SECTOR_SIZE - HEADER_SIZE) / EVENT_SIZE;
+
+quick_error! {
+    /// An event log parsing error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The buffer is too short to hold the event log.
+        Truncated {
+            from(codec::Error)
+            description("Truncated event log.")
+        }
+        /// The checksums doesn't match.
+        ChecksumMismatch {
+            /// The checksum of the data.
+            expected: u64,
+            /// The expected/stored value of the checksum.
+            found: u64,
+        } {
+            display("Mismatching checksums in the event log - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching checksum.")
+        }
+        /// The ring indices are out of range.
+        InvalidRing {
+            description("Invalid event log ring indices.")
+        }
+    }
+}
+
+/// The kind of an event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// A checksum mismatch was detected.
+    ChecksumMismatch = 1,
+    /// A damaged cluster was repaired.
+    Repaired = 2,
+    /// A disk read failed.
+    ReadError = 3,
+    /// A disk write failed.
+    WriteError = 4,
+    /// The free cluster count in the state block doesn't match the freelist.
+    ///
+    /// The cluster is the freelist head.
+    FreeCountMismatch = 5,
+    /// An unknown event kind.
+    ///
+    /// This is used for kinds written by newer implementations.
+    Unknown = 0xFFFF,
+}
+
+impl From<u16> for Kind {
+    fn from(from: u16) -> Kind {
+        match from {
+            1 => Kind::ChecksumMismatch,
+            2 => Kind::Repaired,
+            3 => Kind::ReadError,
+            4 => Kind::WriteError,
+            5 => Kind::FreeCountMismatch,
+            _ => Kind::Unknown,
+        }
+    }
+}
+
+/// An event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Event {
+    /// The time of the event in seconds since the UNIX epoch.
+    pub time: u64,
+    /// The cluster the event concerns.
+    pub cluster: u64,
+    /// The kind of the event.
+    pub kind: Kind,
+}
+
+impl Event {
+    /// Create a new event happening now.
+    pub fn now(kind: Kind, cluster: u64) -> Event {
+        Event {
+            // If the clock is before the epoch, we simply use zero.
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+            cluster: cluster,
+            kind: kind,
+        }
+    }
+}
+
+/// The event log.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Log {
+    /// The events, oldest first.
+    events: Vec<Event>,
+    /// Does the log contain events not yet written to the disk?
+    dirty: bool,
+}
+
+impl Log {
+    /// Record an event.
+    ///
+    /// If the log is full, the oldest event is dropped.
+    pub fn record(&mut self, event: Event) {
+        if self.events.len() == CAPACITY {
+            self.events.remove(0);
+        }
+
+        self.events.push(event);
+        self.dirty = true;
+    }
+
+    /// Get the events, oldest first.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Clear the log.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.dirty = true;
+    }
+
+    /// Does the log contain unwritten events?
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Mark the log as written to the disk.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Decode the event log from a cluster.
+    ///
+    /// This never panics, regardless of the content and length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<Log, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(disk::SECTOR_SIZE - 8)?) & !header::CHECKSUM_FLAG;
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        // Read the ring indices.
+        let start = reader.read_u32()? as usize;
+        let len = reader.read_u32()? as usize;
+        if start >= CAPACITY || len > CAPACITY {
+            return Err(Error::InvalidRing);
+        }
+
+        // Read the events, starting with the oldest.
+        let mut log = Log::default();
+        for i in 0..len {
+            reader.seek(HEADER_SIZE + (start + i) % CAPACITY * EVENT_SIZE)?;
+            log.events.push(Event {
+                time: reader.read_u64()?,
+                cluster: reader.read_u64()?,
+                kind: Kind::from(reader.read_u16()?),
+            });
+        }
+
+        Ok(log)
+    }
+
+    /// Encode the event log into a cluster.
+    ///
+    /// The events are written from the start of the ring, so the oldest event is always at index
+    /// zero.
+    pub fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> Box<[u8]> {
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            // Write the ring indices.
+            writer.seek(8);
+            writer.write_u32(0);
+            writer.write_u32(self.events.len() as u32);
+
+            // Write the events.
+            for event in &self.events {
+                writer.write_u64(event.time);
+                writer.write_u64(event.cluster);
+                writer.write_u16(event.kind as u16);
+                writer.bytes(&[0; 6]);
+            }
+        }
+
+        // Calculate and store the checksum. The checksum flag is left to the caller.
+        let cksum = checksum_algorithm.hash(&buf[8..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_identity() {
+        let mut log = Log::default();
+        assert_eq!(Log::decode(&log.encode(header::ChecksumAlgorithm::SeaHash), header::ChecksumAlgorithm::SeaHash).unwrap().events(), log.events());
+
+        log.record(Event { time: 1000, cluster: 5, kind: Kind::ChecksumMismatch });
+        log.record(Event { time: 2000, cluster: 7, kind: Kind::Repaired });
+        assert_eq!(Log::decode(&log.encode(header::ChecksumAlgorithm::SeaHash), header::ChecksumAlgorithm::SeaHash).unwrap().events(), log.events());
+    }
+
+    #[test]
+    fn golden() {
+        // The encoding must be byte-exact on every target, as images are moved between machines.
+        let mut log = Log::default();
+        log.record(Event { time: 1000, cluster: 5, kind: Kind::ChecksumMismatch });
+        let buf = log.encode(header::ChecksumAlgorithm::SeaHash);
+
+        assert_eq!(&buf[..34], &[
+            // The checksum, with the checksum flag unset.
+            0x2d, 0xc8, 0x8d, 0x53, 0x27, 0x4c, 0x88, 0x67,
+            // The ring indices.
+            0, 0, 0, 0, 1, 0, 0, 0,
+            // The event.
+            0xe8, 0x03, 0, 0, 0, 0, 0, 0,
+            5, 0, 0, 0, 0, 0, 0, 0,
+            1, 0,
+        ][..]);
+        assert!(buf[34..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn ring_overflow() {
+        let mut log = Log::default();
+        for i in 0..CAPACITY as u64 + 5 {
+            log.record(Event { time: i, cluster: i, kind: Kind::ReadError });
+        }
+
+        assert_eq!(log.events().len(), CAPACITY);
+        assert_eq!(log.events()[0].time, 5);
+        assert_eq!(log.events()[CAPACITY - 1].time, CAPACITY as u64 + 4);
+    }
+
+    #[test]
+    fn wrapped_ring() {
+        let mut buf = Log::default().encode(header::ChecksumAlgorithm::SeaHash);
+
+        // Manually write a ring starting at the last slot and wrapping around.
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+            writer.seek(8);
+            writer.write_u32(CAPACITY as u32 - 1);
+            writer.write_u32(2);
+            writer.seek(HEADER_SIZE + (CAPACITY - 1) * EVENT_SIZE);
+            writer.write_u64(1);
+            writer.write_u64(10);
+            writer.write_u16(3);
+            writer.seek(HEADER_SIZE);
+            writer.write_u64(2);
+            writer.write_u64(20);
+            writer.write_u16(4);
+        }
+        let cksum = seahash::hash(&buf[8..]);
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        let log = Log::decode(&buf, header::ChecksumAlgorithm::SeaHash).unwrap();
+        assert_eq!(log.events(), &[
+            Event { time: 1, cluster: 10, kind: Kind::ReadError },
+            Event { time: 2, cluster: 20, kind: Kind::WriteError },
+        ]);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut buf = Log::default().encode(header::ChecksumAlgorithm::SeaHash);
+
+        assert_eq!(Log::decode(&buf[..100], header::ChecksumAlgorithm::SeaHash), Err(Error::Truncated));
+
+        buf[20] = 1;
+        assert!(Log::decode(&buf, header::ChecksumAlgorithm::SeaHash).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Fuzzing entry points.
+//!
+//! This module exposes the decoders of every on-disk structure, such that they can be driven by
+//! fuzzing harnesses (e.g. `cargo-fuzz`). The input is arbitrary, and the decoders shall never
+//! panic, loop forever, or allocate memory proportional to anything but the (bounded) size of the
+//! structure, since images are potentially attacker-controlled.
+//!
+//! The result of the decoding is thrown away, as we're only interested in the absence of crashes.
+//!
+//! Besides fuzzing, the decoders are guarded statically: They (and the `codec::Reader` they are
+//! built on) deny `clippy::indexing_slicing`, so every access to the buffer goes through a
+//! bounds-checked method returning an error. New decoders (e.g. of tree nodes) must do the same,
+//! and get an entry point here.
+
+/// An arbitrary cluster pointer used for error reporting.
+const DUMMY_CLUSTER: u64 = 1;
+/// The number of clusters on the imaginary disk.
+const DISK_SIZE: u64 = 1 << 20;
+/// The address of the state block on the imaginary disk.
+const STATE_BLOCK: u64 = 8;
+
+/// Decode a disk header.
+pub fn header(buf: &[u8]) {
+    let _ = header::DiskHeader::decode(buf);
+}
+
+/// Decode a state block.
+pub fn state_block(buf: &[u8]) {
+    let _ = state_block::StateBlock::decode(
+        buf,
+        header::ChecksumAlgorithm::SeaHash,
+        cluster::Bounds::new(DISK_SIZE, STATE_BLOCK),
+    );
+}
+
+/// Decode a metacluster.
+pub fn metacluster(buf: &[u8]) {
+    let mut freelist = Vec::new();
+    let _ = pages::decode_metacluster(
+        cluster::Pointer::new(DUMMY_CLUSTER).unwrap(),
+        buf,
+        header::ChecksumAlgorithm::SeaHash,
+        cluster::Bounds::new(DISK_SIZE, STATE_BLOCK),
+        &mut freelist,
+    );
+}
+
+/// Decode a data cluster.
+///
+/// The cluster is decoded with every compression algorithm, both with and without verification.
+/// The latter lets the fuzzer reach the decompressors without forging checksums.
+pub fn data_cluster(buf: &[u8]) {
+    for &compression_algorithm in &[
+        state_block::CompressionAlgorithm::Identity,
+        state_block::CompressionAlgorithm::Lz4,
+    ] {
+        for &verify in &[true, false] {
+            let mut target = Vec::new();
+            let _ = pages::decode_data_cluster(
+                cluster::Pointer::new(DUMMY_CLUSTER).unwrap(),
+                buf,
+                header::ChecksumAlgorithm::SeaHash,
+                compression_algorithm,
+                verify,
+                &mut target,
+            );
+        }
+    }
+}
+
+/// Decode a health event log.
+pub fn event_log(buf: &[u8]) {
+    let _ = events::Log::decode(buf, header::ChecksumAlgorithm::SeaHash);
+}
+
+/// Decode a root history.
+pub fn history(buf: &[u8]) {
+    let _ = history::History::decode(buf, header::ChecksumAlgorithm::SeaHash);
+}
+
+/// Decode page pointers.
+///
+/// The input is split into 64-bit integers, each of which is decoded as a page pointer.
+pub fn page_pointers(buf: &[u8]) {
+    let mut reader = codec::Reader::new(buf);
+    while let Ok(x) = reader.read_u64() {
+        let _ = pages::Pointer::decode(x);
+    }
+}
+
+/// Decode an allocation trace.
+///
+/// Traces are usually our own, but they are shared in bug reports, so they're untrusted as well.
+pub fn trace(buf: &[u8]) {
+    let _ = trace::decode(buf);
+}
+
+/// Decode a checksum tree node.
+pub fn checksum_node(buf: &[u8]) {
+    let _ = checksums::Node::decode(buf, header::ChecksumAlgorithm::SeaHash);
+}
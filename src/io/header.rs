@@ -2,11 +2,26 @@
 //!
 //! The disk header provides information on how to read a TFS disk. This module parses and
 //! interprets the disk header so it is meaningful to the programmer.
+//!
+//! The fixed fields of the header are followed by a list of extension records, so that options
+//! can be added without breaking the format. Every record has a type, whose highest bit marks it
+//! as critical: An implementation must refuse disks with critical records it doesn't understand,
+//! while other unknown records are preserved as is.
+//!
+//! The header is stored twice: in the first sector of the disk, and in the last sector of the
+//! reserved header area. When the primary copy is damaged, the backup is used instead, and the
+//! primary copy is restored on the next header flush.
 
 /// The size of the disk header.
 ///
 /// This should be a multiple of the cluster size.
-const DISK_HEADER_SIZE: usize = 4096;
+pub const DISK_HEADER_SIZE: usize = 4096;
+/// The sector of the primary copy of the disk header.
+pub const PRIMARY_HEADER_SECTOR: Sector = 0;
+/// The sector of the backup copy of the disk header.
+///
+/// This is the last sector of the reserved header area.
+pub const BACKUP_HEADER_SECTOR: Sector = DISK_HEADER_SIZE / disk::SECTOR_SIZE - 1;
 /// The current version number.
 ///
 /// The versioning scheme divides this number into two parts. The 16 most significant bits identify
@@ -15,7 +30,61 @@ const DISK_HEADER_SIZE: usize = 4096;
 ///
 /// 1. A must be greater than or equal to B.
 /// 2. A and B must have equal higher parts.
-const VERSION_NUMBER: u32 = 0;
+///
+/// Version 1.1 fixed SeaHash on 32-bit targets, which computed different checksums before (see
+/// `ChecksumAlgorithm::LegacySeaHash`).
+const VERSION_NUMBER: u32 = 1 << 16 | 1;
+/// The first version whose 32-bit builds compute SeaHash correctly.
+///
+/// Images of older versions may carry legacy SeaHash checksums.
+const SEAHASH_FIXED_VERSION: u32 = 1 << 16 | 1;
+/// Get the version number of the on-disk format written by this implementation.
+///
+/// See `VERSION_NUMBER` for the versioning scheme. Embedders can compare this against the version
+/// of an image (`DiskHeader::version_number`), e.g. to warn before opening it with an older
+/// release, which might not be able to read it.
+pub fn format_version() -> u32 {
+    VERSION_NUMBER
+}
+
+/// Check if images of some format version can be read by this implementation.
+pub fn is_compatible(version: u32) -> bool {
+    version >> 16 == VERSION_NUMBER >> 16 && version <= VERSION_NUMBER
+}
+
+/// The bit of 64-bit checksum fields holding the checksum flag.
+///
+/// The flag tells which algorithm made the checksum (see `DiskHeader::checksum_flag`), so it is
+/// excluded from the checksum itself.
+pub const CHECKSUM_FLAG: u64 = 1 << 63;
+
+/// The offset of the extension records in the disk header.
+const EXTENSIONS_START: usize = 136;
+/// The offset of the extension records' checksum in the disk header.
+const EXTENSIONS_CHECKSUM: usize = disk::SECTOR_SIZE - 8;
+/// The number of bytes available for extension records.
+///
+/// Every record takes 4 bytes (its type and length) plus the length of its data.
+pub const EXTENSIONS_CAPACITY: usize = EXTENSIONS_CHECKSUM - EXTENSIONS_START;
+/// The bit of an extension record type marking the record critical.
+pub const CRITICAL_RECORD: u16 = 1 << 15;
+/// The type of the checksum migration record.
+///
+/// This holds the previous checksum algorithm (as a 16-bit integer) while the volume is migrated
+/// to a new one. It is critical, as implementations ignoring it would consider the clusters not
+/// yet migrated corrupt.
+const CHECKSUM_MIGRATION_RECORD: u16 = CRITICAL_RECORD | 1;
+/// The type of the indirection record.
+///
+/// This marks volumes whose clusters might have been moved (see the `indirection` module). It holds
+/// no data, and is critical, as implementations ignoring it would read moved clusters from their
+/// vacated places.
+const INDIRECTION_RECORD: u16 = CRITICAL_RECORD | 2;
+/// The type of the full checksum coverage record.
+///
+/// This marks volumes formatted with `ChecksumCoverage::Full`. It holds no data, and is critical,
+/// as implementations ignoring it would find no checksums in the data cluster headers.
+const FULL_COVERAGE_RECORD: u16 = CRITICAL_RECORD | 3;
 /// The magic number of images with partial TFS compatibility.
 const PARTIAL_COMPATIBILITY_MAGIC_NUMBER: &[u8] = b"~TFS fmt";
 /// The magic number of images with total TFS compatibility.
@@ -23,7 +92,13 @@ const TOTAL_COMPATIBILITY_MAGIC_NUMBER: &[u8] = b"TFS fmt ";
 
 quick_error! {
     /// A disk header reading error.
-    enum ParseError {
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum ParseError {
+        /// The buffer is too short to hold a disk header.
+        Truncated {
+            from(codec::Error)
+            description("Truncated disk header.")
+        }
         /// Unknown format (not TFS).
         UnknownFormat {
             description("Unknown format (not TFS).")
@@ -54,10 +129,36 @@ quick_error! {
         InvalidCipher {
             description("Invalid cipher option.")
         }
+        /// The key sharing threshold is zero or greater than the number of shares.
+        InvalidKeySharing {
+            description("Invalid key sharing parameters.")
+        }
         /// Unknown state flag value.
         UnknownStateFlag {
             description("Unknown state flag.")
         }
+        /// The extension records are malformed.
+        InvalidExtensions {
+            description("Invalid extension records.")
+        }
+        /// A critical extension record is not understood by this implementation.
+        UnknownCriticalRecord {
+            /// The type of the record.
+            kind: u16,
+        } {
+            display("Unknown critical extension record of type {:x}.", kind)
+            description("Unknown critical extension record.")
+        }
+        /// The checksum of the extension records doesn't match.
+        ExtensionChecksumMismatch {
+            /// The checksum of the data.
+            expected: u64,
+            /// The expected/stored value of the checksum.
+            found: u64,
+        } {
+            display("Mismatching checksums in the extension records - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching extension checksum.")
+        }
         /// The checksums doesn't match.
         ChecksumMismatch {
             /// The checksum of the data.
@@ -72,8 +173,8 @@ quick_error! {
 }
 
 /// TFS magic number.
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum MagicNumber {
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MagicNumber {
     /// The image is partially compatible with the official TFS specification.
     PartialCompatibility,
     /// The image is completely compatible with the official TFS specification.
@@ -105,12 +206,20 @@ impl Into<&'static [u8]> for MagicNumber {
 }
 
 /// A checksum algorithm configuration option.
-enum ChecksumAlgorithm {
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ChecksumAlgorithm {
     /// SeaHash checksum.
     ///
     /// SeaHash was designed for TFS, and is described [in this
     /// post](http://ticki.github.io/blog/seahash-explained/).
     SeaHash = 1,
+    /// SeaHash, as computed by 32-bit builds of format version 1.0.
+    ///
+    /// Those read the lower half of every 64-bit word of the input twice, so the checksums of the
+    /// images they wrote don't match SeaHash. Such images are detected by their header checksum
+    /// (see `DiskHeader::decode()`), and can be moved to SeaHash with a checksum migration (see
+    /// `Driver::begin_checksum_migration()`). It is never chosen for new volumes.
+    LegacySeaHash = 2,
 }
 
 impl ChecksumAlgorithm {
@@ -120,25 +229,63 @@ impl ChecksumAlgorithm {
         match self.state.state_block.checksum {
             // Hash the thing via SeaHash, then take the 16 lowest bits (truncating cast).
             ChecksumAlgorithm::SeaHash => seahash::hash(buf),
+            // Double the lower halves of the words, as the legacy builds read them.
+            ChecksumAlgorithm::LegacySeaHash => seahash::hash(&legacy_words(buf)),
         }
     }
 }
 
+/// Rewrite a buffer, such that its SeaHash is the legacy SeaHash of `buf`.
+///
+/// The legacy builds read the lower half of every full 64-bit word twice, and the trailing bytes
+/// correctly.
+fn legacy_words(buf: &[u8]) -> Vec<u8> {
+    let mut words = buf.to_vec();
+    for word in words.chunks_mut(8).filter(|word| word.len() == 8) {
+        let (low, high) = word.split_at_mut(4);
+        high.copy_from_slice(low);
+    }
+
+    words
+}
+
 impl TryFrom<u16> for ChecksumAlgorithm {
     type Err = Error;
 
     fn try_from(from: u16) -> Result<ChecksumAlgorithm, Error> {
         match from {
             1 => Ok(ChecksumAlgorithm::SeaHash),
+            2 => Ok(ChecksumAlgorithm::LegacySeaHash),
             1 << 15... => Err(Error::UnknownChecksumAlgorithm),
             _ => Err(Error::InvalidChecksumAlgorithm),
         }
     }
 }
 
+/// The checksum coverage of the data clusters.
+///
+/// This is chosen when formatting the volume (see `pages::Manager::format_with_coverage()`).
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ChecksumCoverage {
+    /// The data clusters are covered by the 14-bit checksum in their header.
+    ///
+    /// The checksum tree (see the `checksums` module) can be enabled on top.
+    Header,
+    /// The data clusters are covered by full-width checksums in the checksum tree.
+    ///
+    /// The tree is mandatory, and the data cluster headers carry no checksum, only their flags.
+    Full,
+}
+
+impl Default for ChecksumCoverage {
+    fn default() -> ChecksumCoverage {
+        ChecksumCoverage::Header
+    }
+}
+
 /// Cipher option.
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum Cipher {
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Cipher {
     /// Disk encryption disabled.
     Identity = 0,
     /// Use the SPECK cipher.
@@ -168,8 +315,8 @@ impl TryFrom<u16> for Cipher {
 /// The state flag defines the state of the disk, telling the user if it is in a consistent
 /// state or not. It is important for doing non-trivial things like garbage-collection, where the
 /// disk needs to enter an inconsistent state for a small period of time.
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum StateFlag {
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum StateFlag {
     /// The disk was properly closed and shut down.
     Closed = 0,
     /// The disk is active/was forcibly shut down.
@@ -180,36 +327,145 @@ enum StateFlag {
     Inconsistent = 2,
 }
 
+/// Error counters of a disk.
+///
+/// These are kept in the disk header (rather than the state block), since they concern the
+/// physical device, and every device of a multi-device configuration has its own header.
+#[derive(Default, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ErrorCounters {
+    /// The number of failed reads.
+    pub read: u32,
+    /// The number of failed writes.
+    pub write: u32,
+    /// The number of checksum mismatches in data read from the disk.
+    pub checksum: u32,
+}
+
+impl ErrorCounters {
+    /// The total number of errors.
+    pub fn total(&self) -> u32 {
+        self.read.saturating_add(self.write).saturating_add(self.checksum)
+    }
+}
+
+/// The key sharing record.
+///
+/// When present, the volume key is random and split into shares (see the `shamir` module), which
+/// are handed to different custodians. The shares themselves are never stored on the disk.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct KeySharing {
+    /// The number of shares needed to reconstruct the key.
+    pub threshold: u8,
+    /// The total number of shares.
+    pub shares: u8,
+    /// The key check value.
+    ///
+    /// This is used to detect wrong shares.
+    pub key_check: u64,
+}
+
+/// An extension record of the disk header.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Record {
+    /// The type of the record.
+    ///
+    /// The highest bit (`CRITICAL_RECORD`) marks the record critical. Zero is reserved as the
+    /// terminator.
+    pub kind: u16,
+    /// The data of the record.
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Is the record critical?
+    pub fn is_critical(&self) -> bool {
+        self.kind & CRITICAL_RECORD != 0
+    }
+}
+
 /// The disk header.
-#[derive(Default, PartialEq, Eq, Clone, Copy)]
-struct DiskHeader {
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DiskHeader {
     /// The magic number.
-    magic_number: MagicNumber,
+    pub magic_number: MagicNumber,
     /// The version number.
-    version_number: u32,
+    pub version_number: u32,
     /// The chosen checksum algorithm.
-    checksum_algorithm: ChecksumAlgorithm,
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// The checksum flag of the chosen algorithm.
+    ///
+    /// Every checksummed cluster carries a flag telling which algorithm made its checksum: It is
+    /// this flag for the current algorithm, and the opposite for the previous one. The flag is
+    /// flipped whenever a checksum migration starts, so a cluster is only ever checked against a
+    /// single algorithm.
+    pub checksum_flag: bool,
     /// The address of the state block.
-    state_block_address: clusters::Pointer,
+    pub state_block_address: clusters::Pointer,
     /// The state flag.
-    state_flag: StateFlag,
+    pub state_flag: StateFlag,
     /// The cipher.
-    cipher: Cipher,
+    pub cipher: Cipher,
     /// The encryption paramters.
     ///
     /// These are used as defined by the choice of cipher. Some ciphers might use it for salt or
     /// settings, and others not use it at all.
-    encryption_parameters: [u8; 16],
+    pub encryption_parameters: [u8; 16],
+    /// The key sharing record, if the key is split into shares.
+    pub key_sharing: Option<KeySharing>,
+    /// The error counters of the disk.
+    pub error_counters: ErrorCounters,
+    /// The previous checksum algorithm, if a checksum migration is in progress.
+    ///
+    /// During the migration, structures carry checksums of either algorithm. This is stored as an
+    /// extension record.
+    pub previous_checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Might clusters have been moved through the indirection table?
+    ///
+    /// This is stored as an extension record.
+    pub indirection: bool,
+    /// The checksum coverage of the data clusters.
+    ///
+    /// This is stored as an extension record, if the coverage is full.
+    pub checksum_coverage: ChecksumCoverage,
+    /// The extension records not understood by this implementation.
+    ///
+    /// These are kept so they survive rewriting the header. Their total size (including the
+    /// records of the known options) must not exceed `EXTENSIONS_CAPACITY`.
+    pub extensions: Vec<Record>,
+}
+
+impl Default for DiskHeader {
+    fn default() -> DiskHeader {
+        DiskHeader {
+            magic_number: Default::default(),
+            // Fresh headers are of the current version.
+            version_number: VERSION_NUMBER,
+            checksum_algorithm: Default::default(),
+            checksum_flag: false,
+            state_block_address: Default::default(),
+            state_flag: Default::default(),
+            cipher: Default::default(),
+            encryption_parameters: Default::default(),
+            key_sharing: None,
+            error_counters: Default::default(),
+            previous_checksum_algorithm: None,
+            indirection: false,
+            checksum_coverage: ChecksumCoverage::Header,
+            extensions: Vec::new(),
+        }
+    }
 }
 
 impl DiskHeader {
     /// Parse the disk header from some sequence of bytes.
     ///
     /// This will construct it into memory while performing error checks on the header to ensure
-    /// correctness.
-    fn decode(buf: &[u8]) -> Result<DiskHeader, ParseError> {
+    /// correctness. It never panics, regardless of the content and length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8]) -> Result<DiskHeader, ParseError> {
         // Start with some default value, which will be filled out later.
         let mut ret = DiskHeader::default();
+        let mut reader = codec::Reader::new(buf);
 
         // # Introducer Section
         //
@@ -217,14 +473,14 @@ impl DiskHeader {
         // disk image. It is rarely changed unless updates or reformatting happens.
 
         // Load the magic number.
-        ret.magic_number = MagicNumber::try_from(&buf[..8])?;
+        ret.magic_number = MagicNumber::try_from(reader.bytes(8)?)?;
 
         // Load the version number.
-        ret.version_number = LittleEndian::read(buf[8..]);
+        ret.version_number = reader.read_u32()?;
         // Check if the version is compatible. If the higher half doesn't match, there were a
         // breaking change. Otherwise, if the version number is lower or equal to the current
         // version, it's compatible.
-        if ret.version_number >> 16 != VERSION_NUMBER >> 16 || ret.version_number > VERSION_NUMBER {
+        if !is_compatible(ret.version_number) {
             // The version is not compatible; abort.
             return Err(ParseError::IncompatibleVersion);
         }
@@ -234,7 +490,10 @@ impl DiskHeader {
         // This section stores certain configuration options needs to properly load the disk header.
 
         // Load the checksum algorithm config field.
-        ret.checksum_algorithm = ChecksumAlgorithm::try_from(LittleEndian::read(buf[16..]))?;
+        reader.seek(16)?;
+        ret.checksum_algorithm = ChecksumAlgorithm::try_from(reader.read_u16()?)?;
+        // Load the checksum flag.
+        ret.checksum_flag = reader.read_u8()? != 0;
 
         // # State section
         //
@@ -242,69 +501,304 @@ impl DiskHeader {
         // file system.
 
         // Load the state block pointer.
-        ret.state_block_address = clusters::Pointer::new(LittleEndian::read(buf[32..]));
+        reader.seek(32)?;
+        ret.state_block_address = clusters::Pointer::new(reader.read_u64()?);
 
         // Load the state flag.
-        ret.state_flag = StateFlag::from(buf[40])?;
+        ret.state_flag = StateFlag::from(reader.read_u8()?)?;
 
         // # Encryption section
         //
         // This section contains information about how the disk was encrypted, if at all.
 
         // Load the encryption algorithm choice.
-        ret.cipher = Cipher::try_from(LittleEndian::read(buf[64..]))?;
+        reader.seek(64)?;
+        ret.cipher = Cipher::try_from(reader.read_u16()?)?;
 
         // Load the encryption parameters (e.g. salt).
-        ret.encryption_parameters.copy_from_slice(&buf[66..][..16]);
+        ret.encryption_parameters.copy_from_slice(reader.bytes(16)?);
+
+        // Load the key sharing record. A threshold of zero means that the key isn't shared.
+        reader.seek(100)?;
+        let threshold = reader.read_u8()?;
+        let shares = reader.read_u8()?;
+        reader.seek(104)?;
+        let key_check = reader.read_u64()?;
+        if threshold != 0 {
+            if threshold > shares {
+                return Err(ParseError::InvalidKeySharing);
+            }
+
+            ret.key_sharing = Some(KeySharing {
+                threshold: threshold,
+                shares: shares,
+                key_check: key_check,
+            });
+        }
+
+        // # Health section
+        //
+        // This section contains statistics about the health of the disk.
+
+        // Load the error counters.
+        reader.seek(88)?;
+        ret.error_counters.read = reader.read_u32()?;
+        ret.error_counters.write = reader.read_u32()?;
+        ret.error_counters.checksum = reader.read_u32()?;
 
         // Make sure that the checksum of the disk header matches the 8 byte field in the end.
-        let expected = LittleEndian::read(&buf[128..]);
-        let found = ret.checksum_algorithm.hash(&buf[..128]);
+        reader.seek(0)?;
+        let checksummed = reader.peek(128)?;
+        let found = ret.checksum_algorithm.hash(checksummed);
+        reader.seek(128)?;
+        let expected = reader.read_u64()?;
+        if expected != found {
+            // Old images written by 32-bit builds carry legacy SeaHash checksums throughout, so if
+            // the header does, the image is read by that algorithm.
+            if ret.version_number < SEAHASH_FIXED_VERSION
+                && ret.checksum_algorithm == ChecksumAlgorithm::SeaHash
+                && ChecksumAlgorithm::LegacySeaHash.hash(checksummed) == expected {
+                ret.checksum_algorithm = ChecksumAlgorithm::LegacySeaHash;
+            } else {
+                return Err(Error::ChecksumMismatch {
+                    expected: expected,
+                    found: found,
+                });
+            }
+        }
+
+        // # Extension section
+        //
+        // This section contains the extension records, which have their own checksum.
+
+        // Make sure that the checksum of the records matches. An all-zero section (written by
+        // older implementations) holds no records.
+        reader.seek(EXTENSIONS_START)?;
+        let area = reader.bytes(EXTENSIONS_CAPACITY)?;
+        let expected = reader.read_u64()?;
+        if expected == 0 && area.iter().all(|&x| x == 0) {
+            return Ok(ret);
+        }
+        let found = ret.checksum_algorithm.hash(area);
         if expected != found {
-            return Err(Error::ChecksumMismatch {
+            return Err(ParseError::ExtensionChecksumMismatch {
                 expected: expected,
                 found: found,
             });
         }
+
+        // Read the records until the terminator or the end of the section.
+        let mut records = codec::Reader::new(area);
+        while let Ok(kind) = records.read_u16() {
+            if kind == 0 {
+                break;
+            }
+
+            let len = records.read_u16().map_err(|_| ParseError::InvalidExtensions)?;
+            let data = records.bytes(len as usize).map_err(|_| ParseError::InvalidExtensions)?;
+
+            match kind {
+                // Load the previous checksum algorithm.
+                CHECKSUM_MIGRATION_RECORD => {
+                    let previous = codec::Reader::new(data).read_u16().map_err(|_| ParseError::InvalidExtensions)?;
+                    let mut previous = ChecksumAlgorithm::try_from(previous)?;
+                    // The previous algorithm of a legacy image was computed by the same build.
+                    if ret.checksum_algorithm == ChecksumAlgorithm::LegacySeaHash && previous == ChecksumAlgorithm::SeaHash {
+                        previous = ChecksumAlgorithm::LegacySeaHash;
+                    }
+                    ret.previous_checksum_algorithm = Some(previous);
+                },
+                // Note the use of the indirection table.
+                INDIRECTION_RECORD => ret.indirection = true,
+                // Note the full checksum coverage.
+                FULL_COVERAGE_RECORD => ret.checksum_coverage = ChecksumCoverage::Full,
+                // Unknown critical records mean that we can't read the disk correctly.
+                _ if kind & CRITICAL_RECORD != 0 => return Err(ParseError::UnknownCriticalRecord { kind: kind }),
+                // Keep the other unknown records.
+                _ => ret.extensions.push(Record {
+                    kind: kind,
+                    data: data.to_vec(),
+                }),
+            }
+        }
+
+        Ok(ret)
     }
 
     /// Encode the header into a sector-sized buffer.
-    fn encode(&self) -> [u8; disk::SECTOR_SIZE] {
+    pub fn encode(&self) -> [u8; disk::SECTOR_SIZE] {
         // Create a buffer to hold the data.
         let mut buf = [0; disk::SECTOR_SIZE];
 
-        // Write the magic number.
-        buf[..8].copy_from_slice(self.magic_number.into());
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            // Write the magic number.
+            writer.bytes(self.magic_number.into());
+
+            // Write the current version number.
+            writer.write_u32(VERSION_NUMBER);
 
-        // Write the current version number.
-        LittleEndian::write(&mut buf[8..], VERSION_NUMBER);
+            // Write the checksum algorithm.
+            writer.seek(16);
+            writer.write_u16(self.checksum_algorithm as u16);
+            writer.write_u8(self.checksum_flag as u8);
 
-        // Write the checksum algorithm.
-        LittleEndian::write(&mut buf[16..], self.checksum_algorithm as u16);
+            // Write the state block address.
+            writer.seek(32);
+            writer.write_u64(self.state_block_address);
 
-        // Write the state block address.
-        LittleEndian::write(&mut buf[32..], self.state_block_address);
+            // Write the state flag.
+            writer.write_u8(self.state_flag as u8);
 
-        // Write the state flag.
-        buf[40] = self.state_flag as u8;
+            // Write the cipher algorithm.
+            writer.seek(64);
+            writer.write_u16(self.cipher as u16);
 
-        // Write the cipher algorithm.
-        LittleEndian::write(&mut buf[64..], self.cipher as u16);
+            // Write the encryption parameters.
+            writer.bytes(&self.encryption_parameters);
 
-        // Write the encryption parameters.
-        buf[66..][..16].copy_from_slice(self.encryption_parameters);
+            // Write the key sharing record, if any.
+            if let Some(key_sharing) = self.key_sharing {
+                writer.seek(100);
+                writer.write_u8(key_sharing.threshold);
+                writer.write_u8(key_sharing.shares);
+                writer.seek(104);
+                writer.write_u64(key_sharing.key_check);
+            }
+
+            // Write the error counters.
+            writer.seek(88);
+            writer.write_u32(self.error_counters.read);
+            writer.write_u32(self.error_counters.write);
+            writer.write_u32(self.error_counters.checksum);
+        }
 
         // Calculate and write the checksum.
-        LittleEndian::write(&mut buf[128..], self.checksum_algorithm.hash(&buf[..128]));
+        let cksum = self.checksum_algorithm.hash(&buf[..128]);
+        let mut writer = codec::Writer::new(&mut buf);
+        writer.seek(128);
+        writer.write_u64(cksum);
+
+        // Write the extension records. The rest of the section is zeroed, so the terminator
+        // follows implicitly, unless the section is full.
+        writer.seek(EXTENSIONS_START);
+        if let Some(previous) = self.previous_checksum_algorithm {
+            writer.write_u16(CHECKSUM_MIGRATION_RECORD);
+            writer.write_u16(2);
+            writer.write_u16(previous as u16);
+        }
+        if self.indirection {
+            writer.write_u16(INDIRECTION_RECORD);
+            writer.write_u16(0);
+        }
+        if self.checksum_coverage == ChecksumCoverage::Full {
+            writer.write_u16(FULL_COVERAGE_RECORD);
+            writer.write_u16(0);
+        }
+        for record in &self.extensions {
+            assert!(record.kind != 0, "Extension record of reserved type zero.");
+            writer.write_u16(record.kind);
+            writer.write_u16(record.data.len() as u16);
+            writer.bytes(&record.data);
+        }
+        assert!(writer.position() <= EXTENSIONS_CHECKSUM, "Extension records exceed the capacity.");
+
+        // Calculate and write the checksum of the extension records.
+        let cksum = self.checksum_algorithm.hash(&buf[EXTENSIONS_START..EXTENSIONS_CHECKSUM]);
+        let mut writer = codec::Writer::new(&mut buf);
+        writer.seek(EXTENSIONS_CHECKSUM);
+        writer.write_u64(cksum);
 
         buf
     }
 }
 
+quick_error! {
+    /// An error reading a copy of the disk header.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum CopyError {
+        /// The sector of the copy couldn't be read.
+        Disk(err: disk::Error) {
+            from()
+            description("Disk I/O error")
+            display("Disk I/O error: {}", err)
+        }
+        /// The copy couldn't be decoded.
+        Parse(err: ParseError) {
+            from()
+            description("Disk header parsing error")
+            display("Disk header parsing error: {}", err)
+        }
+    }
+}
+
+/// The copies of the disk header on some disk.
+///
+/// Both copies are read and decoded independently, so inspection and repair tools (e.g. fsck) can
+/// tell which one is damaged, and a copy which can't be read doesn't prevent using the other.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Copies {
+    /// The primary copy.
+    pub primary: Result<DiskHeader, CopyError>,
+    /// The backup copy.
+    pub backup: Result<DiskHeader, CopyError>,
+}
+
+impl Copies {
+    /// Read and decode both copies of the disk header.
+    ///
+    /// This never fails; the I/O and decoding errors are kept in the copies.
+    pub fn read<D: Disk>(disk: &mut D) -> Copies {
+        Copies {
+            primary: Copies::read_copy(disk, PRIMARY_HEADER_SECTOR),
+            backup: Copies::read_copy(disk, BACKUP_HEADER_SECTOR),
+        }
+    }
+
+    /// Read and decode the copy in some sector.
+    fn read_copy<D: Disk>(disk: &mut D, sector: Sector) -> Result<DiskHeader, CopyError> {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        disk.read(sector, &mut buf)?;
+
+        Ok(DiskHeader::decode(&buf)?)
+    }
+
+    /// Get the disk header.
+    ///
+    /// The primary copy is preferred. If neither copy can be read and decoded, the error of the
+    /// primary copy is returned.
+    pub fn header(&self) -> Result<DiskHeader, CopyError> {
+        match (&self.primary, &self.backup) {
+            (&Ok(ref header), _) | (&Err(_), &Ok(ref header)) => Ok(header.clone()),
+            (&Err(err), &Err(_)) => Err(err),
+        }
+    }
+
+    /// Are both copies intact and equal?
+    pub fn is_consistent(&self) -> bool {
+        match (&self.primary, &self.backup) {
+            (&Ok(ref primary), &Ok(ref backup)) => primary == backup,
+            _ => false,
+        }
+    }
+}
+
+/// Write the disk header to some disk.
+///
+/// Both copies are written, the primary copy first, so at least one of them is intact if the
+/// writing is interrupted.
+pub fn write<D: Disk>(disk: &mut D, header: &DiskHeader) -> Result<(), disk::Error> {
+    let buf = header.encode();
+
+    disk.write(PRIMARY_HEADER_SECTOR, &buf)?;
+    disk.write(BACKUP_HEADER_SECTOR, &buf)
+}
+
 /// A driver transforming a normal disk into a header-less decrypted disk.
 ///
 /// This makes it more convinient to work with.
-struct Driver<D: Disk> {
+pub struct Driver<D: Disk> {
     /// The cached disk header.
     ///
     /// The disk header contains various very basic information about the disk and how to interact
@@ -317,21 +811,46 @@ struct Driver<D: Disk> {
     disk: D,
     /// The cipher and key.
     cipher: crypto::Cipher,
+    /// The number of errors after which the disk is considered degraded.
+    ///
+    /// `None` means that the disk is never considered degraded.
+    degrade_threshold: Option<u32>,
+    /// Have the error counters changed since the header was last written?
+    error_counters_dirty: bool,
 }
 
 quick_error! {
     /// A driver loading error.
-    enum OpenError {
+    #[derive(Debug)]
+    pub enum OpenError {
         /// The state flag was set to "inconsistent".
         InconsistentState {
             description("The state flag is marked inconsistent.")
         }
+        /// The encryption configuration changed while the disk was open.
+        ///
+        /// The key would have to be obtained again, so the disk must be reopened.
+        EncryptionChanged {
+            description("The encryption configuration changed.")
+        }
+        /// The key couldn't be obtained.
+        Key(err: crypto::KeyError) {
+            from()
+            description("Key error")
+            display("Key error: {}", err)
+        }
         /// A disk header parsing error.
         Parse(err: ParseError) {
             from()
             description("Disk header parsing error")
             display("Disk header parsing error: {}", err)
         }
+        /// Neither copy of the disk header could be read.
+        Header(err: CopyError) {
+            from()
+            description("Disk header error")
+            display("Disk header error: {}", err)
+        }
         /// A disk error.
         Disk(err: disk::Error) {
             from()
@@ -345,14 +864,10 @@ impl<D: Disk> Driver<D> {
     /// Set up the driver from some disk.
     ///
     /// This will load the disk header and construct the driver. It will also set the disk to be in
-    /// open state.
-    fn open(disk: D, password: &[u8]) -> Result<Driver<D>, OpenError> {
-        // Load the disk header into some buffer.
-        let mut header_buf = [0; disk::SECTOR_SIZE];
-        disk.read(0, &mut header_buf)?;
-
-        // Decode the disk header.
-        let mut header = DiskHeader::decode(header_buf)?;
+    /// open state. If the disk is encrypted, `key_provider` is consulted for the secret.
+    pub fn open(mut disk: D, key_provider: &mut crypto::KeyProvider) -> Result<Driver<D>, OpenError> {
+        // Load the disk header, falling back to the backup copy if the primary copy is damaged.
+        let mut header = Copies::read(&mut disk).header()?;
 
         // TODO: Throw a warning if the flag is still in loading state.
         match header.state_flag {
@@ -368,25 +883,55 @@ impl<D: Disk> Driver<D> {
         // Construct the driver.
         let mut driver = Driver {
             // Generate the cipher (key, configuration etc.) from the disk header.
-            cipher: crypto::Cipher(header.cipher, password),
+            cipher: crypto::Cipher::from_provider(&header, key_provider)?,
             header: header,
             disk: disk,
+            degrade_threshold: None,
+            error_counters_dirty: false,
         };
 
-        // Flush the updated header.
-        driver.flush_header();
+        // Flush the updated header. This also restores a damaged copy.
+        driver.flush_header()?;
 
         Ok(driver)
     }
 
+    /// Reload the disk header.
+    ///
+    /// This is used when the disk has been modified externally (e.g. by a repair tool) while the
+    /// driver was open. The header is read again, and marked open. As the key isn't available
+    /// anymore, changes of the encryption configuration are rejected with
+    /// `OpenError::EncryptionChanged`.
+    pub fn reload_header(&mut self) -> Result<(), OpenError> {
+        let mut header = Copies::read(&mut self.disk).header()?;
+
+        match header.state_flag {
+            // The external writer might have closed the disk, or left it open.
+            StateFlag::Closed | StateFlag::Open => header.state_flag = StateFlag::Open,
+            StateFlag::Inconsistent => return Err(OpenError::InconsistentState),
+        }
+        if (header.cipher, header.encryption_parameters, header.key_check)
+            != (self.header.cipher, self.header.encryption_parameters, self.header.key_check) {
+            return Err(OpenError::EncryptionChanged);
+        }
+
+        self.header = header;
+        self.flush_header()?;
+
+        Ok(())
+    }
+
     /// Initialize the disk.
     ///
     /// This stores disk header and makes the disk ready for use, returning the driver.
-    fn init(disk: D) -> Result<Driver<D>, disk::Error> {
+    pub fn init(disk: D) -> Result<Driver<D>, disk::Error> {
         // Construct the driver.
         let mut driver = Driver {
             header: DiskHeader::default(),
             disk: disk,
+            cipher: crypto::Cipher::Identity,
+            degrade_threshold: None,
+            error_counters_dirty: false,
         };
 
         // Flush the default header.
@@ -395,10 +940,140 @@ impl<D: Disk> Driver<D> {
         Ok(driver)
     }
 
+    /// Initialize an encrypted disk whose key is split into shares.
+    ///
+    /// A random volume key is generated for `cipher`, and split into `count` shares, of which any
+    /// `threshold` reconstruct it (see `crypto::Cipher::split_key()`). The key sharing record is
+    /// stored in the disk header, and the driver is returned along with the shares, which must be
+    /// handed to the custodians, as they are never stored on the disk.
+    ///
+    /// This panics if `cipher` is `Cipher::Identity`, or if `threshold` is zero or greater than
+    /// `count`.
+    pub fn init_with_key_sharing<R: FnMut(&mut [u8])>(
+        disk: D,
+        cipher: Cipher,
+        threshold: u8,
+        count: u8,
+        random: R,
+    ) -> Result<(Driver<D>, Vec<shamir::Share>), disk::Error> {
+        assert!(cipher != Cipher::Identity, "Key sharing without encryption.");
+
+        let (key, key_sharing, shares) = crypto::Cipher::split_key(cipher, threshold, count, random);
+
+        let mut header = DiskHeader::default();
+        header.cipher = cipher;
+        header.key_sharing = Some(key_sharing);
+
+        // Construct the driver.
+        let mut driver = Driver {
+            header: header,
+            disk: disk,
+            cipher: key,
+            degrade_threshold: None,
+            error_counters_dirty: false,
+        };
+
+        // Flush the header with the key sharing record.
+        driver.flush_header()?;
+
+        Ok((driver, shares))
+    }
+
     /// Flush the stored disk header.
     fn flush_header(&mut self) -> Result<(), disk::Error> {
-        // Encode and write it to the disk.
-        self.disk.write(0, &self.header.encode())
+        // Encode and write both copies to the disk.
+        write(&mut self.disk, &self.header)?;
+        self.error_counters_dirty = false;
+
+        Ok(())
+    }
+
+    /// Start migrating to a new checksum algorithm.
+    ///
+    /// New writes use `checksum_algorithm`, while the current algorithm is kept as the previous
+    /// one, so the structures not yet migrated can still be verified. The checksum flag is flipped,
+    /// so the migrated structures can be told apart from the others.
+    pub fn begin_checksum_migration(&mut self, checksum_algorithm: ChecksumAlgorithm) -> Result<(), disk::Error> {
+        self.header.previous_checksum_algorithm = Some(self.header.checksum_algorithm);
+        self.header.checksum_algorithm = checksum_algorithm;
+        self.header.checksum_flag = !self.header.checksum_flag;
+        self.flush_header()
+    }
+
+    /// Finish a checksum migration.
+    ///
+    /// This must only be done when every structure carries a checksum of the new algorithm.
+    pub fn end_checksum_migration(&mut self) -> Result<(), disk::Error> {
+        self.header.previous_checksum_algorithm = None;
+        self.flush_header()
+    }
+
+    /// Mark the volume as using the indirection table.
+    ///
+    /// This must be done before the first cluster is moved, so older implementations refuse the
+    /// volume rather than reading moved clusters from their vacated places.
+    pub fn enable_indirection(&mut self) -> Result<(), disk::Error> {
+        self.header.indirection = true;
+        self.flush_header()
+    }
+
+    /// Set the checksum coverage of the data clusters.
+    ///
+    /// This must only be done on a freshly initialized disk, before formatting it (see
+    /// `pages::Manager::format_with_coverage()`), as the existing data clusters aren't converted.
+    pub fn set_checksum_coverage(&mut self, checksum_coverage: ChecksumCoverage) -> Result<(), disk::Error> {
+        self.header.checksum_coverage = checksum_coverage;
+        self.flush_header()
+    }
+
+    /// Get the error counters of the disk.
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.header.error_counters
+    }
+
+    /// Reset the error counters of the disk.
+    ///
+    /// This should be done after the disk has been replaced or the cause has been fixed.
+    pub fn clear_error_counters(&mut self) -> Result<(), disk::Error> {
+        self.header.error_counters = ErrorCounters::default();
+        self.flush_header()
+    }
+
+    /// Count a checksum mismatch in data read from the disk.
+    ///
+    /// Checksums are verified above the driver, so this is called by the page manager.
+    pub fn record_checksum_error(&mut self) {
+        self.header.error_counters.checksum = self.header.error_counters.checksum.saturating_add(1);
+        self.error_counters_dirty = true;
+    }
+
+    /// Have the error counters changed since the disk header was last written?
+    ///
+    /// The driver doesn't write the counters itself, as the errors happen in the middle of the
+    /// writes of the cache. Instead, the page manager queues the header (see `.encode_header()`)
+    /// through the cache on its next commit, ordered with the other writes.
+    pub fn error_counters_dirty(&self) -> bool {
+        self.error_counters_dirty
+    }
+
+    /// Encode the disk header for a write through the cache, and mark the error counters clean.
+    ///
+    /// The buffer must be written to both `PRIMARY_HEADER_SECTOR` and `BACKUP_HEADER_SECTOR`.
+    pub fn encode_header(&mut self) -> Box<[u8]> {
+        self.error_counters_dirty = false;
+        Box::new(self.header.encode())
+    }
+
+    /// Set the number of errors after which the disk is considered degraded.
+    pub fn set_degrade_threshold(&mut self, threshold: Option<u32>) {
+        self.degrade_threshold = threshold;
+    }
+
+    /// Is the disk degraded?
+    ///
+    /// This is the case when the total number of errors has reached the degrade threshold.
+    pub fn is_degraded(&self) -> bool {
+        self.degrade_threshold.map_or(false, |threshold| self.header.error_counters.total() >= threshold)
     }
 }
 
@@ -416,19 +1091,66 @@ impl<D: Disk> Disk for Driver<D> {
         self.disk.number_of_sectors()
     }
 
-    fn write(sector: Sector, offset: SectorOffset, buffer: &[u8]) -> Result<(), Error> {
-        match self.header.cipher {
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), Error> {
+        // The header is written through the cache as well (see `.encode_header()`). The header
+        // held by the driver is the authority, so a queued copy never rolls back the changes made
+        // since it was queued.
+        let header;
+        let buffer = if sector == PRIMARY_HEADER_SECTOR || sector == BACKUP_HEADER_SECTOR {
+            header = self.header.encode();
+            &header[..]
+        } else {
+            buffer
+        };
+
+        let res = match self.header.cipher {
             // Encryption disabled; forward the call to the inner disk.
-            &Cipher::Identity => self.disk.write(sector, offset, buffer),
+            Cipher::Identity => self.disk.write(sector, buffer),
             _ => unimplemented!(),
+        };
+
+        // Count the error, if it is a failure of the device.
+        if let Err(ref err) = res {
+            if err.is_media_error() {
+                self.header.error_counters.write = self.header.error_counters.write.saturating_add(1);
+                self.error_counters_dirty = true;
+            }
         }
+
+        res
     }
-    fn read(sector: Sector, offset: SectorOffset, buffer: &mut [u8]) -> Result<(), Error> {
-        match self.header.cipher {
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), Error> {
+        let res = match self.header.cipher {
             // Encryption disabled; forward the call to the inner disk.
-            &Cipher::Identity => self.disk.read(sector, offset, buffer),
+            Cipher::Identity => self.disk.read(sector, buffer),
             _ => unimplemented!(),
+        };
+
+        // Count the error, if it is a failure of the device.
+        if let Err(ref err) = res {
+            if err.is_media_error() {
+                self.header.error_counters.read = self.header.error_counters.read.saturating_add(1);
+                self.error_counters_dirty = true;
+            }
         }
+
+        res
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.disk.sync()
+    }
+
+    fn geometry(&self) -> disk::Geometry {
+        self.disk.geometry()
+    }
+
+    fn report_zones(&mut self) -> Result<Vec<disk::Zone>, Error> {
+        self.disk.report_zones()
+    }
+
+    fn reset_zone(&mut self, start: Sector) -> Result<(), Error> {
+        self.disk.reset_zone(start)
     }
 }
 
@@ -452,6 +1174,29 @@ mod tests {
 
         header.state_block_address = 500;
         assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.key_sharing = Some(KeySharing {
+            threshold: 3,
+            shares: 5,
+            key_check: 0xDEADBEEF,
+        });
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.error_counters.read = 3;
+        header.error_counters.checksum = 0xFFFFFFFF;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.previous_checksum_algorithm = Some(ChecksumAlgorithm::SeaHash);
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.checksum_flag = true;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.indirection = true;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.checksum_coverage = ChecksumCoverage::Full;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
     }
 
     #[test]
@@ -513,6 +1258,14 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector), Err(Error::UnknownFormat));
     }
 
+    #[test]
+    fn truncated() {
+        let sector = DiskHeader::default().encode();
+
+        assert_eq!(DiskHeader::decode(&[]), Err(Error::Truncated));
+        assert_eq!(DiskHeader::decode(&sector[..130]), Err(Error::Truncated));
+    }
+
     #[test]
     fn incompatible_version() {
         let mut sector = DiskHeader::default().encode();
@@ -533,6 +1286,63 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector), Err(Error::UnknownCipher));
     }
 
+    #[test]
+    fn invalid_key_sharing() {
+        let mut sector = DiskHeader::default().encode();
+        sector[100] = 3;
+        sector[101] = 2;
+        LittleEndian::write(&mut sector[128..], seahash::hash(sector[..128]));
+        assert_eq!(DiskHeader::decode(sector), Err(Error::InvalidKeySharing));
+    }
+
+    #[test]
+    fn key_sharing_init() {
+        /// A key provider handing out fixed shares.
+        struct Shares(Vec<shamir::Share>);
+
+        impl crypto::KeyProvider for Shares {
+            fn provide(&mut self, key_sharing: Option<KeySharing>) -> Result<crypto::Secret, crypto::KeyError> {
+                assert!(key_sharing.is_some());
+                Ok(crypto::Secret::Shares(self.0.clone()))
+            }
+        }
+
+        // A deterministic (and very insecure) randomness source.
+        let mut state = 0u8;
+        let random = |buf: &mut [u8]| for x in buf {
+            state = state.wrapping_mul(31).wrapping_add(17);
+            *x = state;
+        };
+
+        let mut buf = vec![0; DISK_HEADER_SIZE];
+        let mut shares = {
+            let (driver, shares) = Driver::init_with_key_sharing(&mut buf[..], Cipher::Speck128, 2, 3, random).unwrap();
+            assert_eq!(driver.header.cipher, Cipher::Speck128);
+            shares
+        };
+        assert_eq!(shares.len(), 3);
+
+        // Any two shares open the disk.
+        {
+            let driver = Driver::open(&mut buf[..], &mut Shares(vec![shares[2], shares[0]])).unwrap();
+            let key_sharing = driver.header.key_sharing.unwrap();
+            assert_eq!((key_sharing.threshold, key_sharing.shares), (2, 3));
+        }
+
+        // A passphrase doesn't.
+        match Driver::open(&mut buf[..], &mut b"hunter2".to_vec()) {
+            Err(OpenError::Key(crypto::KeyError::WrongKind)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // Neither does a wrong share.
+        shares[1].data[0] ^= 1;
+        match Driver::open(&mut buf[..], &mut Shares(shares[..2].to_vec())) {
+            Err(OpenError::Key(crypto::KeyError::Shares(shamir::Error::KeyMismatch))) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
     #[test]
     fn unknown_state_flag() {
         let mut sector = DiskHeader::default().encode();
@@ -553,6 +1363,98 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector), Err(Error::UnknownChecksumAlgorithm));
     }
 
+    #[test]
+    fn backup_copy() {
+        let mut disk = storage::StorageDisk::new(vec![0; DISK_HEADER_SIZE]);
+        let mut header = DiskHeader::default();
+        header.state_block_address = 500;
+        write(&mut disk, &header).unwrap();
+
+        let copies = Copies::read(&mut disk);
+        assert!(copies.is_consistent());
+        assert_eq!(copies.header(), Ok(header.clone()));
+
+        // Damage the primary copy.
+        disk.write(PRIMARY_HEADER_SECTOR, &[0; disk::SECTOR_SIZE]).unwrap();
+        let copies = Copies::read(&mut disk);
+        assert!(!copies.is_consistent());
+        assert_eq!(copies.primary, Err(CopyError::Parse(ParseError::UnknownFormat)));
+        assert_eq!(copies.header(), Ok(header));
+
+        // Damage the backup copy as well.
+        disk.write(BACKUP_HEADER_SECTOR, &[0; disk::SECTOR_SIZE]).unwrap();
+        assert_eq!(Copies::read(&mut disk).header(), Err(CopyError::Parse(ParseError::UnknownFormat)));
+    }
+
+    #[test]
+    fn unreadable_backup_copy() {
+        let mut disk = storage::StorageDisk::new(vec![0; DISK_HEADER_SIZE]);
+        let header = DiskHeader::default();
+        write(&mut disk, &header).unwrap();
+
+        // Cut off the sector of the backup copy, so reading it fails.
+        let mut storage = disk.into_inner();
+        storage.truncate(DISK_HEADER_SIZE - disk::SECTOR_SIZE);
+        let mut disk = storage::StorageDisk::new(storage);
+
+        // The primary copy is still used.
+        let copies = Copies::read(&mut disk);
+        assert_eq!(copies.backup, Err(CopyError::Disk(disk::Error::OutOfBounds)));
+        assert_eq!(copies.header(), Ok(header));
+
+        // Failing to read both copies fails.
+        let mut disk = storage::StorageDisk::new(Vec::new());
+        assert_eq!(Copies::read(&mut disk).header(), Err(CopyError::Disk(disk::Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn extension_records() {
+        let mut header = DiskHeader::default();
+        header.extensions.push(Record {
+            kind: 7,
+            data: vec![1, 2, 3],
+        });
+        header.extensions.push(Record {
+            kind: 8,
+            data: Vec::new(),
+        });
+        assert_eq!(DiskHeader::decode(&header.encode()).unwrap(), header);
+
+        // Unknown critical records are rejected.
+        header.extensions[1].kind |= CRITICAL_RECORD;
+        assert_eq!(DiskHeader::decode(&header.encode()), Err(ParseError::UnknownCriticalRecord {
+            kind: 8 | CRITICAL_RECORD,
+        }));
+
+        // Records are covered by their own checksum.
+        let mut sector = header.encode();
+        sector[EXTENSIONS_START + 4] = 9;
+        assert!(DiskHeader::decode(&sector).is_err());
+    }
+
+    #[test]
+    fn no_extension_section() {
+        let mut sector = DiskHeader::default().encode();
+        // Older implementations left the section zeroed.
+        for byte in &mut sector[EXTENSIONS_START..] {
+            *byte = 0;
+        }
+
+        assert_eq!(DiskHeader::decode(&sector).unwrap(), DiskHeader::default());
+    }
+
+    #[test]
+    fn malformed_extensions() {
+        let mut sector = DiskHeader::default().encode();
+        // A record running past the end of the section.
+        codec::Writer::new(&mut sector[EXTENSIONS_START..]).write_u16(7);
+        codec::Writer::new(&mut sector[EXTENSIONS_START + 2..]).write_u16(0xFFFF);
+        let cksum = seahash::hash(&sector[EXTENSIONS_START..EXTENSIONS_CHECKSUM]);
+        codec::Writer::new(&mut sector[EXTENSIONS_CHECKSUM..]).write_u64(cksum);
+
+        assert_eq!(DiskHeader::decode(&sector), Err(ParseError::InvalidExtensions));
+    }
+
     #[test]
     fn checksum_mismatch() {
         let mut sector = DiskHeader::default().encode();
@@ -563,6 +1465,98 @@ mod tests {
         sector = DiskHeader::default().encode();
 
         sector[500] = 28;
-        assert_eq!(DiskHeader::decode(sector), Err(Error::ChecksumMismatch));
+        assert_eq!(DiskHeader::decode(sector), Err(Error::ExtensionChecksumMismatch));
+    }
+
+    #[test]
+    fn golden() {
+        // A version 1.0 header (the first version with checksum flags). If this fails, the layout
+        // has changed, and images of this version won't open anymore: Either revert the change,
+        // or bump the version and keep decoding this fixture (unless the bump is a breaking one).
+        let mut sector = [0; disk::SECTOR_SIZE];
+        sector[..8].copy_from_slice(b"TFS fmt ");
+        // The version number (1.0).
+        sector[10] = 1;
+        // The checksum algorithm (SeaHash), and the checksum flag (unset).
+        sector[16] = 1;
+        // The state block address (8), and the state flag (closed).
+        sector[32] = 8;
+        // The checksum.
+        sector[128..136].copy_from_slice(&[0x1f, 0xbb, 0x27, 0x2e, 0xd6, 0xd0, 0x5e, 0xd6]);
+        // An unknown, non-critical extension record of type 0x42.
+        sector[136..142].copy_from_slice(&[0x42, 0x00, 0x02, 0x00, 0x01, 0x02]);
+        // The checksum of the extension records.
+        sector[504..].copy_from_slice(&[0xa1, 0xb6, 0x30, 0x15, 0x6f, 0x6e, 0x3a, 0xd8]);
+
+        let header = DiskHeader::decode(&sector).unwrap();
+        assert_eq!(header.magic_number, MagicNumber::TotalCompatibility);
+        assert_eq!(header.version_number, 1 << 16);
+        assert_eq!(header.checksum_algorithm, ChecksumAlgorithm::SeaHash);
+        assert!(!header.checksum_flag);
+        assert_eq!(header.state_block_address, 8);
+        assert_eq!(header.state_flag, StateFlag::Closed);
+        assert_eq!(header.cipher, Cipher::Identity);
+        assert_eq!(header.key_sharing, None);
+        assert_eq!(header.previous_checksum_algorithm, None);
+        assert_eq!(header.extensions, vec![Record { kind: 0x42, data: vec![1, 2] }]);
+
+        // Rewriting the header must reproduce the image, except for the version, which is bumped
+        // to 1.1 (and thus the checksum).
+        sector[8] = 1;
+        sector[128..136].copy_from_slice(&[0x95, 0xe0, 0x65, 0x1a, 0x4a, 0x0e, 0xca, 0x1e]);
+        assert_eq!(&header.encode()[..], &sector[..]);
+
+        // The version 1.1 image reads the same.
+        let upgraded = DiskHeader::decode(&sector).unwrap();
+        assert_eq!(upgraded.version_number, 1 << 16 | 1);
+        assert_eq!(upgraded.checksum_algorithm, ChecksumAlgorithm::SeaHash);
+        assert_eq!(upgraded.extensions, header.extensions);
+    }
+
+    #[test]
+    fn legacy_seahash() {
+        // The lower halves of the full words are doubled, and the trailing bytes are kept.
+        assert_eq!(legacy_words(&[1, 2, 3, 4, 5, 6, 7, 8, 9]), vec![1, 2, 3, 4, 1, 2, 3, 4, 9]);
+        assert_eq!(ChecksumAlgorithm::LegacySeaHash.hash(&[1, 2, 3]), ChecksumAlgorithm::SeaHash.hash(&[1, 2, 3]));
+
+        // The golden version 1.0 header, as written by a 32-bit build.
+        let mut sector = [0; disk::SECTOR_SIZE];
+        sector[..8].copy_from_slice(b"TFS fmt ");
+        sector[10] = 1;
+        sector[16] = 1;
+        sector[32] = 8;
+        // The legacy checksum.
+        sector[128..136].copy_from_slice(&[0x94, 0xa9, 0xa1, 0xd2, 0x95, 0x6b, 0x60, 0x99]);
+        sector[136..142].copy_from_slice(&[0x42, 0x00, 0x02, 0x00, 0x01, 0x02]);
+        // The legacy checksum of the extension records.
+        sector[504..].copy_from_slice(&[0x2f, 0x3d, 0x18, 0x84, 0xb6, 0x59, 0x2e, 0xf2]);
+
+        let header = DiskHeader::decode(&sector).unwrap();
+        assert_eq!(header.checksum_algorithm, ChecksumAlgorithm::LegacySeaHash);
+        assert_eq!(header.extensions, vec![Record { kind: 0x42, data: vec![1, 2] }]);
+
+        // Rewriting the header stores the algorithm, so the image keeps being read by it.
+        let rewritten = header.encode();
+        assert_eq!(rewritten[16], ChecksumAlgorithm::LegacySeaHash as u8);
+        assert_eq!(DiskHeader::decode(&rewritten).unwrap().checksum_algorithm, ChecksumAlgorithm::LegacySeaHash);
+
+        // Images of the current version never carry legacy checksums unless stated.
+        sector[8] = 1;
+        let cksum = ChecksumAlgorithm::LegacySeaHash.hash(&sector[..128]);
+        codec::Writer::new(&mut sector[128..]).write_u64(cksum);
+        match DiskHeader::decode(&sector) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn format_version_compatibility() {
+        // Bumping the version requires adding golden images of the new version.
+        assert_eq!(format_version(), 1 << 16 | 1);
+        assert!(is_compatible(1 << 16));
+        assert!(is_compatible(format_version()));
+        assert!(!is_compatible(format_version() + 1));
+        assert!(!is_compatible(format_version() ^ 1 << 16));
     }
 }
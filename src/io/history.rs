@@ -0,0 +1,251 @@
+//! The root history.
+//!
+//! Every time the superpage pointer changes, the previous root is recorded in a small on-disk ring
+//! (like the uberblock ring of ZFS). This allows opening the volume read-only at an older
+//! generation, for forensics, or for recovery when the latest root is damaged.
+//!
+//! The page manager keeps the cluster holding the superpage of every generation in the history
+//! allocated, even if its pages are released, until the generation drops out of the history (see
+//! `Manager::release_page()`). The clusters below the superpage are up to the layers above, so an
+//! old generation is only intact as long as the clusters it references haven't been reused by the
+//! allocator. Since allocation is LIFO, recent generations are the most likely to survive.
+//!
+//! The history occupies a single cluster, which is laid out as follows:
+//!
+//! - 8 bytes: The checksum of the rest of the cluster.
+//! - 4 bytes: The index of the oldest entry in the ring.
+//! - 4 bytes: The number of entries in the ring.
+//! - The entries, each 16 bytes: an 8 byte generation number and an 8 byte superpage pointer.
+//!
+//! When the ring is full, the oldest entry is overwritten.
+
+/// The size (in bytes) of the history header.
+const HEADER_SIZE: usize = 16;
+/// The size (in bytes) of an entry.
+const ENTRY_SIZE: usize = 16;
+/// The number of generations the history can hold.
+pub const CAPACITY: usize = (disk::SECTOR_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+
+quick_error! {
+    /// A history parsing error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The buffer is too short to hold the history.
+        Truncated {
+            from(codec::Error)
+            description("Truncated root history.")
+        }
+        /// The checksums doesn't match.
+        ChecksumMismatch {
+            /// The checksum of the data.
+            expected: u64,
+            /// The expected/stored value of the checksum.
+            found: u64,
+        } {
+            display("Mismatching checksums in the root history - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching checksum.")
+        }
+        /// The ring indices are out of range.
+        InvalidRing {
+            description("Invalid root history ring indices.")
+        }
+        /// A superpage pointer is corrupt.
+        InvalidSuperpage {
+            description("Invalid superpage pointer in the root history.")
+        }
+    }
+}
+
+/// A past generation of the volume.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Generation {
+    /// The generation number.
+    pub generation: u64,
+    /// The superpage of this generation.
+    pub superpage: pages::Pointer,
+}
+
+/// The root history.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct History {
+    /// The generations, oldest first.
+    generations: Vec<Generation>,
+    /// Does the history contain generations not yet written to the disk?
+    dirty: bool,
+}
+
+impl History {
+    /// Record a generation.
+    ///
+    /// If the history is full, the oldest generation is dropped and returned.
+    pub fn record(&mut self, generation: Generation) -> Option<Generation> {
+        let dropped = if self.generations.len() == CAPACITY {
+            Some(self.generations.remove(0))
+        } else {
+            None
+        };
+
+        self.generations.push(generation);
+        self.dirty = true;
+
+        dropped
+    }
+
+    /// Get the generations, oldest first.
+    pub fn generations(&self) -> &[Generation] {
+        &self.generations
+    }
+
+    /// Find some generation.
+    pub fn find(&self, generation: u64) -> Option<Generation> {
+        self.generations.iter().find(|x| x.generation == generation).cloned()
+    }
+
+    /// Does the history contain unwritten generations?
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Mark the history as written to the disk.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Decode the history from a cluster.
+    ///
+    /// This never panics, regardless of the content and length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<History, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(disk::SECTOR_SIZE - 8)?) & !header::CHECKSUM_FLAG;
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        // Read the ring indices.
+        let start = reader.read_u32()? as usize;
+        let len = reader.read_u32()? as usize;
+        if start >= CAPACITY || len > CAPACITY {
+            return Err(Error::InvalidRing);
+        }
+
+        // Read the entries, starting with the oldest.
+        let mut history = History::default();
+        for i in 0..len {
+            reader.seek(HEADER_SIZE + (start + i) % CAPACITY * ENTRY_SIZE)?;
+            history.generations.push(Generation {
+                generation: reader.read_u64()?,
+                superpage: pages::Pointer::decode(reader.read_u64()?).ok_or(Error::InvalidSuperpage)?,
+            });
+        }
+
+        Ok(history)
+    }
+
+    /// Encode the history into a cluster.
+    ///
+    /// The entries are written from the start of the ring, so the oldest entry is always at index
+    /// zero.
+    pub fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> Box<[u8]> {
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            // Write the ring indices.
+            writer.seek(8);
+            writer.write_u32(0);
+            writer.write_u32(self.generations.len() as u32);
+
+            // Write the entries.
+            for generation in &self.generations {
+                writer.write_u64(generation.generation);
+                writer.write_u64(generation.superpage.encode());
+            }
+        }
+
+        // Calculate and store the checksum. The checksum flag is left to the caller.
+        let cksum = checksum_algorithm.hash(&buf[8..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation(n: u64) -> Generation {
+        Generation {
+            generation: n,
+            superpage: pages::Pointer::new(cluster::Pointer::new(n + 100).unwrap(), 0),
+        }
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let mut history = History::default();
+        assert_eq!(History::decode(&history.encode(header::ChecksumAlgorithm::SeaHash), header::ChecksumAlgorithm::SeaHash).unwrap().generations(), history.generations());
+
+        history.record(generation(1));
+        history.record(generation(2));
+        assert_eq!(History::decode(&history.encode(header::ChecksumAlgorithm::SeaHash), header::ChecksumAlgorithm::SeaHash).unwrap().generations(), history.generations());
+    }
+
+    #[test]
+    fn ring_overflow() {
+        let mut history = History::default();
+        for i in 0..CAPACITY as u64 {
+            assert_eq!(history.record(generation(i)), None);
+        }
+        for i in 0..3 {
+            assert_eq!(history.record(generation(CAPACITY as u64 + i)), Some(generation(i)));
+        }
+
+        assert_eq!(history.generations().len(), CAPACITY);
+        assert_eq!(history.find(2), None);
+        assert_eq!(history.find(3), Some(generation(3)));
+        assert_eq!(history.find(CAPACITY as u64 + 2), Some(generation(CAPACITY as u64 + 2)));
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut buf = History::default().encode(header::ChecksumAlgorithm::SeaHash);
+
+        assert_eq!(History::decode(&buf[..100], header::ChecksumAlgorithm::SeaHash), Err(Error::Truncated));
+
+        buf[20] = 1;
+        assert!(History::decode(&buf, header::ChecksumAlgorithm::SeaHash).is_err());
+    }
+
+    #[test]
+    fn golden() {
+        // A history as written by format version 1.0 (see `header::format_version()`). If this
+        // fails, the layout has changed, and old images won't open anymore.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        // The checksum, with the checksum flag unset.
+        buf[..8].copy_from_slice(&[0xac, 0x7b, 0x47, 0xfe, 0x97, 0xce, 0xb8, 0x67]);
+        // The ring indices (one entry, starting at zero).
+        buf[12] = 1;
+        // The entry: generation 2, with the superpage being page 1 of cluster 10.
+        buf[16] = 2;
+        buf[24..26].copy_from_slice(&[0x1b, 0xa0]);
+
+        let history = History::decode(&buf, header::ChecksumAlgorithm::SeaHash).unwrap();
+        assert_eq!(history.generations(), &[Generation {
+            generation: 2,
+            superpage: pages::Pointer::new(cluster::Pointer::new(10).unwrap(), 1),
+        }]);
+
+        // Rewriting the history must reproduce the image.
+        assert_eq!(&history.encode(header::ChecksumAlgorithm::SeaHash)[..], &buf[..]);
+    }
+}
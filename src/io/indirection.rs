@@ -0,0 +1,332 @@
+//! The cluster indirection table.
+//!
+//! Page pointers name the cluster holding the page, so moving a cluster (e.g. for defragmentation,
+//! shrinking, or evacuating a device) would mean rewriting every structure pointing to it. The
+//! indirection table decouples the two: The cluster of a page pointer is a _virtual_ cluster,
+//! which the table maps to the physical cluster holding it. Moving a cluster is then a copy and a
+//! table update (see `Manager::queue_move_cluster()`).
+//!
+//! Most clusters are never moved, so the table only holds the moved ones, and a virtual cluster
+//! absent from it is its own physical cluster. Virtual and physical clusters share the numbering:
+//! When a cluster is moved for the first time, its physical cluster is vacated, but its number is
+//! still in use as a virtual cluster, so it can't go back to the freelist. It is a _spare_ instead,
+//! which later moves take as their target first. A cluster number returns to the freelist once it
+//! is neither a virtual nor a physical cluster in use (see `Table::is_used()`).
+//!
+//! The table is stored as a chain of clusters linked from the state block. Every cluster starts
+//! with an 8 byte checksum of the rest of the cluster, followed by the pointer to the next cluster
+//! of the chain (zero ending it), and up to `ENTRIES` pairs of 64-bit virtual and physical
+//! clusters, in ascending order of the virtual clusters, ended by a zero pair or the end of the
+//! cluster. The chain is rewritten copy-on-write when the table changes.
+//!
+//! The table is held in memory as a whole, which is the read-path cache of the map: Resolving a
+//! cluster costs a lookup, and no I/O, and nothing at all while the table is empty.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// The size (in bytes) of the header of a table cluster.
+///
+/// This is the checksum and the link to the next cluster.
+const CLUSTER_HEADER: usize = 16;
+/// The number of entries of a table cluster.
+pub const ENTRIES: usize = (disk::SECTOR_SIZE - CLUSTER_HEADER) / 16;
+
+quick_error! {
+    /// An indirection table decoding error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The table cluster is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated indirection table cluster.")
+        }
+        /// The checksum of the table cluster doesn't match.
+        ChecksumMismatch {
+            /// The checksum stored in the cluster.
+            expected: u64,
+            /// The checksum of the cluster's content.
+            found: u64,
+        } {
+            display("Mismatching indirection table checksum - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching indirection table checksum.")
+        }
+        /// The chain of table clusters links back to one of its clusters.
+        Cycle {
+            /// The cluster linked twice.
+            cluster: u64,
+        } {
+            display("Cycle in the indirection table chain at cluster {:x}.", cluster)
+            description("Cycle in the indirection table chain.")
+        }
+        /// An entry is out of bounds, or maps a cluster to itself or to a taken physical cluster.
+        InvalidEntry {
+            /// The virtual cluster of the entry.
+            cluster: u64,
+        } {
+            display("Invalid indirection table entry of cluster {:x}.", cluster)
+            description("Invalid indirection table entry.")
+        }
+    }
+}
+
+/// A cluster of the stored table.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Chunk {
+    /// The next cluster of the chain, or zero.
+    pub next: u64,
+    /// The `(virtual, physical)` entries of the cluster.
+    pub entries: Vec<(u64, u64)>,
+}
+
+impl Chunk {
+    /// Decode a table cluster.
+    ///
+    /// This verifies the checksum of the cluster `buf`. The entries aren't checked; that is left
+    /// to `Table::from_entries()`. It never panics, regardless of the content and length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<Chunk, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum of the cluster matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(disk::SECTOR_SIZE - 8)?) & !header::CHECKSUM_FLAG;
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        let next = reader.read_u64()?;
+        let mut entries = Vec::new();
+        for _ in 0..ENTRIES {
+            let entry = (reader.read_u64()?, reader.read_u64()?);
+            if entry.0 == 0 {
+                // A zero entry ends the cluster.
+                break;
+            }
+
+            entries.push(entry);
+        }
+
+        Ok(Chunk {
+            next: next,
+            entries: entries,
+        })
+    }
+
+    /// Encode the table cluster into a cluster-sized buffer.
+    ///
+    /// At most `ENTRIES` entries fit.
+    pub fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> Box<[u8]> {
+        assert!(self.entries.len() <= ENTRIES, "Too many entries in an indirection table cluster.");
+
+        // Start with an all-null cluster buffer.
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            writer.seek(8);
+            writer.write_u64(self.next);
+            for &(cluster, physical) in &self.entries {
+                writer.write_u64(cluster);
+                writer.write_u64(physical);
+            }
+        }
+
+        // Checksum the rest of the cluster, and write it at the start of the buffer. The checksum
+        // flag is left to the caller.
+        let cksum = checksum_algorithm.hash(&buf[8..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
+
+        buf
+    }
+}
+
+/// The indirection table.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Table {
+    /// The moved virtual clusters, mapped to their physical clusters.
+    forward: BTreeMap<u64, u64>,
+    /// The physical clusters of the moved clusters, mapped to their virtual clusters.
+    backward: HashMap<u64, u64>,
+}
+
+impl Table {
+    /// Build the table from its stored entries.
+    ///
+    /// Every cluster is checked against `bounds`, and every physical cluster must be taken once,
+    /// and differ from its virtual cluster.
+    pub fn from_entries(entries: &[(u64, u64)], bounds: cluster::Bounds) -> Result<Table, Error> {
+        let mut table = Table::default();
+        for &(cluster, physical) in entries {
+            if bounds.check(cluster).is_none()
+                || bounds.check(physical).is_none()
+                || cluster == physical
+                || table.forward.contains_key(&cluster)
+                || table.backward.contains_key(&physical) {
+                return Err(Error::InvalidEntry { cluster: cluster });
+            }
+
+            table.insert(cluster, physical);
+        }
+
+        Ok(table)
+    }
+
+    /// Get the entries of the table, in ascending order of the virtual clusters.
+    pub fn entries(&self) -> Vec<(u64, u64)> {
+        self.forward.iter().map(|(&cluster, &physical)| (cluster, physical)).collect()
+    }
+
+    /// Is the table empty?
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty()
+    }
+
+    /// Resolve a virtual cluster to its physical cluster.
+    pub fn resolve(&self, cluster: u64) -> u64 {
+        if self.forward.is_empty() {
+            // The common case, a volume where nothing was moved.
+            cluster
+        } else {
+            self.forward.get(&cluster).cloned().unwrap_or(cluster)
+        }
+    }
+
+    /// Has some virtual cluster been moved?
+    pub fn is_moved(&self, cluster: u64) -> bool {
+        self.forward.contains_key(&cluster)
+    }
+
+    /// Get the virtual cluster held by some physical cluster.
+    ///
+    /// `None` is returned if the physical cluster is a spare, i.e. holds nothing.
+    pub fn owner(&self, physical: u64) -> Option<u64> {
+        match self.backward.get(&physical) {
+            Some(&cluster) => Some(cluster),
+            None if self.forward.contains_key(&physical) => None,
+            None => Some(physical),
+        }
+    }
+
+    /// Is some cluster number in use by the table, as a moved virtual cluster or as the physical
+    /// cluster of one?
+    ///
+    /// Freed cluster numbers must only go back to the freelist if they aren't.
+    pub fn is_used(&self, cluster: u64) -> bool {
+        self.forward.contains_key(&cluster) || self.backward.contains_key(&cluster)
+    }
+
+    /// Is some physical cluster a spare?
+    ///
+    /// Spares are the vacated physical clusters of moved clusters, which can't be freed, as their
+    /// numbers are still in use as virtual clusters.
+    pub fn is_spare(&self, physical: u64) -> bool {
+        self.forward.contains_key(&physical) && !self.backward.contains_key(&physical)
+    }
+
+    /// Find a spare satisfying some predicate.
+    pub fn find_spare<F: Fn(u64) -> bool>(&self, f: F) -> Option<u64> {
+        self.forward.keys().cloned().find(|&cluster| !self.backward.contains_key(&cluster) && f(cluster))
+    }
+
+    /// Map a virtual cluster to some physical cluster.
+    ///
+    /// Mapping a cluster to itself removes its entry. The old physical cluster is returned.
+    pub fn insert(&mut self, cluster: u64, physical: u64) -> u64 {
+        let old = self.remove(cluster).unwrap_or(cluster);
+        if cluster != physical {
+            self.forward.insert(cluster, physical);
+            self.backward.insert(physical, cluster);
+        }
+
+        old
+    }
+
+    /// Remove the entry of a virtual cluster.
+    ///
+    /// Its physical cluster is returned, if it was moved.
+    pub fn remove(&mut self, cluster: u64) -> Option<u64> {
+        let physical = self.forward.remove(&cluster)?;
+        self.backward.remove(&physical);
+
+        Some(physical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_round_trip() {
+        let chunk = Chunk {
+            next: 40,
+            entries: vec![(10, 20), (11, 21)],
+        };
+
+        let mut buf = chunk.encode(header::ChecksumAlgorithm::SeaHash);
+        assert_eq!(Chunk::decode(&buf, header::ChecksumAlgorithm::SeaHash), Ok(chunk));
+
+        // Corrupt an entry.
+        buf[CLUSTER_HEADER] ^= 1;
+        match Chunk::decode(&buf, header::ChecksumAlgorithm::SeaHash) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(Chunk::decode(&buf[..100], header::ChecksumAlgorithm::SeaHash), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn spares() {
+        let mut table = Table::default();
+        assert_eq!(table.resolve(10), 10);
+
+        // Moving 10 to 20 vacates 10, which stays in use as a virtual cluster.
+        assert_eq!(table.insert(10, 20), 10);
+        assert_eq!(table.resolve(10), 20);
+        assert!(table.is_used(10) && table.is_used(20));
+        assert!(table.is_spare(10));
+        assert_eq!(table.owner(20), Some(10));
+        assert_eq!(table.owner(10), None);
+        assert_eq!(table.owner(30), Some(30));
+
+        // The spare is the target of the next move.
+        assert_eq!(table.find_spare(|_| true), Some(10));
+        assert_eq!(table.insert(11, 10), 11);
+        assert!(!table.is_spare(10));
+        assert_eq!(table.find_spare(|_| true), Some(11));
+
+        // Moving a cluster back to itself removes its entry.
+        assert_eq!(table.insert(11, 11), 10);
+        assert!(!table.is_moved(11));
+        assert_eq!(table.remove(10), Some(20));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let bounds = cluster::Bounds::new(64, 8);
+
+        assert!(Table::from_entries(&[(10, 20), (11, 21)], bounds).is_ok());
+        for entries in &[
+            // Out of bounds.
+            vec![(10, 64)],
+            vec![(8, 20)],
+            // Mapped to itself.
+            vec![(10, 10)],
+            // A physical cluster taken twice.
+            vec![(10, 20), (11, 20)],
+            // A virtual cluster mapped twice.
+            vec![(10, 20), (10, 21)],
+        ] {
+            match Table::from_entries(entries, bounds) {
+                Err(Error::InvalidEntry { .. }) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -1,2 +1,25 @@
+mod checksums;
+mod codec;
 mod config;
+mod dead_pages;
 mod disk;
+mod events;
+mod history;
+mod indirection;
+mod overlay;
+mod progress;
+mod replication;
+mod shadow;
+mod shamir;
+mod stats;
+mod storage;
+mod thin;
+mod throttle;
+mod trace;
+mod verify;
+mod watermark;
+mod zones;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
@@ -0,0 +1,468 @@
+//! Overlays.
+//!
+//! A golden image (e.g. the root volume of a VM template) is best shared by every instance,
+//! rather than copied for each. This module provides a `Disk` layering a writable upper disk over
+//! a read-only base disk, like a qcow2 backing file: Reads of sectors never written through the
+//! overlay go to the base, while writes copy the sector up to the upper disk first. Clusters are
+//! one sector each, so a page is copied up along with the cluster holding it, and the rest of the
+//! base is never touched.
+//!
+//! Every writable instance has an upper disk of its own, while the base is only ever read, so any
+//! number of overlays can share it. Note that the base must not be opened as a volume of its own
+//! while overlays use it, as opening a volume writes its disk header (see `header::Driver`).
+//!
+//! The upper disk starts with a header sector, followed by the copy-up bitmap, followed by a copy
+//! of the base's address space:
+//!
+//! - The header holds an 8 byte checksum of the rest of the sector, an 8 byte magic number, the
+//!   size of the base (in sectors, 8 bytes), the checksum of the disk header of the base (8 bytes),
+//!   and the checksum of its state block (8 bytes), so an overlay is never opened over the wrong
+//!   base, nor over a base changed since the overlay was created.
+//! - Every sector of the bitmap holds an 8 byte checksum of the rest of the sector, followed by
+//!   the bits of `BITS_PER_SECTOR` consecutive sectors, set if the sector has been copied up.
+//!
+//! The upper disk is as large as the base, plus the metadata. As it only holds the sectors copied
+//! up, it is mostly empty, and can be made compact by thin provisioning (see the `thin` module).
+
+use std::cmp;
+
+use disk::{self, Disk, Sector};
+
+/// The magic number of the header.
+const MAGIC: &[u8; 8] = b"TFSOVLY\x01";
+/// The size (in bytes) of the checksum starting every sector of the metadata.
+const CHECKSUM_SIZE: usize = 8;
+/// The number of sectors covered by a sector of the bitmap.
+const BITS_PER_SECTOR: usize = (disk::SECTOR_SIZE - CHECKSUM_SIZE) * 8;
+
+quick_error! {
+    /// An overlay error.
+    #[derive(Debug)]
+    pub enum Error {
+        /// A disk error.
+        Disk(err: disk::Error) {
+            from()
+            description("Overlay disk I/O error")
+            display("Overlay disk I/O error: {}", err)
+        }
+        /// The metadata is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated overlay metadata.")
+        }
+        /// The magic number doesn't match.
+        ///
+        /// The upper disk isn't an overlay, or was formatted by an incompatible version.
+        UnknownFormat {
+            description("Unknown overlay format.")
+        }
+        /// The checksum of a metadata sector doesn't match.
+        ChecksumMismatch {
+            /// The sector of the upper disk.
+            sector: Sector,
+            /// The checksum stored in the sector.
+            expected: u64,
+            /// The checksum of the sector.
+            found: u64,
+        } {
+            display("Mismatching checksum in overlay sector {} - expected {:x}, found {:x}.", sector, expected, found)
+            description("Mismatching overlay checksum.")
+        }
+        /// The base isn't the one the overlay was created over.
+        ///
+        /// This is also returned if the base was changed since the overlay was created.
+        BaseMismatch {
+            description("Overlay opened over the wrong base.")
+        }
+        /// The disk header of the base is invalid.
+        Base(err: header::ParseError) {
+            from()
+            description("Invalid base disk header")
+            display("Invalid base disk header: {}", err)
+        }
+        /// The upper disk is too small to cover the base.
+        TooSmall {
+            description("Upper disk too small for the base.")
+        }
+    }
+}
+
+/// Checksum a metadata sector, i.e. everything following the checksum.
+fn checksum(buf: &[u8]) -> u64 {
+    seahash::hash(&buf[CHECKSUM_SIZE..])
+}
+
+/// Verify the checksum of metadata sector `sector`.
+fn verify(sector: Sector, buf: &[u8]) -> Result<(), Error> {
+    let expected = codec::Reader::new(buf).read_u64()?;
+    let found = checksum(buf);
+    if expected != found {
+        return Err(Error::ChecksumMismatch {
+            sector: sector,
+            expected: expected,
+            found: found,
+        });
+    }
+
+    Ok(())
+}
+
+/// The identity of a base.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Identity {
+    /// The checksum of the first sector, which holds the disk header of the volume.
+    header: u64,
+    /// The checksum of the state block.
+    ///
+    /// The state block is rewritten on every commit, so this tells a base written to after the
+    /// overlay was created apart from the original, even though its disk header might be unchanged.
+    state_block: u64,
+}
+
+impl Identity {
+    /// Get the identity of a base.
+    fn read<B: Disk>(base: &mut B) -> Result<Identity, Error> {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        base.read(0, &mut buf)?;
+        let header = seahash::hash(&buf);
+        let address = u64::from(header::DiskHeader::decode(&buf)?.state_block_address);
+
+        base.read(address as Sector, &mut buf)?;
+
+        Ok(Identity {
+            header: header,
+            state_block: seahash::hash(&buf),
+        })
+    }
+}
+
+/// An overlay of a writable disk over a read-only base.
+pub struct OverlayDisk<B, D> {
+    /// The base disk.
+    ///
+    /// This is never written.
+    base: B,
+    /// The upper disk.
+    upper: D,
+    /// The size (in sectors) of the base.
+    size: Sector,
+    /// The first sector of the copy of the base's address space on the upper disk.
+    data_start: Sector,
+    /// The copy-up bitmap.
+    ///
+    /// The bits are set for the sectors which have been copied up.
+    bitmap: Vec<u8>,
+    /// The number of sectors copied up.
+    copied: Sector,
+    /// The sectors of the bitmap changed since the last sync.
+    dirty: Vec<bool>,
+}
+
+impl<B: Disk, D: Disk> OverlayDisk<B, D> {
+    /// Create an overlay over `base`, on the upper disk `upper`.
+    ///
+    /// Anything stored on the upper disk is lost. The upper disk must hold at least the sectors
+    /// of the base, plus the metadata.
+    pub fn format(mut base: B, upper: D) -> Result<OverlayDisk<B, D>, Error> {
+        let size = base.number_of_sectors();
+        let identity = Identity::read(&mut base)?;
+        let mut disk = OverlayDisk::new(base, upper, size, vec![0; (size + 7) / 8])?;
+
+        // Write the header.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+            writer.seek(CHECKSUM_SIZE);
+            writer.bytes(MAGIC);
+            writer.write_u64(size as u64);
+            writer.write_u64(identity.header);
+            writer.write_u64(identity.state_block);
+        }
+        let cksum = checksum(&buf);
+        codec::Writer::new(&mut buf).write_u64(cksum);
+        disk.upper.write(0, &buf)?;
+
+        // Write the empty bitmap.
+        for dirty in &mut disk.dirty {
+            *dirty = true;
+        }
+        disk.sync()?;
+
+        Ok(disk)
+    }
+
+    /// Open an overlay over `base`, from the upper disk `upper`.
+    ///
+    /// This loads the bitmap, verifying its checksums. If `base` isn't the base the overlay was
+    /// created over, `Error::BaseMismatch` is returned.
+    pub fn open(mut base: B, mut upper: D) -> Result<OverlayDisk<B, D>, Error> {
+        // Load the header.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        upper.read(0, &mut buf)?;
+        verify(0, &buf)?;
+        let mut reader = codec::Reader::new(&buf);
+        reader.seek(CHECKSUM_SIZE)?;
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(Error::UnknownFormat);
+        }
+        let size = reader.read_u64()? as Sector;
+        let identity = Identity {
+            header: reader.read_u64()?,
+            state_block: reader.read_u64()?,
+        };
+        if size != base.number_of_sectors() || identity != Identity::read(&mut base)? {
+            return Err(Error::BaseMismatch);
+        }
+
+        // Load the bitmap.
+        let len = (size + 7) / 8;
+        let mut bitmap = Vec::with_capacity(len);
+        for sector in 1..1 + (size + BITS_PER_SECTOR - 1) / BITS_PER_SECTOR {
+            upper.read(sector, &mut buf)?;
+            verify(sector, &buf)?;
+
+            let mut reader = codec::Reader::new(&buf);
+            reader.seek(CHECKSUM_SIZE)?;
+            bitmap.extend_from_slice(reader.bytes(cmp::min(BITS_PER_SECTOR / 8, len - bitmap.len()))?);
+        }
+
+        let mut disk = OverlayDisk::new(base, upper, size, bitmap)?;
+        disk.copied = disk.bitmap.iter().map(|byte| byte.count_ones() as Sector).sum();
+
+        Ok(disk)
+    }
+
+    /// Create the overlay from its parts.
+    ///
+    /// Nothing is written.
+    fn new(base: B, upper: D, size: Sector, bitmap: Vec<u8>) -> Result<OverlayDisk<B, D>, Error> {
+        let bitmap_sectors = (size + BITS_PER_SECTOR - 1) / BITS_PER_SECTOR;
+        let data_start = 1 + bitmap_sectors;
+        if upper.number_of_sectors() < data_start + size {
+            return Err(Error::TooSmall);
+        }
+
+        Ok(OverlayDisk {
+            base: base,
+            upper: upper,
+            size: size,
+            data_start: data_start,
+            bitmap: bitmap,
+            copied: 0,
+            dirty: vec![false; bitmap_sectors],
+        })
+    }
+
+    /// Get the base and the upper disk back.
+    ///
+    /// Changes of the bitmap not synced yet are lost.
+    pub fn into_inner(self) -> (B, D) {
+        (self.base, self.upper)
+    }
+
+    /// Get the number of sectors copied up.
+    pub fn copied_sectors(&self) -> Sector {
+        self.copied
+    }
+
+    /// Has some sector been copied up?
+    pub fn is_copied(&self, sector: Sector) -> bool {
+        self.bitmap[sector / 8] & (1 << (sector % 8)) != 0
+    }
+
+    /// Mark a sector as copied up.
+    fn mark_copied(&mut self, sector: Sector) {
+        self.bitmap[sector / 8] |= 1 << (sector % 8);
+        self.copied += 1;
+        self.dirty[sector / BITS_PER_SECTOR] = true;
+    }
+
+    /// Check that an access of `len` bytes to `sector` is within the disk.
+    fn check(&self, sector: Sector, len: usize) -> Result<(), disk::Error> {
+        if sector >= self.size
+            || sector as u64 * disk::SECTOR_SIZE as u64 + len as u64 > self.size as u64 * disk::SECTOR_SIZE as u64 {
+            Err(disk::Error::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<B: Disk, D: Disk> Disk for OverlayDisk<B, D> {
+    fn number_of_sectors(&self) -> Sector {
+        self.size
+    }
+
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), disk::Error> {
+        self.check(sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks(disk::SECTOR_SIZE).enumerate() {
+            let sector = sector + i;
+            if !self.is_copied(sector) {
+                if chunk.len() < disk::SECTOR_SIZE {
+                    // The write only covers part of the sector, so the rest is copied up from the
+                    // base.
+                    let mut buf = [0; disk::SECTOR_SIZE];
+                    self.base.read(sector, &mut buf)?;
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    self.upper.write(self.data_start + sector, &buf)?;
+                } else {
+                    self.upper.write(self.data_start + sector, chunk)?;
+                }
+
+                self.mark_copied(sector);
+            } else {
+                self.upper.write(self.data_start + sector, chunk)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), disk::Error> {
+        self.check(sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks_mut(disk::SECTOR_SIZE).enumerate() {
+            let sector = sector + i;
+            if self.is_copied(sector) {
+                self.upper.read(self.data_start + sector, chunk)?;
+            } else {
+                self.base.read(sector, chunk)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), disk::Error> {
+        if self.dirty.iter().any(|&dirty| dirty) {
+            // The copied sectors must reach stable storage before the bitmap marking them, so a
+            // crash never shadows the base with a sector never written.
+            self.upper.sync()?;
+
+            let dirty: Vec<usize> = self.dirty.iter().enumerate()
+                .filter(|&(_, &dirty)| dirty)
+                .map(|(i, _)| i)
+                .collect();
+            for i in dirty {
+                // Encode the bits of this sector of the bitmap, and checksum them.
+                let mut buf = [0; disk::SECTOR_SIZE];
+                {
+                    let start = i * BITS_PER_SECTOR / 8;
+                    let end = cmp::min(start + BITS_PER_SECTOR / 8, self.bitmap.len());
+                    let mut writer = codec::Writer::new(&mut buf);
+                    writer.seek(CHECKSUM_SIZE);
+                    writer.bytes(&self.bitmap[start..end]);
+                }
+                let cksum = checksum(&buf);
+                codec::Writer::new(&mut buf).write_u64(cksum);
+
+                self.upper.write(1 + i, &buf)?;
+                self.dirty[i] = false;
+            }
+        }
+
+        self.upper.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::StorageDisk;
+
+    /// Create a base of 16 sectors.
+    ///
+    /// The first sector holds a disk header with the state block in sector 1, and every other
+    /// sector is filled with its number.
+    fn base() -> StorageDisk<Vec<u8>> {
+        let mut base = StorageDisk::new(vec![0; 16 * disk::SECTOR_SIZE]);
+        for sector in 1..16 {
+            base.write(sector, &[sector as u8; disk::SECTOR_SIZE]).unwrap();
+        }
+
+        let mut header = header::DiskHeader::default();
+        header.state_block_address = 1;
+        base.write(0, &header.encode()).unwrap();
+
+        base
+    }
+
+    /// Create an upper disk for a base of 16 sectors.
+    ///
+    /// The header and the bitmap take two sectors.
+    fn upper() -> StorageDisk<Vec<u8>> {
+        StorageDisk::new(vec![0; 18 * disk::SECTOR_SIZE])
+    }
+
+    #[test]
+    fn copy_up() {
+        let mut disk = OverlayDisk::format(base(), upper()).unwrap();
+        assert_eq!(disk.number_of_sectors(), 16);
+
+        // Reads go to the base.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        disk.read(3, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[3; disk::SECTOR_SIZE][..]);
+
+        // Writes copy up, partial ones keeping the rest of the sector.
+        disk.write(3, &[0xFF; disk::SECTOR_SIZE]).unwrap();
+        disk.write(4, &[0xFF; 10]).unwrap();
+        assert_eq!(disk.copied_sectors(), 2);
+        assert!(disk.is_copied(3) && disk.is_copied(4) && !disk.is_copied(5));
+
+        let mut buf = vec![0; 3 * disk::SECTOR_SIZE];
+        disk.read(3, &mut buf).unwrap();
+        assert!(buf[..disk::SECTOR_SIZE + 10].iter().all(|&x| x == 0xFF));
+        assert!(buf[disk::SECTOR_SIZE + 10..2 * disk::SECTOR_SIZE].iter().all(|&x| x == 4));
+        assert!(buf[2 * disk::SECTOR_SIZE..].iter().all(|&x| x == 5));
+
+        // The base is left untouched, and the copies survive reopening, once synced.
+        disk.sync().unwrap();
+        let (mut base, upper) = disk.into_inner();
+        let mut buf = [0; disk::SECTOR_SIZE];
+        base.read(3, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[3; disk::SECTOR_SIZE][..]);
+
+        let mut disk = OverlayDisk::open(base, upper).unwrap();
+        assert_eq!(disk.copied_sectors(), 2);
+        disk.read(3, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0xFF; disk::SECTOR_SIZE][..]);
+
+        assert!(disk.read(16, &mut buf).is_err());
+    }
+
+    #[test]
+    fn wrong_base() {
+        // A base with a different disk header.
+        let (_, upper) = OverlayDisk::format(base(), upper()).unwrap().into_inner();
+        let mut other = base();
+        let mut header = header::DiskHeader::default();
+        header.state_block_address = 2;
+        other.write(0, &header.encode()).unwrap();
+        match OverlayDisk::open(other, upper) {
+            Err(Error::BaseMismatch) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A base changed since the overlay was created.
+        let (_, upper) = OverlayDisk::format(base(), upper()).unwrap().into_inner();
+        let mut other = base();
+        other.write(1, &[0xFF; disk::SECTOR_SIZE]).unwrap();
+        match OverlayDisk::open(other, upper) {
+            Err(Error::BaseMismatch) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A base which isn't a volume.
+        match OverlayDisk::format(StorageDisk::new(vec![0; 16 * disk::SECTOR_SIZE]), upper()) {
+            Err(Error::Base(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        match OverlayDisk::format(base(), StorageDisk::new(vec![0; 17 * disk::SECTOR_SIZE])) {
+            Err(Error::TooSmall) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
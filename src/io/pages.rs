@@ -4,18 +4,174 @@
 //! non-obviously, since clusters can hold more than one page at once (compression). Every cluster
 //! will maximize the number of pages held and when it's filled up, a new cluster will be fetched.
 
+use std::{cmp, fmt, io, mem};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
 /// The size (in bytes) of the metacluster header.
 const METACLUSTER_HEADER: usize = 8;
 /// The size (in bytes) of the metacluster's non-header section.
 const METACLUSTER_SIZE: usize = disk::SECTOR - METACLUSTER_HEADER;
+/// The slot of a metacluster linking the duplicate of the next metacluster.
+///
+/// The slot before it stays null, ending the pointers, so a metacluster linking a duplicate holds
+/// two pointers less. See `decode_metacluster()`.
+const METACLUSTER_COPY_SLOT: usize = METACLUSTER_SIZE / cluster::POINTER_SIZE - 1;
 /// The size (in bytes) of the data cluster header.
 const DATA_CLUSTER_HEADER: usize = 2;
 /// The size (in bytes) of the data cluster's non-header section.
 const DATA_CLUSTER_SIZE: usize = disk::SECTOR - DATA_CLUSTER_HEADER;
+/// The size (in bytes) of a page.
+///
+/// A page exactly fills the non-header section of a data cluster, so an uncompressed page can
+/// always be stored in a single cluster.
+pub const PAGE_SIZE: usize = DATA_CLUSTER_SIZE;
+/// The maximal number of pages a single cluster can hold.
+///
+/// This is limited by the size of the index field of the page pointer.
+pub const MAX_PAGES_PER_CLUSTER: usize = 1 << POINTER_INDEX_BITS;
+/// The maximal size (in bytes) of the decompressed data of a cluster.
+///
+/// A cluster decompressing to more than this can't be pointed to in full, so it is treated as
+/// invalid. The decompressor stops as soon as the limit is crossed, so a crafted cluster can't
+/// make it allocate more than this (or spend time proportional to anything but this), whatever
+/// the compression algorithm.
+const MAX_DECOMPRESSED_SIZE: usize = MAX_PAGES_PER_CLUSTER * PAGE_SIZE;
+/// The number of bits of a page pointer used for the checksum nibble.
+const POINTER_CHECKSUM_BITS: u32 = 4;
+/// The number of bits of a page pointer used for the page's index in the cluster.
+const POINTER_INDEX_BITS: u32 = 8;
+/// The number of bits of a page pointer used for the cluster.
+const POINTER_CLUSTER_BITS: u32 = 64 - POINTER_INDEX_BITS - POINTER_CHECKSUM_BITS;
+
+/// A pointer to some page.
+///
+/// Since clusters can hold multiple pages, a page is identified by a cluster and an index into the
+/// decompressed data of said cluster. This is encoded into 64 bits:
+///
+/// - The 52 highest bits are the cluster.
+/// - The next 8 bits are the index of the page in the cluster.
+/// - The 4 lowest bits are the checksum nibble.
+///
+/// This is the page pointer layout of the specification. The checksum nibble is a (weak) checksum
+/// of the other fields, which is verified when the pointer is decoded. This catches most corrupted
+/// pointers before they turn into wild reads.
+///
+/// As opposed to `cluster::Pointer`, which points to a physical cluster on the disk, this points
+/// to a virtual page, and should be used in every API above the page manager.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pointer {
+    /// The cluster holding the page.
+    cluster: cluster::Pointer,
+    /// The index of the page in the (decompressed) cluster.
+    index: u8,
+}
+
+impl Pointer {
+    /// Create a pointer to the `index`'th page of `cluster`.
+    pub fn new(cluster: cluster::Pointer, index: u8) -> Pointer {
+        Pointer {
+            cluster: cluster,
+            index: index,
+        }
+    }
+
+    /// Decode a pointer from its 64-bit representation.
+    ///
+    /// This returns `None` if the cluster is null, too large, or the checksum nibble doesn't
+    /// match.
+    #[deny(clippy::indexing_slicing)]
+    pub fn decode(x: u64) -> Option<Pointer> {
+        // Extract the cluster and the index from the higher bits.
+        let cluster = cluster::Pointer::new(x >> (POINTER_INDEX_BITS + POINTER_CHECKSUM_BITS))?;
+        let ptr = Pointer::new(cluster, (x >> POINTER_CHECKSUM_BITS) as u8);
+
+        // Validate the checksum nibble.
+        if ptr.encode() == x {
+            Some(ptr)
+        } else {
+            None
+        }
+    }
+
+    /// Encode the pointer into its 64-bit representation.
+    ///
+    /// This panics if the cluster doesn't fit in 52 bits, which no supported disk can reach.
+    pub fn encode(self) -> u64 {
+        let cluster = u64::from(self.cluster);
+        assert!(cluster >> POINTER_CLUSTER_BITS == 0, "Cluster pointer too large for a page pointer.");
+
+        // Pack the fields.
+        let x = cluster << (POINTER_INDEX_BITS + POINTER_CHECKSUM_BITS)
+            | (self.index as u64) << POINTER_CHECKSUM_BITS;
+
+        // Calculate the checksum nibble by xor-folding the packed fields.
+        let mut nibble = 0;
+        let mut rest = x >> POINTER_CHECKSUM_BITS;
+        while rest != 0 {
+            nibble ^= rest;
+            rest >>= POINTER_CHECKSUM_BITS;
+        }
+
+        x | nibble & ((1 << POINTER_CHECKSUM_BITS) - 1)
+    }
+
+    /// Get the cluster holding the page.
+    pub fn cluster(self) -> cluster::Pointer {
+        self.cluster
+    }
+
+    /// Get the index of the page in its cluster.
+    pub fn index(self) -> u8 {
+        self.index
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.cluster, self.index)
+    }
+}
+
+/// An extent.
+///
+/// An extent is a buffer of arbitrary length, which is stored in a sequence of pages. The last
+/// page is padded with zeros.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Extent {
+    /// The pages holding the data, in order.
+    pub pages: Vec<Pointer>,
+    /// The length (in bytes) of the data.
+    pub len: usize,
+}
+
+impl Extent {
+    /// Is the extent stored in consecutive clusters?
+    ///
+    /// Reading a contiguous extent only requires a single sequential disk read.
+    pub fn is_contiguous(&self) -> bool {
+        self.pages.windows(2).all(|w| {
+            let (a, b) = (u64::from(w[0].cluster()), u64::from(w[1].cluster()));
+            b == a || b == a + 1
+        })
+    }
+}
 
 quick_error! {
     /// A page management error.
-    enum Error {
+    #[derive(Debug)]
+    pub enum Error {
+        /// The cluster buffer is too short.
+        ///
+        /// Clusters are always read in whole, so this indicates a bug or a bogus image.
+        Truncated {
+            cluster: cluster::Pointer,
+        } {
+            display("Truncated cluster {}.", cluster)
+            description("Truncated cluster.")
+        }
         /// No clusters left in the freelist.
         ///
         /// This is the equivalent to OOM, but with disk space.
@@ -35,6 +191,30 @@ quick_error! {
             display("Mismatching checksums in cluster {} - expected {:x}, found {:x}.", cluster, expected, found)
             description("Mismatching checksum.")
         }
+        /// The checksum of a page doesn't match the checksum provided by the caller.
+        ///
+        /// This indicates that the page was corrupted between the caller and the page manager.
+        PageChecksumMismatch {
+            /// The checksum provided by the caller.
+            expected: u64,
+            /// The checksum of the page.
+            found: u64,
+        } {
+            display("Mismatching page checksums - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching page checksum.")
+        }
+        /// A checksum migration is already in progress.
+        ///
+        /// It must be finished through `Manager::migrate_checksums()` before starting another.
+        MigrationInProgress {
+            description("Checksum migration in progress.")
+        }
+        /// The checksum tree can't be disabled, as the volume has full checksum coverage.
+        ///
+        /// See `header::ChecksumCoverage`.
+        ChecksumTreeRequired {
+            description("The checksum tree is required by the checksum coverage.")
+        }
         /// The compressed data is invalid and cannot be decompressed.
         ///
         /// Multiple reasons exists for this to happen:
@@ -54,258 +234,5849 @@ quick_error! {
             description("Disk I/O error")
             display("Disk I/O error: {}", err)
         }
+        /// A cluster pointer is out of bounds.
+        ///
+        /// The pointer points past the end of the disk or to a reserved cluster, and thus is
+        /// certainly corrupt.
+        PointerOutOfBounds {
+            cluster: u64,
+        } {
+            display("Cluster pointer {:x} is out of bounds.", cluster)
+            description("Cluster pointer out of bounds.")
+        }
+        /// The freelist chain contains a cycle.
+        ///
+        /// A metacluster links (directly or indirectly) back to itself, which would make the
+        /// allocator hand out the same clusters over and over again.
+        FreelistCycle {
+            cluster: cluster::Pointer,
+        } {
+            display("Cycle in the freelist chain at metacluster {}.", cluster)
+            description("Cycle in the freelist chain.")
+        }
+        /// The page pointer points outside its cluster.
+        ///
+        /// The cluster holds fewer pages than the pointer's index suggests.
+        PageOutOfBounds {
+            ptr: Pointer,
+        } {
+            display("Page pointer {} is past the end of its cluster.", ptr)
+            description("Page pointer out of bounds.")
+        }
+        /// The requested generation isn't in the root history.
+        UnknownGeneration {
+            generation: u64,
+        } {
+            display("Generation {} is not in the root history.", generation)
+            description("Unknown generation.")
+        }
+        /// A root history parsing error.
+        History(err: history::Error) {
+            from()
+            description("Root history parsing error")
+            display("Root history parsing error: {}", err)
+        }
+        /// The write would block.
+        ///
+        /// The dirty data in the cache exceeds the limit, and the backpressure mode is
+        /// `Backpressure::WouldBlock`. The caller should retry after flushing the cache.
+        WouldBlock {
+            description("Too much dirty data in the cache.")
+        }
+        /// A state block parsing error.
+        StateBlock(err: state_block::Error) {
+            from()
+            description("State block parsing error")
+            display("State block parsing error: {}", err)
+        }
+        /// The manager is busy.
+        ///
+        /// The operation requires an empty pipeline and no live readers.
+        Busy {
+            description("Pipeline not empty or readers alive.")
+        }
+        /// A disk header error.
+        Header(err: header::OpenError) {
+            from()
+            description("Disk header error")
+            display("Disk header error: {}", err)
+        }
+        /// The savepoint belongs to an earlier transaction.
+        ///
+        /// The transaction was committed or reverted since the savepoint was created.
+        StaleSavepoint {
+            description("Savepoint of an ended transaction.")
+        }
+        /// The operation was cancelled.
+        Cancelled {
+            from(progress::Cancelled)
+            description("Operation cancelled.")
+        }
+        /// The allocation shadow caught an allocator bug.
+        ///
+        /// This is only returned in release builds, as debug builds panic instead. See
+        /// `Manager::set_alloc_check()`.
+        Shadow(err: shadow::Error) {
+            from()
+            description("Allocator bug")
+            display("Allocator bug: {}", err)
+        }
+        /// A zone tracking error.
+        Zone(err: zones::Error) {
+            from()
+            description("Zone tracking error")
+            display("Zone tracking error: {}", err)
+        }
+        /// The indirection table is corrupt.
+        Indirection(err: indirection::Error) {
+            from()
+            description("Corrupt indirection table")
+            display("Corrupt indirection table: {}", err)
+        }
+        /// The dead page list is corrupt.
+        DeadPages(err: dead_pages::Error) {
+            from()
+            description("Corrupt dead page list")
+            display("Corrupt dead page list: {}", err)
+        }
     }
 }
 
-/// A state of a page manager.
-struct State {
-    /// The state block.
-    ///
-    /// The state block stores the state of the file system including allocation state,
-    /// configuration, and more.
-    state_block: state_block::StateBlock,
-    /// The first chunk of the freelist.
-    ///
-    /// This list is used as the allocation primitive of TFS. It is a simple freelist-based extent
-    /// allocation system, but there is one twist: To optimize the data locality, the list is
-    /// unrolled.
+impl Error {
+    /// Is the error transient?
     ///
-    /// The first element (if any) points to _another_ freelist chunk (a "metacluster"), which can
-    /// be used to traverse to the next metacluster when needed.
-    freelist: Vec<cluster::Pointer>,
-    /// The last allocated cluster.
-    last_cluster: cluster::Pointer,
-    /// The last allocated cluster's data decompressed.
+    /// Transient errors might go away by retrying, as opposed to errors caused by corrupt data,
+    /// damaged media, or misuse. Of the disk errors, only interruptions are transient: Reading a
+    /// corrupt sector or past the end of the disk fails the same way every time.
     ///
-    /// This is used for packing pages into the cluster, by appending the new page to this vector
-    /// and then compressing it to see if it fits into the cluster. If it fails to fit, the vector
-    /// is reset and a new cluster is allocated.
-    last_cluster_data: Vec<u8>,
+    /// `Error::WouldBlock` isn't transient either, as retrying doesn't flush the cache, so it would
+    /// merely fail again. It is left to the caller, who chose not to block on the flush.
+    fn is_transient(&self) -> bool {
+        match *self {
+            Error::Disk(disk::Error::Interrupted) => true,
+            _ => false,
+        }
+    }
 }
 
-/// The page manager.
-///
-/// This is the center point of the I/O stack, providing allocation, deallocation, compression,
-/// etc. It manages the clusters (with the page abstraction) and caches the disks.
-struct Manager<D> {
-    /// The inner disk.
-    disk: Cache<header::Driver<D>>,
-    /// The state of the manager.
-    state: State,
-    /// The state of the manager on the time of last cache commit.
+/// The checksum algorithms of a volume.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ChecksumAlgorithms {
+    /// The current algorithm.
+    current: header::ChecksumAlgorithm,
+    /// The checksum flag of the current algorithm.
     ///
-    /// This contains the state of the page manager upon the last cache commit (pipeline flush). It
-    /// is used to roll back the page manager when an error occurs.
-    committed_state: State,
+    /// See `header::DiskHeader::checksum_flag`.
+    flag: bool,
+    /// The previous algorithm, if a checksum migration is in progress.
+    previous: Option<header::ChecksumAlgorithm>,
 }
 
-impl<D: Disk> Manager<D> {
-    /// Commit the transactions in the pipeline to the cache.
+impl ChecksumAlgorithms {
+    /// Get the algorithm of a structure carrying some checksum flag.
     ///
-    /// This runs over the transactions in the pipeline and applies them to the cache. In a sense,
-    /// it can be seen as a form of checkpoint as you can revert to the last commit through
-    /// `.revert()`, as it stores the old state.
-    fn commit(&mut self) {
-        // Update the stored committed state to the current state, which we will commit.
-        self.committed_state = self.state.clone();
-        // Commit the cache pipeline.
-        self.disk.commit();
+    /// During a checksum migration (see `Manager::set_checksum_algorithm()`), the structures not
+    /// yet migrated carry the flag of the previous algorithm. Only the algorithm identified by the
+    /// flag is checked, as trying both would double the rate of corruption going unnoticed. Outside
+    /// of migrations, every structure carries the flag of the current algorithm, so a mismatching
+    /// flag can only be corruption, which the current algorithm catches.
+    fn select(self, flag: bool) -> header::ChecksumAlgorithm {
+        match self.previous {
+            Some(previous) if flag != self.flag => previous,
+            _ => self.current,
+        }
     }
+}
 
-    /// Revert to the last commit.
-    ///
-    /// This will reset the state to after the previous cache commit.
-    fn revert(&mut self) {
-        // Revert the state to when it was committed last time.
-        self.state = self.committed_state.clone();
-        // Revert the cache pipeline.
-        self.disk.revert();
-    }
+/// Get the checksum flag of a structure starting with a 64-bit checksum.
+///
+/// This is the case for every cluster but data clusters. If the buffer is truncated, `false` is
+/// returned, and it is left to the decoder to fail.
+fn checksum_flag(buf: &[u8]) -> bool {
+    codec::Reader::new(buf).read_u64().map_or(false, |cksum| cksum & header::CHECKSUM_FLAG != 0)
+}
 
-    /// Queue a page allocation.
-    ///
-    /// This adds a transaction to the cache pipeline to allocate a page. It can be committed
-    /// through `.commit()`.
-    fn queue_alloc(&mut self, buf: &[u8]) -> Result<Pointer, Error> {
-        // Allocate a buffer for constructing the cluster.
-        let mut cluster = vec![0; DATA_CLUSTER_HEADER];
-        // Extend the last allocated cluster with the new page.
-        self.state.last_cluster_data.extend_from_slice(buf);
-        // Compress the last allocated cluster.
-        self.compress(self.state.last_cluster_data, &mut cluster);
+/// Set the checksum flag of a structure starting with a 64-bit checksum.
+fn set_checksum_flag(buf: &mut [u8], flag: bool) {
+    let cksum = codec::Reader::new(buf).read_u64().expect("Truncated structure.") & !header::CHECKSUM_FLAG;
+    codec::Writer::new(buf).write_u64(if flag { cksum | header::CHECKSUM_FLAG } else { cksum });
+}
 
-        if cluster.len() <= disk::SECTOR_SIZE {
-            // The pages could fit in the cluster.
+/// Get the checksum flag of a data cluster.
+///
+/// If the buffer is truncated, `false` is returned, and it is left to the decoder to fail.
+fn data_cluster_checksum_flag(buf: &[u8]) -> bool {
+    codec::Reader::new(buf).read_u16().map_or(false, |header| header & 2 != 0)
+}
 
-            // Pad with zeros until the sector is full.
-            while cluster.len() != disk::SECTOR_SIZE {
-                cluster.push(0);
-            }
+/// Decode a metacluster.
+///
+/// This verifies the checksum of the metacluster `buf` (stored at `cluster`) and reads its
+/// pointers into `freelist`, which is cleared beforehand. Every pointer is checked against
+/// `bounds`. It never panics, regardless of the content and length of `buf`, and it never reads
+/// more than `METACLUSTER_SIZE` bytes worth of pointers.
+///
+/// On volumes with two copies of metadata, the last slot of the metacluster (past the null
+/// pointer ending the freelist) links the duplicate of the next metacluster, which is returned.
+/// Older implementations stop at the null pointer, and never see it.
+#[deny(clippy::indexing_slicing)]
+pub fn decode_metacluster(
+    cluster: cluster::Pointer,
+    buf: &[u8],
+    checksum_algorithm: header::ChecksumAlgorithm,
+    bounds: cluster::Bounds,
+    freelist: &mut Vec<cluster::Pointer>,
+) -> Result<Option<cluster::Pointer>, Error> {
+    let mut reader = codec::Reader::new(buf);
+    // Convert truncation errors to errors about this cluster.
+    let truncated = |_| Error::Truncated { cluster: cluster };
 
-            // Calculate and write the checksum.
-            LittleEndian::write(&mut cluster, self.checksum(cluster[DATA_CLUSTER_HEADER..]) as u16);
-            // Set the compression flag in the checksum field.
-            cluster[1] <<= 1;
-            cluster[1] |= 1;
+    // Make sure that the checksum of the metacluster matches the 8 byte field in the start. The
+    // checksum flag picks the algorithm, and is up to the caller.
+    let expected = reader.read_u64().map_err(truncated)? & !header::CHECKSUM_FLAG;
+    let found = checksum_algorithm.hash(reader.peek(METACLUSTER_SIZE).map_err(truncated)?) & !header::CHECKSUM_FLAG;
+    if expected != found {
+        return Err(Error::ChecksumMismatch {
+            cluster: cluster,
+            expected: expected,
+            found: found,
+        });
+    }
 
-            // Queue the write of the recompress cluster.
-            self.state.queue(self.state.last_cluster, cluster.into_boxed_slice());
-        } else {
-            // Unable to fit the pages into the cluster.
+    // Clear the old freelist.
+    freelist.clear();
 
-            // Truncate the unusable compressed buffer.
-            cluster.truncate(DATA_CLUSTER_HEADER);
-            // Extend the cluster with the buffer to allocate.
-            cluster.extend_from_slice(&buf);
+    // Read every pointer of the metacluster into the freelist.
+    for _ in 0..METACLUSTER_SIZE / cluster::POINTER_SIZE {
+        let ptr = reader.read_u64().map_err(truncated)?;
+        if ptr == 0 {
+            // A null pointer marks the end of the metacluster.
+            break;
+        }
+
+        // Make sure the pointer is valid, before it is handed out by the allocator.
+        freelist.push(bounds.check(ptr).ok_or(Error::PointerOutOfBounds { cluster: ptr })?);
+    }
+
+    // Read the link to the duplicate of the next metacluster, unless the slot is taken by the
+    // freelist.
+    if freelist.len() >= METACLUSTER_COPY_SLOT {
+        return Ok(None);
+    }
+    reader.seek(METACLUSTER_HEADER + METACLUSTER_COPY_SLOT * cluster::POINTER_SIZE).map_err(truncated)?;
+    match reader.read_u64().map_err(truncated)? {
+        0 => Ok(None),
+        ptr => bounds.check(ptr).map(Some).ok_or(Error::PointerOutOfBounds { cluster: ptr }),
+    }
+}
 
-            // Calculate and write the checksum.
-            LittleEndian::write(&mut cluster, self.checksum(cluster[DATA_CLUSTER_HEADER..]) as u16);
-            // Set the compression flag in the checksum field to zero (i.e. uncompressed).
-            cluster[1] <<= 1;
+/// Encode a metacluster.
+///
+/// This encodes the pointers of `freelist`, and the link to the duplicate of the next
+/// metacluster `next_copy` (if any) into a cluster-sized buffer, which can be decoded by
+/// `decode_metacluster`. The checksum is made by the current algorithm of `algorithms`, and
+/// carries its flag.
+fn encode_metacluster(
+    freelist: &[cluster::Pointer],
+    next_copy: Option<cluster::Pointer>,
+    algorithms: ChecksumAlgorithms,
+) -> Box<[u8]> {
+    // Start with an all-null cluster buffer.
+    let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
 
-            // We cannot fit more into the last allocated cluster, so we clear it.
-            self.state.last_cluster_data.clear();
-            // Update it with the new given data.
-            self.state.last_cluster_data.extend_from_slice(&buf);
+    {
+        let mut writer = codec::Writer::new(&mut buf);
 
-            // Pop from the freelist and set this as the new last allocated cluster.
-            self.state.last_cluster = self.queue_freelist_pop()?;
+        // Write every pointer of the freelist into the buffer.
+        writer.seek(METACLUSTER_HEADER);
+        for &ptr in freelist {
+            writer.write_u64(ptr.into());
+        }
 
-            // Queue a write to the new cluster.
-            self.disk.queue(self.state.last_cluster, cluster);
+        // Write the link to the duplicate of the next metacluster into the last slot.
+        if let Some(copy) = next_copy {
+            assert!(freelist.len() < METACLUSTER_COPY_SLOT, "Too many pointers in a metacluster linking a duplicate.");
+            writer.seek(METACLUSTER_HEADER + METACLUSTER_COPY_SLOT * cluster::POINTER_SIZE);
+            writer.write_u64(copy.into());
         }
     }
 
-    /// Calculate the checksum of some buffer, based on the user configuration.
-    fn checksum(&self, buf: &[u8]) -> u64 {
-        self.state.state_block.checksum_algorithm.hash(buf)
+    // Checksum the non-checksum part of the buffer, and write it at the start of the buffer.
+    let cksum = algorithms.current.hash(&buf[METACLUSTER_HEADER..]);
+    codec::Writer::new(&mut buf).write_u64(cksum);
+    set_checksum_flag(&mut buf, algorithms.flag);
+
+    buf
+}
+
+/// Write the header of a data cluster.
+///
+/// This writes the checksum of the payload (the buffer except the header), made by the current
+/// algorithm of `algorithms`, its checksum flag, and the compression flag into the header of the
+/// data cluster `buf`.
+fn write_data_cluster_header(buf: &mut [u8], algorithms: ChecksumAlgorithms, compressed: bool) {
+    // The lowest bit is the compression flag, the next bit is the checksum flag, and the rest of
+    // the header is the 14 lowest bits of the checksum.
+    let cksum = algorithms.current.hash(&buf[DATA_CLUSTER_HEADER..]) as u16;
+    codec::Writer::new(buf).write_u16(cksum << 2 | (algorithms.flag as u16) << 1 | compressed as u16);
+}
+
+/// Write the header of a data cluster with full checksum coverage.
+///
+/// This is `write_data_cluster_header()` without the checksum, which is left zero, as the cluster
+/// is covered by its checksum tree entry instead (see `header::ChecksumCoverage`).
+fn write_data_cluster_flags(buf: &mut [u8], algorithms: ChecksumAlgorithms, compressed: bool) {
+    codec::Writer::new(buf).write_u16((algorithms.flag as u16) << 1 | compressed as u16);
+}
+
+/// Decode a node of the checksum tree stored at `cluster`.
+///
+/// The checksum is verified by the algorithm its checksum flag selects from `algorithms`.
+fn decode_checksum_node(cluster: cluster::Pointer, buf: &[u8], algorithms: ChecksumAlgorithms) -> Result<checksums::Node, Error> {
+    // Convert the errors to errors about this cluster.
+    checksums::Node::decode(buf, algorithms.select(checksum_flag(buf))).map_err(|err| match err {
+        checksums::Error::Truncated => Error::Truncated { cluster: cluster },
+        checksums::Error::ChecksumMismatch { expected, found } => Error::ChecksumMismatch {
+            cluster: cluster,
+            expected: expected,
+            found: found,
+        },
+    })
+}
+
+/// Decode a data cluster.
+///
+/// This verifies the checksum of the data cluster `buf` (stored at `cluster`), unless `verify` is
+/// false, and decompresses it (if compressed) into `target`. It never panics, regardless of the
+/// content and length of `buf`, and never appends more than `MAX_DECOMPRESSED_SIZE` bytes to
+/// `target`: Data decompressing to more is rejected as `Error::InvalidCompression`, keeping the
+/// data decompressed before the limit was hit.
+#[deny(clippy::indexing_slicing)]
+pub fn decode_data_cluster(
+    cluster: cluster::Pointer,
+    buf: &[u8],
+    checksum_algorithm: header::ChecksumAlgorithm,
+    compression_algorithm: state_block::CompressionAlgorithm,
+    verify: bool,
+    target: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut reader = codec::Reader::new(buf);
+    // Convert truncation errors to errors about this cluster.
+    let truncated = |_| Error::Truncated { cluster: cluster };
+
+    // The lowest bit of the header is the compression flag, the next bit is the checksum flag
+    // (which picks the algorithm, and is up to the caller), and the rest of the header is the 14
+    // lowest bits of the checksum.
+    let header = reader.read_u16().map_err(truncated)?;
+    let compressed = header & 1 != 0;
+    let data = reader.bytes(DATA_CLUSTER_SIZE).map_err(truncated)?;
+
+    // Make sure that the checksum matches.
+    let expected = (header >> 2) as u64;
+    let found = checksum_algorithm.hash(data) & 0x3FFF;
+    if verify && expected != found {
+        return Err(Error::ChecksumMismatch {
+            cluster: cluster,
+            expected: expected,
+            found: found,
+        });
     }
 
-    /// Compress some data based on the compression configuration option.
-    ///
-    /// This compresses `source` into `target` based on the chosen configuration method, defined in
-    /// the state block.
-    fn compress(&self, source: &[u8], target: &mut Vec<u8>) {
-        match self.state.state_block.compression_algorithm {
+    if compressed {
+        match compression_algorithm {
             // Memcpy as a compression algorithm!!!11!
-            CompressionAlgorithm::Identity => target.extend_from_slice(source),
-            // Compress via LZ4.
-            CompressionAlgorithm::Lz4 => lz4_compress::compress_into(source, target),
+            CompressionAlgorithm::Identity => target.extend_from_slice(data),
+            // Decompress from LZ4.
+            CompressionAlgorithm::Lz4 => lz4_compress::decompress_into_bounded(data, target, MAX_DECOMPRESSED_SIZE)
+                .map_err(|_| Error::InvalidCompression { cluster: cluster })?,
         }
+    } else {
+        // The cluster is stored uncompressed.
+        target.extend_from_slice(data);
     }
 
-    /// Decompress some data based on the compression configuration option.
+    Ok(())
+}
+
+/// A pointer to a metadata page.
+///
+/// See `Manager::queue_alloc_metadata()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MetadataPointer {
+    /// The primary copy of the page.
+    pub primary: Pointer,
+    /// The duplicate of the page, if any.
+    pub copy: Option<Pointer>,
+}
+
+/// A page read from a possibly damaged cluster.
+///
+/// See `Manager::read_salvage()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Salvaged {
+    /// The page was recovered intact.
+    Intact(Box<[u8]>),
+    /// The page is lost.
+    Lost,
+}
+
+/// A checksum verification policy.
+///
+/// This controls how much CPU is spent on verifying data read through the page manager.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verification {
+    /// Verify every read.
     ///
-    /// This decompresses `source` into `target` based on the chosen configuration method, defined
-    /// in the state block.
-    fn decompress(&self, source: &[u8], target: &mut Vec<u8>) -> Result<(), Error> {
-        match self.state.state_block.compression_algorithm {
-            // Memcpy as a compression algorithm!!!11!
-            CompressionAlgorithm::Identity => target.extend_from_slice(source),
-            // Decompress from LZ4.
-            CompressionAlgorithm::Lz4 => lz4_compress::decompress_from(source, target)?,
-        }
+    /// Clusters are verified even when served from the in-memory cache, which catches memory
+    /// corruption (e.g. on machines without ECC memory) at the expense of hashing on every read.
+    Paranoid,
+    /// Verify clusters when they're fetched from the disk.
+    ///
+    /// Once a cluster has been verified (or written by ourselves), it is trusted as long as it
+    /// stays in the cache.
+    TrustCache,
+    /// Verify clusters lazily.
+    ///
+    /// Reads aren't verified at all. Instead, the clusters which weren't verified before are
+    /// remembered, and verified in a batch by `Manager::verify_pending()`, e.g. when the volume is
+    /// idle. Damage is thus caught later, and the reads before it may have returned damaged data.
+    Lazy,
+}
 
-        Ok(())
+impl Default for Verification {
+    fn default() -> Verification {
+        Verification::Paranoid
     }
+}
 
-    /// Queue a state block flush.
+/// A durability mode.
+///
+/// This controls whether flushes wait for the data to reach stable storage, and whether
+/// transactions stay atomic.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Durability {
+    /// Sync the disk after every flush, and keep every transaction atomic.
     ///
-    /// This queues a new transaction flushing the state block.
-    fn queue_state_block_flush(&mut self) {
-        self.disk.queue(self.header.state_block_address, self.state.state_block.into());
+    /// Once `.flush()` returns, the committed data survives power loss. In-place updates (see
+    /// `UpdateMode::InPlace`) fall back to copy-on-write.
+    ///
+    /// The sync at the end is the only barrier: The writes of a flush are issued in dependency
+    /// order, but not separated by barriers, so a crash in the middle of a flush is only survived
+    /// intact by devices writing in the order they're told.
+    Full,
+    /// Sync the disk after every flush, but allow in-place updates.
+    ///
+    /// This is `Durability::Full`, except that the pages updated with `UpdateMode::InPlace` give up
+    /// the atomicity of their transaction.
+    InPlace,
+    /// Don't sync the disk.
+    ///
+    /// No barriers are issued at all, and there is no journal to fall back on: The device is free
+    /// to keep the writes in its volatile cache, and to write them back in any order. A crash
+    /// might thus lose recent commits, or leave the state block pointing to clusters which never
+    /// reached the disk, corrupting the volume. This is only meant for throwaway volumes, e.g. in
+    /// CI, where performance matters more than crash safety. In-place updates are allowed.
+    Eventual,
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Durability::Full
     }
+}
 
-    /// Queue a freelist head flush.
+/// How a page update is written.
+///
+/// This is the durability mode of `Manager::queue_update()`, chosen per update, so the layers
+/// above can make it a per-file setting. In-place updates must be allowed by the durability mode
+/// of the manager as well (see `Durability`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpdateMode {
+    /// Write the new version elsewhere, and release the old one on commit.
     ///
-    /// This queues a new transaction flushing the freelist head.
-    fn queue_freelist_head_flush(&mut self) {
-        // Start with an all-null cluster buffer.
-        let mut buf = Box::new([0; disk::SECTOR_SIZE]);
+    /// A crash leaves either the old or the new version, together with the rest of the
+    /// transaction.
+    CopyOnWrite,
+    /// Overwrite the page in place, if possible.
+    ///
+    /// This avoids the allocator churn of hot pages, but gives up the atomicity of the
+    /// transaction for the page: A crash might leave the new version together with the old state
+    /// of the rest of the volume, or vice versa (the sector write itself is atomic on most disks).
+    /// This suits self-describing data, e.g. the blocks of a database write-ahead log.
+    InPlace,
+}
 
-        // Write every pointer of the freelist into the buffer.
-        for (n, i) in self.free.iter().enumerate() {
-            LittleEndian::write(&mut buf[cluster::POINTER_SIZE * i + METACLUSTER_HEADER..], i);
-        }
+/// The space usage of a volume by structure type.
+///
+/// Every count is in clusters. See `Manager::space_by_type()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SpaceUsage {
+    /// The number of clusters on the disk.
+    pub total: u64,
+    /// The number of reserved clusters (the disk header and the state block).
+    pub reserved: u64,
+    /// The number of clusters holding the freelist head, the event log, the root history, the
+    /// indirection table, the dead page list, and the duplicates of metadata.
+    pub metadata: u64,
+    /// The number of metaclusters in the freelist chain, except the head.
+    pub metaclusters: u64,
+    /// The number of free clusters, not counting metaclusters.
+    pub free: u64,
+}
 
-        // Checksum the non-checksum part of the buffer, and write it at the start of the buffer.
-        LittleEndian::write(&mut buf, self.checksum(&buf[2..]));
+impl SpaceUsage {
+    /// Get the number of data clusters.
+    ///
+    /// These are the clusters not accounted for otherwise, i.e. the clusters holding pages. If the
+    /// counts exceed the size of the disk, some clusters were counted twice (e.g. free clusters in
+    /// use), and `None` is returned.
+    pub fn data(&self) -> Option<u64> {
+        self.total
+            .checked_sub(self.reserved)?
+            .checked_sub(self.metadata)?
+            .checked_sub(self.metaclusters)?
+            .checked_sub(self.free)
+    }
+}
 
-        // Queue the write of the updated buffer.
-        self.disk.queue(self.state.state_block.freelist_head, buf);
+impl fmt::Display for SpaceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "reserved:     {}", self.reserved)?;
+        writeln!(f, "metadata:     {}", self.metadata)?;
+        writeln!(f, "metaclusters: {}", self.metaclusters)?;
+        writeln!(f, "free:         {}", self.free)?;
+        match self.data() {
+            Some(data) => writeln!(f, "data:         {}", data)?,
+            None => writeln!(f, "data:         inconsistent")?,
+        }
+        write!(f, "total:        {}", self.total)
     }
+}
 
-    /// Queue a pop from the freelist.
-    ///
-    /// This adds a new transaction to the cache pipeline, which will pop from the top of the
-    /// freelist and return the result.
-    fn queue_freelist_pop(&mut self) -> Result<cluster::Pointer, Error> {
-        // Pop from the metacluster.
-        if let Some(cluster) = self.state.freelist.pop() {
-            if self.freelist.head.free.is_empty() {
-                // The head metacluster is exhausted, so we load the next metacluster (specified to be
-                // the last pointer in the metacluster), i.e. `cluster`. The old metacluster is then
-                // used as the popped cluster.
-                mem::swap(&mut self.state.state_block.cluster, &mut cluster);
-                self.state.load_freelist(self.disk.read(self.state_block.freelist_head)?);
+/// The size of the uncommitted transaction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TransactionSize {
+    /// The number of bytes of allocated pages.
+    pub bytes: u64,
+    /// The number of allocations.
+    pub ops: u64,
+}
 
-                // We've updated the state block, so we queue a flush to the disk.
-                self.queue_state_block_flush();
-            } else {
-                // Since the freelist head was changed after the pop, we queue a flush.
-                self.queue_freelist_head_flush();
-            }
+/// The automatic commit thresholds.
+///
+/// `None` means unlimited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct AutoCommit {
+    /// The maximal number of bytes in a transaction.
+    pub max_bytes: Option<u64>,
+    /// The maximal number of operations in a transaction.
+    pub max_ops: Option<u64>,
+}
 
-            Ok(cluster)
+impl AutoCommit {
+    /// Does a transaction of some size exceed the thresholds?
+    fn is_exceeded(&self, size: TransactionSize) -> bool {
+        self.max_bytes.map_or(false, |max| size.bytes >= max)
+            || self.max_ops.map_or(false, |max| size.ops >= max)
+    }
+}
+
+/// A savepoint in a transaction.
+///
+/// This is obtained through `Manager::savepoint()`, and can be rolled back to through
+/// `Manager::rollback_to()`.
+struct Savepoint {
+    /// The transaction epoch of the savepoint.
+    ///
+    /// The savepoint is only valid within the transaction it was created in.
+    epoch: u64,
+    /// The length of the cache pipeline.
+    pipeline: usize,
+    /// The state of the manager.
+    state: State,
+    /// The size of the transaction.
+    transaction: TransactionSize,
+}
+
+/// A retry policy of transactions.
+///
+/// See `Manager::transact()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// The maximal number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The time to wait before the first retry.
+    ///
+    /// This is doubled on every further retry.
+    pub initial_backoff: Duration,
+    /// The maximal time to wait between attempts.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::new(0, 0),
+            max_backoff: Duration::new(0, 0),
+        }
+    }
+
+    /// Get the time to wait after the failed attempt number `attempt` (starting at one).
+    fn backoff(&self, attempt: u32) -> Duration {
+        // Double the backoff for every attempt, without overflowing.
+        let mut backoff = self.initial_backoff;
+        for _ in 1..attempt {
+            if backoff >= self.max_backoff {
+                break;
+            }
+            backoff = backoff * 2;
+        }
+
+        cmp::min(backoff, self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The outcome of a transaction.
+///
+/// See `Manager::transact()`.
+enum Outcome<T> {
+    /// The transaction was committed.
+    Committed {
+        /// The value returned by the transaction.
+        value: T,
+        /// The number of attempts it took.
+        attempts: u32,
+    },
+    /// The transaction failed and was reverted.
+    Failed {
+        /// The error of the last attempt.
+        err: Error,
+        /// The number of attempts made.
+        attempts: u32,
+    },
+}
+
+/// A memory pressure hook.
+///
+/// It is called with the current memory usage of the cache (in bytes), and returns the number of
+/// bytes to shrink the cache to, if the environment is under memory pressure (e.g. as reported by
+/// the cgroup `memory.pressure` file), and `None` otherwise.
+pub type MemoryPressureHook = Box<FnMut(usize) -> Option<usize>>;
+
+/// A durability callback.
+///
+/// It is called with the generation of the commit once it has reached the disk, or with the error
+/// which prevented it from doing so. See `Manager::commit_notify()`.
+pub type DurabilityCallback = Box<FnMut(Result<u64, &Error>)>;
+
+/// A backpressure mode.
+///
+/// This defines what happens to allocations when the dirty data in the cache exceeds its limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backpressure {
+    /// Block the allocation while flushing the cache.
+    Block,
+    /// Fail the allocation with `Error::WouldBlock`.
+    ///
+    /// This is intended for asynchronous callers, which flush the cache elsewhere.
+    WouldBlock,
+}
+
+/// Create the buffer overwriting a freed cluster.
+///
+/// This returns `None` if the purge method doesn't overwrite the cluster. The pseudorandom data
+/// is derived from the cluster pointer; it doesn't need to be unpredictable, merely unrelated to
+/// the old data.
+fn purge_buffer(method: state_block::PurgeMethod, cluster: cluster::Pointer) -> Option<Box<[u8]>> {
+    match method {
+        state_block::PurgeMethod::None => None,
+        state_block::PurgeMethod::Zero => Some(vec![0; disk::SECTOR_SIZE].into_boxed_slice()),
+        state_block::PurgeMethod::Random => {
+            let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+            // Seed the generator with the cluster (which is never zero, so neither is the seed).
+            let mut state = u64::from(cluster);
+
+            // Fill the buffer with the output of an xorshift generator.
+            for chunk in buf.chunks_mut(8) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                codec::Writer::new(chunk).write_u64(state);
+            }
+
+            Some(buf)
+        },
+    }
+}
+
+/// Classify an error found during verification.
+fn problem(err: &Error) -> verify::Problem {
+    match *err {
+        Error::Disk(_) => verify::Problem::Unreadable,
+        Error::ChecksumMismatch { .. } => verify::Problem::ChecksumMismatch,
+        Error::PointerOutOfBounds { .. } => verify::Problem::PointerOutOfBounds,
+        _ => verify::Problem::Undecodable,
+    }
+}
+
+/// Split a buffer into pages and allocate them as an extent.
+///
+/// `alloc` is called on every page, in order, with the last page padded with zeros.
+fn alloc_extent<F>(buf: &[u8], mut alloc: F) -> Result<Extent, Error>
+    where F: FnMut(&[u8]) -> Result<Pointer, Error> {
+    let mut extent = Extent {
+        pages: Vec::with_capacity((buf.len() + PAGE_SIZE - 1) / PAGE_SIZE),
+        len: buf.len(),
+    };
+
+    for chunk in buf.chunks(PAGE_SIZE) {
+        if chunk.len() == PAGE_SIZE {
+            extent.pages.push(alloc(chunk)?);
         } else {
-            // We ran out of clusters :(.
-            Err(Error::OutOfClusters)
+            // Pad the last page.
+            let mut page = chunk.to_vec();
+            page.resize(PAGE_SIZE, 0);
+            extent.pages.push(alloc(&page)?);
         }
     }
 
-    /// Queue a push to the freelist.
+    Ok(extent)
+}
+
+/// Find a run of `n` consecutive free clusters, starting at an aligned cluster.
+///
+/// The run is searched for among the clusters of the freelist head `freelist`, except for the
+/// bottom one, which links the next metacluster. Clusters are one sector each, so they are aligned
+/// as sectors. The lowest such run is returned, if any.
+fn find_aligned_run(freelist: &[cluster::Pointer], n: usize, geometry: disk::Geometry) -> Option<u64> {
+    let free: HashSet<u64> = freelist.iter().skip(1).map(|&cluster| cluster.into()).collect();
+    // Every run starts at the aligned cluster at or after some free cluster.
+    free.iter()
+        .filter_map(|&cluster| geometry.align_up(cluster as disk::Sector))
+        .map(|start| start as u64)
+        .filter(|&start| (0..n as u64).all(|i| free.contains(&(start + i))))
+        .min()
+}
+
+/// Extract a page from the decompressed data of its cluster.
+#[deny(clippy::indexing_slicing)]
+fn extract_page(ptr: Pointer, data: &[u8]) -> Result<Box<[u8]>, Error> {
+    let start = ptr.index() as usize * PAGE_SIZE;
+    data.get(start..start + PAGE_SIZE)
+        .map(|page| page.to_vec().into_boxed_slice())
+        .ok_or(Error::PageOutOfBounds { ptr: ptr })
+}
+
+/// A cycle detector for the freelist chain.
+///
+/// This implements Brent's algorithm, which detects cycles in constant memory, and with each
+/// metacluster being visited at most a constant number of times more than without cycle
+/// detection. The "tortoise" is teleported to the "hare" every time the number of steps hits a
+/// power of two, so if the hare ever meets the tortoise, there is a cycle.
+#[derive(Clone, Default)]
+struct CycleDetector {
+    /// The metacluster the tortoise sits on.
+    tortoise: Option<cluster::Pointer>,
+    /// The number of steps since the tortoise was teleported.
+    steps: u64,
+    /// The number of steps before the tortoise is teleported next.
+    power: u64,
+}
+
+impl CycleDetector {
+    /// Step to some metacluster.
     ///
-    /// This adds a new transaction to the cache pipeline, which will push some free cluster to the
-    /// top of the freelist.
-    fn queue_freelist_push(&mut self, cluster: cluster::Pointer) -> Result<(), Error> {
-        // If enabled, purge the data of the cluster.
-        if cfg!(feature = "security") {
-            self.disk.queue(cluster, vec![0; disk::SECTOR_SIZE].into_boxed_slice());
+    /// This returns an error if the metacluster was seen before, i.e. there is a cycle.
+    fn step(&mut self, metacluster: cluster::Pointer) -> Result<(), Error> {
+        if self.tortoise == Some(metacluster) {
+            // The hare has caught up with the tortoise.
+            return Err(Error::FreelistCycle { cluster: metacluster });
         }
 
-        if self.state.freelist.len() == METACLUSTER_SIZE / cluster::POINTER_SIZE {
-            // The freelist head is full, and therefore we use following algorithm:
-            //
-            // 1. Create a new metacluster at `cluster`.
-            // 2. Link said metacluster to the old metacluster.
-            // 3. Queue a flush.
+        self.steps += 1;
+        if self.steps >= self.power {
+            // Teleport the tortoise to the hare, and double the distance.
+            self.tortoise = Some(metacluster);
+            self.power = cmp::max(1, self.power * 2);
+            self.steps = 0;
+        }
 
-            // Clear the in-memory freelist head mirror.
-            self.state.freelist.clear();
-            // Put the link to the old freelist head into the new metacluster.
-            self.state.freelist.push(state_block.freelist_head);
+        Ok(())
+    }
 
-            // Update the freelist head pointer to point to the new metacluster.
-            self.state.state_block.freelist_head = cluster;
-            // Queue a flush of the new freelist head. This won't leave the system in an
-            // inconsistent state as it merely creates a new metacluster, which is first linked
-            // later. If the state block flush fails, the metacluster will merely be an orphan
-            // cluster, and therefore simply leaked space.
-            self.queue_freelist_head_flush();
-            // Queue a flush of the state block (or, in particular, the freelist head pointer).
-            // This is completely consistent as the freelist head must flush before, thus rendering
-            // the pointed cluster a valid metacluster.
-            self.queue_state_block_flush();
+    /// Reset the detector.
+    ///
+    /// This must be done whenever a new metacluster is linked into the chain, as the chain might
+    /// then legitimately revisit metaclusters.
+    fn reset(&mut self) {
+        *self = CycleDetector::default();
+    }
+}
+
+/// An identifier of a packing stream.
+///
+/// See `Manager::allocator()`.
+pub type StreamId = u32;
+
+/// The stream used by `Manager::queue_alloc()`.
+const DEFAULT_STREAM: StreamId = 0;
+/// The streams used for duplicates of metadata pages.
+///
+/// Keeping the duplicates in streams of their own ensures that they never share a cluster with
+/// their primary copies. The first stream places its clusters in the lower half of the volume,
+/// and the second in the upper half (see `Half`). These streams are reserved.
+const METADATA_COPY_STREAMS: [StreamId; 2] = [!0 - 1, !0];
+
+/// A half of the volume.
+///
+/// The duplicates of metadata are placed in the other half than their primary copies (see
+/// `Manager::copy_half()`), so damage to a region of the disk rarely takes out both.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Half {
+    /// The clusters below the middle of the volume.
+    Lower,
+    /// The clusters from the middle of the volume on.
+    Upper,
+}
+
+impl Half {
+    /// Get the half of the volume the clusters of a stream are placed in, if any.
+    ///
+    /// This is only the case for the streams of metadata duplicates.
+    fn of_stream(stream: StreamId) -> Option<Half> {
+        match METADATA_COPY_STREAMS.iter().position(|&id| id == stream) {
+            Some(0) => Some(Half::Lower),
+            Some(_) => Some(Half::Upper),
+            None => None,
+        }
+    }
+}
+
+/// A packing stream.
+///
+/// Pages allocated in the same stream are packed into the same clusters. Keeping separate streams
+/// for unrelated data (e.g. different files) improves the locality, and makes it more likely that
+/// whole clusters are freed together.
+#[derive(Clone, Default)]
+struct Stream {
+    /// The last allocated cluster.
+    ///
+    /// This is `None` if no cluster has been allocated in this stream since the volume was opened.
+    last_cluster: Option<cluster::Pointer>,
+    /// The last allocated cluster's data decompressed.
+    ///
+    /// This is used for packing pages into the cluster, by appending the new page to this vector
+    /// and then compressing it to see if it fits into the cluster. If it fails to fit, the vector
+    /// is reset and a new cluster is allocated.
+    last_cluster_data: Vec<u8>,
+    /// The size (in bytes) of the last allocated cluster's payload as stored.
+    ///
+    /// This is the compressed size, if the cluster holds several pages. It is kept for the
+    /// compression statistics (see `stats::Packing::bytes_saved()`).
+    last_cluster_size: usize,
+}
+
+/// A state of a page manager.
+struct State {
+    /// The state block.
+    ///
+    /// The state block stores the state of the file system including allocation state,
+    /// configuration, and more.
+    state_block: state_block::StateBlock,
+    /// The first chunk of the freelist.
+    ///
+    /// This list is used as the allocation primitive of TFS. It is a simple freelist-based extent
+    /// allocation system, but there is one twist: To optimize the data locality, the list is
+    /// unrolled.
+    ///
+    /// The first element (if any) points to _another_ freelist chunk (a "metacluster"), which can
+    /// be used to traverse to the next metacluster when needed.
+    freelist: Vec<cluster::Pointer>,
+    /// The duplicate of the metacluster linked by the freelist head, if any.
+    ///
+    /// This is stored in the last slot of the head (see `decode_metacluster()`).
+    freelist_next_copy: Option<cluster::Pointer>,
+    /// Does the freelist head differ from its metacluster on the disk?
+    ///
+    /// Pushes and pops only change the in-memory head, which is flushed once on commit, rather
+    /// than on every operation.
+    freelist_dirty: bool,
+    /// Does the state block differ from its copy on the disk?
+    ///
+    /// This is set when a full push makes the pushed cluster the new head metacluster, and, like
+    /// the head itself, the state block is then flushed on commit. Deferring is safe there, as
+    /// the old head stays a metacluster. When a pop exhausts the head, however, the old head is
+    /// reused right away, so the state block is flushed immediately (see
+    /// `.queue_freelist_pop()`), as are other changes of the state block.
+    state_block_dirty: bool,
+    /// The open packing streams.
+    ///
+    /// Streams are created on their first allocation, and live until closed.
+    streams: HashMap<StreamId, Stream>,
+    /// The cycle detector for loading metaclusters.
+    ///
+    /// This is stepped every time a new metacluster is loaded into the freelist head, so a
+    /// corrupted chain is detected rather than handing out the same clusters forever.
+    freelist_cycle: CycleDetector,
+    /// The number of free clusters.
+    ///
+    /// Counting requires walking the whole freelist, so this is `None` until it is needed.
+    free_clusters: Option<u64>,
+    /// The freed clusters, which can't be reused yet.
+    ///
+    /// While readers (see `Manager::reader()`) are alive, freed clusters might still be part of
+    /// their snapshot, so they are held back until the last reader is dropped. Every cluster is
+    /// kept along with the subsystem freeing it, for the trace.
+    deferred_frees: Vec<(cluster::Pointer, trace::Subsystem)>,
+    /// The freed clusters to purge on commit.
+    ///
+    /// The state block of the last commit might still reference them, so the purge writes are
+    /// queued after the state block is flushed (see `Manager::queue_purges()`). Clusters reused in
+    /// the meantime are dropped, as they're overwritten anyway.
+    purges: HashSet<cluster::Pointer>,
+    /// The pages superseded in the current transaction.
+    ///
+    /// These are the old versions of the pages rewritten through `Manager::queue_rewrite()`.
+    /// They stay intact until the transaction is committed, and are released by the commit.
+    rewritten: Vec<Pointer>,
+    /// The released pages of clusters which still hold live pages.
+    ///
+    /// A cluster is freed once every page in it is released. The dead pages are stored in the dead
+    /// page list on commit (see the `dead_pages` module), so they survive remounting.
+    dead_pages: HashMap<cluster::Pointer, HashSet<u8>>,
+    /// Do the dead pages differ from the list on the disk?
+    dead_pages_dirty: bool,
+    /// The clusters of the stored dead page list chain.
+    dead_pages_clusters: Vec<cluster::Pointer>,
+    /// The checksum tree entries changed in the current transaction.
+    ///
+    /// These are the entries of the data clusters written (or freed, with a zero entry) since the
+    /// last commit, which applies them to the tree (see `Manager::queue_checksum_tree_flush()`).
+    checksum_updates: HashMap<cluster::Pointer, u64>,
+    /// The zones of the disk, if it is zoned.
+    ///
+    /// Data clusters are appended to the zones rather than popped from the freelist (see
+    /// `Manager::queue_alloc_cluster()`). The zones are part of the state, so a revert rewinds
+    /// their write pointers along with the allocations.
+    zones: Option<zones::Zones>,
+    /// The cluster indirection table.
+    ///
+    /// Data clusters are read from the physical clusters the table maps them to (see the
+    /// `indirection` module). The table is shared with the readers of the committed state, and
+    /// copied when changed.
+    indirection: Arc<indirection::Table>,
+    /// Does the indirection table differ from its chain on the disk?
+    indirection_dirty: bool,
+    /// The clusters of the stored indirection table chain.
+    indirection_clusters: Vec<cluster::Pointer>,
+    /// The physical clusters vacated by moves in the current transaction.
+    ///
+    /// Like the rewritten pages, they stay intact until the transaction is committed, and are
+    /// freed by the commit (see `Manager::queue_move_cluster()`).
+    vacated: Vec<cluster::Pointer>,
+}
+
+impl State {
+    /// Create the state of a freshly loaded state block.
+    ///
+    /// The freelist is empty until loaded, and no streams are open.
+    fn new(state_block: state_block::StateBlock) -> State {
+        State {
+            freelist: Vec::new(),
+            freelist_next_copy: None,
+            freelist_dirty: false,
+            state_block_dirty: false,
+            streams: HashMap::new(),
+            freelist_cycle: CycleDetector::default(),
+            free_clusters: None,
+            deferred_frees: Vec::new(),
+            purges: HashSet::new(),
+            rewritten: Vec::new(),
+            dead_pages: HashMap::new(),
+            dead_pages_dirty: false,
+            dead_pages_clusters: Vec::new(),
+            checksum_updates: HashMap::new(),
+            zones: None,
+            indirection: Arc::new(indirection::Table::default()),
+            indirection_dirty: false,
+            indirection_clusters: Vec::new(),
+            vacated: Vec::new(),
+            state_block: state_block,
+        }
+    }
+}
+
+/// Load the state block.
+///
+/// The checksum is verified with the algorithm its checksum flag identifies, as the state block
+/// might not be migrated yet, if a checksum migration is in progress.
+fn load_state_block<D: Disk>(disk: &mut Cache<header::Driver<D>>, bounds: cluster::Bounds) -> Result<state_block::StateBlock, Error> {
+    let header = disk.inner().header.clone();
+    let algorithms = ChecksumAlgorithms {
+        current: header.checksum_algorithm,
+        flag: header.checksum_flag,
+        previous: header.previous_checksum_algorithm,
+    };
+
+    let buf = disk.read(header.state_block_address)?;
+    state_block::StateBlock::decode(buf, algorithms.select(checksum_flag(buf)), bounds).map_err(Error::from)
+}
+
+/// The page manager.
+///
+/// This is the center point of the I/O stack, providing allocation, deallocation, compression,
+/// etc. It manages the clusters (with the page abstraction) and caches the disks.
+pub struct Manager<D> {
+    /// The inner disk.
+    disk: Cache<header::Driver<D>>,
+    /// The state of the manager.
+    state: State,
+    /// The state of the manager on the time of last cache commit.
+    ///
+    /// This contains the state of the page manager upon the last cache commit (pipeline flush). It
+    /// is used to roll back the page manager when an error occurs.
+    committed_state: State,
+    /// The bounds of valid cluster pointers.
+    ///
+    /// Every pointer read from the disk is checked against these.
+    bounds: cluster::Bounds,
+    /// The low-space watermarks.
+    watermarks: watermark::Watermarks,
+    /// The health event log.
+    ///
+    /// This is kept outside the state, so events aren't lost on revert. It is written to the disk
+    /// on commit.
+    events: events::Log,
+    /// The root history.
+    ///
+    /// Like the event log, this is kept outside the state and written on commit.
+    history: history::History,
+    /// The checksum verification policy of page reads.
+    verification: Verification,
+    /// The clusters read without verification, with `Verification::Lazy`.
+    ///
+    /// Like the event log, this is kept outside the state, as the reads aren't undone on revert.
+    unverified: HashSet<cluster::Pointer>,
+    /// The durability mode of flushes.
+    durability: Durability,
+    /// The size of the uncommitted transaction.
+    transaction: TransactionSize,
+    /// The transaction epoch.
+    ///
+    /// This is incremented whenever a transaction ends (by commit or revert), invalidating its
+    /// savepoints.
+    epoch: u64,
+    /// The automatic commit thresholds.
+    auto_commit: AutoCommit,
+    /// The limit (in bytes) of dirty data in the cache, if any.
+    ///
+    /// When it is exceeded, allocations are subject to backpressure.
+    dirty_limit: Option<usize>,
+    /// The backpressure mode.
+    backpressure: Backpressure,
+    /// The runtime statistics.
+    stats: stats::Stats,
+    /// The replicator, if the volume is replicated.
+    replicator: Option<replication::Replicator>,
+    /// Is the volume opened in rescue mode?
+    ///
+    /// See `Manager::open_rescue()`.
+    rescue: bool,
+    /// The memory pressure hook, if any.
+    ///
+    /// This is polled on every commit. If it returns a target (in bytes), the manager shrinks
+    /// towards it.
+    memory_pressure: Option<MemoryPressureHook>,
+    /// The callbacks waiting for their commits to become durable.
+    ///
+    /// These are called on the next flush, with the generation of their commit.
+    durability_waiters: Vec<(u64, DurabilityCallback)>,
+    /// The trace recorder, if tracing.
+    tracer: Option<trace::Recorder>,
+    /// The allocation shadow, if allocations are checked.
+    ///
+    /// Like the event log, this is kept outside the state, as cloning it on every savepoint would
+    /// be too costly. It is cleared instead when the state is rolled back.
+    shadow: Option<shadow::Shadow>,
+    /// The pin shared with the readers.
+    ///
+    /// Every reader holds a reference, so there are live readers if the count is above one.
+    readers: Arc<()>,
+}
+
+impl<D: Disk> Manager<D> {
+    /// Open the page manager from some driver.
+    ///
+    /// This loads the state block and the freelist head from the disk. If the volume is sealed,
+    /// the cache is set to be read-only.
+    fn open(driver: header::Driver<D>) -> Result<Manager<D>, Error> {
+        Manager::open_mode(driver, false)
+    }
+
+    /// Open the page manager in rescue mode.
+    ///
+    /// This is intended for recovering data from damaged volumes. The volume is opened read-only,
+    /// and damaged structures are tolerated as far as possible:
+    ///
+    /// - A damaged freelist is ignored (nothing can be allocated anyway).
+    /// - Pages with mismatching checksums are returned anyway, after the mismatch is recorded in
+    ///   the event log.
+    /// - Clusters which fail to decompress yield the pages decompressed before the failure.
+    ///
+    /// Only the state block must be intact, as nothing can be found without it.
+    fn open_rescue(driver: header::Driver<D>) -> Result<Manager<D>, Error> {
+        Manager::open_mode(driver, true)
+    }
+
+    /// Open the page manager, possibly in rescue mode.
+    fn open_mode(driver: header::Driver<D>, rescue: bool) -> Result<Manager<D>, Error> {
+        // Calculate the bounds of cluster pointers on this disk.
+        let bounds = cluster::Bounds::new(driver.number_of_sectors() as u64, driver.header.state_block_address.into());
+        // Wrap the driver in a cache.
+        let mut disk = Cache::new(driver);
+
+        // Load the state block.
+        let state_block = load_state_block(&mut disk, bounds)?;
+
+        // Reject every write, if the volume is sealed or being rescued.
+        disk.set_read_only(state_block.sealed || rescue);
+
+        let mut manager = Manager::new(disk, bounds, state_block, rescue);
+        manager.load_metadata()?;
+
+        Ok(manager)
+    }
+
+    /// Format a fresh volume.
+    ///
+    /// This writes an empty volume to the disk of `driver`, which should be freshly initialized
+    /// (see `header::Driver::init()`). The superpage is an empty page, to be filled by the layers
+    /// above, and every other cluster (but the reserved ones) goes on the freelist, the lowest
+    /// allocated first. The volume is committed and flushed, so it can be opened afterwards.
+    ///
+    /// On zoned disks, every zone is reset, and only the clusters outside of the zones (i.e. in
+    /// the conventional zones) go on the freelist, as the clusters of the zones are handed out by
+    /// appending (see `.queue_alloc_cluster()`).
+    ///
+    /// If the disk has no room for the freelist head and the superpage, `Error::OutOfClusters` is
+    /// returned.
+    ///
+    /// The data clusters are covered by their header checksums. See `.format_with_coverage()`.
+    pub fn format(driver: header::Driver<D>) -> Result<Manager<D>, Error> {
+        Manager::format_with_coverage(driver, header::ChecksumCoverage::Header)
+    }
+
+    /// Format a fresh volume with some checksum coverage of the data clusters.
+    ///
+    /// This is like `.format()`, but with full coverage, the data clusters are covered by
+    /// full-width checksums in the checksum tree (see the `checksums` module) rather than by their
+    /// header checksums. The tree is enabled from the start, so every data cluster has an entry,
+    /// and can't be disabled. The coverage is recorded in the disk header, and can't be changed
+    /// afterwards.
+    pub fn format_with_coverage(
+        mut driver: header::Driver<D>,
+        checksum_coverage: header::ChecksumCoverage,
+    ) -> Result<Manager<D>, Error> {
+        driver.set_checksum_coverage(checksum_coverage)?;
+        let bounds = cluster::Bounds::new(driver.number_of_sectors() as u64, driver.header.state_block_address.into());
+
+        let zones = if driver.geometry().zoned {
+            let mut report = driver.report_zones()?;
+            for zone in &mut report {
+                if zone.write_pointer != zone.start {
+                    driver.reset_zone(zone.start)?;
+                    zone.write_pointer = zone.start;
+                }
+            }
+            Some(zones::Zones::new(report)?)
         } else {
-            // There is space for more clusters in the head metacluster.
+            None
+        };
 
-            // Push the cluster pointer to the freelist head.
-            self.state.freelist.push(cluster);
-            // Queue a flush of the new freelist head.
-            self.queue_freelist_head_flush();
+        let mut clusters = (0..bounds.size()).rev()
+            .filter(|&cluster| zones.as_ref().map_or(true, |zones| !zones.contains(cluster as disk::Sector)))
+            .filter_map(|cluster| bounds.check(cluster));
+        let head = clusters.next().ok_or(Error::OutOfClusters)?;
+        let superpage = clusters.next().ok_or(Error::OutOfClusters)?;
 
-            // lulz @ these comments. like shit, ticki, they add basically nothing you fuking dumb
-            // monkey. seriously stop it
+        let state_block = state_block::StateBlock::new(head, Pointer::new(superpage, 0));
+        let mut manager = Manager::new(Cache::new(driver), bounds, state_block, false);
+        // The freelist is empty, so the count is known.
+        manager.state.free_clusters = Some(0);
+        manager.state.zones = zones.clone();
+        // With full coverage, the checksum tree covers every data cluster, the superpage included.
+        manager.state.state_block.checksum_tree = checksum_coverage == header::ChecksumCoverage::Full;
+
+        // Write the superpage.
+        let buf = manager.raw_cluster(&[0; PAGE_SIZE]);
+        manager.record_checksum(superpage, &buf);
+        manager.disk.queue(superpage, buf)?;
+
+        // The empty head terminates the chain, so it must be written as well. It stays at the
+        // bottom, as the first push makes the pushed cluster a new head linking it.
+        manager.state.freelist_dirty = true;
+        // Push the remaining clusters, highest first, so the lowest ends up on top.
+        for cluster in clusters {
+            manager.queue_freelist_push(cluster, trace::Subsystem::Freelist)?;
+        }
+        manager.queue_state_block_flush()?;
+        manager.commit()?;
+        manager.flush()?;
+
+        Ok(manager)
+    }
+
+    /// Create the page manager from a cache and a state block.
+    ///
+    /// Nothing is loaded; the structures linked from the state block are left empty.
+    fn new(
+        disk: Cache<header::Driver<D>>,
+        bounds: cluster::Bounds,
+        state_block: state_block::StateBlock,
+        rescue: bool,
+    ) -> Manager<D> {
+        let state = State::new(state_block);
+        Manager {
+            disk: disk,
+            committed_state: state.clone(),
+            state: state,
+            bounds: bounds,
+            watermarks: watermark::Watermarks::default(),
+            events: events::Log::default(),
+            history: history::History::default(),
+            verification: Verification::default(),
+            unverified: HashSet::new(),
+            durability: Durability::default(),
+            transaction: TransactionSize::default(),
+            epoch: 0,
+            auto_commit: AutoCommit::default(),
+            dirty_limit: None,
+            backpressure: Backpressure::Block,
+            memory_pressure: None,
+            stats: stats::Stats::default(),
+            replicator: None,
+            rescue: rescue,
+            readers: Arc::new(()),
+            durability_waiters: Vec::new(),
+            tracer: None,
+            // Debug builds check every allocation by default.
+            shadow: if cfg!(debug_assertions) {
+                Some(shadow::Shadow::new(bounds, shadow::Config::default()))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Load the structures linked from the state block.
+    ///
+    /// This loads the freelist head, the indirection table, the event log, the root history, and
+    /// the last cluster of the default stream into a freshly loaded state. In rescue mode, damaged
+    /// structures are skipped.
+    fn load_metadata(&mut self) -> Result<(), Error> {
+        let rescue = self.rescue;
+
+        // Track the zones of zoned disks.
+        self.state.zones = if self.geometry().zoned {
+            Some(zones::Zones::new(self.disk.inner_mut().report_zones()?)?)
+        } else {
+            None
+        };
+
+        // Load the freelist head. In rescue mode, a damaged freelist is left empty.
+        match self.load_freelist() {
+            Err(_) if rescue => self.state.freelist.clear(),
+            res => res?,
+        }
+        // Load the indirection table, if any. It is needed to read any data cluster, so a damaged
+        // table fails opening, except in rescue mode, where the moved clusters are then read from
+        // their old places (and most likely fail verification).
+        if let Some(first) = self.state.state_block.indirection {
+            match self.load_indirection(first) {
+                Ok((table, clusters)) => {
+                    self.state.indirection = Arc::new(table);
+                    self.state.indirection_clusters = clusters;
+                },
+                Err(_) if rescue => (),
+                Err(err) => return Err(err),
+            }
+        }
+        // Load the dead page list, if any. A damaged list merely leaks the clusters of the dead
+        // pages, so it doesn't prevent the volume from opening, and the list starts out empty.
+        if let Some(first) = self.state.state_block.dead_pages {
+            if let Ok((dead_pages, clusters)) = self.load_dead_pages(first) {
+                self.state.dead_pages = dead_pages;
+                self.state.dead_pages_clusters = clusters;
+            }
+        }
+        self.committed_state = self.state.clone();
+
+        // Load the event log, if any.
+        if let Some(event_log) = self.state.state_block.event_log {
+            let algorithms = self.checksum_algorithms();
+            // A damaged event log shouldn't prevent the volume from opening, so we start a new log
+            // (recording why the old one was lost) instead of failing. The new log overwrites the
+            // old one on the next flush.
+            let lost = match self.disk.read(event_log) {
+                Ok(buf) => match events::Log::decode(buf, algorithms.select(checksum_flag(buf))) {
+                    Ok(log) => {
+                        self.events = log;
+                        None
+                    },
+                    Err(_) => Some(events::Kind::ChecksumMismatch),
+                },
+                Err(_) => Some(events::Kind::ReadError),
+            };
+            if let Some(kind) = lost {
+                self.events = events::Log::default();
+                self.events.record(events::Event::now(kind, event_log.into()));
+            }
+        }
+
+        // Load the root history, if any.
+        if let Some(history) = self.state.state_block.history {
+            let algorithms = self.checksum_algorithms();
+            let copy = self.state.state_block.history_copy;
+            match self.read_duplicated(history, copy, |buf| {
+                history::History::decode(buf, algorithms.select(checksum_flag(buf))).map_err(Error::from)
+            }) {
+                Ok(history) => self.history = history,
+                // In rescue mode, a damaged history is ignored.
+                Err(_) if rescue => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Compare the recorded free cluster count against the freelist, if any. A mismatch means
+        // that clusters were lost from the freelist (or added to it) without damaging any
+        // metacluster, which would otherwise go unnoticed. It is recorded in the event log, and
+        // the count is corrected on the next commit.
+        if let Some(expected) = self.state.state_block.free_clusters {
+            if self.free_clusters().ok() != Some(expected) {
+                let head = self.state.state_block.freelist_head;
+                self.events.record(events::Event::now(events::Kind::FreeCountMismatch, head.into()));
+            }
+        }
+
+        // Resume packing into the last allocated cluster of the default stream, if any.
+        if let Some(last_cluster) = self.state.state_block.last_cluster {
+            let pages = self.state.state_block.last_cluster_pages as usize;
+            let mut data = Vec::new();
+            // The cluster is only reused if it is intact, and holds as many pages as recorded.
+            // Otherwise, we simply start a new cluster, as if it was never recorded.
+            if !self.disk.is_read_only()
+                && self.fetch_cluster(last_cluster, &mut data).is_ok()
+                && data.len() == pages * PAGE_SIZE {
+                // Clusters of a single page are stored uncompressed.
+                let size = if pages > 1 {
+                    let mut compressed = Vec::new();
+                    self.compress(&data, &mut compressed);
+                    compressed.len()
+                } else {
+                    PAGE_SIZE
+                };
+                self.state.streams.insert(DEFAULT_STREAM, Stream {
+                    last_cluster: Some(last_cluster),
+                    last_cluster_data: data,
+                    last_cluster_size: size,
+                });
+                self.committed_state.streams = self.state.streams.clone();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reload the volume after it has been modified externally.
+    ///
+    /// If the disk was written behind the back of the manager (e.g. by a repair tool) while the
+    /// volume was open, the cached data and state can't be trusted anymore. Rather than reopening
+    /// the volume, this reloads the disk header and the state block, and invalidates the cache
+    /// (see `Cache::invalidate()`). The runtime configuration (verification, limits, hooks, …)
+    /// is kept, and so is the replicator, which should resync afterwards.
+    ///
+    /// The pipeline must be empty, and there must be no readers (see `.reader()`), as their
+    /// snapshots might not survive the external changes; otherwise, `Error::Busy` is returned.
+    /// The committed data is flushed before reloading, so our own writes aren't lost.
+    fn reload(&mut self) -> Result<(), Error> {
+        if !self.disk.pipeline_is_empty() || self.has_readers() {
+            return Err(Error::Busy);
+        }
+
+        // Write out our own changes, before looking at the disk.
+        self.flush()?;
+
+        // Reload the disk header, and drop the stale cache entries.
+        self.disk.inner_mut().reload_header()?;
+        self.disk.invalidate();
+
+        // The disk might have been resized, or the state block moved.
+        let driver = self.disk.inner();
+        self.bounds = cluster::Bounds::new(driver.number_of_sectors() as u64, driver.header.state_block_address.into());
+
+        // Reload the state block, and the structures linked from it.
+        let state_block = load_state_block(&mut self.disk, self.bounds)?;
+        self.disk.set_read_only(state_block.sealed || self.rescue);
+        self.state = State::new(state_block);
+        self.committed_state = self.state.clone();
+        self.events = events::Log::default();
+        self.history = history::History::default();
+        self.transaction = TransactionSize::default();
+        self.epoch += 1;
+        // The shadow knows nothing about the external changes, so it starts over.
+        self.shadow = self.shadow.as_ref().map(|shadow| shadow::Shadow::new(self.bounds, shadow.config()));
+
+        self.load_metadata()
+    }
+
+    /// Open the page manager at some past generation.
+    ///
+    /// The volume is opened read-only, with the superpage of generation `generation` from the
+    /// root history. This can be used for inspecting old states of the volume, or for recovering
+    /// from a damaged superpage. The cluster of the superpage is kept while the generation is in
+    /// the history, but the generation might be partially overwritten, if the clusters below it
+    /// have been reused since.
+    fn open_at_generation(driver: header::Driver<D>, generation: u64) -> Result<Manager<D>, Error> {
+        let mut manager = Manager::open(driver)?;
+        manager.disk.set_read_only(true);
+
+        // The current generation isn't in the history.
+        if generation != manager.state.state_block.generation {
+            let superpage = manager.history.find(generation)
+                .ok_or(Error::UnknownGeneration { generation: generation })?
+                .superpage;
+            manager.state.state_block.superpage = superpage;
+            manager.state.state_block.generation = generation;
+            manager.committed_state = manager.state.clone();
         }
+
+        Ok(manager)
+    }
+
+    /// Start or stop tracing.
+    ///
+    /// While a recorder is set, every cluster allocation, free, read, and write is recorded (see
+    /// the `trace` module). The previous recorder, if any, is returned, so it can be finished.
+    fn set_tracer(&mut self, tracer: Option<trace::Recorder>) -> Option<trace::Recorder> {
+        mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Start or stop checking allocations.
+    ///
+    /// While enabled, every cluster allocated or freed is checked against a shadow record of the
+    /// allocated clusters (see the `shadow` module), catching double frees, frees of reserved
+    /// clusters, and allocations of clusters in use. The shadow is seeded with the freelist, which
+    /// is read in whole. Violations panic in debug builds, and return `Error::Shadow` in release
+    /// builds.
+    ///
+    /// Debug builds check every cluster by default. `Config::default()` samples in release builds,
+    /// to keep the cost low enough for production.
+    fn set_alloc_check(&mut self, config: Option<shadow::Config>) -> Result<(), Error> {
+        self.shadow = None;
+
+        if let Some(config) = config {
+            let mut shadow = shadow::Shadow::new(self.bounds, config);
+            // Every free cluster is known, so freeing it again is caught right away.
+            let mut res = Ok(());
+            self.walk_freelist(|cluster| {
+                if res.is_ok() {
+                    res = shadow.free(cluster.into());
+                }
+            })?;
+            res?;
+
+            self.shadow = Some(shadow);
+        }
+
+        Ok(())
+    }
+
+    /// Check an allocation or a free against the allocation shadow, if any.
+    fn check_shadow<F>(&mut self, check: F) -> Result<(), Error>
+        where F: FnOnce(&mut shadow::Shadow) -> Result<(), shadow::Error> {
+        if let Some(ref mut shadow) = self.shadow {
+            if let Err(err) = check(shadow) {
+                // Allocator bugs are best caught where they happen, so debug builds abort.
+                if cfg!(debug_assertions) {
+                    panic!("Allocator bug: {}", err);
+                }
+
+                return Err(err.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a cluster operation in the trace, if tracing.
+    ///
+    /// Reads and writes transfer the whole cluster, while allocations and frees transfer nothing.
+    fn trace(&mut self, kind: trace::Kind, subsystem: trace::Subsystem, cluster: cluster::Pointer) {
+        if let Some(ref mut tracer) = self.tracer {
+            let len = match kind {
+                trace::Kind::Read | trace::Kind::Write => disk::SECTOR_SIZE,
+                _ => 0,
+            };
+            tracer.record(kind, subsystem, cluster.into(), len);
+        }
+    }
+
+    /// Get the runtime statistics.
+    fn stats(&self) -> &stats::Stats {
+        &self.stats
+    }
+
+    /// Reset the runtime statistics.
+    fn reset_stats(&mut self) {
+        self.stats = stats::Stats::default();
+    }
+
+    /// Flush the cache to the disk.
+    ///
+    /// This writes every committed transaction to the disk.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        let mut res = self.disk.flush_all().map_err(Error::from);
+        // Wait for the writes to reach stable storage, unless the durability is relaxed.
+        if res.is_ok() && self.durability != Durability::Eventual {
+            res = self.disk.inner_mut().sync().map_err(Error::from);
+        }
+        self.stats.flush.record(start.elapsed());
+
+        // Notify the commits waiting for the flush. On failure, they're told the error, as it is
+        // unknown which of the writes reached the disk.
+        for (generation, mut callback) in self.durability_waiters.drain(..) {
+            callback(match res {
+                Ok(()) => Ok(generation),
+                Err(ref err) => Err(err),
+            });
+        }
+
+        res
+    }
+
+    /// Get the past generations of the root history, oldest first.
+    fn generations(&self) -> &[history::Generation] {
+        self.history.generations()
+    }
+
+    /// Set the I/O priority class of the following operations.
+    ///
+    /// Background tasks (e.g. scrubbing) should lower the priority before doing their work, and
+    /// restore it afterwards, such that their writes are flushed after the user's.
+    fn set_priority(&mut self, priority: cache::Priority) {
+        self.disk.set_priority(priority);
+    }
+
+    /// Set the throttle of some background priority class.
+    ///
+    /// This caps the bandwidth and operation rate of the maintenance passes running in this
+    /// class, so they can run continuously without hurting the latency of user I/O.
+    fn set_throttle(&mut self, priority: cache::Priority, config: throttle::Config) {
+        self.disk.set_throttle(priority, config);
+    }
+
+    /// Set the purge method of the volume.
+    ///
+    /// This defines how the data of clusters is destroyed when they're freed. It is stored in the
+    /// state block, and thus applies until changed again (also after reopening the volume).
+    fn set_purge_method(&mut self, method: state_block::PurgeMethod) -> Result<(), Error> {
+        self.state.state_block.purge_method = method;
+        self.queue_state_block_flush()
+    }
+
+    /// Set the packing policy of the volume.
+    ///
+    /// This trades space for fewer recompressions on allocation. It is stored in the state block.
+    fn set_packing_policy(&mut self, policy: state_block::PackingPolicy) -> Result<(), Error> {
+        self.state.state_block.packing_policy = policy;
+        self.queue_state_block_flush()
+    }
+
+    /// Set if metadata pages are stored raw.
+    ///
+    /// This only affects metadata pages allocated afterwards. See `.queue_alloc_metadata()`.
+    fn set_raw_metadata(&mut self, raw: bool) -> Result<(), Error> {
+        self.state.state_block.raw_metadata = raw;
+        self.queue_state_block_flush()
+    }
+
+    /// Set the number of copies of metadata.
+    ///
+    /// With two copies, metadata pages, the root history, and the freelist metaclusters are
+    /// duplicated into the other half of the volume (see `.copy_half()`), giving some protection
+    /// against damaged regions of the disk on volumes without mirrors. This only affects metadata
+    /// written afterwards. `copies` must be one or two.
+    fn set_metadata_copies(&mut self, copies: u8) -> Result<(), Error> {
+        assert!(copies == 1 || copies == 2, "Invalid number of metadata copies.");
+
+        self.state.state_block.metadata_copies = copies;
+        if copies == 1 {
+            // Drop the duplicate of the history; the cluster is freed rather than left dangling.
+            if let Some(copy) = self.state.state_block.history_copy.take() {
+                self.queue_freelist_push(copy, trace::Subsystem::History)?;
+            }
+        }
+        self.queue_freelist_head_copy()?;
+
+        self.queue_state_block_flush()
+    }
+
+    /// Enable or disable the checksum tree.
+    ///
+    /// With the checksum tree enabled, the full-width checksums of the data clusters written
+    /// afterwards are recorded in the tree (see the `checksums` module), and verified on reads and
+    /// by `.verify()`, on top of the 14-bit checksum in the cluster header. Clusters written
+    /// before have no entry, so the tree is best enabled right after formatting. Disabling it
+    /// frees the tree.
+    ///
+    /// Volumes with full checksum coverage (see `.format_with_coverage()`) have the tree from the
+    /// start, and it can't be disabled; `Error::ChecksumTreeRequired` is returned.
+    fn set_checksum_tree(&mut self, enabled: bool) -> Result<(), Error> {
+        if !enabled && self.full_coverage() {
+            return Err(Error::ChecksumTreeRequired);
+        }
+
+        self.state.state_block.checksum_tree = enabled;
+
+        if !enabled {
+            self.state.checksum_updates.clear();
+
+            // Free every node of the tree. The nodes below undecodable nodes can't be found, and
+            // are leaked.
+            let bounds = self.bounds;
+            let mut nodes = Vec::new();
+            self.walk_checksum_tree(|cluster, _, _, _| nodes.extend(bounds.check(cluster)));
+            self.state.state_block.checksum_tree_root = None;
+            self.queue_freelist_push_iter(nodes, trace::Subsystem::ChecksumTree)?;
+        }
+
+        self.queue_state_block_flush()
+    }
+
+    /// Enable or disable recording the free cluster count.
+    ///
+    /// With recording enabled, the number of free clusters is kept in the state block, updated
+    /// along with the freelist by every commit. On mount, it is compared against the freelist,
+    /// and mismatches are recorded in the event log; `.verify()` compares them as well. This makes
+    /// mounting walk the whole freelist.
+    fn set_free_count_recording(&mut self, enabled: bool) -> Result<(), Error> {
+        self.state.state_block.free_clusters = if enabled {
+            Some(self.free_clusters()?)
+        } else {
+            None
+        };
+
+        self.queue_state_block_flush()
+    }
+
+    /// Set the checksum verification policy of page reads.
+    ///
+    /// This trades CPU time for protection against corruption of cached data, and, with
+    /// `Verification::Lazy`, for how early damage is caught. Metaclusters and the state block are
+    /// always verified.
+    fn set_verification(&mut self, verification: Verification) {
+        self.verification = verification;
+    }
+
+    /// Set the durability mode.
+    ///
+    /// This trades crash safety for performance, see `Durability`. It isn't stored on the volume,
+    /// so it must be chosen every time the volume is opened.
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Set the automatic commit thresholds.
+    ///
+    /// When the uncommitted transaction reaches a threshold, the next allocation commits it first.
+    /// This keeps long jobs (e.g. imports) from piling up an unbounded pipeline, which is costly to
+    /// hold and to revert. Note that a revert then only rolls back to the last automatic commit, so
+    /// the pages allocated before it might leak, if the operation they belong to is abandoned.
+    fn set_auto_commit(&mut self, auto_commit: AutoCommit) {
+        self.auto_commit = auto_commit;
+    }
+
+    /// Get the size of the uncommitted transaction.
+    fn transaction_size(&self) -> TransactionSize {
+        self.transaction
+    }
+
+    /// Commit, if the uncommitted transaction exceeds the automatic commit thresholds.
+    fn apply_auto_commit(&mut self) -> Result<(), Error> {
+        if self.auto_commit.is_exceeded(self.transaction) {
+            self.commit()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Account an allocation in the size of the uncommitted transaction.
+    fn account_alloc(&mut self) {
+        self.transaction.bytes += PAGE_SIZE as u64;
+        self.transaction.ops += 1;
+    }
+
+    /// Set the limit of dirty data in the cache.
+    ///
+    /// When the dirty data (committed, but not flushed) exceeds `limit` bytes, allocations apply
+    /// backpressure as defined by `backpressure`. This keeps the memory usage bounded under
+    /// write-heavy loads. `None` means no limit.
+    fn set_dirty_limit(&mut self, limit: Option<usize>, backpressure: Backpressure) {
+        self.dirty_limit = limit;
+        self.backpressure = backpressure;
+    }
+
+    /// Set the memory pressure hook.
+    ///
+    /// The hook is polled on every commit, and can make the manager shrink its memory usage.
+    fn set_memory_pressure_hook(&mut self, hook: Option<MemoryPressureHook>) {
+        self.memory_pressure = hook;
+    }
+
+    /// Shrink the memory usage of the manager.
+    ///
+    /// This evicts clean blocks from the cache until it holds at most `target` bytes (dirty blocks
+    /// are kept), and releases the excess capacity of the internal buffers.
+    fn shrink(&mut self, target: usize) {
+        self.disk.shrink(target);
+
+        // Release the excess capacity of the state buffers. Note that `last_cluster_data` keeps
+        // its content, as it is needed for packing further pages.
+        for state in &mut [&mut self.state, &mut self.committed_state] {
+            for stream in state.streams.values_mut() {
+                stream.last_cluster_data.shrink_to_fit();
+            }
+            state.streams.shrink_to_fit();
+            state.freelist.shrink_to_fit();
+        }
+    }
+
+    /// Apply backpressure, if the dirty data in the cache exceeds the limit.
+    fn apply_backpressure(&mut self) -> Result<(), Error> {
+        match self.dirty_limit {
+            Some(limit) if self.disk.dirty_bytes() > limit => match self.backpressure {
+                // Catch up by flushing the cache ourselves.
+                Backpressure::Block => self.flush(),
+                Backpressure::WouldBlock => Err(Error::WouldBlock),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the disk header.
+    fn header(&self) -> &header::DiskHeader {
+        &self.disk.inner().header
+    }
+
+    /// Get the geometry of the disk.
+    ///
+    /// This is what the disk reports (see `Disk::geometry()`). Single clusters are popped in
+    /// whatever order they were freed, but direct extents of at least the optimal I/O size are
+    /// placed in aligned runs, if the freelist head has one (see `.queue_alloc_extent_direct()`
+    /// and `.queue_freelist_pop_run()`). To keep such runs available, aligned clusters aren't
+    /// used as metaclusters.
+    fn geometry(&self) -> disk::Geometry {
+        self.disk.inner().geometry()
+    }
+
+    /// Get the checksum algorithms of the volume.
+    fn checksum_algorithms(&self) -> ChecksumAlgorithms {
+        ChecksumAlgorithms {
+            current: self.header().checksum_algorithm,
+            flag: self.header().checksum_flag,
+            previous: self.header().previous_checksum_algorithm,
+        }
+    }
+
+    /// Set the checksum flag of an encoded structure to the flag of the current algorithm.
+    ///
+    /// The structure must start with its 64-bit checksum, made by the current algorithm.
+    fn flag_checksum(&self, mut buf: Box<[u8]>) -> Box<[u8]> {
+        set_checksum_flag(&mut buf, self.header().checksum_flag);
+        buf
+    }
+
+    /// Get the number of free clusters.
+    ///
+    /// The first call walks the whole freelist to count the clusters, after which the count is
+    /// maintained by the allocator.
+    fn free_clusters(&mut self) -> Result<u64, Error> {
+        if let Some(free) = self.state.free_clusters {
+            return Ok(free);
+        }
+
+        // Count the clusters of the freelist.
+        let mut free = 0;
+        self.walk_freelist(|_| free += 1)?;
+
+        self.state.free_clusters = Some(free);
+        // The count is valid for the committed state as well, as long as the pipeline is empty.
+        if self.disk.pipeline_is_empty() {
+            self.committed_state.free_clusters = Some(free);
+        }
+
+        Ok(free)
+    }
+
+    /// Get the space usage by structure type.
+    ///
+    /// This answers what is using the disk. It walks the whole freelist chain, and is thus slow.
+    /// The counts are checked to add up, so it doubles as a sanity check (see
+    /// `SpaceUsage::data()`).
+    ///
+    /// If the chain contains a cycle, `Error::FreelistCycle` is returned.
+    fn space_by_type(&mut self) -> Result<SpaceUsage, Error> {
+        let total = self.disk.inner().number_of_sectors() as u64;
+        let mut usage = SpaceUsage {
+            total: total,
+            // Count the clusters outside the bounds.
+            reserved: total.saturating_sub(self.bounds.size()) + self.bounds.reserved(),
+            ..SpaceUsage::default()
+        };
+
+        // The freelist head, the event log, the root history, the indirection table, the dead
+        // page list, and the duplicates.
+        let state_block = &self.state.state_block;
+        usage.metadata = 1 + state_block.event_log.iter()
+            .chain(&state_block.history)
+            .chain(&state_block.history_copy)
+            .chain(&state_block.freelist_head_copy)
+            .chain(&self.state.indirection_clusters)
+            .chain(&self.state.dead_pages_clusters)
+            .count() as u64;
+
+        // Walk the freelist chain. The first pointer of every metacluster links to the next
+        // metacluster, so it is counted as a metacluster rather than a free cluster. Their
+        // duplicates are metadata.
+        let mut cycle = CycleDetector::default();
+        let mut freelist = self.state.freelist.clone();
+        let mut copy = self.state.freelist_next_copy;
+        while let Some(&next) = freelist.first() {
+            usage.metaclusters += 1;
+            usage.metadata += copy.is_some() as u64;
+            usage.free += freelist.len() as u64 - 1;
+            cycle.step(next)?;
+
+            copy = self.read_metacluster(next, copy, &mut freelist)?;
+        }
+
+        Ok(usage)
+    }
+
+    /// Add a low-space watermark.
+    ///
+    /// `callback` is called whenever the number of free clusters falls below `threshold`. Note
+    /// that this happens when the allocation is queued, so the callback might be called for
+    /// allocations which are later reverted.
+    fn add_watermark(&mut self, threshold: u64, callback: watermark::Callback) -> Result<(), Error> {
+        // Make sure the free clusters are counted.
+        let free = self.free_clusters()?;
+
+        self.watermarks.add(threshold, callback);
+        // Trigger the watermark right away, if we're already below it.
+        self.watermarks.update(free);
+
+        Ok(())
+    }
+
+    /// Update the free cluster count by `delta`, and check the watermarks.
+    fn update_free_clusters(&mut self, delta: i64) {
+        if let Some(free) = self.state.free_clusters.as_mut() {
+            *free = (*free as i64 + delta) as u64;
+            self.watermarks.update(*free);
+        }
+    }
+
+    /// Walk the whole freelist.
+    ///
+    /// This calls `f` on every free cluster, including the metaclusters. It reads the whole
+    /// freelist chain, and is thus slow; it is intended for consistency checks.
+    ///
+    /// If the chain contains a cycle, `Error::FreelistCycle` is returned.
+    fn walk_freelist<F: FnMut(cluster::Pointer)>(&mut self, mut f: F) -> Result<(), Error> {
+        let mut cycle = CycleDetector::default();
+        // Start with the in-memory freelist head, as it might not be flushed yet.
+        let mut freelist = self.state.freelist.clone();
+        let mut copy = self.state.freelist_next_copy;
+
+        loop {
+            // Visit the free clusters of this metacluster.
+            for &cluster in &freelist {
+                f(cluster);
+            }
+
+            // The first pointer links to the next metacluster. An empty metacluster terminates the
+            // chain.
+            let next = match freelist.first() {
+                Some(&next) => next,
+                None => return Ok(()),
+            };
+
+            // Make sure we haven't been here before.
+            cycle.step(next)?;
+
+            // Load the next metacluster.
+            copy = self.read_metacluster(next, copy, &mut freelist)?;
+        }
+    }
+
+    /// Verify the volume.
+    ///
+    /// This checks every structure reachable from the state block: the freelist chain (checksums,
+    /// pointer bounds, and cycles), the freelist against the clusters in use (no cluster may be
+    /// both free and in use, or free twice), the data clusters known to the page manager, and the
+    /// reference counts of the clusters holding dead pages (see `.release_page()`). Rather than
+    /// stopping at the first problem, every problem is collected into the report.
+    ///
+    /// The page manager doesn't know who points to its pages, so the references themselves are
+    /// only checked by `.verify_references()`.
+    ///
+    /// Note that this keeps a set of every free cluster in memory.
+    fn verify(&mut self) -> verify::Report {
+        // This can't fail, as nobody else holds the cancellation token.
+        self.verify_with(&progress::Task::default()).unwrap()
+    }
+
+    /// Verify the volume, reporting progress through `task`.
+    ///
+    /// This is like `.verify()`, but the operation can be cancelled through the token of `task`,
+    /// in which case `progress::Cancelled` is returned.
+    fn verify_with(&mut self, task: &progress::Task) -> Result<verify::Report, progress::Cancelled> {
+        let mut report = verify::Report::default();
+        // Every visited cluster counts as processed. If the free clusters have been counted, we
+        // know the total up front.
+        if let Some(free) = self.state.free_clusters {
+            task.progress.set_total(free * disk::SECTOR_SIZE as u64);
+        }
+
+        let algorithms = self.checksum_algorithms();
+        let bounds = self.bounds;
+        let mut free = HashSet::new();
+
+        // Walk the freelist chain, starting with the in-memory head.
+        let mut cycle = CycleDetector::default();
+        let mut freelist = self.state.freelist.clone();
+        let mut copy = self.state.freelist_next_copy;
+        let mut copies = Vec::new();
+        loop {
+            for &cluster in &freelist {
+                report.free_clusters += 1;
+                if !free.insert(u64::from(cluster)) {
+                    report.add(cluster.into(), verify::Problem::DoubleFree);
+                }
+            }
+
+            task.progress.advance(freelist.len() as u64 * disk::SECTOR_SIZE as u64);
+            task.cancel.check()?;
+
+            // Follow the link to the next metacluster, if any.
+            let next = match freelist.first() {
+                Some(&next) => next,
+                None => break,
+            };
+            if cycle.step(next).is_err() {
+                report.add(next.into(), verify::Problem::FreelistCycle);
+                break;
+            }
+
+            report.metaclusters += 1;
+            copies.extend(copy);
+            let mut decode = |cluster, buf: &[u8]| {
+                decode_metacluster(cluster, buf, algorithms.select(checksum_flag(buf)), bounds, &mut freelist)
+            };
+            let res = self.disk.read(next).map_err(Error::from).and_then(|buf| decode(next, buf));
+            let res = match (res, copy) {
+                (Err(err), Some(copy)) => {
+                    // Follow the chain through the duplicate of the broken metacluster.
+                    report.add(next.into(), problem(&err));
+                    self.disk.read(copy).map_err(Error::from).and_then(|buf| decode(copy, buf))
+                },
+                (res, _) => res,
+            };
+            match res {
+                Ok(next_copy) => copy = next_copy,
+                Err(err) => {
+                    // We can't follow the chain past a broken metacluster.
+                    report.add(copy.unwrap_or(next).into(), problem(&err));
+                    break;
+                },
+            }
+        }
+
+        // Collect the clusters in use by the page manager itself.
+        let superpage = self.state.state_block.superpage.cluster();
+        let mut in_use = vec![self.state.state_block.freelist_head, superpage];
+        in_use.extend(self.state.state_block.event_log);
+        in_use.extend(self.state.state_block.history);
+        in_use.extend(self.state.state_block.history_copy);
+        // The duplicates of the freelist head and the metaclusters.
+        in_use.extend(self.state.state_block.freelist_head_copy);
+        in_use.extend(copies);
+        in_use.extend(self.state.streams.values().filter_map(|stream| stream.last_cluster));
+        // The indirection table chain, and the physical clusters of the moved clusters.
+        in_use.extend(self.state.indirection_clusters.iter().cloned());
+        in_use.extend(self.state.indirection.entries().into_iter().filter_map(|(_, physical)| bounds.check(physical)));
+        // The dead page list chain, and the clusters holding dead pages.
+        in_use.extend(self.state.dead_pages_clusters.iter().cloned());
+        in_use.extend(self.state.dead_pages.keys().cloned());
+
+        // Walk the checksum tree, if any. Besides its nodes being in use, the tree tells which
+        // clusters are data clusters: those with an entry (unless cleared in this transaction).
+        let forgotten: HashSet<u64> = self.state.checksum_updates.iter()
+            .filter(|&(_, &entry)| entry == 0)
+            .map(|(&cluster, _)| cluster.into())
+            .collect();
+        let mut checksummed = Vec::new();
+        self.walk_checksum_tree(|cluster, first, level, res| match *res {
+            Ok(ref node) => {
+                in_use.extend(bounds.check(cluster));
+                if level == 0 {
+                    for (i, &entry) in node.entries.iter().enumerate() {
+                        let covered = first + i as u64;
+                        if entry != 0 && !forgotten.contains(&covered) {
+                            checksummed.extend(bounds.check(covered));
+                        }
+                    }
+                }
+            },
+            Err(ref err) => report.add(cluster, problem(err)),
+        });
+        in_use.extend(checksummed.iter().cloned());
+
+        for &cluster in &in_use {
+            if free.contains(&u64::from(cluster)) {
+                report.add(cluster.into(), verify::Problem::FreeInUse);
+            }
+        }
+
+        // Compare the free cluster count against the freelist, if the count is recorded. The
+        // count is maintained by the allocator, so this catches clusters lost in the chain since
+        // mounting.
+        if self.state.state_block.free_clusters.is_some() && self.state.free_clusters != Some(report.free_clusters) {
+            report.add(self.state.state_block.freelist_head.into(), verify::Problem::FreeCountMismatch);
+        }
+
+        // Check the data clusters.
+        let mut data_clusters = vec![superpage];
+        data_clusters.extend(self.state.streams.values().filter_map(|stream| stream.last_cluster));
+        data_clusters.extend(checksummed);
+        data_clusters.extend(self.state.dead_pages.keys().cloned());
+        data_clusters.sort();
+        data_clusters.dedup();
+        for cluster in data_clusters {
+            task.cancel.check()?;
+
+            report.data_clusters += 1;
+            if let Err(err) = self.read_cluster(cluster) {
+                report.add(cluster.into(), problem(&err));
+            }
+        }
+
+        // Check the reference counts of the clusters holding dead pages, i.e. their numbers of
+        // live pages. The dead pages must be pages of the cluster, and some page must be live, as
+        // the cluster would have been freed otherwise, unless it is a retained root.
+        let mut dead_pages: Vec<_> = self.state.dead_pages.iter()
+            .map(|(&cluster, dead)| (cluster, dead.clone()))
+            .collect();
+        dead_pages.sort_by_key(|&(cluster, _)| u64::from(cluster));
+        let mut data = Vec::new();
+        for (cluster, dead) in dead_pages {
+            task.cancel.check()?;
+
+            if self.fetch_cluster(cluster, &mut data).is_err() {
+                // This was reported along with the data clusters.
+                continue;
+            }
+            let pages = data.len() / PAGE_SIZE;
+            if dead.iter().any(|&index| index as usize >= pages)
+                || (dead.len() >= pages && !self.is_retained_root(cluster, &self.history)) {
+                report.add(cluster.into(), verify::Problem::ReferenceCountMismatch);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Verify the volume, and the references of the layers above to its pages.
+    ///
+    /// `references` are the page pointers held by the caller (e.g. every pointer of the object
+    /// tree). On top of `.verify()`, they are cross-checked with the reference counts of their
+    /// clusters: Every reference must name a live page of a cluster in use, or it is dangling, and
+    /// every live page of a referenced cluster must be referenced exactly once. Clusters nobody
+    /// refers to aren't checked.
+    fn verify_references<I>(&mut self, references: I) -> verify::Report
+        where I: IntoIterator<Item = Pointer> {
+        let mut report = self.verify();
+
+        // Collect the free clusters. A cycle was reported by the verification already.
+        let mut free = HashSet::new();
+        let _ = self.walk_freelist(|cluster| {
+            free.insert(u64::from(cluster));
+        });
+
+        // Count the references to every page.
+        let mut counts: HashMap<cluster::Pointer, HashMap<u8, usize>> = HashMap::new();
+        for ptr in references {
+            let cluster = ptr.cluster();
+            if free.contains(&u64::from(cluster))
+                || self.state.dead_pages.get(&cluster).map_or(false, |dead| dead.contains(&ptr.index())) {
+                report.add(cluster.into(), verify::Problem::DanglingReference);
+            } else {
+                *counts.entry(cluster).or_insert_with(HashMap::new).entry(ptr.index()).or_insert(0) += 1;
+            }
+        }
+
+        // Compare the references against the live pages of the clusters.
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|&(cluster, _)| u64::from(cluster));
+        let mut data = Vec::new();
+        for (cluster, pages) in counts {
+            if let Err(err) = self.fetch_cluster(cluster, &mut data) {
+                report.add(cluster.into(), problem(&err));
+                continue;
+            }
+            let total = data.len() / PAGE_SIZE;
+            if pages.keys().any(|&index| index as usize >= total) {
+                report.add(cluster.into(), verify::Problem::DanglingReference);
+                continue;
+            }
+
+            let live = total - self.state.dead_pages.get(&cluster).map_or(0, |dead| dead.len());
+            if pages.len() != live || pages.values().any(|&count| count != 1) {
+                report.add(cluster.into(), verify::Problem::ReferenceCountMismatch);
+            }
+        }
+
+        report
+    }
+
+    /// Change the checksum algorithm of the volume.
+    ///
+    /// Changing the algorithm doesn't invalidate the existing checksums: New writes use the new
+    /// algorithm, while the structures not yet migrated are told apart by their checksum flag, and
+    /// verified with the old one, until the migration is finished by
+    /// `.migrate_checksums()` (e.g. in a background task). The migration state is kept in the
+    /// disk header, so it survives remounting.
+    ///
+    /// If a migration is already in progress, `Error::MigrationInProgress` is returned.
+    fn set_checksum_algorithm(&mut self, checksum_algorithm: header::ChecksumAlgorithm) -> Result<(), Error> {
+        if self.header().previous_checksum_algorithm.is_some() {
+            return Err(Error::MigrationInProgress);
+        }
+        if self.header().checksum_algorithm == checksum_algorithm {
+            // Nothing to migrate.
+            return Ok(());
+        }
+
+        self.disk.inner_mut().begin_checksum_migration(checksum_algorithm)?;
+
+        Ok(())
+    }
+
+    /// Finish a checksum migration, reporting progress through `task`.
+    ///
+    /// This rewrites every structure still carrying a checksum of the previous algorithm, and then
+    /// ends the migration. The metadata is simply flushed again, while the headers of the data
+    /// clusters are rewritten in place (the data itself is left untouched). The work is committed
+    /// in batches, so cancelling keeps the progress made so far; the migration can be resumed by
+    /// calling this again.
+    ///
+    /// If the checksum tree is enabled, the entry of every data cluster is recorded again with the
+    /// new algorithm, which rewrites the whole tree along the way.
+    ///
+    /// Data clusters not matching the algorithm of their checksum flag are damaged. They are
+    /// recorded in the event log and left as is, as rewriting their checksums would hide the
+    /// damage.
+    fn migrate_checksums(&mut self, task: &progress::Task) -> Result<(), Error> {
+        /// The number of rewritten data clusters per commit.
+        const BATCH: usize = 64;
+
+        let algorithms = self.checksum_algorithms();
+        if algorithms.previous.is_none() {
+            // No migration is in progress.
+            return Ok(());
+        }
+        let bounds = self.bounds;
+
+        // Rewrite the freelist chain, collecting the free clusters on the way.
+        let mut free = HashSet::new();
+        let mut cycle = CycleDetector::default();
+        let mut freelist = self.state.freelist.clone();
+        let mut copy = self.state.freelist_next_copy;
+        let mut copies = Vec::new();
+        self.queue_freelist_head_flush()?;
+        while let Some(&next) = freelist.first() {
+            free.extend(freelist.iter().map(|&cluster| u64::from(cluster)));
+            cycle.step(next)?;
+
+            // Rewrite the metacluster along with its duplicate, if any.
+            let next_copy = self.read_metacluster(next, copy, &mut freelist)?;
+            let buf = encode_metacluster(&freelist, next_copy, algorithms);
+            if let Some(copy) = copy {
+                self.disk.queue(copy, buf.clone())?;
+                copies.push(copy);
+            }
+            self.disk.queue(next, buf)?;
+            copy = next_copy;
+        }
+
+        // Rewrite the other metadata.
+        self.queue_state_block_flush()?;
+        if self.state.state_block.event_log.is_some() {
+            self.queue_event_log_flush()?;
+        }
+        if self.state.state_block.history.is_some() {
+            let history = self.history.clone();
+            self.queue_history_flush(&history)?;
+        }
+        // The indirection table is written to a new chain on commit, and the old chain is freed.
+        if !self.state.indirection.is_empty() {
+            free.extend(self.state.indirection_clusters.iter().map(|&cluster| u64::from(cluster)));
+            self.state.indirection_dirty = true;
+        }
+        // So is the dead page list.
+        if !self.state.dead_pages.is_empty() {
+            free.extend(self.state.dead_pages_clusters.iter().map(|&cluster| u64::from(cluster)));
+            self.state.dead_pages_dirty = true;
+        }
+        self.commit()?;
+
+        // The clusters which aren't data clusters.
+        let mut metadata = vec![self.state.state_block.freelist_head];
+        metadata.extend(self.state.indirection_clusters.iter().cloned());
+        metadata.extend(self.state.dead_pages_clusters.iter().cloned());
+        metadata.extend(self.state.state_block.event_log);
+        metadata.extend(self.state.state_block.history);
+        metadata.extend(self.state.state_block.history_copy);
+        metadata.extend(self.state.state_block.freelist_head_copy);
+        metadata.extend(copies);
+        // The nodes of the checksum tree are rewritten as the entries change (see below).
+        let mut nodes = Vec::new();
+        self.walk_checksum_tree(|cluster, _, _, _| nodes.extend(bounds.check(cluster)));
+        metadata.extend(nodes);
+        let checksum_tree = self.state.state_block.checksum_tree;
+        let full_coverage = self.full_coverage();
+
+        // Rewrite the headers of the data clusters, i.e. the clusters which are neither free nor
+        // metadata.
+        let sectors = self.disk.inner().number_of_sectors() as u64;
+        task.progress.set_total(sectors * disk::SECTOR_SIZE as u64);
+        let mut batch = 0;
+        for cluster in 0..sectors {
+            task.cancel.check()?;
+            task.progress.advance(disk::SECTOR_SIZE as u64);
+
+            let cluster = match bounds.check(cluster) {
+                Some(cluster) if !free.contains(&u64::from(cluster)) && !metadata.contains(&cluster) => cluster,
+                _ => continue,
+            };
+            // The checksum tree entry is that of the cluster held by the physical cluster. Spares
+            // hold nothing.
+            let owner = match self.state.indirection.owner(cluster.into()).and_then(|owner| bounds.check(owner)) {
+                Some(owner) => owner,
+                None => continue,
+            };
+
+            let mut buf = self.disk.read(cluster)?.to_vec();
+            let header = codec::Reader::new(&buf).read_u16().map_err(|_| Error::Truncated { cluster: cluster })?;
+            let expected = (header >> 2) as u64;
+            // The checksum flag tells whether the cluster is migrated already.
+            let flag = data_cluster_checksum_flag(&buf);
+            let migrated = flag == algorithms.flag;
+            // With full coverage, the cluster is checked against its checksum tree entry, which
+            // is made by the same algorithm as the cluster's flag tells.
+            let intact = if full_coverage {
+                let hash = algorithms.select(flag).hash(&buf);
+                self.verify_checksum_entry(owner, hash).is_ok()
+            } else {
+                algorithms.select(flag).hash(&buf[DATA_CLUSTER_HEADER..]) & 0x3FFF == expected
+            };
+
+            if !intact {
+                // The cluster is damaged.
+                self.events.record(events::Event::now(events::Kind::ChecksumMismatch, cluster.into()));
+                continue;
+            } else if migrated && !checksum_tree {
+                // Already migrated.
+                continue;
+            }
+
+            if migrated {
+                // The header is migrated, but the checksum tree entry might not be.
+                self.record_checksum(owner, &buf);
+            } else {
+                // Rewrite the header, keeping the compression flag, and record the new checksum.
+                if full_coverage {
+                    write_data_cluster_flags(&mut buf, algorithms, header & 1 != 0);
+                } else {
+                    write_data_cluster_header(&mut buf, algorithms, header & 1 != 0);
+                }
+                self.record_checksum(owner, &buf);
+                self.disk.queue(cluster, buf.into_boxed_slice())?;
+            }
+
+            batch += 1;
+            if batch == BATCH {
+                self.commit()?;
+                batch = 0;
+            }
+        }
+        self.commit()?;
+
+        // Every structure carries the new checksums now.
+        self.disk.inner_mut().end_checksum_migration()?;
+
+        Ok(())
+    }
+
+    /// Load the freelist head.
+    ///
+    /// This replaces the in-memory freelist head by the pointers stored in the metacluster pointed
+    /// to by the state block, or its duplicate, if the metacluster is damaged.
+    fn load_freelist(&mut self) -> Result<(), Error> {
+        let head = self.state.state_block.freelist_head;
+        let copy = self.state.state_block.freelist_head_copy;
+
+        let mut freelist = Vec::new();
+        self.state.freelist_next_copy = self.read_metacluster(head, copy, &mut freelist)?;
+        self.state.freelist = freelist;
+
+        Ok(())
+    }
+
+    /// Read a metacluster of the freelist chain.
+    ///
+    /// The pointers of the metacluster `cluster` are read into `freelist`, and the duplicate of the
+    /// metacluster it links is returned, if any. If `cluster` is damaged, its duplicate `copy` (if
+    /// any) is read instead.
+    fn read_metacluster(
+        &mut self,
+        cluster: cluster::Pointer,
+        copy: Option<cluster::Pointer>,
+        freelist: &mut Vec<cluster::Pointer>,
+    ) -> Result<Option<cluster::Pointer>, Error> {
+        let algorithms = self.checksum_algorithms();
+        let bounds = self.bounds;
+
+        self.read_duplicated(cluster, copy, |buf| {
+            decode_metacluster(cluster, buf, algorithms.select(checksum_flag(buf)), bounds, freelist)
+        })
+    }
+
+    /// Load the indirection table.
+    ///
+    /// This reads the chain of table clusters starting at `first`, and returns the table along
+    /// with the clusters of the chain.
+    fn load_indirection(&mut self, first: cluster::Pointer) -> Result<(indirection::Table, Vec<cluster::Pointer>), Error> {
+        let algorithms = self.checksum_algorithms();
+        let bounds = self.bounds;
+
+        let mut entries = Vec::new();
+        let mut clusters = Vec::new();
+        let mut next = first;
+        loop {
+            // A corrupted chain might link back to itself.
+            if clusters.contains(&next) {
+                return Err(indirection::Error::Cycle { cluster: next.into() }.into());
+            }
+            clusters.push(next);
+
+            self.trace(trace::Kind::Read, trace::Subsystem::Indirection, next);
+            let chunk = {
+                let buf = self.disk.read(next)?;
+                indirection::Chunk::decode(buf, algorithms.select(checksum_flag(buf)))?
+            };
+            entries.extend(chunk.entries);
+
+            next = match chunk.next {
+                0 => break,
+                cluster => bounds.check(cluster).ok_or(Error::PointerOutOfBounds { cluster: cluster })?,
+            };
+        }
+
+        Ok((indirection::Table::from_entries(&entries, bounds)?, clusters))
+    }
+
+    /// Load the dead page list.
+    ///
+    /// This reads the chain of list clusters starting at `first`, and returns the dead pages by
+    /// cluster, along with the clusters of the chain.
+    fn load_dead_pages(&mut self, first: cluster::Pointer)
+        -> Result<(HashMap<cluster::Pointer, HashSet<u8>>, Vec<cluster::Pointer>), Error> {
+        let algorithms = self.checksum_algorithms();
+        let bounds = self.bounds;
+
+        let mut dead_pages = HashMap::new();
+        let mut clusters = Vec::new();
+        let mut next = first;
+        loop {
+            // A corrupted chain might link back to itself.
+            if clusters.contains(&next) {
+                return Err(dead_pages::Error::Cycle { cluster: next.into() }.into());
+            }
+            clusters.push(next);
+
+            self.trace(trace::Kind::Read, trace::Subsystem::DeadPages, next);
+            let chunk = {
+                let buf = self.disk.read(next)?;
+                dead_pages::Chunk::decode(buf, algorithms.select(checksum_flag(buf)))?
+            };
+            for ptr in chunk.pages {
+                let cluster = ptr.cluster();
+                if bounds.check(cluster.into()).is_none() {
+                    return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+                }
+                dead_pages.entry(cluster).or_insert_with(HashSet::new).insert(ptr.index());
+            }
+
+            next = match chunk.next {
+                0 => break,
+                cluster => bounds.check(cluster).ok_or(Error::PointerOutOfBounds { cluster: cluster })?,
+            };
+        }
+
+        Ok((dead_pages, clusters))
+    }
+
+    /// Seal the volume.
+    ///
+    /// This commits the pipeline, marks the volume sealed in the state block, and makes the cache
+    /// read-only, so every future write is rejected, also after reopening the volume. It can only
+    /// be undone by `.unseal()`.
+    fn seal(&mut self) -> Result<(), Error> {
+        // Set the seal flag and flush it together with the rest of the pipeline.
+        self.state.state_block.sealed = true;
+        self.queue_state_block_flush()?;
+        self.commit()?;
+
+        // From now on, the cache rejects writes.
+        self.disk.set_read_only(true);
+
+        Ok(())
+    }
+
+    /// Unseal the volume.
+    ///
+    /// This makes a sealed volume writable again, and clears the seal flag in the state block.
+    fn unseal(&mut self) -> Result<(), Error> {
+        // Make the cache writable, so we can flush the state block.
+        self.disk.set_read_only(false);
+
+        // Clear the seal flag and flush it.
+        self.state.state_block.sealed = false;
+        self.queue_state_block_flush()?;
+        self.commit()?;
+
+        Ok(())
+    }
+
+    /// Commit the transactions in the pipeline to the cache.
+    ///
+    /// This runs over the transactions in the pipeline and applies them to the cache. In a sense,
+    /// it can be seen as a form of checkpoint as you can revert to the last commit through
+    /// `.revert()`, as it stores the old state.
+    ///
+    /// New events in the health event log are written along with the commit.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.commit_inner();
+        self.stats.commit.record(start.elapsed());
+
+        res
+    }
+
+    /// Commit the pipeline, and get notified when it is durable.
+    ///
+    /// `.commit()` only commits to the cache, and the data reaches the disk on the next flush.
+    /// Instead of blocking on `.flush()` right away, callers can commit through this, keep
+    /// computing, and flush later (or leave it to a background flusher). `callback` is called by
+    /// the flush, with the generation the commit created. Every commit changing the volume creates
+    /// a generation, so the generations of the commits are increasing. An empty commit creates
+    /// none, and is notified with the generation of the last commit.
+    ///
+    /// If the commit itself fails, the callback isn't registered, and the error is returned.
+    fn commit_notify(&mut self, callback: DurabilityCallback) -> Result<(), Error> {
+        self.commit()?;
+
+        let generation = self.committed_state.state_block.generation;
+        self.durability_waiters.push((generation, callback));
+
+        Ok(())
+    }
+
+    /// Commit the pipeline, without keeping statistics.
+    fn commit_inner(&mut self) -> Result<(), Error> {
+        // Release the deferred frees, if no reader can see the clusters anymore.
+        if !self.state.deferred_frees.is_empty() && !self.has_readers() {
+            let frees = mem::replace(&mut self.state.deferred_frees, Vec::new());
+            for (cluster, subsystem) in frees {
+                self.queue_freelist_push(cluster, subsystem)?;
+            }
+        }
+
+        // Release the pages superseded in this transaction. The commit makes the new versions
+        // visible, so the old ones aren't needed anymore.
+        for ptr in mem::replace(&mut self.state.rewritten, Vec::new()) {
+            self.release_page(ptr)?;
+        }
+        // Likewise, free the physical clusters vacated by moves.
+        let vacated = mem::replace(&mut self.state.vacated, Vec::new());
+        self.queue_freelist_push_iter(vacated, trace::Subsystem::Data)?;
+
+        // Every commit changing the volume creates a new generation, unless the volume is
+        // read-only, in which case nothing is written. Empty commits keep the generation, so they
+        // don't rewrite the state block and the freelist head for nothing.
+        let (old_generation, old_superpage) = {
+            let old = &self.committed_state.state_block;
+            (old.generation, old.superpage)
+        };
+        let changed = !self.disk.pipeline_is_empty()
+            || self.state.state_block_dirty
+            || self.state.freelist_dirty
+            || self.state.dead_pages_dirty
+            || self.state.indirection_dirty
+            || !self.state.checksum_updates.is_empty()
+            || self.state.state_block.superpage != old_superpage;
+        if changed && !self.disk.is_read_only() {
+            self.state.state_block.generation = old_generation + 1;
+            self.state.state_block_dirty = true;
+        }
+
+        // If the root changed, record the previous one in the history. The new history only
+        // replaces the old one once the commit succeeded (see below), so a failed commit leaves no
+        // generation behind which was never committed.
+        let mut new_history = None;
+        if self.state.state_block.superpage != old_superpage {
+            let mut history = self.history.clone();
+            let dropped = history.record(history::Generation {
+                generation: old_generation,
+                superpage: old_superpage,
+            });
+            self.queue_history_flush(&history)?;
+
+            // The root of the dropped generation isn't retained anymore, so its cluster is freed,
+            // if it was released meanwhile. This comes before the dead page list is written.
+            if let Some(dropped) = dropped {
+                let cluster = dropped.superpage.cluster();
+                if !self.is_retained_root(cluster, &history) {
+                    self.queue_free_if_dead(cluster)?;
+                }
+            }
+            new_history = Some(history);
+        }
+
+        // Write the indirection table, if it changed in this transaction.
+        if self.state.indirection_dirty && !self.disk.is_read_only() {
+            self.queue_indirection_flush()?;
+        }
+        // Likewise, write the dead page list (changed by the releases above, among others).
+        if self.state.dead_pages_dirty && !self.disk.is_read_only() {
+            self.queue_dead_pages_flush()?;
+        }
+
+        // Apply the checksums of the clusters written in this transaction to the checksum tree.
+        if !self.state.checksum_updates.is_empty() && !self.disk.is_read_only() {
+            self.queue_checksum_tree_flush()?;
+        }
+
+        // Write the event log, unless the volume is read-only, in which case the events are only
+        // kept in memory.
+        if self.events.is_dirty() && !self.disk.is_read_only() {
+            self.queue_event_log_flush()?;
+        }
+        // Likewise, write the disk header, if the error counters changed. It goes through the
+        // pipeline, so it doesn't race with the writes queued before it.
+        if self.disk.inner().error_counters_dirty() && !self.disk.is_read_only() {
+            let buf = self.disk.inner_mut().encode_header();
+            self.disk.queue(header::PRIMARY_HEADER_SECTOR, buf.clone())?;
+            self.disk.queue(header::BACKUP_HEADER_SECTOR, buf)?;
+        }
+
+        // Update the recorded free cluster count, if any. It is part of the state block, so it
+        // changes atomically with the freelist head.
+        if let (Some(recorded), Some(free)) = (self.state.state_block.free_clusters, self.state.free_clusters) {
+            if recorded != free && !self.disk.is_read_only() {
+                self.state.state_block.free_clusters = Some(free);
+                self.state.state_block_dirty = true;
+            }
+        }
+
+        // Reconcile the freelist with the disk, if it changed in this transaction. This comes
+        // after everything else allocating or freeing clusters, so the head (and the state block
+        // linking it) is written once per commit, however many clusters were allocated. Flushing
+        // the state block flushes the head as well.
+        if self.state.state_block_dirty {
+            self.queue_state_block_flush()?;
+        } else if self.state.freelist_dirty {
+            self.queue_freelist_head_flush()?;
+        }
+
+        // Persist the last cluster of the default stream, so packing resumes after remounting.
+        let (last_cluster, last_cluster_pages) = self.state.streams.get(&DEFAULT_STREAM)
+            .map_or((None, 0), |stream| (stream.last_cluster, (stream.last_cluster_data.len() / PAGE_SIZE) as u16));
+        if (last_cluster, last_cluster_pages) != (self.state.state_block.last_cluster, self.state.state_block.last_cluster_pages)
+            && !self.disk.is_read_only() {
+            self.state.state_block.last_cluster = last_cluster;
+            self.state.state_block.last_cluster_pages = last_cluster_pages;
+            self.queue_state_block_flush()?;
+        }
+
+        // Purge the freed clusters, now that the state block no longer references them.
+        self.queue_purges()?;
+
+        // Hand the transaction group to the replicator, if any.
+        if let Some(ref mut replicator) = self.replicator {
+            if !self.disk.pipeline_is_empty() {
+                replicator.push(self.disk.pipeline());
+            }
+        }
+
+        // Record the commit along with the size of the transaction.
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.record(trace::Kind::Commit, trace::Subsystem::Data, 0, self.transaction.bytes as usize);
+        }
+
+        // Update the stored committed state to the current state, which we will commit.
+        self.committed_state = self.state.clone();
+        self.transaction = TransactionSize::default();
+        self.epoch += 1;
+        if let Some(mut history) = new_history {
+            history.mark_clean();
+            self.history = history;
+        }
+        // Commit the cache pipeline.
+        self.disk.commit();
+
+        // Shrink, if the environment is under memory pressure.
+        let usage = self.disk.memory_usage();
+        if let Some(target) = self.memory_pressure.as_mut().and_then(|hook| hook(usage)) {
+            self.shrink(target);
+        }
+
+        Ok(())
+    }
+
+    /// Create a reader of the committed state.
+    ///
+    /// The reader serves page reads from a snapshot of the last committed generation, through
+    /// `disk`, an independent handle to the same device (e.g. a second file descriptor). As it
+    /// shares nothing with the manager, it can be moved to another thread, and serve reads while
+    /// the manager keeps writing. Creating one is cheap, so readers can be created per request.
+    ///
+    /// The snapshot is kept valid by two measures:
+    ///
+    /// 1. The clusters freed while readers are alive are held back (see `State::deferred_frees`),
+    ///    so they're neither purged nor reused under a reader.
+    /// 2. The packing streams are closed, so clusters of the snapshot are never repacked in place.
+    ///
+    /// The cache is flushed first, as the reader can only see what is on the disk. The reader
+    /// doesn't decrypt, so only unencrypted volumes are supported, like the header driver.
+    fn reader<R: Disk>(&mut self, disk: R) -> Result<Reader<R>, Error> {
+        self.flush()?;
+
+        // Don't repack the clusters the reader might see. The streams are closed in the committed
+        // state as well, so they aren't resurrected by a revert.
+        self.state.streams.clear();
+        self.committed_state.streams.clear();
+
+        let state_block = &self.committed_state.state_block;
+        Ok(Reader {
+            disk: disk,
+            superpage: state_block.superpage,
+            generation: state_block.generation,
+            checksum_algorithms: self.checksum_algorithms(),
+            compression_algorithm: state_block.compression_algorithm,
+            bounds: self.bounds,
+            indirection: self.committed_state.indirection.clone(),
+            checksum_tree_root: state_block.checksum_tree_root,
+            checksum_tree_depth: self.checksum_tree_depth(),
+            full_coverage: self.full_coverage(),
+            _pin: self.readers.clone(),
+        })
+    }
+
+    /// Are there live readers?
+    fn has_readers(&self) -> bool {
+        Arc::strong_count(&self.readers) > 1
+    }
+
+    /// Start replicating the volume.
+    ///
+    /// From now on, every committed transaction group is queued for replication to `target`. The
+    /// groups are sent by `.replicate()`. At most `max_backlog` groups are kept while the target
+    /// lags behind. The target should start out as a copy of the volume.
+    fn set_replica(&mut self, target: Box<replication::Target>, max_backlog: usize) -> Result<(), replication::Error> {
+        self.replicator = Some(replication::Replicator::new(target, max_backlog)?);
+
+        Ok(())
+    }
+
+    /// Send the committed transaction groups to the replica.
+    ///
+    /// This is intended to be called periodically, e.g. from a background thread. If the replica
+    /// is unreachable, the groups are kept and resent on the next call.
+    fn replicate(&mut self) -> Result<(), replication::Error> {
+        match self.replicator {
+            Some(ref mut replicator) => replicator.pump(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the events of the health event log, oldest first.
+    ///
+    /// This includes checksum mismatches, repairs, and disk errors, with the time they happened.
+    fn events(&self) -> &[events::Event] {
+        self.events.events()
+    }
+
+    /// Clear the health event log.
+    fn clear_events(&mut self) {
+        self.events.clear();
+    }
+
+    /// Record an event in the health event log, if the error is worth recording.
+    fn record_error(&mut self, cluster: cluster::Pointer, err: &Error) {
+        let kind = match *err {
+            Error::ChecksumMismatch { .. } => {
+                // Count the mismatch against the disk.
+                self.disk.inner_mut().record_checksum_error();
+                events::Kind::ChecksumMismatch
+            },
+            Error::Disk(ref err) if err.is_media_error() => events::Kind::ReadError,
+            // Other errors aren't related to the health of the disk.
+            _ => return,
+        };
+
+        self.events.record(events::Event::now(kind, cluster.into()));
+    }
+
+    /// Queue a flush of the health event log.
+    ///
+    /// If the log has no cluster yet, one is allocated.
+    fn queue_event_log_flush(&mut self) -> Result<(), Error> {
+        let cluster = match self.state.state_block.event_log {
+            Some(cluster) => cluster,
+            None => {
+                // Allocate a cluster for the log and link it from the state block.
+                let cluster = self.queue_freelist_pop()?;
+                self.trace(trace::Kind::Alloc, trace::Subsystem::EventLog, cluster);
+                self.state.state_block.event_log = Some(cluster);
+                self.queue_state_block_flush()?;
+
+                cluster
+            },
+        };
+
+        // Queue the write of the log.
+        let buf = self.flag_checksum(self.events.encode(self.header().checksum_algorithm));
+        self.disk.queue(cluster, buf)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::EventLog, cluster);
+        self.events.mark_clean();
+
+        Ok(())
+    }
+
+    /// Queue a flush of the root history `history`.
+    ///
+    /// If the history has no cluster yet, one is allocated. The state block is flushed as well,
+    /// as it links the history.
+    fn queue_history_flush(&mut self, history: &history::History) -> Result<(), Error> {
+        if self.state.state_block.history.is_none() {
+            // Allocate a cluster for the history.
+            let cluster = self.queue_freelist_pop()?;
+            self.trace(trace::Kind::Alloc, trace::Subsystem::History, cluster);
+            self.state.state_block.history = Some(cluster);
+        }
+        let cluster = self.state.state_block.history.unwrap();
+        if self.state.state_block.metadata_copies == 2 && self.state.state_block.history_copy.is_none() {
+            // Allocate a cluster for the duplicate, in the other half of the volume.
+            let half = self.copy_half(cluster);
+            let copy = self.queue_freelist_pop_in(half)?;
+            self.trace(trace::Kind::Alloc, trace::Subsystem::History, copy);
+            self.state.state_block.history_copy = Some(copy);
+        }
+
+        // Queue the write of the history (and its duplicate).
+        let buf = self.flag_checksum(history.encode(self.header().checksum_algorithm));
+        if let Some(copy) = self.state.state_block.history_copy {
+            self.disk.queue(copy, buf.clone())?;
+            self.trace(trace::Kind::Write, trace::Subsystem::History, copy);
+        }
+        self.disk.queue(cluster, buf)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::History, cluster);
+
+        // Link the history and write the new generation number.
+        self.queue_state_block_flush()
+    }
+
+    /// Queue the update of the checksum tree.
+    ///
+    /// This applies the entries changed in the current transaction to the tree. The tree is
+    /// updated copy-on-write: The changed nodes are written to fresh clusters, and the replaced
+    /// nodes are only freed once the new root is linked from the state block, so they're never
+    /// reused within the transaction, and a crash before the commit leaves the old tree intact.
+    fn queue_checksum_tree_flush(&mut self) -> Result<(), Error> {
+        // Sort the updates by cluster, so the updates below the same node are adjacent.
+        let mut updates: Vec<(u64, u64)> = mem::replace(&mut self.state.checksum_updates, HashMap::new())
+            .into_iter()
+            .map(|(cluster, entry)| (cluster.into(), entry))
+            .collect();
+        updates.sort();
+
+        // A new tree covers the disk as it is. An existing tree is deepened until it covers the
+        // disk (e.g. after the disk grew): The old root covers the lowest clusters, so it becomes
+        // the first child of the new root.
+        let mut old_root = self.state.state_block.checksum_tree_root;
+        let mut depth = self.checksum_tree_depth();
+        let mut replaced = Vec::new();
+        if old_root.is_none() {
+            depth = checksums::depth(self.bounds.size());
+        }
+        while depth < checksums::depth(self.bounds.size()) {
+            let mut node = checksums::Node::default();
+            node.entries[0] = old_root.map_or(0, u64::from);
+            old_root = Some(self.queue_checksum_node(&node)?);
+            depth += 1;
+        }
+
+        let root = self.update_checksum_node(old_root, depth - 1, &updates, &mut replaced)?;
+
+        if root != self.state.state_block.checksum_tree_root || depth != self.checksum_tree_depth() {
+            // Link the new root.
+            self.state.state_block.checksum_tree_root = root;
+            self.state.state_block.checksum_tree_depth = depth as u8;
+            self.queue_state_block_flush()?;
+        }
+
+        // Free the replaced nodes.
+        self.queue_freelist_push_iter(replaced, trace::Subsystem::ChecksumTree)?;
+
+        Ok(())
+    }
+
+    /// Update a node of the checksum tree, and the nodes below it.
+    ///
+    /// `updates` are the `(cluster, entry)` pairs of the clusters below the node at `level`,
+    /// sorted by cluster. If the node changes, it is written to a fresh cluster, and the old node
+    /// is pushed to `replaced`. The (possibly new) node is returned, or `None` if it is empty.
+    fn update_checksum_node(
+        &mut self,
+        node: Option<cluster::Pointer>,
+        level: u32,
+        updates: &[(u64, u64)],
+        replaced: &mut Vec<cluster::Pointer>,
+    ) -> Result<Option<cluster::Pointer>, Error> {
+        // Load the node, or start with an empty one.
+        let old = match node {
+            Some(node) => self.read_checksum_node(node)?,
+            None => checksums::Node::default(),
+        };
+        let mut new = old.clone();
+
+        if level == 0 {
+            // The node is a leaf, so the entries are the checksums themselves.
+            for &(cluster, entry) in updates {
+                new.entries[checksums::slot(cluster, 0)] = entry;
+            }
+        } else {
+            // Update the children, one group of adjacent updates at a time.
+            let mut rest = updates;
+            while let Some(&(first, _)) = rest.first() {
+                let slot = checksums::slot(first, level);
+                let len = rest.iter().take_while(|&&(cluster, _)| checksums::slot(cluster, level) == slot).count();
+                let (group, tail) = rest.split_at(len);
+                rest = tail;
+
+                let child = match new.entries[slot] {
+                    0 => None,
+                    ptr => Some(self.bounds.check(ptr).ok_or(Error::PointerOutOfBounds { cluster: ptr })?),
+                };
+                new.entries[slot] = self.update_checksum_node(child, level - 1, group, replaced)?
+                    .map_or(0, u64::from);
+            }
+        }
+
+        // Keep the node, if nothing changed (e.g. only absent entries were cleared).
+        if new == old {
+            return Ok(node);
+        }
+        replaced.extend(node);
+
+        // Empty nodes are dropped from the tree.
+        if new.is_empty() {
+            return Ok(None);
+        }
+
+        self.queue_checksum_node(&new).map(Some)
+    }
+
+    /// Queue the write of a checksum tree node to a fresh cluster.
+    ///
+    /// The cluster is returned.
+    fn queue_checksum_node(&mut self, node: &checksums::Node) -> Result<cluster::Pointer, Error> {
+        let cluster = self.queue_freelist_pop()?;
+        self.trace(trace::Kind::Alloc, trace::Subsystem::ChecksumTree, cluster);
+        let buf = self.flag_checksum(node.encode(self.header().checksum_algorithm));
+        self.disk.queue(cluster, buf)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::ChecksumTree, cluster);
+
+        Ok(cluster)
+    }
+
+    /// Get the depth of the checksum tree.
+    ///
+    /// This is the recorded depth (see `StateBlock::checksum_tree_depth`), or, for trees
+    /// recorded without it, the depth covering the disk.
+    fn checksum_tree_depth(&self) -> u32 {
+        match self.state.state_block.checksum_tree_depth {
+            0 => checksums::depth(self.bounds.size()),
+            depth => depth as u32,
+        }
+    }
+
+    /// Revert to the last commit.
+    ///
+    /// This will reset the state to after the previous cache commit.
+    fn revert(&mut self) {
+        // Revert the state to when it was committed last time.
+        self.state = self.committed_state.clone();
+        self.transaction = TransactionSize::default();
+        self.epoch += 1;
+        // The allocations since are undone behind the back of the shadow.
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.clear();
+        }
+        // Revert the cache pipeline.
+        self.disk.revert();
+    }
+
+    /// Run a transaction, reverting and retrying on failure.
+    ///
+    /// `transaction` queues the operations, which are then committed. If either fails, the
+    /// pipeline is reverted. Transient errors (see `Error::is_transient()`) are retried after a
+    /// backoff, as given by `policy`, while other errors fail right away. As the transaction runs
+    /// from scratch on every attempt, it must not have side effects outside the manager.
+    ///
+    /// Any uncommitted operations queued before are committed along with the transaction, and are
+    /// reverted if it fails.
+    ///
+    /// Note that committing only applies the transaction to the cache. The writes reach the disk
+    /// on the next `.flush()`, after this has returned `Outcome::Committed`, so errors of the
+    /// flush aren't retried here. They are returned by the flush instead (and passed to the
+    /// durability callbacks), and as the failed writes are kept dirty in the cache, the flush can
+    /// simply be retried, without running the transaction again.
+    fn transact<T, F>(&mut self, policy: RetryPolicy, mut transaction: F) -> Outcome<T>
+        where F: FnMut(&mut Manager<D>) -> Result<T, Error> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+
+            // Queue the operations and commit them.
+            let res = transaction(self).and_then(|value| self.commit().map(|()| value));
+            let err = match res {
+                Ok(value) => return Outcome::Committed {
+                    value: value,
+                    attempts: attempts,
+                },
+                Err(err) => err,
+            };
+
+            // Roll back the failed attempt.
+            self.revert();
+
+            // Give up if the error is permanent, or we're out of attempts.
+            if !err.is_transient() || attempts >= policy.max_attempts {
+                return Outcome::Failed {
+                    err: err,
+                    attempts: attempts,
+                };
+            }
+
+            thread::sleep(policy.backoff(attempts));
+        }
+    }
+
+    /// Create a savepoint in the current transaction.
+    ///
+    /// `.rollback_to()` undoes everything queued after the savepoint, while keeping what was
+    /// queued before. This allows a failed sub-operation (e.g. one file of a batch import) to be
+    /// undone without reverting the whole transaction. Savepoints can be nested arbitrarily.
+    ///
+    /// The savepoint is only valid until the transaction ends. Note that automatic commits (see
+    /// `.set_auto_commit()`) end the transaction as well.
+    fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            epoch: self.epoch,
+            pipeline: self.disk.pipeline_len(),
+            state: self.state.clone(),
+            transaction: self.transaction,
+        }
+    }
+
+    /// Roll back to a savepoint.
+    ///
+    /// The savepoint is consumed, but the savepoints created before it stay valid. If the
+    /// transaction of the savepoint has ended, `Error::StaleSavepoint` is returned, and nothing is
+    /// rolled back.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        if savepoint.epoch != self.epoch {
+            return Err(Error::StaleSavepoint);
+        }
+
+        self.state = savepoint.state;
+        self.transaction = savepoint.transaction;
+        self.disk.revert_to(savepoint.pipeline);
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Read a page.
+    ///
+    /// This reads and decompresses the cluster of `ptr`, and returns the page's data.
+    ///
+    /// Note that this doesn't respond to allocations in the pipeline, only committed transactions.
+    pub fn read(&mut self, ptr: Pointer) -> Result<Box<[u8]>, Error> {
+        let start = Instant::now();
+        let res = self.read_cluster(ptr.cluster()).and_then(|data| extract_page(ptr, &data));
+        self.stats.read.record(start.elapsed());
+
+        res
+    }
+
+    /// Prefetch some pages.
+    ///
+    /// This is a hint from the layers above that the pages will likely be read soon (e.g. the
+    /// entries of a large directory). Their clusters are fetched into the cache in ascending
+    /// order, as background I/O.
+    ///
+    /// Since it is only a hint, errors are ignored; they'll be reported when the pages are
+    /// actually read.
+    fn prefetch(&mut self, ptrs: &[Pointer]) {
+        // Find the distinct clusters, in ascending order.
+        let mut clusters: Vec<u64> = ptrs.iter().map(|ptr| ptr.cluster().into()).collect();
+        clusters.sort();
+        clusters.dedup();
+
+        for cluster in clusters {
+            // Skip pointers outside the disk.
+            if let Some(cluster) = self.bounds.check(cluster) {
+                let physical = self.resolve(cluster);
+                let _ = self.disk.prefetch(physical);
+            }
+        }
+    }
+
+    /// Read the pages of an extent.
+    ///
+    /// Rather than reading the pages one by one, this reads every cluster backing the extent
+    /// once, in ascending order, so that the disk is accessed sequentially and clusters holding
+    /// multiple pages of the extent are only decoded once.
+    ///
+    /// The pages are returned in the order of the extent.
+    fn read_extent(&mut self, extent: &Extent) -> Result<Vec<Box<[u8]>>, Error> {
+        // Find the distinct clusters of the extent, in ascending order.
+        let mut clusters: Vec<u64> = extent.pages.iter().map(|ptr| ptr.cluster().into()).collect();
+        clusters.sort();
+        clusters.dedup();
+
+        // Read and decode the clusters.
+        let mut decoded = Vec::with_capacity(clusters.len());
+        for &cluster in &clusters {
+            // This can't fail, as the pointers in the extent are non-null.
+            let cluster = cluster::Pointer::new(cluster).unwrap();
+            decoded.push(self.read_cluster(cluster)?);
+        }
+
+        // Extract the pages.
+        extent.pages.iter().map(|&ptr| {
+            // The cluster is certainly in the list, as we built it from the extent.
+            let i = clusters.binary_search(&ptr.cluster().into()).unwrap();
+            extract_page(ptr, &decoded[i])
+        }).collect()
+    }
+
+    /// Resolve a data cluster to the physical cluster holding it.
+    ///
+    /// See the `indirection` module.
+    fn resolve(&self, cluster: cluster::Pointer) -> cluster::Pointer {
+        // The table only holds non-null clusters, so this can't fail.
+        cluster::Pointer::new(self.state.indirection.resolve(cluster.into())).unwrap()
+    }
+
+    /// Read and decode a data cluster.
+    ///
+    /// The decompressed content of the cluster is returned.
+    fn read_cluster(&mut self, cluster: cluster::Pointer) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        if let Err(err) = self.fetch_cluster(cluster, &mut data) {
+            if !self.rescue {
+                return Err(err);
+            }
+
+            let algorithms = self.checksum_algorithms();
+            let compression_algorithm = self.state.state_block.compression_algorithm;
+
+            // We're rescuing, so we return as much data as we can.
+            match err {
+                Error::ChecksumMismatch { .. } => {
+                    // Decode the cluster again, this time skipping verification.
+                    data.clear();
+                    let physical = self.resolve(cluster);
+                    let buf = self.disk.read(physical)?;
+                    let checksum_algorithm = algorithms.select(data_cluster_checksum_flag(buf));
+                    match decode_data_cluster(cluster, buf, checksum_algorithm, compression_algorithm, false, &mut data) {
+                        // Keep the partially decompressed data.
+                        Ok(()) | Err(Error::InvalidCompression { .. }) => (),
+                        Err(err) => return Err(err),
+                    }
+                },
+                // Keep the partially decompressed data.
+                Error::InvalidCompression { .. } => (),
+                _ => return Err(err),
+            }
+
+            // Don't mark the cluster verified.
+            return Ok(data);
+        }
+
+        Ok(data)
+    }
+
+    /// Read and decode a data cluster into `data`.
+    ///
+    /// Errors are recorded in the event log. On success, the cluster is marked verified.
+    fn fetch_cluster(&mut self, cluster: cluster::Pointer, data: &mut Vec<u8>) -> Result<(), Error> {
+        // Make sure that the pointer is within the disk.
+        if self.bounds.check(cluster.into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+        }
+
+        // Read and decode the cluster from its physical cluster.
+        let physical = self.resolve(cluster);
+        self.trace(trace::Kind::Read, trace::Subsystem::Data, physical);
+        let algorithms = self.checksum_algorithms();
+        let compression_algorithm = self.state.state_block.compression_algorithm;
+        let checksum_tree = self.state.state_block.checksum_tree;
+        // With full coverage, the header carries no checksum, and the tree is enabled.
+        let full_coverage = self.full_coverage();
+        let mut hash = None;
+        let res = match self.disk.read(physical) {
+            Ok(buf) => {
+                // Skip the verification if we trust the cached cluster, or defer it if we verify
+                // lazily.
+                let verify = match self.verification {
+                    Verification::Paranoid => true,
+                    Verification::TrustCache => !self.disk.is_verified(physical),
+                    Verification::Lazy => {
+                        if !self.disk.is_verified(physical) {
+                            self.unverified.insert(cluster);
+                        }
+
+                        false
+                    },
+                };
+                // The checksum tree entry is made by the same algorithm as the cluster header.
+                let checksum_algorithm = algorithms.select(data_cluster_checksum_flag(buf));
+                // Checksum the whole cluster, if it is to be verified against the checksum tree.
+                if verify && checksum_tree {
+                    hash = Some(checksum_algorithm.hash(buf));
+                }
+
+                data.clear();
+                decode_data_cluster(cluster, buf, checksum_algorithm, compression_algorithm, verify && !full_coverage, data)
+            },
+            Err(err) => Err(err.into()),
+        };
+        // Verify the cluster against its full-width checksum, if any.
+        let res = match (res, hash) {
+            (Ok(()), Some(hash)) => self.verify_checksum_entry(cluster, hash),
+            (res, _) => res,
+        };
+
+        match res {
+            // The cluster wasn't verified, so it isn't trusted yet.
+            Ok(()) if self.verification == Verification::Lazy && self.unverified.contains(&cluster) => Ok(()),
+            Ok(()) => {
+                // The cluster passed verification, so it can be trusted while it stays in the
+                // cache.
+                self.disk.mark_verified(physical);
+                Ok(())
+            },
+            Err(err) => {
+                // Record the error in the event log before we give up.
+                self.record_error(cluster, &err);
+                Err(err)
+            },
+        }
+    }
+
+    /// Verify the clusters read without verification.
+    ///
+    /// With `Verification::Lazy`, reads skip the verification, and leave it to this. Every
+    /// cluster read unverified since the last call is read from the disk, and verified like an
+    /// eager read would have. Damaged clusters are recorded in the event log, and returned, so the
+    /// reads of their pages can be retried (e.g. with `.read_salvage()`). Verified clusters are
+    /// trusted as long as they stay in the cache.
+    fn verify_pending(&mut self) -> Result<Vec<cluster::Pointer>, Error> {
+        let mut clusters: Vec<_> = self.unverified.drain().collect();
+        clusters.sort_by_key(|&cluster| u64::from(cluster));
+
+        let mut damaged = Vec::new();
+        let mut data = Vec::new();
+        for cluster in clusters {
+            // Clusters freed in the meantime are dropped from the set when pushed to the freelist
+            // (see `.queue_freelist_push()`), but the volume might have shrunk since.
+            if self.bounds.check(cluster.into()).is_none() {
+                continue;
+            }
+
+            // Clusters rewritten in the meantime are trusted already.
+            let physical = self.resolve(cluster);
+            if self.disk.is_verified(physical) {
+                continue;
+            }
+
+            let res = match self.disk.read(physical) {
+                Ok(buf) => Ok(buf.to_vec()),
+                Err(err) => Err(err.into()),
+            }.and_then(|buf| self.decode_verified(cluster, &buf, &mut data));
+            match res {
+                Ok(()) => self.disk.mark_verified(physical),
+                Err(Error::Disk(err)) if !err.is_media_error() => return Err(Error::Disk(err)),
+                Err(err) => {
+                    self.record_error(cluster, &err);
+                    damaged.push(cluster);
+                },
+            }
+        }
+
+        Ok(damaged)
+    }
+
+    /// Decode and verify a data cluster read around `.fetch_cluster()` (e.g. from the replica).
+    ///
+    /// The cluster is verified against its checksum tree entry, if the tree is enabled, and then
+    /// decoded into `data`, verifying its header checksum, unless the volume has full checksum
+    /// coverage. The tree is checked first, so partially decompressed data is only kept if the
+    /// cluster is intact.
+    fn decode_verified(&mut self, cluster: cluster::Pointer, buf: &[u8], data: &mut Vec<u8>) -> Result<(), Error> {
+        let checksum_algorithm = self.checksum_algorithms().select(data_cluster_checksum_flag(buf));
+        let compression_algorithm = self.state.state_block.compression_algorithm;
+
+        if self.state.state_block.checksum_tree {
+            self.verify_checksum_entry(cluster, checksum_algorithm.hash(buf))?;
+        }
+
+        decode_data_cluster(cluster, buf, checksum_algorithm, compression_algorithm, !self.full_coverage(), data)
+    }
+
+    /// Read some pages, salvaging what can be salvaged from damaged clusters.
+    ///
+    /// As opposed to `.read()`, a damaged cluster doesn't fail the read. Instead, recovery is
+    /// attempted in order:
+    ///
+    /// 1. If the volume is replicated, the cluster is read from the replica. If the replicated
+    ///    copy is intact, the local copy is repaired with it (on the next commit).
+    /// 2. If the cluster failed to decompress, but its checksum matched, the pages decompressed
+    ///    before the point of corruption are kept.
+    ///
+    /// The pages which couldn't be recovered are marked `Salvaged::Lost`. The pages are returned
+    /// in the order of `ptrs`.
+    fn read_salvage(&mut self, ptrs: &[Pointer]) -> Result<Vec<Salvaged>, Error> {
+        // Find the distinct clusters, in ascending order.
+        let mut clusters: Vec<u64> = ptrs.iter().map(|ptr| ptr.cluster().into()).collect();
+        clusters.sort();
+        clusters.dedup();
+
+        // Read and salvage the clusters.
+        let mut decoded = Vec::with_capacity(clusters.len());
+        for &cluster in &clusters {
+            // This can't fail, as the pointers are non-null.
+            let cluster = cluster::Pointer::new(cluster).unwrap();
+            decoded.push(self.salvage_cluster(cluster)?);
+        }
+
+        // Extract the pages.
+        ptrs.iter().map(|&ptr| {
+            // The cluster is certainly in the list, as we built it from the pointers.
+            let i = clusters.binary_search(&ptr.cluster().into()).unwrap();
+            let (ref data, complete) = decoded[i];
+
+            match extract_page(ptr, data) {
+                Ok(page) => Ok(Salvaged::Intact(page)),
+                // The page was beyond the point of corruption.
+                Err(Error::PageOutOfBounds { .. }) if !complete => Ok(Salvaged::Lost),
+                Err(err) => Err(err),
+            }
+        }).collect()
+    }
+
+    /// Read and decode a data cluster, salvaging it if it is damaged.
+    ///
+    /// The (possibly partial) decompressed data is returned along with a flag telling if it is
+    /// complete. See `.read_salvage()`.
+    fn salvage_cluster(&mut self, cluster: cluster::Pointer) -> Result<(Vec<u8>, bool), Error> {
+        let mut data = Vec::new();
+        match self.fetch_cluster(cluster, &mut data) {
+            Ok(()) => return Ok((data, true)),
+            // Damage can be salvaged.
+            Err(Error::ChecksumMismatch { .. })
+                | Err(Error::InvalidCompression { .. })
+                | Err(Error::Disk(_)) => (),
+            Err(err) => return Err(err),
+        }
+
+        let physical = self.resolve(cluster);
+
+        // Try the replica, if any.
+        let replicated = self.replicator.as_mut().and_then(|replicator| {
+            replicator.read(u64::from(physical) as disk::Sector)
+        });
+        if let Some(buf) = replicated {
+            data.clear();
+            if self.decode_verified(cluster, &buf, &mut data).is_ok() {
+                // Repair the local copy, unless the volume is read-only.
+                if self.disk.queue(physical, buf).is_ok() {
+                    self.events.record(events::Event::now(events::Kind::Repaired, cluster.into()));
+                }
+
+                return Ok((data, true));
+            }
+        }
+
+        // Decode the cluster again, keeping the partially decompressed data. Only the pages
+        // decompressed before the corruption are kept, and only if the checksum matched, as the
+        // data can't be trusted otherwise.
+        data.clear();
+        let res = match self.disk.read(physical) {
+            Ok(buf) => {
+                let buf = buf.to_vec();
+                self.decode_verified(cluster, &buf, &mut data)
+            },
+            Err(err) => Err(err.into()),
+        };
+        if let Err(Error::InvalidCompression { .. }) = res {
+            Ok((data, false))
+        } else {
+            // Nothing can be salvaged.
+            Ok((Vec::new(), false))
+        }
+    }
+
+    /// Record the checksum of a data cluster queued for writing.
+    ///
+    /// This does nothing, unless the checksum tree is enabled. The entry is applied to the tree
+    /// on commit.
+    fn record_checksum(&mut self, cluster: cluster::Pointer, buf: &[u8]) {
+        if self.state.state_block.checksum_tree {
+            let entry = checksums::entry(self.checksum(buf));
+            self.state.checksum_updates.insert(cluster, entry);
+        }
+    }
+
+    /// Clear the checksum tree entry of a freed data cluster.
+    ///
+    /// Otherwise, the stale entry would fail verification once the cluster is purged, and
+    /// `.verify()` would keep checking it.
+    fn forget_checksum(&mut self, cluster: cluster::Pointer) {
+        if self.state.state_block.checksum_tree {
+            self.state.checksum_updates.insert(cluster, 0);
+        }
+    }
+
+    /// Look up the checksum tree entry of a data cluster.
+    ///
+    /// Zero is returned if the cluster has no entry.
+    fn lookup_checksum(&mut self, cluster: cluster::Pointer) -> Result<u64, Error> {
+        // Entries changed in this transaction aren't in the tree yet.
+        if let Some(&entry) = self.state.checksum_updates.get(&cluster) {
+            return Ok(entry);
+        }
+
+        let mut node = match self.state.state_block.checksum_tree_root {
+            Some(root) => root,
+            None => return Ok(0),
+        };
+
+        // Descend from the root to the leaf covering the cluster.
+        let mut level = self.checksum_tree_depth() - 1;
+        loop {
+            let entry = self.read_checksum_node(node)?.entries[checksums::slot(cluster.into(), level)];
+            if level == 0 || entry == 0 {
+                return Ok(entry);
+            }
+
+            node = self.bounds.check(entry).ok_or(Error::PointerOutOfBounds { cluster: entry })?;
+            level -= 1;
+        }
+    }
+
+    /// Verify a data cluster against its checksum tree entry.
+    ///
+    /// `hash` is the checksum of the whole cluster, made by the algorithm its checksum flag
+    /// identifies. Clusters without an entry pass, unless the volume has full checksum coverage,
+    /// where every data cluster has one. In rescue mode, a damaged tree is ignored.
+    fn verify_checksum_entry(&mut self, cluster: cluster::Pointer, hash: u64) -> Result<(), Error> {
+        let expected = match self.lookup_checksum(cluster) {
+            Ok(expected) => expected,
+            Err(_) if self.rescue => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let found = checksums::entry(hash);
+        if (expected == 0 && !self.full_coverage()) || expected == found {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch {
+                cluster: cluster,
+                expected: expected,
+                found: found,
+            })
+        }
+    }
+
+    /// Read and decode a node of the checksum tree.
+    fn read_checksum_node(&mut self, cluster: cluster::Pointer) -> Result<checksums::Node, Error> {
+        self.trace(trace::Kind::Read, trace::Subsystem::ChecksumTree, cluster);
+        let algorithms = self.checksum_algorithms();
+        let buf = self.disk.read(cluster)?;
+
+        decode_checksum_node(cluster, buf, algorithms)
+    }
+
+    /// Visit the nodes of the checksum tree.
+    ///
+    /// `f` is called on every node with its cluster (as stored in its parent, so it might be out
+    /// of bounds), the first cluster it covers, its level (zero being the leaves), and the result
+    /// of decoding it. The nodes below undecodable nodes are skipped.
+    fn walk_checksum_tree<F>(&mut self, mut f: F)
+        where F: FnMut(u64, u64, u32, &Result<checksums::Node, Error>) {
+        let root = match self.state.state_block.checksum_tree_root {
+            Some(root) => root,
+            None => return,
+        };
+
+        let mut stack = vec![(u64::from(root), 0, self.checksum_tree_depth() - 1)];
+        while let Some((cluster, first, level)) = stack.pop() {
+            let res = match self.bounds.check(cluster) {
+                Some(cluster) => self.read_checksum_node(cluster),
+                None => Err(Error::PointerOutOfBounds { cluster: cluster }),
+            };
+
+            // Descend into the children of inner nodes.
+            if let Ok(ref node) = res {
+                if level > 0 {
+                    let span = (checksums::FANOUT as u64).pow(level);
+                    for (i, &child) in node.entries.iter().enumerate() {
+                        if child != 0 {
+                            stack.push((child, first + i as u64 * span, level - 1));
+                        }
+                    }
+                }
+            }
+
+            f(cluster, first, level, &res);
+        }
+    }
+
+    /// Get the checksum of a page.
+    ///
+    /// The checksum is calculated with the checksum algorithm of the volume over the page's data.
+    /// Since the page is read (and hence its cluster verified) in the process, this allows layers
+    /// above to implement end-to-end verification, by comparing it to a checksum they've stored
+    /// themselves.
+    fn page_checksum(&mut self, ptr: Pointer) -> Result<u64, Error> {
+        let page = self.read(ptr)?;
+        Ok(self.checksum(&page))
+    }
+
+    /// Queue a page allocation with an expected checksum.
+    ///
+    /// This is like `.queue_alloc()`, but it first verifies that the checksum of `buf` (as given
+    /// by `.page_checksum()`) is `expected`, such that corruption of the buffer between the caller
+    /// and the page manager is caught. If it doesn't match, `Error::PageChecksumMismatch` is
+    /// returned and nothing is queued.
+    fn queue_alloc_checked(&mut self, buf: &[u8], expected: u64) -> Result<Pointer, Error> {
+        let found = self.checksum(buf);
+        if expected != found {
+            return Err(Error::PageChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        self.queue_alloc(buf)
+    }
+
+    /// Queue a page allocation.
+    ///
+    /// This adds a transaction to the cache pipeline to allocate a page. It can be committed
+    /// through `.commit()`. `buf` must be `PAGE_SIZE` bytes long.
+    ///
+    /// The pointer to the allocated page is returned.
+    pub fn queue_alloc(&mut self, buf: &[u8]) -> Result<Pointer, Error> {
+        self.allocator(DEFAULT_STREAM).queue_alloc(buf)
+    }
+
+    /// Queue a copy-on-write update of a page.
+    ///
+    /// The new version `buf` is written to a freshly allocated page, and its pointer is returned;
+    /// `ptr` itself is never overwritten. The old version is released by the next commit, so a
+    /// crash (or a revert) before the commit leaves it intact. This is the building block for
+    /// crash-safe updates of the trees above: Rewrite the nodes bottom-up, and commit once the new
+    /// root is in place.
+    ///
+    /// Pages share clusters, so the cluster of `ptr` is only freed once all of its pages are
+    /// released. Purging it is queued after the state block of the commit (see `.queue_purges()`),
+    /// so a crash never destroys a page the state block on the disk still reaches. `ptr` must not
+    /// be referenced anywhere else (e.g. by a snapshot), as its cluster might be reused after the
+    /// commit.
+    fn queue_rewrite(&mut self, ptr: Pointer, buf: &[u8]) -> Result<Pointer, Error> {
+        // Make sure that the old pointer is within the disk, so we don't free garbage.
+        let cluster = ptr.cluster();
+        if self.bounds.check(cluster.into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+        }
+
+        let new = self.queue_alloc(buf)?;
+        self.state.rewritten.push(ptr);
+
+        Ok(new)
+    }
+
+    /// Queue an update of a page.
+    ///
+    /// With `UpdateMode::CopyOnWrite`, this is `.queue_rewrite()`. With `UpdateMode::InPlace`, the
+    /// page is overwritten in place, and `ptr` is returned, if the page can be overwritten (see
+    /// `.is_overwritable()`). Otherwise, it falls back to a copy-on-write update, so the caller
+    /// must always use the returned pointer.
+    fn queue_update(&mut self, ptr: Pointer, buf: &[u8], mode: UpdateMode) -> Result<Pointer, Error> {
+        if mode == UpdateMode::CopyOnWrite || !self.is_overwritable(ptr)? {
+            return self.queue_rewrite(ptr, buf);
+        }
+
+        assert_eq!(buf.len(), PAGE_SIZE, "Allocating a page of invalid size.");
+        self.apply_backpressure()?;
+        self.apply_auto_commit()?;
+
+        // Queue the new version over the old one. It still goes through the pipeline, so a revert
+        // before the commit keeps the old version.
+        let cluster = self.raw_cluster(buf);
+        self.record_checksum(ptr.cluster(), &cluster);
+        self.disk.queue(ptr.cluster(), cluster)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::Data, ptr.cluster());
+        self.account_alloc();
+
+        Ok(ptr)
+    }
+
+    /// Check if a page can be overwritten in place.
+    ///
+    /// This is the case if the durability mode allows in-place updates, and the page is alone in
+    /// an uncompressed cluster (as allocated by `.queue_alloc_raw()`), which no reader can see,
+    /// which isn't being released, and which isn't in a sequential zone. The caller must make sure
+    /// that the page isn't shared (e.g. by a snapshot), as we have no reference counts to tell.
+    fn is_overwritable(&mut self, ptr: Pointer) -> Result<bool, Error> {
+        let cluster = ptr.cluster();
+        if self.durability == Durability::Full
+            || ptr.index() != 0
+            || self.in_zone(cluster)
+            || self.has_readers()
+            || self.state.dead_pages.contains_key(&cluster)
+            || self.state.rewritten.iter().any(|old| old.cluster() == cluster)
+            || self.state.streams.values().any(|stream| stream.last_cluster == Some(cluster))
+            // Moved clusters are only updated by moving them again.
+            || self.state.indirection.is_moved(cluster.into())
+            // With full coverage, a crash between writing the cluster and its checksum tree entry
+            // would leave no valid checksum, losing the cluster.
+            || self.full_coverage() {
+            return Ok(false);
+        }
+
+        // Make sure that the pointer is within the disk.
+        if self.bounds.check(cluster.into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+        }
+
+        // The lowest bit of the data cluster header is the compression flag. Uncompressed
+        // clusters hold a single page.
+        let header = codec::Reader::new(self.disk.read(cluster)?).read_u16()
+            .map_err(|_| Error::Truncated { cluster: cluster })?;
+        Ok(header & 1 == 0)
+    }
+
+    /// Queue a move of a data cluster.
+    ///
+    /// The content of the cluster is copied verbatim (checksums included) to another physical
+    /// cluster, and the indirection table is updated to map the cluster there, so the pointers to
+    /// its pages stay valid (see the `indirection` module). The target is a spare, if one can be
+    /// reused, or a cluster popped from the freelist. The old physical cluster stays intact until
+    /// the transaction is committed, so a revert or a crash leaves the old mapping usable. The new
+    /// physical cluster is returned.
+    ///
+    /// This is the building block of defragmentation, shrinking, and evacuating devices. The
+    /// cluster is verified before being copied, so damage isn't spread.
+    fn queue_move_cluster(&mut self, cluster: cluster::Pointer) -> Result<cluster::Pointer, Error> {
+        let mut data = Vec::new();
+        self.fetch_cluster(cluster, &mut data)?;
+        let old = self.resolve(cluster);
+        let buf = self.disk.read(old)?.to_vec().into_boxed_slice();
+
+        // Implementations unaware of the table would read the moved clusters from their old
+        // places, so they must refuse the volume from now on.
+        if !self.header().indirection {
+            self.disk.inner_mut().enable_indirection()?;
+        }
+
+        // Reuse a spare, unless it might be seen by a reader, or is still in use by the committed
+        // state (which a revert would bring back). Spares in zones can't be overwritten.
+        let spare = if self.has_readers() {
+            None
+        } else {
+            let committed = &self.committed_state.indirection;
+            let zones = &self.state.zones;
+            self.state.indirection.find_spare(|spare| {
+                committed.is_spare(spare) && !zones.as_ref().map_or(false, |zones| zones.contains(spare as disk::Sector))
+            })
+        };
+        let target = match spare {
+            Some(spare) => self.bounds.check(spare).ok_or(Error::PointerOutOfBounds { cluster: spare })?,
+            None => {
+                let target = self.queue_freelist_pop()?;
+                self.trace(trace::Kind::Alloc, trace::Subsystem::Data, target);
+                target
+            },
+        };
+        self.disk.queue(target, buf)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::Data, target);
+
+        Arc::make_mut(&mut self.state.indirection).insert(cluster.into(), target.into());
+        self.state.indirection_dirty = true;
+        // Packing would append to the cluster at its old place, so the streams move on.
+        for stream in self.state.streams.values_mut() {
+            if stream.last_cluster == Some(cluster) {
+                stream.last_cluster = None;
+                stream.last_cluster_data.clear();
+            }
+        }
+
+        // Free the old physical cluster on commit, unless it became a spare, i.e. the cluster was
+        // moved for the first time.
+        if old != cluster && !self.state.indirection.is_used(old.into()) {
+            self.state.vacated.push(old);
+        }
+
+        Ok(target)
+    }
+
+    /// Queue the free of a data cluster.
+    ///
+    /// If the cluster was moved, its entry is removed from the indirection table, and both its
+    /// number and its physical cluster are freed, unless still in use by the table (see
+    /// `indirection::Table::is_used()`).
+    fn queue_free_data_cluster(&mut self, cluster: cluster::Pointer) -> Result<(), Error> {
+        if !self.state.indirection.is_moved(cluster.into()) {
+            return self.queue_freelist_push(cluster, trace::Subsystem::Data);
+        }
+
+        let physical = self.resolve(cluster);
+        Arc::make_mut(&mut self.state.indirection).remove(cluster.into());
+        self.state.indirection_dirty = true;
+        for &freed in &[cluster, physical] {
+            if !self.state.indirection.is_used(freed.into()) {
+                self.queue_freelist_push(freed, trace::Subsystem::Data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release a page, freeing its cluster if no live page is left in it.
+    ///
+    /// The cluster holding the superpage of a generation in the root history is kept, so the
+    /// generation stays readable (see `.open_at_generation()`), and only freed once the generation
+    /// drops out of the history.
+    pub fn release_page(&mut self, ptr: Pointer) -> Result<(), Error> {
+        let cluster = ptr.cluster();
+        self.state.dead_pages.entry(cluster).or_insert_with(HashSet::new).insert(ptr.index());
+        self.state.dead_pages_dirty = true;
+
+        if self.is_retained_root(cluster, &self.history) {
+            return Ok(());
+        }
+
+        self.queue_free_if_dead(cluster)
+    }
+
+    /// Is some cluster holding the root of a retained generation?
+    ///
+    /// These are the current and the committed superpage, and the superpages of `history`.
+    fn is_retained_root(&self, cluster: cluster::Pointer, history: &history::History) -> bool {
+        self.state.state_block.superpage.cluster() == cluster
+            || self.committed_state.state_block.superpage.cluster() == cluster
+            || history.generations().iter().any(|generation| generation.superpage.cluster() == cluster)
+    }
+
+    /// Free a cluster, if every page in it is released.
+    fn queue_free_if_dead(&mut self, cluster: cluster::Pointer) -> Result<(), Error> {
+        let dead = self.state.dead_pages.get(&cluster).map_or(0, HashSet::len);
+        if dead == 0 {
+            return Ok(());
+        }
+
+        // Pages might still be packed into the last cluster of a stream, so it is kept until the
+        // stream has moved on, and another of its pages is released. Clusters in zones are never
+        // packed any further.
+        if !self.in_zone(cluster)
+            && self.state.streams.values().any(|stream| stream.last_cluster == Some(cluster)) {
+            return Ok(());
+        }
+
+        // Count the pages of the cluster. If it can't be read, it is kept, rather than failing the
+        // commit, and merely leaked.
+        let mut data = Vec::new();
+        if self.fetch_cluster(cluster, &mut data).is_ok() && dead >= data.len() / PAGE_SIZE {
+            self.state.dead_pages.remove(&cluster);
+            self.state.dead_pages_dirty = true;
+            self.forget_checksum(cluster);
+            self.queue_free_data_cluster(cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the allocation context of some packing stream.
+    ///
+    /// Pages allocated through different streams are never packed into the same cluster, so
+    /// interleaved writes of unrelated data don't get mixed up. Allocations through
+    /// `.queue_alloc()` go to the default stream, `DEFAULT_STREAM`.
+    fn allocator(&mut self, stream: StreamId) -> Allocator<D> {
+        Allocator {
+            manager: self,
+            stream: stream,
+        }
+    }
+
+    /// Close a packing stream.
+    ///
+    /// This releases the state of the stream. Its last cluster is not packed any further; a later
+    /// allocation in the same stream starts a new cluster.
+    fn close_stream(&mut self, stream: StreamId) {
+        self.state.streams.remove(&stream);
+    }
+
+    /// Queue a page allocation in some stream, keeping statistics.
+    fn queue_alloc_stream(&mut self, id: StreamId, buf: &[u8]) -> Result<Pointer, Error> {
+        let start = Instant::now();
+
+        // Commit first, if the transaction has grown too large. This must happen before the
+        // stream is taken out of the state, so the committed state keeps it.
+        self.apply_auto_commit()?;
+
+        // Take the stream out of the state while allocating, and put it back afterwards.
+        let mut stream = self.state.streams.remove(&id).unwrap_or_default();
+        let res = self.queue_alloc_inner(&mut stream, Half::of_stream(id), buf);
+        self.state.streams.insert(id, stream);
+        if res.is_ok() {
+            self.account_alloc();
+        }
+
+        self.stats.alloc.record(start.elapsed());
+
+        res
+    }
+
+    /// Queue a page allocation in some stream, without keeping statistics.
+    ///
+    /// If `half` is given, the page is only packed into a cluster in that half of the volume, and
+    /// new clusters are preferably taken from it (see `.queue_freelist_pop_in()`).
+    fn queue_alloc_inner(&mut self, stream: &mut Stream, half: Option<Half>, buf: &[u8]) -> Result<Pointer, Error> {
+        assert_eq!(buf.len(), PAGE_SIZE, "Allocating a page of invalid size.");
+
+        // Don't let the dirty data grow unboundedly.
+        self.apply_backpressure()?;
+
+        // Allocate a buffer for constructing the cluster.
+        let mut cluster = vec![0; DATA_CLUSTER_HEADER];
+        // The number of pages (including the new one) in the last allocated cluster.
+        let pages = stream.last_cluster_data.len() / PAGE_SIZE + 1;
+        // Only try to pack the page into the last allocated cluster, if the packing policy allows
+        // another page in it. This saves the recompression otherwise.
+        let pack = stream.last_cluster.map_or(false, |last_cluster| {
+            !self.in_zone(last_cluster) && half.map_or(true, |half| self.half(last_cluster) == half)
+        }) && pages <= self.state.state_block.packing_policy.max_pages();
+
+        if pack {
+            // Extend the last allocated cluster with the new page.
+            stream.last_cluster_data.extend_from_slice(buf);
+            // Compress the last allocated cluster.
+            self.compress(&stream.last_cluster_data, &mut cluster);
+        }
+
+        match stream.last_cluster {
+            Some(last_cluster) if pack && cluster.len() <= disk::SECTOR_SIZE => {
+                // The pages could fit in the cluster.
+                let size = cluster.len() - DATA_CLUSTER_HEADER;
+
+                // Pad with zeros until the sector is full.
+                while cluster.len() != disk::SECTOR_SIZE {
+                    cluster.push(0);
+                }
+
+                // Calculate and write the checksum, and set the compression flag.
+                self.seal_data_cluster(&mut cluster, true);
+
+                // Queue the write of the recompress cluster.
+                self.record_checksum(last_cluster, &cluster);
+                self.disk.queue(last_cluster, cluster.into_boxed_slice())?;
+                self.trace(trace::Kind::Write, trace::Subsystem::Data, last_cluster);
+                self.stats.packing.record(stats::Placement::Packed, stream.last_cluster_size, size);
+                stream.last_cluster_size = size;
+
+                // The new page is the last one in the cluster.
+                Ok(Pointer::new(last_cluster, (pages - 1) as u8))
+            }
+            _ => {
+                // Unable to fit the pages into the cluster (or there is no last allocated cluster,
+                // or the packing policy forbids packing more pages into it).
+
+                // Truncate the unusable compressed buffer.
+                cluster.truncate(DATA_CLUSTER_HEADER);
+                // Extend the cluster with the buffer to allocate.
+                cluster.extend_from_slice(&buf);
+
+                // Calculate and write the checksum, and unset the compression flag (i.e.
+                // uncompressed).
+                self.seal_data_cluster(&mut cluster, false);
+
+                // We cannot fit more into the last allocated cluster, so we clear it.
+                stream.last_cluster_data.clear();
+                // Update it with the new given data.
+                stream.last_cluster_data.extend_from_slice(&buf);
+
+                // Allocate a cluster and set this as the new last allocated cluster.
+                let last_cluster = match half {
+                    Some(half) => self.queue_freelist_pop_in(half)?,
+                    None => self.queue_alloc_cluster()?,
+                };
+                self.trace(trace::Kind::Alloc, trace::Subsystem::Data, last_cluster);
+                stream.last_cluster = Some(last_cluster);
+
+                // Queue a write to the new cluster.
+                self.record_checksum(last_cluster, &cluster);
+                self.disk.queue(last_cluster, cluster.into_boxed_slice())?;
+                self.trace(trace::Kind::Write, trace::Subsystem::Data, last_cluster);
+                let placement = if pack {
+                    stats::Placement::DidntFit
+                } else {
+                    stats::Placement::NewCluster
+                };
+                self.stats.packing.record(placement, 0, PAGE_SIZE);
+                stream.last_cluster_size = PAGE_SIZE;
+
+                // The new page is the only one in the cluster.
+                Ok(Pointer::new(last_cluster, 0))
+            }
+        }
+    }
+
+    /// Queue the allocation of a metadata page.
+    ///
+    /// Metadata pages (e.g. object tree nodes) are allocated like other pages, unless raw
+    /// metadata is enabled in the state block. In that case, the page is stored uncompressed in a
+    /// cluster of its own, and duplicated into another cluster. This makes the metadata carvable
+    /// from the raw disk: Every such cluster is self-contained and verifiable by its checksum.
+    ///
+    /// Otherwise, the page is duplicated if the volume keeps two copies of metadata. The
+    /// duplicate is packed in a stream of its own, so it never shares a cluster with the primary,
+    /// and placed in the other half of the volume (see `.copy_half()`).
+    ///
+    /// The page must be read through `.read_metadata()`.
+    fn queue_alloc_metadata(&mut self, buf: &[u8]) -> Result<MetadataPointer, Error> {
+        if self.state.state_block.raw_metadata {
+            return Ok(MetadataPointer {
+                primary: self.queue_alloc_raw(buf)?,
+                copy: Some(self.queue_alloc_raw(buf)?),
+            });
+        }
+
+        let primary = self.queue_alloc(buf)?;
+        let copy = if self.state.state_block.metadata_copies == 2 {
+            let stream = METADATA_COPY_STREAMS[self.copy_half(primary.cluster()) as usize];
+            Some(self.allocator(stream).queue_alloc(buf)?)
+        } else {
+            None
+        };
+
+        Ok(MetadataPointer {
+            primary: primary,
+            copy: copy,
+        })
+    }
+
+    /// Queue the allocation of a page in an uncompressed cluster of its own.
+    fn queue_alloc_raw(&mut self, buf: &[u8]) -> Result<Pointer, Error> {
+        self.queue_alloc_raw_in(buf, None)
+    }
+
+    /// Queue the allocation of a page in an uncompressed cluster of its own.
+    ///
+    /// The page goes into `cluster`, which must already be taken off the freelist, or into a newly
+    /// allocated cluster (see `.queue_alloc_cluster()`), if `None`.
+    fn queue_alloc_raw_in(&mut self, buf: &[u8], cluster: Option<cluster::Pointer>) -> Result<Pointer, Error> {
+        assert_eq!(buf.len(), PAGE_SIZE, "Allocating a page of invalid size.");
+
+        // Don't let the dirty data nor the transaction grow unboundedly.
+        self.apply_backpressure()?;
+        self.apply_auto_commit()?;
+
+        // Allocate a cluster (unless given one) and queue a write to it.
+        let ptr = match cluster {
+            Some(cluster) => cluster,
+            None => self.queue_alloc_cluster()?,
+        };
+        let cluster = self.raw_cluster(buf);
+        self.trace(trace::Kind::Alloc, trace::Subsystem::Data, ptr);
+        self.record_checksum(ptr, &cluster);
+        self.disk.queue(ptr, cluster)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::Data, ptr);
+        self.account_alloc();
+
+        Ok(Pointer::new(ptr, 0))
+    }
+
+    /// Construct an uncompressed data cluster holding a single page.
+    fn raw_cluster(&self, buf: &[u8]) -> Box<[u8]> {
+        // Unset the compression flag (i.e. uncompressed).
+        let mut cluster = vec![0; DATA_CLUSTER_HEADER];
+        cluster.extend_from_slice(buf);
+        self.seal_data_cluster(&mut cluster, false);
+
+        cluster.into_boxed_slice()
+    }
+
+    /// Write the header of a data cluster.
+    ///
+    /// This writes the checksum into the header, unless the volume has full checksum coverage, in
+    /// which case the header only holds the flags (see `write_data_cluster_flags()`).
+    fn seal_data_cluster(&self, cluster: &mut [u8], compressed: bool) {
+        if self.full_coverage() {
+            write_data_cluster_flags(cluster, self.checksum_algorithms(), compressed);
+        } else {
+            write_data_cluster_header(cluster, self.checksum_algorithms(), compressed);
+        }
+    }
+
+    /// Does the volume have full checksum coverage?
+    ///
+    /// See `header::ChecksumCoverage`.
+    fn full_coverage(&self) -> bool {
+        self.header().checksum_coverage == header::ChecksumCoverage::Full
+    }
+
+    /// Read and decode a duplicated cluster.
+    ///
+    /// `decode` is tried on the primary copy, and then on the duplicate (if any). If both fail,
+    /// the error of the primary copy is returned.
+    fn read_duplicated<T, F>(&mut self, primary: cluster::Pointer, copy: Option<cluster::Pointer>, mut decode: F)
+        -> Result<T, Error>
+        where F: FnMut(&[u8]) -> Result<T, Error> {
+        let res = self.disk.read(primary).map_err(Error::from).and_then(|buf| decode(buf));
+
+        match (res, copy) {
+            (Err(err), Some(copy)) => {
+                // Record the damage of the primary copy before falling back.
+                self.record_error(primary, &err);
+                self.disk.read(copy).map_err(Error::from).and_then(|buf| decode(buf)).map_err(|_| err)
+            },
+            (res, _) => res,
+        }
+    }
+
+    /// Read a metadata page.
+    ///
+    /// If the primary copy can't be read, the duplicate (if any) is read instead. If both fail,
+    /// the error of the primary copy is returned.
+    fn read_metadata(&mut self, ptr: MetadataPointer) -> Result<Box<[u8]>, Error> {
+        match (self.read(ptr.primary), ptr.copy) {
+            (Err(err), Some(copy)) => self.read(copy).map_err(|_| err),
+            (res, _) => res,
+        }
+    }
+
+    /// Queue the allocation of an extent in the default stream.
+    ///
+    /// See `Allocator::queue_alloc_extent()`.
+    fn queue_alloc_extent(&mut self, buf: &[u8]) -> Result<Extent, Error> {
+        self.allocator(DEFAULT_STREAM).queue_alloc_extent(buf)
+    }
+
+    /// Get a reader of an extent.
+    ///
+    /// The reader implements `std::io::Read` and `std::io::Seek`, so the extent can be consumed by
+    /// code written against the standard traits.
+    fn extent_reader<'a>(&'a mut self, extent: &'a Extent) -> ExtentReader<'a, D> {
+        ExtentReader {
+            manager: self,
+            extent: extent,
+            pos: 0,
+            page: None,
+        }
+    }
+
+    /// Get a writer building an extent in some stream.
+    ///
+    /// The writer implements `std::io::Write`. The data is buffered a page at a time, and the
+    /// extent is returned by `ExtentWriter::finish()`.
+    fn extent_writer(&mut self, stream: StreamId) -> ExtentWriter<D> {
+        ExtentWriter {
+            allocator: self.allocator(stream),
+            page: Vec::with_capacity(PAGE_SIZE),
+            extent: Extent {
+                pages: Vec::new(),
+                len: 0,
+            },
+        }
+    }
+
+    /// Queue the allocation of an extent for direct I/O.
+    ///
+    /// As opposed to `.queue_alloc_extent()`, the pages are neither packed nor compressed: Every
+    /// page is stored as is, in a cluster of its own (see `.queue_alloc_raw()`). This costs space,
+    /// but gives predictable placement and latency, for databases doing their own caching and
+    /// compression. Note that the writes still go through the cache until flushed, as the ordering
+    /// of the transaction must be upheld.
+    ///
+    /// Extents of at least the optimal I/O size of the disk (see `.geometry()`) are placed in a run
+    /// of consecutive clusters starting at an aligned cluster, if the freelist head has one, so
+    /// the flush writes them as whole stripes. Otherwise, the clusters are popped one by one.
+    fn queue_alloc_extent_direct(&mut self, buf: &[u8]) -> Result<Extent, Error> {
+        let pages = (buf.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut run = if pages > 1 && pages >= self.geometry().optimal_io_size {
+            self.queue_freelist_pop_run(pages)?.into_iter()
+        } else {
+            Vec::new().into_iter()
+        };
+
+        alloc_extent(buf, |page| {
+            let cluster = run.next();
+            self.queue_alloc_raw_in(page, cluster)
+        })
+    }
+
+    /// Read a page for direct I/O.
+    ///
+    /// The cluster is read without being inserted into the cache, so direct reads don't evict the
+    /// working set of other users. It is always verified, as there is no cached copy to trust.
+    /// This works on any page, but only saves the decompression on pages allocated for direct I/O.
+    fn read_direct(&mut self, ptr: Pointer) -> Result<Box<[u8]>, Error> {
+        let start = Instant::now();
+
+        let cluster = ptr.cluster();
+        // Make sure that the pointer is within the disk.
+        if self.bounds.check(cluster.into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+        }
+
+        // Read and decode the cluster from its physical cluster.
+        let physical = self.resolve(cluster);
+        self.trace(trace::Kind::Read, trace::Subsystem::Data, physical);
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        let mut data = Vec::new();
+        let res = self.disk.read_uncached(physical, &mut buf).map_err(Error::from);
+        let res = res.and_then(|()| self.decode_verified(cluster, &buf, &mut data));
+        self.stats.read.record(start.elapsed());
+
+        if let Err(err) = res {
+            // Record the error in the event log before we give up.
+            self.record_error(cluster, &err);
+            return Err(err);
+        }
+
+        extract_page(ptr, &data)
+    }
+
+    /// Calculate the checksum of some buffer, based on the user configuration.
+    fn checksum(&self, buf: &[u8]) -> u64 {
+        self.header().checksum_algorithm.hash(buf)
+    }
+
+    /// Compress some data based on the compression configuration option.
+    ///
+    /// This compresses `source` into `target` based on the chosen configuration method, defined in
+    /// the state block.
+    fn compress(&self, source: &[u8], target: &mut Vec<u8>) {
+        match self.state.state_block.compression_algorithm {
+            // Memcpy as a compression algorithm!!!11!
+            CompressionAlgorithm::Identity => target.extend_from_slice(source),
+            // Compress via LZ4.
+            CompressionAlgorithm::Lz4 => lz4_compress::compress_into(source, target),
+        }
+    }
+
+    /// Decompress some data based on the compression configuration option.
+    ///
+    /// This decompresses `source` into `target` based on the chosen configuration method, defined
+    /// in the state block.
+    fn decompress(&self, source: &[u8], target: &mut Vec<u8>) -> Result<(), Error> {
+        match self.state.state_block.compression_algorithm {
+            // Memcpy as a compression algorithm!!!11!
+            CompressionAlgorithm::Identity => target.extend_from_slice(source),
+            // Decompress from LZ4.
+            CompressionAlgorithm::Lz4 => lz4_compress::decompress_into_bounded(source, target, MAX_DECOMPRESSED_SIZE)?,
+        }
+
+        Ok(())
+    }
+
+    /// Queue a flush of the indirection table.
+    ///
+    /// The table is written to a new chain of clusters, which the state block is made to link,
+    /// and the old chain is freed, so a crash before the commit leaves the old table intact. An
+    /// empty table has no chain.
+    fn queue_indirection_flush(&mut self) -> Result<(), Error> {
+        let entries = self.state.indirection.entries();
+        let checksum_algorithm = self.header().checksum_algorithm;
+
+        // Write the chain back to front, so every cluster can link the next one.
+        let mut clusters = Vec::new();
+        let mut next = 0;
+        for chunk in entries.chunks(indirection::ENTRIES).rev() {
+            let cluster = self.queue_freelist_pop()?;
+            self.trace(trace::Kind::Alloc, trace::Subsystem::Indirection, cluster);
+
+            let buf = indirection::Chunk {
+                next: next,
+                entries: chunk.to_vec(),
+            }.encode(checksum_algorithm);
+            let buf = self.flag_checksum(buf);
+            self.disk.queue(cluster, buf)?;
+            self.trace(trace::Kind::Write, trace::Subsystem::Indirection, cluster);
+
+            next = cluster.into();
+            clusters.push(cluster);
+        }
+        clusters.reverse();
+
+        // Link the new chain, and free the old one.
+        self.state.state_block.indirection = clusters.first().cloned();
+        self.queue_state_block_flush()?;
+        let old = mem::replace(&mut self.state.indirection_clusters, clusters);
+        self.queue_freelist_push_iter(old, trace::Subsystem::Indirection)?;
+        self.state.indirection_dirty = false;
+
+        Ok(())
+    }
+
+    /// Queue a flush of the dead page list.
+    ///
+    /// Like the indirection table (see `.queue_indirection_flush()`), the list is written to a new
+    /// chain of clusters, which the state block is made to link, and the old chain is freed. An
+    /// empty list has no chain.
+    fn queue_dead_pages_flush(&mut self) -> Result<(), Error> {
+        let mut pages = Vec::new();
+        for (&cluster, dead) in &self.state.dead_pages {
+            pages.extend(dead.iter().map(|&index| Pointer::new(cluster, index)));
+        }
+        // Keep the order stable, so an unchanged list is written identically.
+        pages.sort_by_key(|ptr| ptr.encode());
+        let checksum_algorithm = self.header().checksum_algorithm;
+
+        // Write the chain back to front, so every cluster can link the next one.
+        let mut clusters = Vec::new();
+        let mut next = 0;
+        for chunk in pages.chunks(dead_pages::ENTRIES).rev() {
+            let cluster = self.queue_freelist_pop()?;
+            self.trace(trace::Kind::Alloc, trace::Subsystem::DeadPages, cluster);
+
+            let buf = dead_pages::Chunk {
+                next: next,
+                pages: chunk.to_vec(),
+            }.encode(checksum_algorithm);
+            let buf = self.flag_checksum(buf);
+            self.disk.queue(cluster, buf)?;
+            self.trace(trace::Kind::Write, trace::Subsystem::DeadPages, cluster);
+
+            next = cluster.into();
+            clusters.push(cluster);
+        }
+        clusters.reverse();
+
+        // Link the new chain, and free the old one.
+        self.state.state_block.dead_pages = clusters.first().cloned();
+        self.queue_state_block_flush()?;
+        let old = mem::replace(&mut self.state.dead_pages_clusters, clusters);
+        self.queue_freelist_push_iter(old, trace::Subsystem::DeadPages)?;
+        self.state.dead_pages_dirty = false;
+
+        Ok(())
+    }
+
+    /// Queue a state block flush.
+    ///
+    /// This queues a new transaction flushing the state block.
+    fn queue_state_block_flush(&mut self) -> Result<(), Error> {
+        // The state block links the freelist head, so a pending head is flushed first. Otherwise,
+        // the state block could point to a metacluster which was never written.
+        if self.state.freelist_dirty {
+            self.queue_freelist_head_flush()?;
+        }
+
+        // Encode the state block with the checksum algorithm given in the disk header.
+        let buf = self.flag_checksum(self.state.state_block.encode(self.header().checksum_algorithm));
+        let address = self.header().state_block_address;
+        self.disk.queue(address, Box::new(buf))?;
+        self.trace(trace::Kind::Write, trace::Subsystem::StateBlock, address);
+        self.state.state_block_dirty = false;
+
+        Ok(())
+    }
+
+    /// Queue a freelist head flush.
+    ///
+    /// This queues a new transaction flushing the freelist head.
+    fn queue_freelist_head_flush(&mut self) -> Result<(), Error> {
+        // Encode the freelist head into a metacluster.
+        let buf = encode_metacluster(&self.state.freelist, self.state.freelist_next_copy, self.checksum_algorithms());
+
+        // Queue the write of the duplicate, if any. It goes first, so a torn write of either copy
+        // leaves the other one intact.
+        if let Some(copy) = self.state.state_block.freelist_head_copy {
+            self.disk.queue(copy, buf.clone())?;
+            self.trace(trace::Kind::Write, trace::Subsystem::Freelist, copy);
+        }
+
+        // Queue the write of the updated buffer.
+        let head = self.state.state_block.freelist_head;
+        self.disk.queue(head, buf)?;
+        self.trace(trace::Kind::Write, trace::Subsystem::Freelist, head);
+        self.state.freelist_dirty = false;
+
+        Ok(())
+    }
+
+    /// Queue the allocation of a data cluster.
+    ///
+    /// On zoned disks, this appends to the open zone (see `zones::Zones::alloc()`), and falls back
+    /// to the freelist (i.e. the conventional zones) once every zone is full. Otherwise, this pops
+    /// from the freelist. Metadata overwritten in place (e.g. metaclusters) must not use this, and
+    /// pops from the freelist directly.
+    fn queue_alloc_cluster(&mut self) -> Result<cluster::Pointer, Error> {
+        let sector = match self.state.zones {
+            Some(ref mut zones) => zones.alloc(),
+            None => None,
+        };
+
+        match sector {
+            Some(sector) => {
+                let cluster = self.bounds.check(sector as u64)
+                    .ok_or(Error::PointerOutOfBounds { cluster: sector as u64 })?;
+                self.check_shadow(|shadow| shadow.alloc(cluster.into()))?;
+
+                Ok(cluster)
+            }
+            None => self.queue_freelist_pop(),
+        }
+    }
+
+    /// Is some cluster in a zone of a zoned disk?
+    ///
+    /// Such clusters must be written sequentially, so they're never overwritten: They aren't
+    /// packed any further, updated in place, nor purged.
+    fn in_zone(&self, cluster: cluster::Pointer) -> bool {
+        self.state.zones.as_ref().map_or(false, |zones| zones.contains(u64::from(cluster) as disk::Sector))
+    }
+
+    /// Pick the zone to reclaim next.
+    ///
+    /// See `zones::Zones::victim()`. `None` is returned if no zone is full, or the disk isn't
+    /// zoned.
+    fn zone_victim(&self) -> Option<(disk::Sector, disk::Sector)> {
+        self.state.zones.as_ref().and_then(|zones| zones.victim())
+    }
+
+    /// Queue the migration of the live pages of a zone.
+    ///
+    /// This is the first step of reclaiming the zone starting at `start`: Every live page of it is
+    /// rewritten elsewhere (see `.queue_rewrite()`), and the pairs of old and new pointers are
+    /// returned. The page manager doesn't know who points to the pages, so the caller must update
+    /// the references, and commit, which releases the old pages. The zone can then be reset (see
+    /// `.reset_zone()`).
+    ///
+    /// The clusters freed before the volume was opened are unknown (see `zones::Zones::new()`), so
+    /// their pages are migrated as well. The caller must release the new pages it doesn't refer
+    /// to (see `.release_page()`).
+    fn queue_migrate_zone(&mut self, start: disk::Sector) -> Result<Vec<(Pointer, Pointer)>, Error> {
+        let sectors = match self.state.zones {
+            Some(ref zones) => zones.live_sectors(start)?,
+            None => return Err(zones::Error::OutOfZones { sector: start }.into()),
+        };
+
+        // The sectors are physical clusters. Both the cluster a sector holds and, if it was moved
+        // away, the cluster of the sector's number (whose number is still in use, see the
+        // `indirection` module) must be migrated to free the sector.
+        let mut clusters = Vec::new();
+        for sector in sectors {
+            let sector = sector as u64;
+            clusters.extend(self.state.indirection.owner(sector));
+            if self.state.indirection.is_moved(sector) {
+                clusters.push(sector);
+            }
+        }
+        clusters.sort();
+        clusters.dedup();
+
+        let mut relocations = Vec::new();
+        let mut data = Vec::new();
+        for cluster in clusters {
+            let cluster = self.bounds.check(cluster).ok_or(Error::PointerOutOfBounds { cluster: cluster })?;
+            self.fetch_cluster(cluster, &mut data)?;
+
+            for index in 0..data.len() / PAGE_SIZE {
+                let ptr = Pointer::new(cluster, index as u8);
+                // Skip the pages released already.
+                if self.state.dead_pages.get(&cluster).map_or(false, |dead| dead.contains(&ptr.index())) {
+                    continue;
+                }
+
+                let page = data[index * PAGE_SIZE..(index + 1) * PAGE_SIZE].to_vec();
+                let new = self.queue_rewrite(ptr, &page)?;
+                relocations.push((ptr, new));
+            }
+        }
+
+        Ok(relocations)
+    }
+
+    /// Reset a zone, whose live pages were migrated.
+    ///
+    /// Every cluster of the zone must be freed by a committed transaction, i.e. the migration (see
+    /// `.queue_migrate_zone()`) must be committed. Otherwise, `zones::Error::ZoneNotEmpty` is
+    /// returned, and nothing is done. The reset can't be undone, so it takes effect in the
+    /// committed state as well.
+    fn reset_zone(&mut self, start: disk::Sector) -> Result<(), Error> {
+        match (&self.state.zones, &self.committed_state.zones) {
+            (&Some(ref zones), &Some(ref committed)) => {
+                zones.check_empty(start)?;
+                committed.check_empty(start)?;
+            }
+            _ => return Err(zones::Error::OutOfZones { sector: start }.into()),
+        }
+
+        // The reset destroys the old pages, so the references to the migrated pages must be on
+        // stable storage first, whatever the durability mode.
+        self.flush()?;
+        self.disk.inner_mut().sync()?;
+        self.disk.inner_mut().reset_zone(start)?;
+        for zones in self.state.zones.iter_mut().chain(self.committed_state.zones.iter_mut()) {
+            zones.reset(start)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue a pop from the freelist.
+    ///
+    /// This pops from the top of the in-memory freelist head and returns the result. The head is
+    /// written on commit. If the pop exhausts it, the next metacluster becomes the head, and the
+    /// state block linking it is written right away, before the old head is reused.
+    fn queue_freelist_pop(&mut self) -> Result<cluster::Pointer, Error> {
+        // Pop from the metacluster.
+        if let Some(mut cluster) = self.state.freelist.pop() {
+            if self.state.freelist.is_empty() {
+                // The head metacluster is exhausted, so we load the next metacluster (specified to be
+                // the last pointer in the metacluster), i.e. `cluster`. The old metacluster is then
+                // used as the popped cluster.
+                mem::swap(&mut self.state.state_block.freelist_head, &mut cluster);
+                // The duplicate of the next metacluster (if any) becomes the duplicate of the head.
+                let next_copy = self.state.freelist_next_copy.take();
+                let old_copy = mem::replace(&mut self.state.state_block.freelist_head_copy, next_copy);
+                self.state.freelist_cycle.step(self.state.state_block.freelist_head)?;
+                self.load_freelist()?;
+                // The new head is as on the disk. The old one is handed out, so its pending
+                // changes don't matter.
+                self.state.freelist_dirty = false;
+                self.queue_freelist_head_copy()?;
+
+                // The state block must stop linking the old metacluster before it is reused, so
+                // it is flushed right away rather than on commit. The cache chains the writes in
+                // the order queued, so deferring it would let the data written to the handed out
+                // cluster reach the disk while the state block still links it as the freelist
+                // head, and a crash in between would leave an unmountable volume.
+                self.queue_state_block_flush()?;
+
+                // The duplicate of the old head is no longer linked, so it is freed.
+                if let Some(copy) = old_copy {
+                    self.queue_freelist_push(copy, trace::Subsystem::Freelist)?;
+                }
+            } else {
+                // The freelist head was changed by the pop, so it must be flushed on commit.
+                self.state.freelist_dirty = true;
+            }
+
+            // Update the free cluster count.
+            self.update_free_clusters(-1);
+
+            self.check_shadow(|shadow| shadow.alloc(cluster.into()))?;
+            // The cluster is about to be overwritten, so a pending purge is redundant.
+            self.state.purges.remove(&cluster);
+
+            Ok(cluster)
+        } else {
+            // We ran out of clusters :(.
+            Err(Error::OutOfClusters)
+        }
+    }
+
+    /// Queue the purges of the clusters freed in the current transaction.
+    ///
+    /// The data of the clusters is overwritten as configured, with the `security` feature forcing
+    /// at least zeroing. This must be queued after the state block of the transaction: The cache
+    /// writes in the order queued, so purging earlier could destroy clusters (e.g. the superpage
+    /// replaced by a rewrite) which the state block on the disk still references, if the system
+    /// crashes in between.
+    fn queue_purges(&mut self) -> Result<(), Error> {
+        let mut method = self.state.state_block.purge_method;
+        if cfg!(feature = "security") && method == state_block::PurgeMethod::None {
+            method = state_block::PurgeMethod::Zero;
+        }
+
+        let mut purges = mem::replace(&mut self.state.purges, HashSet::new()).into_iter().collect::<Vec<_>>();
+        // Purge in disk order.
+        purges.sort_by_key(|&cluster| u64::from(cluster));
+        for cluster in purges {
+            if let Some(buf) = purge_buffer(method, cluster) {
+                self.disk.queue(cluster, buf)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queue a push to the freelist.
+    ///
+    /// This pushes some free cluster to the top of the in-memory freelist head, which is written on
+    /// commit. If the head is full or empty, the cluster becomes the new head metacluster, which
+    /// is written on commit along with the state block, while the pending changes of the old head
+    /// are written right away, as it is no longer kept in memory. The first pointer of a
+    /// metacluster is always the link to the next one, so clusters are never pushed into an empty
+    /// head, which terminates the chain. The free is traced as coming from
+    /// `subsystem` once the cluster is actually pushed.
+    fn queue_freelist_push(&mut self, cluster: cluster::Pointer, subsystem: trace::Subsystem) -> Result<(), Error> {
+        if self.has_readers() {
+            // The cluster might be part of the snapshot of some reader, so it must neither be
+            // purged nor reused until the reader is gone. It is freed (and traced) on a later
+            // commit.
+            self.state.deferred_frees.push((cluster, subsystem));
+            return Ok(());
+        }
+
+        // Deferred frees are checked when they're actually pushed.
+        self.check_shadow(|shadow| shadow.free(cluster.into()))?;
+        // The content of a free cluster is of no concern, so it isn't verified lazily either.
+        self.unverified.remove(&cluster);
+
+        // Clusters of zones can neither be overwritten nor reused before their zone is reset (see
+        // `.reset_zone()`), so they're merely marked dead, and not purged.
+        let zoned = match self.state.zones {
+            Some(ref mut zones) => match zones.free(u64::from(cluster) as disk::Sector) {
+                Ok(()) => true,
+                Err(zones::Error::OutOfZones { .. }) => false,
+                Err(err) => return Err(err.into()),
+            },
+            None => false,
+        };
+        if zoned {
+            self.trace(trace::Kind::Free, subsystem, cluster);
+            return Ok(());
+        }
+
+        // The cluster is purged on commit, after the state block which stops referencing it.
+        self.state.purges.insert(cluster);
+
+        if self.state.freelist.is_empty() || self.state.freelist.len() >= self.freelist_capacity() {
+            // The freelist head is full, or is the empty metacluster terminating the chain (whose
+            // first pointer would otherwise be taken for a link), and therefore we use following
+            // algorithm:
+            //
+            // 1. Create a new metacluster at `cluster`.
+            // 2. Link said metacluster to the old metacluster.
+            // 3. Queue a flush.
+
+            // Aligned clusters might start aligned runs (see `.queue_freelist_pop_run()`), so they
+            // are kept free rather than used as metaclusters: An unaligned cluster of the head
+            // becomes the metacluster instead, and the cluster takes its place in the head.
+            let mut metacluster = cluster;
+            let geometry = self.geometry();
+            if geometry.alignment > 1 && geometry.is_aligned(u64::from(cluster) as disk::Sector) {
+                let unaligned = self.state.freelist.iter().skip(1)
+                    .position(|&free| !geometry.is_aligned(u64::from(free) as disk::Sector));
+                if let Some(i) = unaligned {
+                    mem::swap(&mut self.state.freelist[i + 1], &mut metacluster);
+                    self.state.freelist_dirty = true;
+                }
+            }
+            // The new metacluster is overwritten by the head flush, so it isn't purged.
+            self.state.purges.remove(&metacluster);
+
+            // With two copies of metadata, the duplicate of the new metacluster is taken from the
+            // old head, before it is flushed.
+            let copy = if self.state.state_block.metadata_copies == 2 {
+                let half = self.copy_half(metacluster);
+                self.queue_freelist_take(half)?
+            } else {
+                None
+            };
+
+            // The old head stops being flushed on commit, so its pending changes are flushed now.
+            if self.state.freelist_dirty {
+                self.queue_freelist_head_flush()?;
+            }
+
+            // Clear the in-memory freelist head mirror.
+            self.state.freelist.clear();
+            // Put the link to the old freelist head into the new metacluster. The duplicate of the
+            // old head is now the duplicate of the next metacluster.
+            self.state.freelist.push(self.state.state_block.freelist_head);
+            self.state.freelist_next_copy = mem::replace(&mut self.state.state_block.freelist_head_copy, copy);
+            // The chain now starts at a new metacluster, so metaclusters we have already
+            // passed might legitimately be visited again.
+            self.state.freelist_cycle.reset();
+
+            // Update the freelist head pointer to point to the new metacluster.
+            self.state.state_block.freelist_head = metacluster;
+            // The new metacluster and the state block linking it are flushed on commit. This is
+            // completely consistent as the freelist head is always flushed before the state block
+            // (see `.queue_state_block_flush()`), thus rendering the pointed cluster a valid
+            // metacluster. If the state block flush fails, the metacluster will merely be an
+            // orphan cluster, and therefore simply leaked space.
+            self.state.freelist_dirty = true;
+            self.state.state_block_dirty = true;
+        } else {
+            // There is space for more clusters in the head metacluster.
+
+            // Push the cluster pointer to the freelist head, which must be flushed on commit.
+            self.state.freelist.push(cluster);
+            self.state.freelist_dirty = true;
+
+            // lulz @ these comments. like shit, ticki, they add basically nothing you fuking dumb
+            // monkey. seriously stop it
+        }
+
+        // Update the free cluster count.
+        self.update_free_clusters(1);
+        self.trace(trace::Kind::Free, subsystem, cluster);
+
+        Ok(())
+    }
+
+    /// Get the number of pointers the freelist head can hold.
+    ///
+    /// A metacluster linking the duplicate of the next metacluster holds two pointers less (see
+    /// `METACLUSTER_COPY_SLOT`).
+    fn freelist_capacity(&self) -> usize {
+        if self.state.freelist_next_copy.is_some() {
+            METACLUSTER_COPY_SLOT - 1
+        } else {
+            METACLUSTER_SIZE / cluster::POINTER_SIZE
+        }
+    }
+
+    /// Get the half of the volume holding some cluster.
+    fn half(&self, cluster: cluster::Pointer) -> Half {
+        if u64::from(cluster) < self.bounds.size() / 2 {
+            Half::Lower
+        } else {
+            Half::Upper
+        }
+    }
+
+    /// Get the half of the volume the duplicate of some cluster is placed in.
+    ///
+    /// This is the other half than the one holding `primary`.
+    fn copy_half(&self, primary: cluster::Pointer) -> Half {
+        match self.half(primary) {
+            Half::Lower => Half::Upper,
+            Half::Upper => Half::Lower,
+        }
+    }
+
+    /// Queue taking a free cluster in some half of the volume out of the freelist head.
+    ///
+    /// Only the freelist head is searched, as the rest of the freelist isn't in memory, so if it
+    /// holds no cluster in `half`, another cluster of the head is taken. The link to the next
+    /// metacluster is never taken, so `None` is returned if the head holds nothing else.
+    fn queue_freelist_take(&mut self, half: Half) -> Result<Option<cluster::Pointer>, Error> {
+        if self.state.freelist.len() < 2 {
+            return Ok(None);
+        }
+
+        // Prefer the topmost cluster in the half, and fall back to the top of the head.
+        let i = (1..self.state.freelist.len()).rev()
+            .find(|&i| self.half(self.state.freelist[i]) == half)
+            .unwrap_or(self.state.freelist.len() - 1);
+        let cluster = self.state.freelist.remove(i);
+        self.state.freelist_dirty = true;
+
+        self.update_free_clusters(-1);
+        self.check_shadow(|shadow| shadow.alloc(cluster.into()))?;
+        self.state.purges.remove(&cluster);
+
+        Ok(Some(cluster))
+    }
+
+    /// Queue a pop from the freelist, preferring some half of the volume.
+    ///
+    /// See `.queue_freelist_take()`. If the freelist head holds nothing but the link to the next
+    /// metacluster, this pops as usual.
+    fn queue_freelist_pop_in(&mut self, half: Half) -> Result<cluster::Pointer, Error> {
+        match self.queue_freelist_take(half)? {
+            Some(cluster) => Ok(cluster),
+            None => self.queue_freelist_pop(),
+        }
+    }
+
+    /// Queue the update of the duplicate of the freelist head.
+    ///
+    /// This matches the duplicate with the number of metadata copies: With two copies, a head
+    /// without a duplicate gets one in the other half of the volume, unless the head holds nothing
+    /// to take it from (see `.queue_freelist_take()`), in which case the next head gets one. With
+    /// one copy, the duplicate is freed. The head and the state block are flushed on commit.
+    fn queue_freelist_head_copy(&mut self) -> Result<(), Error> {
+        match (self.state.state_block.metadata_copies, self.state.state_block.freelist_head_copy) {
+            (2, None) => {
+                let half = self.copy_half(self.state.state_block.freelist_head);
+                let copy = self.queue_freelist_take(half)?;
+                if let Some(copy) = copy {
+                    self.trace(trace::Kind::Alloc, trace::Subsystem::Freelist, copy);
+                }
+                self.state.state_block.freelist_head_copy = copy;
+            },
+            (1, Some(copy)) => {
+                self.state.state_block.freelist_head_copy = None;
+                self.queue_freelist_push(copy, trace::Subsystem::Freelist)?;
+            },
+            _ => return Ok(()),
+        }
+
+        self.state.freelist_dirty = true;
+        self.state.state_block_dirty = true;
+
+        Ok(())
+    }
+
+    /// Queue pops of an aligned run of `n` consecutive clusters from the freelist.
+    ///
+    /// The run is taken from the freelist head (see `find_aligned_run()`), as the rest of the
+    /// freelist isn't in memory. If the head has no such run, nothing is popped, and an empty
+    /// vector is returned.
+    fn queue_freelist_pop_run(&mut self, n: usize) -> Result<Vec<cluster::Pointer>, Error> {
+        let start = match find_aligned_run(&self.state.freelist, n, self.geometry()) {
+            Some(start) => start,
+            None => return Ok(Vec::new()),
+        };
+        let end = start + n as u64;
+
+        // Take the run out of the head, keeping the link to the next metacluster at the bottom.
+        let mut run = Vec::with_capacity(n);
+        let link = self.state.freelist[0];
+        let rest = self.state.freelist.drain(1..).collect::<Vec<_>>();
+        for cluster in rest {
+            if (start..end).contains(&u64::from(cluster)) {
+                run.push(cluster);
+            } else {
+                self.state.freelist.push(cluster);
+            }
+        }
+        debug_assert_eq!(self.state.freelist[0], link);
+        run.sort_by_key(|&cluster| u64::from(cluster));
+        self.state.freelist_dirty = true;
+
+        for &cluster in &run {
+            self.update_free_clusters(-1);
+            self.check_shadow(|shadow| shadow.alloc(cluster.into()))?;
+            self.state.purges.remove(&cluster);
+        }
+
+        Ok(run)
+    }
+
+    /// Queue `n` pops from the freelist.
+    ///
+    /// The popped clusters are returned in the order popped. Like single pops, the changes of the
+    /// freelist head are coalesced into one flush on commit. If the freelist runs out of clusters,
+    /// the clusters popped so far are pushed back, and `Error::OutOfClusters` is returned.
+    fn queue_freelist_pop_n(&mut self, n: usize) -> Result<Vec<cluster::Pointer>, Error> {
+        let mut clusters = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.queue_freelist_pop() {
+                Ok(cluster) => clusters.push(cluster),
+                Err(err) => {
+                    // Push them back in reverse, so the freelist is as it was.
+                    self.queue_freelist_push_iter(clusters.into_iter().rev(), trace::Subsystem::Freelist)?;
+                    return Err(err);
+                },
+            }
+        }
+
+        Ok(clusters)
+    }
+
+    /// Queue pushes of several clusters to the freelist.
+    ///
+    /// The clusters are pushed in order, and the changes of the freelist head are coalesced into
+    /// one flush on commit.
+    fn queue_freelist_push_iter<I>(&mut self, clusters: I, subsystem: trace::Subsystem) -> Result<(), Error>
+        where I: IntoIterator<Item = cluster::Pointer> {
+        for cluster in clusters {
+            self.queue_freelist_push(cluster, subsystem)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An allocation context of a packing stream.
+///
+/// This is obtained through `Manager::allocator()`.
+struct Allocator<'a, D: 'a> {
+    /// The page manager.
+    manager: &'a mut Manager<D>,
+    /// The stream to allocate in.
+    stream: StreamId,
+}
+
+impl<'a, D: Disk> Allocator<'a, D> {
+    /// Queue a page allocation in the stream.
+    ///
+    /// See `Manager::queue_alloc()`.
+    fn queue_alloc(&mut self, buf: &[u8]) -> Result<Pointer, Error> {
+        self.manager.queue_alloc_stream(self.stream, buf)
+    }
+
+    /// Queue the allocation of an extent.
+    ///
+    /// This splits `buf` into pages (padding the last one with zeros), and queues their
+    /// allocations in the stream. Since the pages are allocated right after each other, they are
+    /// packed into the same clusters when possible, and otherwise placed in the clusters popped
+    /// from the freelist, which tend to be consecutive. The descriptor of the extent is returned.
+    fn queue_alloc_extent(&mut self, buf: &[u8]) -> Result<Extent, Error> {
+        alloc_extent(buf, |page| self.queue_alloc(page))
+    }
+}
+
+/// A reader of an extent.
+///
+/// This is obtained through `Manager::extent_reader()`. It keeps the current page, so small reads
+/// don't decode the same cluster over and over again.
+struct ExtentReader<'a, D: 'a> {
+    /// The page manager.
+    manager: &'a mut Manager<D>,
+    /// The extent to read.
+    extent: &'a Extent,
+    /// The current position in the extent (in bytes).
+    pos: u64,
+    /// The index and data of the last page read, if any.
+    page: Option<(usize, Box<[u8]>)>,
+}
+
+impl<'a, D: Disk> io::Read for ExtentReader<'a, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Stop at the end of the extent, rather than reading the padding of the last page.
+        let len = self.extent.len as u64;
+        if self.pos >= len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Read the page of the current position, unless we already have it.
+        let index = (self.pos / PAGE_SIZE as u64) as usize;
+        if self.page.as_ref().map_or(true, |&(cached, _)| cached != index) {
+            let data = self.manager.read(self.extent.pages[index])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            self.page = Some((index, data));
+        }
+
+        // Copy as much as we can from the page.
+        let offset = (self.pos % PAGE_SIZE as u64) as usize;
+        let amount = cmp::min(cmp::min(buf.len(), PAGE_SIZE - offset), (len - self.pos) as usize);
+        let page = &self.page.as_ref().unwrap().1;
+        buf[..amount].copy_from_slice(&page[offset..offset + amount]);
+        self.pos += amount as u64;
+
+        Ok(amount)
+    }
+}
+
+impl<'a, D: Disk> io::Seek for ExtentReader<'a, D> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(pos) => (0, pos as i64),
+            io::SeekFrom::End(offset) => (self.extent.len as i64, offset),
+            io::SeekFrom::Current(offset) => (self.pos as i64, offset),
+        };
+
+        // Seeking past the end is allowed (reads then return nothing), but not before the start.
+        match base.checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as u64;
+                Ok(self.pos)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position.")),
+        }
+    }
+}
+
+/// A writer building an extent.
+///
+/// This is obtained through `Manager::extent_writer()`. Pages are immutable, so the writer only
+/// appends; rewriting parts of an extent is up to the layers above. The extent is only complete
+/// after `.finish()`, which allocates the last, partial page.
+struct ExtentWriter<'a, D: 'a> {
+    /// The allocator of the stream.
+    allocator: Allocator<'a, D>,
+    /// The data of the page being filled.
+    page: Vec<u8>,
+    /// The extent built so far.
+    extent: Extent,
+}
+
+impl<'a, D: Disk> ExtentWriter<'a, D> {
+    /// Finish the extent.
+    ///
+    /// The last page is padded with zeros and allocated, and the descriptor of the extent is
+    /// returned.
+    fn finish(mut self) -> Result<Extent, Error> {
+        if !self.page.is_empty() {
+            self.page.resize(PAGE_SIZE, 0);
+            self.extent.pages.push(self.allocator.queue_alloc(&self.page)?);
+        }
+
+        Ok(self.extent)
+    }
+}
+
+impl<'a, D: Disk> io::Write for ExtentWriter<'a, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Fill the current page.
+        let amount = cmp::min(buf.len(), PAGE_SIZE - self.page.len());
+        self.page.extend_from_slice(&buf[..amount]);
+
+        // Allocate the page once full.
+        if self.page.len() == PAGE_SIZE {
+            let ptr = match self.allocator.queue_alloc(&self.page) {
+                Ok(ptr) => ptr,
+                Err(err) => {
+                    // Nothing of `buf` was written, so take the bytes back out of the page, and
+                    // the write can be retried.
+                    self.page.truncate(PAGE_SIZE - amount);
+                    return Err(io::Error::new(io::ErrorKind::Other, err));
+                },
+            };
+            self.extent.pages.push(ptr);
+            self.page.clear();
+        }
+
+        self.extent.len += amount;
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial pages can't be allocated before the extent is finished, so there is nothing to
+        // do. Committing is up to the owner of the manager.
+        Ok(())
+    }
+}
+
+/// A reader of a committed generation.
+///
+/// This is obtained through `Manager::reader()`. It reads directly from the disk, bypassing the
+/// cache of the manager, and without verification skipping, statistics or event logging.
+struct Reader<R> {
+    /// The disk handle.
+    disk: R,
+    /// The superpage of the snapshot.
+    superpage: Pointer,
+    /// The generation of the snapshot.
+    generation: u64,
+    /// The checksum algorithms at the time of the snapshot.
+    checksum_algorithms: ChecksumAlgorithms,
+    /// The compression algorithm at the time of the snapshot.
+    compression_algorithm: state_block::CompressionAlgorithm,
+    /// The bounds of valid cluster pointers.
+    bounds: cluster::Bounds,
+    /// The indirection table of the snapshot.
+    indirection: Arc<indirection::Table>,
+    /// The root of the checksum tree of the snapshot, if any.
+    checksum_tree_root: Option<cluster::Pointer>,
+    /// The depth of the checksum tree of the snapshot.
+    checksum_tree_depth: u32,
+    /// Does the volume have full checksum coverage?
+    full_coverage: bool,
+    /// The pin keeping freed clusters from being reused.
+    _pin: Arc<()>,
+}
+
+impl<R: Disk> Reader<R> {
+    /// Get the superpage of the snapshot.
+    fn superpage(&self) -> Pointer {
+        self.superpage
+    }
+
+    /// Get the generation of the snapshot.
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Read a page.
+    ///
+    /// See `Manager::read()`.
+    fn read(&mut self, ptr: Pointer) -> Result<Box<[u8]>, Error> {
+        let cluster = ptr.cluster();
+        // Make sure that the pointer is within the disk.
+        if self.bounds.check(cluster.into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: cluster.into() });
+        }
+
+        // Read the cluster from its physical cluster on the disk.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        self.disk.read(self.indirection.resolve(cluster.into()) as disk::Sector, &mut buf)?;
+
+        // Verify it against its checksum tree entry, if any. With full coverage, every data
+        // cluster has one, and the header carries no checksum.
+        let checksum_algorithm = self.checksum_algorithms.select(data_cluster_checksum_flag(&buf));
+        let expected = self.lookup_checksum(cluster)?;
+        let found = checksums::entry(checksum_algorithm.hash(&buf));
+        if (expected != 0 || self.full_coverage) && expected != found {
+            return Err(Error::ChecksumMismatch {
+                cluster: cluster,
+                expected: expected,
+                found: found,
+            });
+        }
+
+        // Decode it and extract the page.
+        let compression_algorithm = self.compression_algorithm;
+        let mut data = Vec::new();
+        decode_data_cluster(cluster, &buf, checksum_algorithm, compression_algorithm, !self.full_coverage, &mut data)?;
+
+        extract_page(ptr, &data)
+    }
+
+    /// Look up the checksum tree entry of a data cluster in the snapshot.
+    ///
+    /// Zero is returned if the cluster has no entry. See `Manager::lookup_checksum()`.
+    fn lookup_checksum(&mut self, cluster: cluster::Pointer) -> Result<u64, Error> {
+        let mut node = match self.checksum_tree_root {
+            Some(root) => root,
+            None => return Ok(0),
+        };
+
+        // Descend from the root to the leaf covering the cluster.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        let mut level = self.checksum_tree_depth - 1;
+        loop {
+            self.disk.read(u64::from(node) as disk::Sector, &mut buf)?;
+            let entry = decode_checksum_node(node, &buf, self.checksum_algorithms)?.entries[checksums::slot(cluster.into(), level)];
+            if level == 0 || entry == 0 {
+                return Ok(entry);
+            }
+
+            node = self.bounds.check(entry).ok_or(Error::PointerOutOfBounds { cluster: entry })?;
+            level -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointer_inverse_identity() {
+        for &(cluster, index) in &[(1, 0), (1, 255), (2000, 3), (0xFFFFFFFFFFFFF, 42)] {
+            let ptr = Pointer::new(cluster::Pointer::new(cluster).unwrap(), index);
+            assert_eq!(Pointer::decode(ptr.encode()), Some(ptr));
+        }
+    }
+
+    #[test]
+    fn pointer_corruption() {
+        let ptr = Pointer::new(cluster::Pointer::new(2000).unwrap(), 3).encode();
+
+        // Null cluster.
+        assert_eq!(Pointer::decode(0), None);
+        assert_eq!(Pointer::decode(ptr & 0xFFF), None);
+        // Single bit flips are always caught by the checksum nibble.
+        for bit in 0..64 {
+            assert_eq!(Pointer::decode(ptr ^ 1 << bit), None);
+        }
+    }
+
+    #[test]
+    fn pointer_layout() {
+        // The layout of the specification: r = 16 (256 c + p) + k, with the checksum nibble k
+        // being the xor of the 4-bit groups of 256 c + p.
+        for &(cluster, index) in &[(1, 0), (10, 1), (2000, 3), ((1 << 52) - 1, 255)] {
+            let packed = 256 * cluster + index as u64;
+            let mut nibble = 0;
+            let mut rest = packed;
+            while rest != 0 {
+                nibble ^= rest & 0xF;
+                rest >>= 4;
+            }
+
+            let ptr = Pointer::new(cluster::Pointer::new(cluster).unwrap(), index);
+            assert_eq!(ptr.encode(), 16 * packed + nibble);
+            assert_eq!(Pointer::decode(16 * packed + nibble), Some(ptr));
+        }
+
+        // Page 1 of cluster 10, as in the golden images.
+        assert_eq!(Pointer::new(cluster::Pointer::new(10).unwrap(), 1).encode(), 0xa01b);
+    }
+
+    #[test]
+    fn extent_contiguity() {
+        let page = |cluster, index| Pointer::new(cluster::Pointer::new(cluster).unwrap(), index);
+
+        assert!(Extent { pages: vec![], len: 0 }.is_contiguous());
+        assert!(Extent { pages: vec![page(5, 0), page(5, 1), page(6, 0), page(7, 0)], len: 2000 }.is_contiguous());
+        assert!(!Extent { pages: vec![page(5, 0), page(7, 0)], len: 1000 }.is_contiguous());
+        assert!(!Extent { pages: vec![page(5, 0), page(4, 0)], len: 1000 }.is_contiguous());
+    }
+
+    #[test]
+    fn auto_commit_thresholds() {
+        let size = TransactionSize { bytes: 1000, ops: 2 };
+
+        assert!(!AutoCommit::default().is_exceeded(size));
+        assert!(AutoCommit { max_bytes: Some(1000), max_ops: None }.is_exceeded(size));
+        assert!(!AutoCommit { max_bytes: Some(1001), max_ops: Some(3) }.is_exceeded(size));
+        assert!(AutoCommit { max_bytes: Some(1 << 20), max_ops: Some(2) }.is_exceeded(size));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(40));
+        assert_eq!(policy.backoff(4), Duration::from_millis(50));
+        assert_eq!(policy.backoff(1000), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn space_usage() {
+        let mut usage = SpaceUsage {
+            total: 100,
+            reserved: 9,
+            metadata: 3,
+            metaclusters: 2,
+            free: 50,
+        };
+        assert_eq!(usage.data(), Some(36));
+
+        // Counting clusters twice shows up as an inconsistency.
+        usage.free = 90;
+        assert_eq!(usage.data(), None);
+    }
+
+    #[test]
+    fn space_by_type() {
+        let mut manager = formatted(64);
+        let usage = manager.space_by_type().unwrap();
+        assert_eq!(usage.total, 64);
+        assert_eq!(usage.reserved, (0..64).filter(|&cluster| manager.bounds.check(cluster).is_none()).count() as u64);
+        // The superpage is the only data cluster of a fresh volume.
+        assert_eq!(usage.data(), Some(1));
+    }
+
+    #[test]
+    fn pointer_display() {
+        let ptr = Pointer::new(cluster::Pointer::new(0xAB).unwrap(), 7);
+        assert_eq!(ptr.to_string(), "ab:7");
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(Error::Disk(disk::Error::Interrupted).is_transient());
+        // Retrying doesn't flush the cache.
+        assert!(!Error::WouldBlock.is_transient());
+
+        // Retrying doesn't repair a sector, or make the disk bigger or writable.
+        assert!(!Error::Disk(disk::Error::OutOfBounds).is_transient());
+        assert!(!Error::Disk(disk::Error::SectorCorrupted).is_transient());
+        assert!(!Error::Disk(disk::Error::ReadOnly).is_transient());
+        assert!(!Error::Disk(disk::Error::BackingExhausted).is_transient());
+        assert!(!Error::StaleSavepoint.is_transient());
+    }
+
+    #[test]
+    fn aligned_runs() {
+        let geometry = disk::Geometry {
+            optimal_io_size: 4,
+            alignment: 4,
+            ..disk::Geometry::default()
+        };
+        let freelist: Vec<cluster::Pointer> = [16, 9, 10, 11, 13, 14, 15, 20, 21, 22, 23, 24].iter()
+            .map(|&cluster| cluster::Pointer::new(cluster).unwrap())
+            .collect();
+
+        // 12 isn't free, and 16 links the next metacluster.
+        assert_eq!(find_aligned_run(&freelist, 4, geometry), Some(20));
+        assert_eq!(find_aligned_run(&freelist, 2, geometry), Some(20));
+        assert_eq!(find_aligned_run(&freelist, 6, geometry), None);
+        assert_eq!(find_aligned_run(&freelist, 3, disk::Geometry::default()), Some(9));
+        assert_eq!(find_aligned_run(&freelist[..1], 1, geometry), None);
+    }
+
+    /// Format a volume of `sectors` sectors in memory.
+    fn formatted(sectors: usize) -> Manager<storage::StorageDisk<Vec<u8>>> {
+        let disk = storage::StorageDisk::new(vec![0; sectors * disk::SECTOR_SIZE]);
+        Manager::format(header::Driver::init(disk).unwrap()).unwrap()
+    }
+
+    /// The checksum algorithms of a volume using SeaHash, outside of migrations.
+    fn seahash_algorithms() -> ChecksumAlgorithms {
+        ChecksumAlgorithms {
+            current: header::ChecksumAlgorithm::SeaHash,
+            flag: false,
+            previous: None,
+        }
+    }
+
+    #[test]
+    fn golden_metacluster() {
+        // A metacluster as written by the current format version (see `header::format_version()`).
+        // If this fails, the layout has changed, and old images won't open anymore.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        // The checksum.
+        buf[..8].copy_from_slice(&[0x3a, 0xa4, 0xb9, 0x42, 0x87, 0x9e, 0xa8, 0x24]);
+        // The link to the next metacluster (13), and the free clusters (14 and 15).
+        buf[8] = 13;
+        buf[16] = 14;
+        buf[24] = 15;
+
+        let cluster = cluster::Pointer::new(20).unwrap();
+        let mut freelist = Vec::new();
+        decode_metacluster(cluster, &buf, header::ChecksumAlgorithm::SeaHash, cluster::Bounds::new(64, 8), &mut freelist).unwrap();
+        assert_eq!(freelist.iter().map(|&x| u64::from(x)).collect::<Vec<_>>(), vec![13, 14, 15]);
+
+        // Rewriting the metacluster must reproduce the image.
+        assert_eq!(&encode_metacluster(&freelist, None, seahash_algorithms())[..], &buf[..]);
+    }
+
+    #[test]
+    fn metacluster_copy_link() {
+        let bounds = cluster::Bounds::new(64, 8);
+        let cluster = cluster::Pointer::new(20).unwrap();
+        let copy = cluster::Pointer::new(40).unwrap();
+        let freelist = vec![cluster::Pointer::new(13).unwrap(), cluster::Pointer::new(14).unwrap()];
+
+        // The link goes into the last slot, past the end of the freelist.
+        let buf = encode_metacluster(&freelist, Some(copy), seahash_algorithms());
+        assert_eq!(buf[METACLUSTER_HEADER + METACLUSTER_COPY_SLOT * cluster::POINTER_SIZE], 40);
+        let mut decoded = Vec::new();
+        let res = decode_metacluster(cluster, &buf, header::ChecksumAlgorithm::SeaHash, bounds, &mut decoded);
+        assert_eq!(res.unwrap(), Some(copy));
+        assert_eq!(decoded, freelist);
+
+        // A full metacluster has no link.
+        let full = vec![cluster; METACLUSTER_SIZE / cluster::POINTER_SIZE];
+        let buf = encode_metacluster(&full, None, seahash_algorithms());
+        let res = decode_metacluster(cluster, &buf, header::ChecksumAlgorithm::SeaHash, bounds, &mut decoded);
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(decoded, full);
+    }
+
+    #[test]
+    fn golden_data_cluster() {
+        // An uncompressed data cluster as written by the current format version.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        // The header: the 14 bit checksum, the checksum flag and the compression flag (both
+        // unset).
+        buf[..2].copy_from_slice(&[0xfc, 0x17]);
+        // The page.
+        buf[2..13].copy_from_slice(b"golden page");
+
+        let cluster = cluster::Pointer::new(20).unwrap();
+        let mut data = Vec::new();
+        decode_data_cluster(cluster, &buf, header::ChecksumAlgorithm::SeaHash, CompressionAlgorithm::Lz4, true, &mut data).unwrap();
+        assert_eq!(&data[..], &buf[DATA_CLUSTER_HEADER..]);
+
+        // Rewriting the header must reproduce the image.
+        let mut rewritten = buf.clone();
+        write_data_cluster_header(&mut rewritten, seahash_algorithms(), false);
+        assert_eq!(rewritten, buf);
+    }
+
+    #[test]
+    fn golden_compressed_cluster() {
+        // Two pages packed into a cluster, and compressed with LZ4, as written by the current
+        // format version. The compressed stream must be the same on every target, or the checksums
+        // of the clusters repacked on another machine would differ.
+        let mut pages: Vec<u8> = (0..PAGE_SIZE).map(|i| b"golden page "[i % 12]).collect();
+        pages.extend((0..PAGE_SIZE).map(|i| (i % 7) as u8));
+
+        let mut buf = vec![0; DATA_CLUSTER_HEADER];
+        lz4_compress::compress_into(&pages, &mut buf);
+        buf.resize(disk::SECTOR_SIZE, 0);
+        write_data_cluster_header(&mut buf, seahash_algorithms(), true);
+
+        assert_eq!(&buf[..32], &[
+            // The header: the 14 bit checksum, the checksum flag (unset), and the compression flag
+            // (set).
+            0x51, 0xb4,
+            // The first page: the literals of one period, and a match repeating them.
+            0xcf, b'g', b'o', b'l', b'd', b'e', b'n', b' ', b'p', b'a', b'g', b'e', b' ',
+            0x0c, 0x00, 0xff, 0xe0,
+            // The second page, likewise.
+            0x7f, 0, 1, 2, 3, 4, 5, 6,
+            0x07, 0x00, 0xff, 0xe5,
+            // The final, empty block.
+            0x00,
+        ][..]);
+        assert!(buf[32..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn checksum_flags() {
+        let algorithms = ChecksumAlgorithms {
+            flag: true,
+            ..seahash_algorithms()
+        };
+        let cluster = cluster::Pointer::new(20).unwrap();
+
+        // The flag is set in the metacluster, and doesn't affect the checksum.
+        let freelist = vec![cluster];
+        let buf = encode_metacluster(&freelist, None, algorithms);
+        assert!(checksum_flag(&buf));
+        assert!(!checksum_flag(&encode_metacluster(&freelist, None, seahash_algorithms())));
+        let mut decoded = Vec::new();
+        decode_metacluster(cluster, &buf, header::ChecksumAlgorithm::SeaHash, cluster::Bounds::new(64, 8), &mut decoded).unwrap();
+        assert_eq!(decoded, freelist);
+
+        // Likewise in the data cluster, next to the compression flag.
+        let mut buf = vec![0; disk::SECTOR_SIZE];
+        buf[2..13].copy_from_slice(b"golden page");
+        write_data_cluster_header(&mut buf, algorithms, true);
+        assert!(data_cluster_checksum_flag(&buf));
+        assert_eq!(&buf[..2], &[0xff, 0x17]);
+        write_data_cluster_header(&mut buf, seahash_algorithms(), false);
+        assert!(!data_cluster_checksum_flag(&buf));
+
+        // Truncated structures have no flag.
+        assert!(!checksum_flag(&[0xff; 7]));
+        assert!(!data_cluster_checksum_flag(&[0xff]));
+    }
+
+    /// A zoned disk, which rejects writes to its zones other than at their write pointers.
+    struct ZonedDisk {
+        /// The inner disk.
+        inner: storage::StorageDisk<Vec<u8>>,
+        /// The zones.
+        zones: Vec<disk::Zone>,
+    }
+
+    impl Disk for ZonedDisk {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buffer: &[u8]) -> Result<(), disk::Error> {
+            if let Some(zone) = self.zones.iter_mut().find(|zone| sector >= zone.start && sector < zone.start + zone.len) {
+                // The writes of a zone must be sequential.
+                if sector != zone.write_pointer {
+                    return Err(disk::Error::OutOfBounds);
+                }
+                zone.write_pointer += 1;
+            }
+
+            self.inner.write(sector, buffer)
+        }
+
+        fn read(&mut self, sector: disk::Sector, buffer: &mut [u8]) -> Result<(), disk::Error> {
+            self.inner.read(sector, buffer)
+        }
+
+        fn geometry(&self) -> disk::Geometry {
+            disk::Geometry {
+                zoned: true,
+                ..disk::Geometry::default()
+            }
+        }
+
+        fn report_zones(&mut self) -> Result<Vec<disk::Zone>, disk::Error> {
+            Ok(self.zones.clone())
+        }
+
+        fn reset_zone(&mut self, start: disk::Sector) -> Result<(), disk::Error> {
+            let zone = self.zones.iter_mut().find(|zone| zone.start == start).ok_or(disk::Error::OutOfBounds)?;
+            zone.write_pointer = zone.start;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zoned_allocation() {
+        // Two zones of four clusters, after a conventional area holding the metadata.
+        let disk = ZonedDisk {
+            inner: storage::StorageDisk::new(vec![0; 64 * disk::SECTOR_SIZE]),
+            zones: vec![
+                disk::Zone { start: 56, len: 4, write_pointer: 56 },
+                disk::Zone { start: 60, len: 4, write_pointer: 60 },
+            ],
+        };
+        let mut manager = Manager::format(header::Driver::init(disk).unwrap()).unwrap();
+
+        // Data clusters are appended to the zones, one page each.
+        let ptrs = (0..4).map(|i| manager.queue_alloc(&[i; PAGE_SIZE]).unwrap()).collect::<Vec<_>>();
+        assert_eq!(ptrs.iter().map(|ptr| u64::from(ptr.cluster())).collect::<Vec<_>>(), vec![56, 57, 58, 59]);
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+
+        // Freed clusters aren't reused, but make the zone a cheap victim.
+        for &i in &[0, 2, 3] {
+            manager.release_page(ptrs[i]).unwrap();
+        }
+        manager.commit().unwrap();
+        assert_eq!(manager.zone_victim(), Some((56, 1)));
+
+        // The live page is migrated into the next zone.
+        let relocations = manager.queue_migrate_zone(56).unwrap();
+        assert_eq!(relocations.len(), 1);
+        let (old, new) = relocations[0];
+        assert_eq!(old, ptrs[1]);
+        assert_eq!(u64::from(new.cluster()), 60);
+        assert_eq!(&manager.read(new).unwrap()[..], &[1; PAGE_SIZE][..]);
+
+        // The zone can only be reset once the migration is committed.
+        match manager.reset_zone(56) {
+            Err(Error::Zone(zones::Error::ZoneNotEmpty { start: 56, live: 1 })) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        manager.commit().unwrap();
+        manager.reset_zone(56).unwrap();
+
+        // The reset zone is appended to again once the open one is full.
+        let ptrs = (0..4).map(|i| manager.queue_alloc(&[i; PAGE_SIZE]).unwrap()).collect::<Vec<_>>();
+        assert_eq!(ptrs.iter().map(|ptr| u64::from(ptr.cluster())).collect::<Vec<_>>(), vec![61, 62, 63, 56]);
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+    }
+
+    #[test]
+    fn moved_clusters() {
+        let mut manager = formatted(64);
+        let ptr = manager.queue_alloc(&[1; PAGE_SIZE]).unwrap();
+        let cluster = ptr.cluster();
+        manager.commit().unwrap();
+        let free = manager.free_clusters().unwrap();
+
+        // The pointer stays valid across the move, until reverted.
+        let first = manager.queue_move_cluster(cluster).unwrap();
+        assert!(first != cluster);
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[1; PAGE_SIZE][..]);
+        assert!(manager.header().indirection);
+        manager.revert();
+        assert!(!manager.state.indirection.is_moved(cluster.into()));
+
+        // The table survives remounting. The old place is a spare, and the table takes a cluster.
+        let first = manager.queue_move_cluster(cluster).unwrap();
+        manager.commit().unwrap();
+        manager.reload().unwrap();
+        assert_eq!(manager.state.indirection.resolve(cluster.into()), u64::from(first));
+        assert!(manager.state.indirection.is_spare(cluster.into()));
+        assert_eq!(manager.free_clusters().unwrap(), free - 2);
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[1; PAGE_SIZE][..]);
+
+        // The next move takes the spare, which moves the cluster home, emptying the table.
+        assert_eq!(manager.queue_move_cluster(cluster).unwrap(), cluster);
+        manager.commit().unwrap();
+        assert!(manager.state.indirection.is_empty());
+        assert_eq!(manager.state.state_block.indirection, None);
+        assert_eq!(manager.free_clusters().unwrap(), free);
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[1; PAGE_SIZE][..]);
+
+        // Freeing a moved cluster frees both its number and its physical cluster.
+        manager.queue_move_cluster(cluster).unwrap();
+        manager.commit().unwrap();
+        manager.release_page(ptr).unwrap();
+        manager.commit().unwrap();
+        assert!(manager.state.indirection.is_empty());
+        assert_eq!(manager.free_clusters().unwrap(), free + 1);
+    }
+
+    #[test]
+    fn checksum_tree_depth() {
+        // A disk small enough for a tree of a single leaf.
+        let mut manager = formatted(60);
+        manager.set_checksum_tree(true).unwrap();
+        let ptr = manager.queue_alloc(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        assert_eq!(manager.state.state_block.checksum_tree_depth, 1);
+        let entry = manager.lookup_checksum(ptr.cluster()).unwrap();
+        assert!(entry != 0);
+
+        // Growing the disk doesn't change the layout of the tree, until it is deepened by the
+        // next commit.
+        manager.bounds = cluster::Bounds::new(60 * checksums::FANOUT as u64, 8);
+        assert_eq!(manager.lookup_checksum(ptr.cluster()).unwrap(), entry);
+        manager.queue_alloc_raw(&[2; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        assert_eq!(manager.state.state_block.checksum_tree_depth, 2);
+        assert_eq!(manager.lookup_checksum(ptr.cluster()).unwrap(), entry);
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[1; PAGE_SIZE][..]);
+    }
+
+    #[test]
+    fn lazy_verification() {
+        let mut manager = formatted(64);
+        let intact = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        let damaged = manager.queue_alloc_raw(&[2; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+
+        // Damage the second cluster behind the cache's back.
+        let cluster = damaged.cluster();
+        let mut buf = manager.disk.read(cluster).unwrap().to_vec();
+        buf[100] ^= 1;
+        manager.disk.inner_mut().write(u64::from(cluster) as disk::Sector, &buf).unwrap();
+        manager.disk.invalidate();
+
+        // The reads don't catch the damage, but the batch does.
+        manager.set_verification(Verification::Lazy);
+        assert_eq!(&manager.read(intact).unwrap()[..], &[1; PAGE_SIZE][..]);
+        manager.read(damaged).unwrap();
+        assert_eq!(manager.verify_pending().unwrap(), vec![cluster]);
+        assert_eq!(manager.verify_pending().unwrap(), Vec::new());
+
+        // The intact cluster is trusted now, and the damaged one is caught by eager reads.
+        manager.read(intact).unwrap();
+        assert_eq!(manager.verify_pending().unwrap(), Vec::new());
+        manager.set_verification(Verification::TrustCache);
+        match manager.read(damaged) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Clusters freed after their read are of no concern anymore, whatever they hold now.
+        manager.set_verification(Verification::Lazy);
+        let freed = manager.queue_alloc_raw(&[3; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        manager.disk.invalidate();
+        manager.read(freed).unwrap();
+        manager.release_page(freed).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        manager.disk.inner_mut().write(u64::from(freed.cluster()) as disk::Sector, &[0; disk::SECTOR_SIZE]).unwrap();
+        manager.disk.invalidate();
+        assert_eq!(manager.verify_pending().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn packing_stats() {
+        let mut manager = formatted(64);
+        manager.queue_alloc(&[0; PAGE_SIZE]).unwrap();
+        manager.queue_alloc(&[0; PAGE_SIZE]).unwrap();
+
+        // The second page is packed, and the pages compress to a few bytes.
+        let packing = &manager.stats().packing;
+        assert_eq!((packing.pages, packing.clusters), (2, 1));
+        let mut compressed = Vec::new();
+        manager.compress(&[0; 2 * PAGE_SIZE], &mut compressed);
+        assert_eq!(packing.bytes_saved(), (2 * PAGE_SIZE - compressed.len()) as u64);
+    }
+
+    #[test]
+    fn full_checksum_coverage() {
+        let disk = storage::StorageDisk::new(vec![0; 64 * disk::SECTOR_SIZE]);
+        let driver = header::Driver::init(disk).unwrap();
+        let mut manager = Manager::format_with_coverage(driver, header::ChecksumCoverage::Full).unwrap();
+        match manager.set_checksum_tree(false) {
+            Err(Error::ChecksumTreeRequired) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let ptr = manager.queue_alloc(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[1; PAGE_SIZE][..]);
+        manager.set_durability(Durability::InPlace);
+        assert!(!manager.is_overwritable(ptr).unwrap());
+
+        // The header of the cluster holds the flags only.
+        let cluster = ptr.cluster();
+        let mut buf = manager.disk.read(cluster).unwrap().to_vec();
+        assert_eq!(codec::Reader::new(&buf).read_u16().unwrap() >> 2, 0);
+
+        // Damage is caught by the checksum tree.
+        buf[100] ^= 1;
+        manager.disk.inner_mut().write(u64::from(cluster) as disk::Sector, &buf).unwrap();
+        manager.disk.invalidate();
+        match manager.read(ptr) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn freelist_chain() {
+        // The freelist spans several metaclusters.
+        let mut manager = formatted(256);
+        let free = manager.free_clusters().unwrap();
+        assert!(free as usize > METACLUSTER_SIZE / cluster::POINTER_SIZE);
+
+        // The chain is walked from the disk, down to the empty metacluster terminating it.
+        manager.reload().unwrap();
+        assert_eq!(manager.free_clusters().unwrap(), free);
+        assert!(manager.verify().is_clean());
+
+        // Every free cluster is popped exactly once, and then the freelist is exhausted.
+        let mut popped = HashSet::new();
+        loop {
+            match manager.queue_freelist_pop() {
+                Ok(cluster) => assert!(popped.insert(u64::from(cluster))),
+                Err(Error::OutOfClusters) => break,
+                Err(err) => panic!("Unexpected error: {:?}", err),
+            }
+        }
+        assert_eq!(popped.len() as u64, free);
+        assert_eq!(manager.free_clusters().unwrap(), 0);
+    }
+
+    #[test]
+    fn metadata_copies() {
+        let mut manager = formatted(64);
+        let free = manager.free_clusters().unwrap();
+        manager.set_metadata_copies(2).unwrap();
+        manager.commit().unwrap();
+
+        // The freelist head is duplicated into the other half of the volume.
+        let head = manager.state.state_block.freelist_head;
+        let copy = manager.state.state_block.freelist_head_copy.unwrap();
+        assert!(manager.half(head) != manager.half(copy));
+        assert_eq!(manager.free_clusters().unwrap(), free - 1);
+
+        // So are metadata pages.
+        let ptr = manager.queue_alloc_metadata(&[7; PAGE_SIZE]).unwrap();
+        let page_copy = ptr.copy.unwrap();
+        assert!(manager.half(ptr.primary.cluster()) != manager.half(page_copy.cluster()));
+        manager.commit().unwrap();
+        assert!(manager.verify().is_clean());
+
+        // A damaged freelist head is read from its duplicate.
+        let head = manager.state.state_block.freelist_head;
+        let free = manager.free_clusters().unwrap();
+        manager.disk.inner_mut().write(u64::from(head) as disk::Sector, &[0; disk::SECTOR_SIZE]).unwrap();
+        manager.reload().unwrap();
+        assert_eq!(manager.free_clusters().unwrap(), free);
+
+        // Going back to a single copy frees the duplicate of the head.
+        manager.set_metadata_copies(1).unwrap();
+        manager.commit().unwrap();
+        assert_eq!(manager.state.state_block.freelist_head_copy, None);
+        assert!(manager.verify().is_clean());
+    }
+
+    #[test]
+    fn reference_counts() {
+        let mut manager = formatted(64);
+        let (a, b, c) = {
+            let mut allocator = manager.allocator(1);
+            (
+                allocator.queue_alloc(&[1; PAGE_SIZE]).unwrap(),
+                allocator.queue_alloc(&[2; PAGE_SIZE]).unwrap(),
+                allocator.queue_alloc(&[3; PAGE_SIZE]).unwrap(),
+            )
+        };
+        assert!(a.cluster() == b.cluster() && b.cluster() == c.cluster());
+        manager.release_page(a).unwrap();
+        manager.commit().unwrap();
+        assert!(manager.verify().is_clean());
+        assert!(manager.verify_references(vec![b, c]).is_clean());
+
+        // A released page can't be referenced.
+        let report = manager.verify_references(vec![a, b, c]);
+        assert_eq!(report.count(verify::Problem::DanglingReference), 1);
+        assert_eq!(report.count(verify::Problem::ReferenceCountMismatch), 0);
+
+        // A live page must be referenced exactly once.
+        let report = manager.verify_references(vec![b]);
+        assert_eq!(report.count(verify::Problem::ReferenceCountMismatch), 1);
+        let report = manager.verify_references(vec![b, c, c]);
+        assert_eq!(report.count(verify::Problem::ReferenceCountMismatch), 1);
+
+        // The dead pages must be pages of the cluster.
+        manager.state.dead_pages.get_mut(&a.cluster()).unwrap().insert(200);
+        assert_eq!(manager.verify().count(verify::Problem::ReferenceCountMismatch), 1);
+    }
+
+    #[test]
+    fn rewrite_and_release() {
+        let mut manager = formatted(64);
+        let (a, b) = {
+            let mut allocator = manager.allocator(1);
+            (allocator.queue_alloc(&[1; PAGE_SIZE]).unwrap(), allocator.queue_alloc(&[2; PAGE_SIZE]).unwrap())
+        };
+        assert_eq!(a.cluster(), b.cluster());
+        manager.commit().unwrap();
+
+        // The old version stays intact until the commit.
+        let new = manager.queue_rewrite(a, &[3; PAGE_SIZE]).unwrap();
+        assert!(new.cluster() != a.cluster());
+        manager.revert();
+        assert!(manager.state.rewritten.is_empty());
+        assert_eq!(&manager.read(a).unwrap()[..], &[1; PAGE_SIZE][..]);
+
+        // The commit releases the old version. The cluster still holds `b`, so only the page dies,
+        // and the dead page survives remounting.
+        let new = manager.queue_rewrite(a, &[3; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        assert_eq!(&manager.read(new).unwrap()[..], &[3; PAGE_SIZE][..]);
+        assert!(manager.state.state_block.dead_pages.is_some());
+        manager.reload().unwrap();
+        assert!(manager.state.dead_pages[&a.cluster()].contains(&a.index()));
+        let free = manager.free_clusters().unwrap();
+
+        // Releasing the last live page frees the cluster, and the list, which is empty then.
+        manager.release_page(b).unwrap();
+        manager.commit().unwrap();
+        assert!(manager.state.dead_pages.is_empty());
+        assert_eq!(manager.state.state_block.dead_pages, None);
+        assert_eq!(manager.free_clusters().unwrap(), free + 2);
+    }
+
+    #[test]
+    fn rewrite_purge_order() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// A replica recording the sectors written by the groups, in order.
+        struct Recorder(Rc<RefCell<Vec<disk::Sector>>>);
+
+        impl replication::Target for Recorder {
+            fn generation(&mut self) -> Result<u64, disk::Error> {
+                Ok(0)
+            }
+
+            fn apply(&mut self, group: &replication::Group) -> Result<(), disk::Error> {
+                self.0.borrow_mut().extend(group.writes.iter().map(|&(sector, _)| sector));
+                Ok(())
+            }
+        }
+
+        let mut manager = formatted(64);
+        manager.set_purge_method(state_block::PurgeMethod::Zero).unwrap();
+        let old = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        manager.set_replica(Box::new(Recorder(writes.clone())), 4).unwrap();
+        manager.queue_rewrite(old, &[2; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.replicate().unwrap();
+
+        // The released cluster is purged, but only after the state block stops referencing it, so
+        // a crash in between leaves the old version intact.
+        let writes = writes.borrow();
+        let state_block = manager.disk.inner().header.state_block_address;
+        let old = u64::from(old.cluster()) as disk::Sector;
+        let flushed = writes.iter().rposition(|&sector| sector == state_block).unwrap();
+        let purged = writes.iter().position(|&sector| sector == old).unwrap();
+        assert!(flushed < purged);
+    }
+
+    #[test]
+    fn retained_roots() {
+        let mut manager = formatted(64);
+        let a = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        let b = manager.queue_alloc_raw(&[2; PAGE_SIZE]).unwrap();
+        let c = manager.queue_alloc_raw(&[3; PAGE_SIZE]).unwrap();
+        manager.state.state_block.superpage = a;
+        manager.commit().unwrap();
+
+        // A reverted root change records nothing.
+        manager.state.state_block.superpage = b;
+        manager.revert();
+        assert_eq!(manager.generations().len(), 1);
+
+        // The replaced root is in the history, so its cluster is kept, although released.
+        manager.state.state_block.superpage = b;
+        manager.release_page(a).unwrap();
+        manager.commit().unwrap();
+        assert_eq!(manager.generations().last().unwrap().superpage, a);
+        assert_eq!(&manager.read(a).unwrap()[..], &[1; PAGE_SIZE][..]);
+        let free = manager.free_clusters().unwrap();
+
+        // Once its generation drops out of the history, the cluster is freed, along with the dead
+        // page list holding it.
+        for i in 0..history::CAPACITY {
+            manager.state.state_block.superpage = if i % 2 == 0 { c } else { b };
+            manager.commit().unwrap();
+        }
+        assert!(manager.generations().iter().all(|generation| generation.superpage != a));
+        assert!(manager.state.dead_pages.is_empty());
+        assert_eq!(manager.free_clusters().unwrap(), free + 2);
+    }
+
+    #[test]
+    fn commit_notifications() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut manager = formatted(64);
+        let generation = manager.committed_state.state_block.generation;
+
+        // Every commit changing the volume gets a generation of its own, whether or not the root
+        // changed.
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        for i in 0..2 {
+            manager.queue_alloc(&[i; PAGE_SIZE]).unwrap();
+            let notified = notified.clone();
+            manager.commit_notify(Box::new(move |res: Result<u64, &Error>| notified.borrow_mut().push(res.unwrap()))).unwrap();
+        }
+        assert!(notified.borrow().is_empty());
+
+        // An empty commit changes nothing, so it gets the generation of the last one.
+        {
+            let notified = notified.clone();
+            manager.commit_notify(Box::new(move |res: Result<u64, &Error>| notified.borrow_mut().push(res.unwrap()))).unwrap();
+        }
+        assert_eq!(manager.committed_state.state_block.generation, generation + 2);
+
+        manager.flush().unwrap();
+        assert_eq!(*notified.borrow(), vec![generation + 1, generation + 2, generation + 2]);
+    }
+
+    #[test]
+    fn purge_on_commit() {
+        let mut manager = formatted(64);
+        manager.set_purge_method(state_block::PurgeMethod::Zero).unwrap();
+        let ptr = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+
+        // The state block on the disk still references the cluster, so the free doesn't purge it.
+        let cluster = ptr.cluster();
+        let sector = u64::from(cluster) as disk::Sector;
+        manager.release_page(ptr).unwrap();
+        assert!(manager.disk.pipeline().iter().all(|&(written, _)| written != sector));
+
+        // The commit does, after the state block.
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        let mut buf = [1; disk::SECTOR_SIZE];
+        manager.disk.inner_mut().read(sector, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        // Clusters reused before the commit aren't purged at all.
+        let ptr = manager.queue_alloc_raw(&[2; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        manager.release_page(ptr).unwrap();
+        let reused = manager.queue_alloc_raw(&[3; PAGE_SIZE]).unwrap();
+        assert_eq!(reused.cluster(), ptr.cluster());
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        assert_eq!(&manager.read(reused).unwrap()[..], &[3; PAGE_SIZE][..]);
+    }
+
+    #[test]
+    fn error_counters() {
+        let mut manager = formatted(64);
+        let ptr = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+
+        // Requests out of bounds say nothing about the health of the disk.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        assert!(manager.disk.inner_mut().read(1 << 20, &mut buf).is_err());
+        assert_eq!(manager.disk.inner().error_counters(), header::ErrorCounters::default());
+
+        // Damage is counted, and the counters are written with the next commit.
+        let cluster = ptr.cluster();
+        let mut buf = manager.disk.read(cluster).unwrap().to_vec();
+        buf[100] ^= 1;
+        manager.disk.inner_mut().write(u64::from(cluster) as disk::Sector, &buf).unwrap();
+        manager.disk.invalidate();
+        assert!(manager.read(ptr).is_err());
+        assert_eq!(manager.disk.inner().error_counters().checksum, 1);
+        assert!(manager.disk.inner().error_counters_dirty());
+        assert_eq!(header::Copies::read(manager.disk.inner_mut()).header().unwrap().error_counters.checksum, 0);
+
+        manager.commit().unwrap();
+        manager.flush().unwrap();
+        assert!(!manager.disk.inner().error_counters_dirty());
+        let copies = header::Copies::read(manager.disk.inner_mut());
+        assert!(copies.is_consistent());
+        assert_eq!(copies.header().unwrap().error_counters.checksum, 1);
+    }
+
+    #[test]
+    fn in_place_updates() {
+        let mut manager = formatted(64);
+        let ptr = manager.queue_alloc_raw(&[1; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+
+        // Full durability keeps the transaction atomic, so the update is copy-on-write.
+        assert!(manager.queue_update(ptr, &[2; PAGE_SIZE], UpdateMode::InPlace).unwrap() != ptr);
+        manager.revert();
+
+        manager.set_durability(Durability::InPlace);
+        let free = manager.free_clusters().unwrap();
+        assert_eq!(manager.queue_update(ptr, &[2; PAGE_SIZE], UpdateMode::InPlace).unwrap(), ptr);
+        manager.commit().unwrap();
+        assert_eq!(&manager.read(ptr).unwrap()[..], &[2; PAGE_SIZE][..]);
+        assert_eq!(manager.free_clusters().unwrap(), free);
+
+        // The mode is still chosen per update, and a page being released isn't overwritten.
+        let new = manager.queue_update(ptr, &[3; PAGE_SIZE], UpdateMode::CopyOnWrite).unwrap();
+        assert!(new != ptr);
+        assert!(!manager.is_overwritable(ptr).unwrap());
+        assert!(manager.queue_update(ptr, &[4; PAGE_SIZE], UpdateMode::InPlace).unwrap() != ptr);
+
+        // Compressed clusters are never overwritten.
+        let packed = manager.queue_alloc(&[5; PAGE_SIZE]).unwrap();
+        manager.commit().unwrap();
+        assert!(!manager.is_overwritable(packed).unwrap());
+    }
+
+    #[test]
+    fn failed_extent_write() {
+        use std::io::Write;
+
+        let mut manager = formatted(16);
+        // Use up the free clusters.
+        while manager.queue_alloc_raw(&[0; PAGE_SIZE]).is_ok() {}
+
+        let mut writer = manager.extent_writer(1);
+        writer.write_all(&[1; 100]).unwrap();
+        assert!(writer.write(&[2; PAGE_SIZE]).is_err());
+        // The failed write left the page as it was.
+        assert_eq!(writer.page, vec![1; 100]);
+        assert_eq!(writer.extent.len, 100);
+        assert!(writer.extent.pages.is_empty());
     }
 }
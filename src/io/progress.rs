@@ -0,0 +1,177 @@
+//! Progress reporting and cancellation of long operations.
+//!
+//! Long operations (scrubbing, verification, defragmentation, rekeying, resizing, garbage
+//! collection, imports) run for minutes or hours, so they report their progress through a shared
+//! `Progress` handle, and check a `Cancel` token between work units, such that they can be
+//! stopped cooperatively. Both handles are cheap to clone and can be shared with other threads.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+quick_error! {
+    /// The operation was cancelled.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Cancelled {
+        /// The operation was cancelled through its token.
+        Cancelled {
+            description("Operation cancelled.")
+        }
+    }
+}
+
+/// The shared state of a progress handle.
+#[derive(Debug)]
+struct ProgressState {
+    /// The number of bytes processed.
+    done: AtomicU64,
+    /// The total number of bytes to process, or zero if unknown.
+    total: AtomicU64,
+    /// The time the operation started.
+    start: Instant,
+}
+
+/// A progress handle.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// The shared state.
+    state: Arc<ProgressState>,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress {
+            state: Arc::new(ProgressState {
+                done: AtomicU64::new(0),
+                total: AtomicU64::new(0),
+                start: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Progress {
+    /// Set the total number of bytes to process.
+    ///
+    /// This can be updated as the operation goes, if the total is only estimated in the start.
+    pub fn set_total(&self, total: u64) {
+        self.state.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Mark `bytes` more bytes as processed.
+    pub fn advance(&self, bytes: u64) {
+        self.state.done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Get the number of bytes processed.
+    pub fn done(&self) -> u64 {
+        self.state.done.load(Ordering::Relaxed)
+    }
+
+    /// Get the total number of bytes to process, if known.
+    pub fn total(&self) -> Option<u64> {
+        match self.state.total.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(total),
+        }
+    }
+
+    /// Get the completion percentage, if the total is known.
+    pub fn percent(&self) -> Option<f64> {
+        self.total().map(|total| (self.done() as f64 / total as f64 * 100.0).min(100.0))
+    }
+
+    /// Estimate the remaining time, if the total is known.
+    ///
+    /// This assumes that the rate so far stays constant.
+    pub fn eta(&self) -> Option<Duration> {
+        let elapsed = self.state.start.elapsed();
+        eta(self.done(), self.total()?, elapsed)
+    }
+}
+
+/// Estimate the remaining time from the progress so far.
+fn eta(done: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        // We have no rate to extrapolate from.
+        return None;
+    }
+
+    let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    let remaining = total.saturating_sub(done) as f64 * elapsed / done as f64;
+    Some(Duration::new(remaining as u64, (remaining.fract() * 1e9) as u32))
+}
+
+/// A cancellation token.
+#[derive(Clone, Default, Debug)]
+pub struct Cancel {
+    /// Has the operation been cancelled?
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Cancel {
+    /// Request cancellation of the operation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Has cancellation been requested?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Check for cancellation.
+    ///
+    /// This is called by the operation between work units, and fails if cancellation has been
+    /// requested.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The handles of a long operation.
+#[derive(Clone, Default, Debug)]
+pub struct Task {
+    /// The progress of the operation.
+    pub progress: Progress,
+    /// The cancellation token of the operation.
+    pub cancel: Cancel,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn progress() {
+        let progress = Progress::default();
+        assert_eq!(progress.percent(), None);
+
+        progress.set_total(200);
+        progress.clone().advance(50);
+        assert_eq!(progress.done(), 50);
+        assert_eq!(progress.percent(), Some(25.0));
+    }
+
+    #[test]
+    fn estimate() {
+        assert_eq!(eta(0, 100, Duration::from_secs(10)), None);
+        assert_eq!(eta(25, 100, Duration::from_secs(10)), Some(Duration::from_secs(30)));
+        assert_eq!(eta(100, 100, Duration::from_secs(10)), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn cancel() {
+        let task = Task::default();
+        assert_eq!(task.cancel.check(), Ok(()));
+
+        task.clone().cancel.cancel();
+        assert!(task.cancel.is_cancelled());
+        assert_eq!(task.cancel.check(), Err(Cancelled::Cancelled));
+    }
+}
@@ -0,0 +1,441 @@
+//! Continuous replication.
+//!
+//! Every committed transaction group (the writes of one pipeline commit) can be mirrored to a
+//! secondary volume. This is a lighter alternative to send/receive: The replica is kept a few
+//! commits behind the primary, and nothing needs to be scanned.
+//!
+//! Replication is asynchronous. Committed groups are put in an in-memory backlog, which is sent
+//! to the replica when `Replicator::pump()` is called (e.g. from a background thread). If the
+//! replica is disconnected, the backlog grows until it hits its limit, after which the oldest
+//! groups are dropped. On reconnection, the replica reports the last generation it applied, and
+//! the replication resumes from there, if the backlog still covers it. Otherwise, a full
+//! resynchronization (e.g. through a backup) is required.
+//!
+//! Only writes through the cache are replicated. Disk header updates (e.g. the state flag) are
+//! specific to each device and are not.
+
+use std::collections::VecDeque;
+
+quick_error! {
+    /// A replication error.
+    #[derive(Debug)]
+    pub enum Error {
+        /// The replica is too far behind to catch up from the backlog.
+        ///
+        /// It must be resynchronized through other means.
+        ResyncRequired {
+            /// The last generation applied by the replica.
+            replica: u64,
+            /// The oldest generation in the backlog.
+            oldest: u64,
+        } {
+            display("Replica at generation {} can't catch up, as the backlog starts at {}.", replica, oldest)
+            description("Replica requires resynchronization.")
+        }
+        /// The backlog limit is zero.
+        ///
+        /// Every group would be dropped before being sent.
+        EmptyBacklog {
+            description("Replication backlog limit of zero.")
+        }
+        /// The replica failed.
+        ///
+        /// The group is kept in the backlog, and will be resent on the next pump.
+        Target(err: disk::Error) {
+            from()
+            description("Replica I/O error")
+            display("Replica I/O error: {}", err)
+        }
+    }
+}
+
+/// A transaction group.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Group {
+    /// The generation of the group.
+    ///
+    /// Generations are consecutive.
+    pub generation: u64,
+    /// The writes of the group, in order.
+    pub writes: Vec<(disk::Sector, Box<[u8]>)>,
+}
+
+/// A replication target.
+///
+/// This can be a local disk or a connection to a remote volume.
+pub trait Target {
+    /// Get the last generation applied by the replica.
+    fn generation(&mut self) -> Result<u64, disk::Error>;
+    /// Apply a transaction group.
+    ///
+    /// The group must be applied atomically, and the replica must remember its generation.
+    fn apply(&mut self, group: &Group) -> Result<(), disk::Error>;
+
+    /// Read a sector from the replica.
+    ///
+    /// This is used for repairing damaged clusters of the primary. Targets which can't be read
+    /// (e.g. write-only streams) return `None`, which is the default.
+    fn read(&mut self, _sector: disk::Sector) -> Option<Box<[u8]>> {
+        None
+    }
+}
+
+/// A replicator.
+pub struct Replicator {
+    /// The replication target.
+    target: Box<Target>,
+    /// The groups not yet applied by the target, oldest first.
+    backlog: VecDeque<Group>,
+    /// The maximal number of groups in the backlog.
+    max_backlog: usize,
+    /// The generation of the next group.
+    next_generation: u64,
+    /// Is the target known to be in sync with the backlog?
+    ///
+    /// This is unset when the target fails, or groups are dropped from the backlog, such that its
+    /// generation is queried again before resuming.
+    connected: bool,
+    /// The last generation known to be applied by the target.
+    applied: u64,
+    /// Were groups dropped from the backlog before being applied?
+    ///
+    /// In that case, the target misses writes, so its content can't be used for repairs.
+    diverged: bool,
+}
+
+impl Replicator {
+    /// Create a replicator for some target.
+    ///
+    /// The generations continue from the target's generation. At most `max_backlog` groups are
+    /// kept while the target is behind. If `max_backlog` is zero, `Error::EmptyBacklog` is
+    /// returned.
+    pub fn new(mut target: Box<Target>, max_backlog: usize) -> Result<Replicator, Error> {
+        if max_backlog == 0 {
+            return Err(Error::EmptyBacklog);
+        }
+
+        let generation = target.generation()?;
+
+        Ok(Replicator {
+            target: target,
+            backlog: VecDeque::new(),
+            max_backlog: max_backlog,
+            next_generation: generation + 1,
+            connected: true,
+            applied: generation,
+            diverged: false,
+        })
+    }
+
+    /// Push a committed transaction group to the backlog.
+    ///
+    /// If the backlog is full, the oldest group is dropped. The target then misses a generation,
+    /// so it must be resynchronized through other means (`.pump()` fails with
+    /// `Error::ResyncRequired`), unless it turns out to have applied the group already.
+    pub fn push(&mut self, writes: Vec<(disk::Sector, Box<[u8]>)>) {
+        if self.backlog.len() == self.max_backlog {
+            self.backlog.pop_front();
+            self.diverged = true;
+            // Query the target before sending anything, so the gap is noticed.
+            self.connected = false;
+        }
+
+        self.backlog.push_back(Group {
+            generation: self.next_generation,
+            writes: writes,
+        });
+        self.next_generation += 1;
+    }
+
+    /// Get the number of groups not yet applied by the target.
+    pub fn lag(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Read the replicated content of a sector.
+    ///
+    /// The newest write to the sector in the backlog is preferred, as the target doesn't have it
+    /// yet. Otherwise, the sector is read from the target, unless it has diverged from the
+    /// primary.
+    pub fn read(&mut self, sector: disk::Sector) -> Option<Box<[u8]>> {
+        // Search the backlog, newest write first.
+        for group in self.backlog.iter().rev() {
+            if let Some(&(_, ref data)) = group.writes.iter().rev().find(|&&(s, _)| s == sector) {
+                return Some(data.clone());
+            }
+        }
+
+        if self.diverged {
+            None
+        } else {
+            self.target.read(sector)
+        }
+    }
+
+    /// Send the backlog to the target.
+    pub fn pump(&mut self) -> Result<(), Error> {
+        if !self.connected {
+            // Find out where the target is, and drop the groups it already has.
+            let generation = self.target.generation()?;
+            while self.backlog.front().map_or(false, |group| group.generation <= generation) {
+                self.backlog.pop_front();
+            }
+
+            // Make sure the backlog continues where the target stopped.
+            if let Some(group) = self.backlog.front() {
+                if group.generation != generation + 1 {
+                    return Err(Error::ResyncRequired {
+                        replica: generation,
+                        oldest: group.generation,
+                    });
+                }
+            }
+
+            self.applied = generation;
+            self.connected = true;
+        }
+
+        while let Some(group) = self.backlog.pop_front() {
+            // Applying a group past a missing generation would silently corrupt the replica.
+            if group.generation != self.applied + 1 {
+                let oldest = group.generation;
+                self.backlog.push_front(group);
+                self.connected = false;
+                return Err(Error::ResyncRequired {
+                    replica: self.applied,
+                    oldest: oldest,
+                });
+            }
+
+            if let Err(err) = self.target.apply(&group) {
+                // Keep the group and query the target's generation on the next attempt.
+                self.backlog.push_front(group);
+                self.connected = false;
+                return Err(err.into());
+            }
+            self.applied = group.generation;
+        }
+
+        Ok(())
+    }
+}
+
+/// A disk as a replication target.
+///
+/// The generation is only tracked in memory, so after a restart, the replica must be
+/// resynchronized.
+///
+/// The writes of a group can't be applied atomically to a disk. The generation is thus only
+/// advanced once every write of the group is durable, so a group whose application was
+/// interrupted is detected (see `.is_partial()`), and applied again in whole by the next pump.
+pub struct DiskTarget<D> {
+    /// The disk.
+    disk: D,
+    /// The last generation applied.
+    generation: u64,
+    /// The generation being applied, if its application was interrupted.
+    ///
+    /// While set, the disk holds a mix of two generations.
+    partial: Option<u64>,
+}
+
+impl<D: Disk> DiskTarget<D> {
+    /// Create a new disk target at generation `generation`.
+    pub fn new(disk: D, generation: u64) -> DiskTarget<D> {
+        DiskTarget {
+            disk: disk,
+            generation: generation,
+            partial: None,
+        }
+    }
+
+    /// Is the disk partially updated?
+    ///
+    /// This is the case if the application of a group failed midway. Reapplying the group (which
+    /// the replicator does) makes the disk consistent again.
+    pub fn is_partial(&self) -> bool {
+        self.partial.is_some()
+    }
+}
+
+impl<D: Disk> Target for DiskTarget<D> {
+    fn generation(&mut self) -> Result<u64, disk::Error> {
+        Ok(self.generation)
+    }
+
+    fn apply(&mut self, group: &Group) -> Result<(), disk::Error> {
+        self.partial = Some(group.generation);
+        for &(sector, ref data) in &group.writes {
+            self.disk.write(sector, data)?;
+        }
+        // The generation is advanced last, once the writes are durable.
+        self.disk.sync()?;
+
+        self.generation = group.generation;
+        self.partial = None;
+        Ok(())
+    }
+
+    fn read(&mut self, sector: disk::Sector) -> Option<Box<[u8]>> {
+        // A partially updated disk can't be trusted for repairs.
+        if self.is_partial() {
+            return None;
+        }
+
+        let mut buf = vec![0; disk::SECTOR_SIZE].into_boxed_slice();
+        self.disk.read(sector, &mut buf).ok().map(|()| buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A target recording the groups, which can be made to fail.
+    struct Recorder {
+        generation: u64,
+        groups: Rc<RefCell<Vec<u64>>>,
+        failing: Rc<RefCell<bool>>,
+    }
+
+    impl Target for Recorder {
+        fn generation(&mut self) -> Result<u64, disk::Error> {
+            Ok(self.generation)
+        }
+
+        fn apply(&mut self, group: &Group) -> Result<(), disk::Error> {
+            if *self.failing.borrow() {
+                return Err(disk::Error::OutOfBounds);
+            }
+
+            self.generation = group.generation;
+            self.groups.borrow_mut().push(group.generation);
+            Ok(())
+        }
+    }
+
+    fn recorder(generation: u64) -> (Box<Target>, Rc<RefCell<Vec<u64>>>, Rc<RefCell<bool>>) {
+        let groups = Rc::new(RefCell::new(Vec::new()));
+        let failing = Rc::new(RefCell::new(false));
+
+        (Box::new(Recorder {
+            generation: generation,
+            groups: groups.clone(),
+            failing: failing.clone(),
+        }), groups, failing)
+    }
+
+    #[test]
+    fn replicate() {
+        let (target, groups, _) = recorder(10);
+        let mut replicator = Replicator::new(target, 100).unwrap();
+
+        replicator.push(Vec::new());
+        replicator.push(Vec::new());
+        assert_eq!(replicator.lag(), 2);
+        replicator.pump().unwrap();
+        assert_eq!(replicator.lag(), 0);
+        assert_eq!(*groups.borrow(), [11, 12]);
+    }
+
+    #[test]
+    fn read_backlog() {
+        let (target, _, _) = recorder(0);
+        let mut replicator = Replicator::new(target, 2).unwrap();
+
+        replicator.push(vec![(3, vec![1].into_boxed_slice()), (4, vec![2].into_boxed_slice())]);
+        replicator.push(vec![(3, vec![3].into_boxed_slice())]);
+        assert_eq!(replicator.read(3), Some(vec![3].into_boxed_slice()));
+        assert_eq!(replicator.read(4), Some(vec![2].into_boxed_slice()));
+        // The recorder can't be read.
+        assert_eq!(replicator.read(5), None);
+
+        // Overflowing the backlog makes the target diverge.
+        replicator.push(Vec::new());
+        assert_eq!(replicator.read(4), None);
+        assert_eq!(replicator.read(3), Some(vec![3].into_boxed_slice()));
+    }
+
+    #[test]
+    fn reconnect() {
+        let (target, groups, failing) = recorder(0);
+        let mut replicator = Replicator::new(target, 100).unwrap();
+
+        replicator.push(Vec::new());
+        *failing.borrow_mut() = true;
+        assert!(replicator.pump().is_err());
+        replicator.push(Vec::new());
+        assert_eq!(replicator.lag(), 2);
+
+        *failing.borrow_mut() = false;
+        replicator.pump().unwrap();
+        assert_eq!(*groups.borrow(), [1, 2]);
+    }
+
+    #[test]
+    fn overflow_while_connected() {
+        let (target, groups, _) = recorder(0);
+        let mut replicator = Replicator::new(target, 2).unwrap();
+
+        // Generation 1 is dropped without the target being down.
+        replicator.push(Vec::new());
+        replicator.push(Vec::new());
+        replicator.push(Vec::new());
+        match replicator.pump() {
+            Err(Error::ResyncRequired { replica: 0, oldest: 2 }) => (),
+            _ => panic!("Expected a resync."),
+        }
+        assert!(groups.borrow().is_empty());
+
+        match Replicator::new(recorder(0).0, 0) {
+            Err(Error::EmptyBacklog) => (),
+            _ => panic!("Expected an error."),
+        }
+    }
+
+    #[test]
+    fn partial_apply() {
+        // A disk of two sectors, so the second write of the group fails.
+        let disk = storage::StorageDisk::new(vec![0; 2 * disk::SECTOR_SIZE]);
+        let mut target = DiskTarget::new(disk, 0);
+        let data = vec![1; disk::SECTOR_SIZE].into_boxed_slice();
+
+        let group = Group {
+            generation: 1,
+            writes: vec![(0, data.clone()), (2, data.clone())],
+        };
+        assert!(target.apply(&group).is_err());
+        assert!(target.is_partial());
+        assert_eq!(target.generation().unwrap(), 0);
+        assert_eq!(target.read(0), None);
+
+        let group = Group {
+            generation: 1,
+            writes: vec![(0, data.clone()), (1, data.clone())],
+        };
+        target.apply(&group).unwrap();
+        assert!(!target.is_partial());
+        assert_eq!(target.generation().unwrap(), 1);
+        assert_eq!(target.read(1), Some(data));
+    }
+
+    #[test]
+    fn resync_required() {
+        let (target, _, failing) = recorder(0);
+        let mut replicator = Replicator::new(target, 2).unwrap();
+
+        *failing.borrow_mut() = true;
+        replicator.push(Vec::new());
+        assert!(replicator.pump().is_err());
+        replicator.push(Vec::new());
+        replicator.push(Vec::new());
+
+        *failing.borrow_mut() = false;
+        match replicator.pump() {
+            Err(Error::ResyncRequired { replica: 0, oldest: 2 }) => (),
+            _ => panic!("Expected a resync."),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Allocation shadowing.
+//!
+//! Allocator bugs (freeing a cluster twice, or handing out a cluster which is in use) corrupt the
+//! volume silently, and only show up much later, when two structures overwrite each other. The
+//! allocation shadow catches them where they happen: It keeps its own record of which clusters
+//! are allocated, and checks every allocation and free against it.
+//!
+//! The record only covers the clusters the shadow has seen being allocated or freed (or was seeded
+//! with, see `Manager::set_alloc_check()`), as the state of the others is unknown. Its memory is
+//! bounded: Only one in `rate` clusters is tracked, with the rate raised as needed to stay below
+//! the memory limit. Debug builds track every cluster by default, while release builds sample.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+
+/// The default memory limit (in bytes) of the shadow.
+const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+/// The default sampling rate of release builds.
+const RELEASE_RATE: u64 = 64;
+
+quick_error! {
+    /// An allocator bug caught by the shadow.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// A cluster was allocated while already in use.
+        DoubleAlloc {
+            /// The cluster.
+            cluster: u64,
+        } {
+            display("Cluster {:x} allocated while in use.", cluster)
+            description("Allocation of a cluster in use.")
+        }
+        /// A cluster was freed while already free.
+        DoubleFree {
+            /// The cluster.
+            cluster: u64,
+        } {
+            display("Cluster {:x} freed while free.", cluster)
+            description("Free of a free cluster.")
+        }
+        /// A cluster which can never be allocated (e.g. the state block) was freed.
+        InvalidFree {
+            /// The cluster.
+            cluster: u64,
+        } {
+            display("Cluster {:x} is reserved or out of bounds, and can't be freed.", cluster)
+            description("Free of an invalid cluster.")
+        }
+    }
+}
+
+/// The configuration of the allocation shadow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// The memory limit (in bytes).
+    pub limit: usize,
+    /// The minimal sampling rate.
+    ///
+    /// One in `rate` clusters is tracked. One tracks every cluster, memory permitting.
+    pub rate: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            limit: DEFAULT_LIMIT,
+            rate: if cfg!(debug_assertions) { 1 } else { RELEASE_RATE },
+        }
+    }
+}
+
+/// The allocation shadow.
+pub struct Shadow {
+    /// The bounds of the clusters which can be allocated.
+    bounds: cluster::Bounds,
+    /// The configuration of the shadow.
+    config: Config,
+    /// One in `rate` clusters is tracked.
+    rate: u64,
+    /// The tracked clusters, mapped to whether they're allocated.
+    clusters: HashMap<u64, bool>,
+}
+
+impl Shadow {
+    /// Create a shadow of a disk with bounds `bounds`.
+    ///
+    /// Nothing is known about the clusters yet.
+    pub fn new(bounds: cluster::Bounds, config: Config) -> Shadow {
+        // Every entry costs the key and the value, plus about as much again for the table.
+        let entry_size = 2 * (mem::size_of::<u64>() + mem::size_of::<bool>());
+        let max_entries = cmp::max(config.limit / entry_size, 1) as u64;
+        // Raise the rate until the tracked clusters fit into the limit.
+        let rate = cmp::max(config.rate, (bounds.size() + max_entries - 1) / max_entries);
+
+        Shadow {
+            bounds: bounds,
+            config: config,
+            rate: cmp::max(rate, 1),
+            clusters: HashMap::new(),
+        }
+    }
+
+    /// Get the configuration of the shadow.
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Get the sampling rate.
+    ///
+    /// This is the configured rate, raised as needed by the memory limit.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Is some cluster tracked?
+    ///
+    /// Multiplying scatters the sample over the disk, so it doesn't line up with the allocation
+    /// patterns (e.g. every 64th cluster being a metacluster).
+    fn is_tracked(&self, cluster: u64) -> bool {
+        self.rate == 1 || (cluster.wrapping_mul(0x9E3779B97F4A7C15) >> 32) % self.rate == 0
+    }
+
+    /// Check and record the allocation of a cluster.
+    pub fn alloc(&mut self, cluster: u64) -> Result<(), Error> {
+        if self.is_tracked(cluster) && self.clusters.insert(cluster, true) == Some(true) {
+            return Err(Error::DoubleAlloc { cluster: cluster });
+        }
+
+        Ok(())
+    }
+
+    /// Check and record the free of a cluster.
+    pub fn free(&mut self, cluster: u64) -> Result<(), Error> {
+        if self.bounds.check(cluster).is_none() {
+            return Err(Error::InvalidFree { cluster: cluster });
+        }
+
+        if self.is_tracked(cluster) && self.clusters.insert(cluster, false) == Some(false) {
+            return Err(Error::DoubleFree { cluster: cluster });
+        }
+
+        Ok(())
+    }
+
+    /// Forget the recorded state of every cluster.
+    ///
+    /// This is used when the allocations are undone behind the back of the shadow (e.g. by
+    /// reverting a transaction).
+    pub fn clear(&mut self) {
+        self.clusters.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shadow(size: u64, limit: usize, rate: u64) -> Shadow {
+        Shadow::new(cluster::Bounds::new(size, 8), Config {
+            limit: limit,
+            rate: rate,
+        })
+    }
+
+    #[test]
+    fn double_alloc_free() {
+        let mut shadow = shadow(64, DEFAULT_LIMIT, 1);
+
+        // The state of unseen clusters is unknown, so anything goes at first.
+        shadow.free(20).unwrap();
+        shadow.alloc(20).unwrap();
+        assert_eq!(shadow.alloc(20), Err(Error::DoubleAlloc { cluster: 20 }));
+        shadow.free(20).unwrap();
+        assert_eq!(shadow.free(20), Err(Error::DoubleFree { cluster: 20 }));
+
+        // The disk header, the state block, and clusters past the end can't be freed.
+        assert_eq!(shadow.free(2), Err(Error::InvalidFree { cluster: 2 }));
+        assert_eq!(shadow.free(8), Err(Error::InvalidFree { cluster: 8 }));
+        assert_eq!(shadow.free(64), Err(Error::InvalidFree { cluster: 64 }));
+
+        // Forgetting the state accepts anything again.
+        shadow.clear();
+        shadow.free(20).unwrap();
+    }
+
+    #[test]
+    fn memory_bound() {
+        // Room for 1024 entries on a disk of 1 << 20 clusters.
+        let mut shadow = shadow(1 << 20, 1024 * 18, 1);
+        assert_eq!(shadow.rate(), 1024);
+
+        for cluster in 9..1 << 20 {
+            shadow.alloc(cluster).unwrap();
+        }
+        // About one in 1024 clusters is tracked.
+        assert!(shadow.clusters.len() < 2048);
+    }
+}
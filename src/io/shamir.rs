@@ -0,0 +1,224 @@
+//! Shamir secret sharing of volume keys.
+//!
+//! Operators who must avoid single-custodian keys can split the volume key into `n` shares, such
+//! that any `k` of them reconstruct the key, while `k - 1` shares reveal nothing about it.
+//!
+//! Every byte of the key is shared independently: It is the constant term of a random polynomial
+//! of degree `k - 1` over GF(256), and the shares are the values of the polynomials at distinct
+//! nonzero points. The key is reconstructed by Lagrange interpolation at zero.
+
+/// The size (in bytes) of a key.
+pub const KEY_SIZE: usize = 16;
+
+quick_error! {
+    /// A key reconstruction error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// Too few shares were given.
+        TooFewShares {
+            description("Too few key shares.")
+        }
+        /// Two shares have the same index, or a share has index zero.
+        InvalidShare {
+            description("Invalid or duplicate key share.")
+        }
+        /// The reconstructed key doesn't match the key check in the disk header.
+        ///
+        /// This means that at least one of the shares is wrong.
+        KeyMismatch {
+            description("Reconstructed key doesn't match.")
+        }
+    }
+}
+
+/// A key share.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Share {
+    /// The index of the share.
+    ///
+    /// This is the point the polynomials are evaluated at, and is never zero.
+    pub index: u8,
+    /// The share data.
+    pub data: [u8; KEY_SIZE],
+}
+
+/// Multiply two elements of GF(256).
+///
+/// This uses the AES reduction polynomial, `x^8 + x^4 + x^3 + x + 1`.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+
+        // Multiply `a` by `x`, reducing if it overflows.
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1B;
+        }
+
+        b >>= 1;
+    }
+
+    product
+}
+
+/// Invert a nonzero element of GF(256).
+fn inv(a: u8) -> u8 {
+    // The multiplicative group has order 255, so `a^254 = a^-1`.
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+
+    result
+}
+
+/// Split a key into `count` shares, of which any `threshold` reconstruct the key.
+///
+/// `random` is used to fill buffers with cryptographically secure random bytes. This panics if
+/// `threshold` is zero or greater than `count`.
+pub fn split<R: FnMut(&mut [u8])>(key: &[u8; KEY_SIZE], threshold: u8, count: u8, mut random: R) -> Vec<Share> {
+    assert!(threshold != 0 && threshold <= count, "Invalid key sharing threshold.");
+
+    // Generate the random coefficients. The polynomial of the i'th byte is
+    // `key[i] + coefficients[0][i] x + coefficients[1][i] x^2 + ...`.
+    let mut coefficients = vec![[0; KEY_SIZE]; threshold as usize - 1];
+    for coefficient in &mut coefficients {
+        random(coefficient);
+    }
+
+    (1..count as u16 + 1).map(|index| {
+        let index = index as u8;
+        let mut data = [0; KEY_SIZE];
+
+        for i in 0..KEY_SIZE {
+            // Evaluate the polynomial by Horner's method.
+            let mut y = 0;
+            for coefficient in coefficients.iter().rev() {
+                y = mul(y, index) ^ coefficient[i];
+            }
+            data[i] = mul(y, index) ^ key[i];
+        }
+
+        Share {
+            index: index,
+            data: data,
+        }
+    }).collect()
+}
+
+/// Reconstruct a key from `threshold` or more shares.
+///
+/// Only the first `threshold` shares are used. Note that wrong shares can't be detected here; the
+/// result must be compared to the key check in the disk header.
+pub fn combine(shares: &[Share], threshold: u8) -> Result<[u8; KEY_SIZE], Error> {
+    if threshold == 0 || shares.len() < threshold as usize {
+        return Err(Error::TooFewShares);
+    }
+    let shares = &shares[..threshold as usize];
+
+    // Make sure the indices are distinct and nonzero, as the interpolation divides by their
+    // differences.
+    for (n, share) in shares.iter().enumerate() {
+        if share.index == 0 || shares[..n].iter().any(|other| other.index == share.index) {
+            return Err(Error::InvalidShare);
+        }
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for share in shares {
+        // Calculate the Lagrange basis polynomial of this share at zero. Note that subtraction is
+        // XOR in GF(256).
+        let mut basis = 1;
+        for other in shares {
+            if other.index != share.index {
+                basis = mul(basis, mul(other.index, inv(other.index ^ share.index)));
+            }
+        }
+
+        for i in 0..KEY_SIZE {
+            key[i] ^= mul(share.data[i], basis);
+        }
+    }
+
+    Ok(key)
+}
+
+/// Calculate the key check value of a key.
+///
+/// This is stored in the disk header, such that a key reconstructed from wrong shares is rejected
+/// rather than silently decrypting garbage. It is a hash of the key, which reveals nothing useful
+/// about a random 128-bit key.
+pub fn key_check(key: &[u8; KEY_SIZE]) -> u64 {
+    seahash::hash(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A deterministic (and very insecure) randomness source for testing.
+    fn random() -> impl FnMut(&mut [u8]) {
+        let mut state = 0u8;
+        move |buf: &mut [u8]| for x in buf {
+            state = state.wrapping_mul(31).wrapping_add(17);
+            *x = state;
+        }
+    }
+
+    #[test]
+    fn field() {
+        assert_eq!(mul(0x57, 0x83), 0xC1);
+        for a in 1..256 {
+            assert_eq!(mul(a as u8, inv(a as u8)), 1);
+        }
+    }
+
+    #[test]
+    fn split_combine() {
+        let key = [42; KEY_SIZE];
+        let shares = split(&key, 3, 5, random());
+        assert_eq!(shares.len(), 5);
+
+        // Any three shares reconstruct the key.
+        assert_eq!(combine(&shares, 3), Ok(key));
+        assert_eq!(combine(&[shares[4], shares[0], shares[2]], 3), Ok(key));
+        assert_eq!(combine(&[shares[1], shares[3], shares[4]], 3), Ok(key));
+
+        // Two don't.
+        assert_eq!(combine(&shares[..2], 3), Err(Error::TooFewShares));
+        assert!(combine(&shares[..2], 2).unwrap() != key);
+    }
+
+    #[test]
+    fn single_share() {
+        let key = [7; KEY_SIZE];
+        let shares = split(&key, 1, 2, random());
+        assert_eq!(combine(&shares[1..], 1), Ok(key));
+    }
+
+    #[test]
+    fn invalid_shares() {
+        let shares = split(&[1; KEY_SIZE], 2, 3, random());
+        assert_eq!(combine(&[shares[0], shares[0]], 2), Err(Error::InvalidShare));
+        assert_eq!(combine(&[Share { index: 0, data: [0; KEY_SIZE] }, shares[1]], 2), Err(Error::InvalidShare));
+    }
+
+    #[test]
+    fn key_mismatch() {
+        let key = [3; KEY_SIZE];
+        let mut shares = split(&key, 2, 2, random());
+        shares[1].data[0] ^= 1;
+
+        assert!(key_check(&combine(&shares, 2).unwrap()) != key_check(&key));
+    }
+}
@@ -1,6 +1,13 @@
+use std::cmp;
+
 quick_error! {
     /// A state block parsing error.
     enum Error {
+        /// The buffer is too short to hold a state block.
+        Truncated {
+            from(codec::Error)
+            description("Truncated state block.")
+        }
         /// Unknown or implementation-specific compression algorithm.
         UnknownCompressionAlgorithm {
             description("Unknown compression algorithm option.")
@@ -9,12 +16,37 @@ quick_error! {
         InvalidCompressionAlgorithm {
             description("Invalid compression algorithm option.")
         }
+        /// Unknown or implementation-specific purge method.
+        UnknownPurgeMethod {
+            description("Unknown purge method option.")
+        }
+        /// Invalid purge method.
+        InvalidPurgeMethod {
+            description("Invalid purge method option.")
+        }
+        /// Invalid number of metadata copies.
+        InvalidMetadataCopies {
+            description("Invalid number of metadata copies.")
+        }
+        /// A cluster pointer is out of bounds.
+        ///
+        /// The pointer points past the end of the disk or to a reserved cluster.
+        PointerOutOfBounds {
+            cluster: u64,
+        } {
+            display("Cluster pointer {:x} in the state block is out of bounds.", cluster)
+            description("Cluster pointer out of bounds.")
+        }
+        /// The superpage pointer is null or corrupt.
+        InvalidSuperpage {
+            description("Invalid superpage pointer.")
+        }
         /// The checksums doesn't match.
         ChecksumMismatch {
             /// The checksum of the data.
-            expected: u16,
+            expected: u64,
             /// The expected/stored value of the checksum.
-            found: u16,
+            found: u64,
         } {
             display("Mismatching checksums in the state block - expected {:x}, found {:x}.", expected, found)
             description("Mismatching checksum.")
@@ -23,6 +55,7 @@ quick_error! {
 }
 
 /// A compression algorithm configuration option.
+#[derive(PartialEq, Eq, Clone, Copy)]
 enum CompressionAlgorithm {
     /// Identity function/compression disabled.
     Identity = 0,
@@ -47,6 +80,83 @@ impl TryFrom<u16> for CompressionAlgorithm {
     }
 }
 
+/// A purge method configuration option.
+///
+/// This defines how the data of freed clusters is destroyed.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum PurgeMethod {
+    /// Leave the data of freed clusters as is.
+    None = 0,
+    /// Overwrite freed clusters with zeros.
+    Zero = 1,
+    /// Overwrite freed clusters with pseudorandom data.
+    ///
+    /// On some media, this makes the old data harder to recover than zeroing.
+    Random = 2,
+}
+
+impl TryFrom<u16> for PurgeMethod {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<PurgeMethod, Error> {
+        match from {
+            0 => Ok(PurgeMethod::None),
+            1 => Ok(PurgeMethod::Zero),
+            2 => Ok(PurgeMethod::Random),
+            1 << 15... => Err(Error::UnknownPurgeMethod),
+            _ => Err(Error::InvalidPurgeMethod),
+        }
+    }
+}
+
+/// A packing policy configuration option.
+///
+/// Packing a page into the last allocated cluster requires recompressing the whole cluster, so
+/// the more pages are packed, the more expensive allocations get. This policy bounds the number of
+/// pages per cluster, trading space for latency.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum PackingPolicy {
+    /// Pack as many pages as fit.
+    Always,
+    /// Pack at most some number of pages (at least two) into a cluster.
+    UpTo(u16),
+    /// Never pack; every page gets its own cluster.
+    Never,
+}
+
+impl PackingPolicy {
+    /// Get the maximal number of pages in a cluster.
+    fn max_pages(self) -> usize {
+        match self {
+            PackingPolicy::Always => pages::MAX_PAGES_PER_CLUSTER,
+            PackingPolicy::UpTo(n) => cmp::min(n as usize, pages::MAX_PAGES_PER_CLUSTER),
+            PackingPolicy::Never => 1,
+        }
+    }
+}
+
+impl From<u16> for PackingPolicy {
+    fn from(from: u16) -> PackingPolicy {
+        // The policy is stored as the maximal number of pages per cluster, with zero meaning
+        // unlimited.
+        match from {
+            0 => PackingPolicy::Always,
+            1 => PackingPolicy::Never,
+            n => PackingPolicy::UpTo(n),
+        }
+    }
+}
+
+impl From<PackingPolicy> for u16 {
+    fn from(from: PackingPolicy) -> u16 {
+        match from {
+            PackingPolicy::Always => 0,
+            PackingPolicy::UpTo(n) => cmp::max(n, 2),
+            PackingPolicy::Never => 1,
+        }
+    }
+}
+
 /// The TFS state block.
 struct StateBlock {
     /// The chosen compression algorithm.
@@ -55,14 +165,137 @@ struct StateBlock {
     freelist_head: cluster::Pointer,
     /// A pointer to the superpage.
     superpage: pages::Pointer,
+    /// Is the volume sealed?
+    ///
+    /// A sealed volume is read-only: No writes are accepted until it is explicitly unsealed. This
+    /// is used for golden images and forensics, where the image must stay untouched.
+    sealed: bool,
+    /// A pointer to the health event log, if any.
+    ///
+    /// The log is allocated when the first event is recorded.
+    event_log: Option<cluster::Pointer>,
+    /// The purge method of freed clusters.
+    purge_method: PurgeMethod,
+    /// The generation number.
+    ///
+    /// This is incremented by every commit.
+    generation: u64,
+    /// A pointer to the root history, if any.
+    ///
+    /// The history is allocated when the superpage pointer changes the first time.
+    history: Option<cluster::Pointer>,
+    /// The packing policy.
+    packing_policy: PackingPolicy,
+    /// Are metadata pages stored raw?
+    ///
+    /// If set, metadata pages are stored uncompressed and duplicated, each in a cluster of its
+    /// own, so a recovery tool can carve them from the raw disk, even if the compression state is
+    /// lost.
+    raw_metadata: bool,
+    /// The number of copies of metadata.
+    ///
+    /// This is either one or two. With two copies, metadata pages, the root history, and the
+    /// freelist metaclusters are duplicated into the other half of the volume, and reads fall back
+    /// to the duplicate, if the primary copy is damaged.
+    metadata_copies: u8,
+    /// A pointer to the duplicate of the root history, if any.
+    history_copy: Option<cluster::Pointer>,
+    /// A pointer to the last allocated cluster of the default packing stream, if any.
+    ///
+    /// This allows packing into the cluster to resume after remounting.
+    last_cluster: Option<cluster::Pointer>,
+    /// The number of pages in the last allocated cluster.
+    last_cluster_pages: u16,
+    /// Is the checksum tree enabled?
+    ///
+    /// If set, the full-width checksums of written data clusters are recorded in the checksum
+    /// tree (see the `checksums` module), and verified on reads.
+    checksum_tree: bool,
+    /// The depth of the checksum tree (see `checksums::depth()`).
+    ///
+    /// This is set when the root is allocated, and only grows, so resizing the disk doesn't
+    /// change the layout of an existing tree. Zero (from older implementations) means the depth
+    /// covering the disk as it is.
+    checksum_tree_depth: u8,
+    /// A pointer to the root of the checksum tree, if any.
+    ///
+    /// The root is allocated when the first checksum is recorded.
+    checksum_tree_root: Option<cluster::Pointer>,
+    /// The number of free clusters, if recorded.
+    ///
+    /// This is the number of clusters in the freelist (see `Manager::free_clusters()`), as of the
+    /// commit writing the state block. Mounting and verification compare it against the freelist,
+    /// so a corrupt freelist is detected even if every metacluster is intact.
+    free_clusters: Option<u64>,
+    /// A pointer to the first cluster of the indirection table, if any.
+    ///
+    /// The table is allocated when the first cluster is moved (see the `indirection` module).
+    indirection: Option<cluster::Pointer>,
+    /// A pointer to the first cluster of the dead page list, if any.
+    ///
+    /// The list is allocated when the first page of a cluster holding other live pages is
+    /// released (see the `dead_pages` module).
+    dead_pages: Option<cluster::Pointer>,
+    /// A pointer to the duplicate of the freelist head, if any.
+    ///
+    /// The duplicates of the other metaclusters are linked from the metaclusters linking them
+    /// (see `pages::decode_metacluster()`).
+    freelist_head_copy: Option<cluster::Pointer>,
+}
+
+/// Read an optional cluster pointer.
+///
+/// A null pointer means `None`, and otherwise the pointer is checked against `bounds`.
+fn read_optional_pointer(reader: &mut codec::Reader, bounds: cluster::Bounds) -> Result<Option<cluster::Pointer>, Error> {
+    match reader.read_u64()? {
+        0 => Ok(None),
+        ptr => bounds.check(ptr).map(Some).ok_or(Error::PointerOutOfBounds { cluster: ptr }),
+    }
 }
 
 impl StateBlock {
+    /// Create the state block of a fresh volume.
+    ///
+    /// Everything but the freelist head and the superpage starts out at its default: LZ4
+    /// compression, no purging, unlimited packing, and a single copy of metadata.
+    fn new(freelist_head: cluster::Pointer, superpage: pages::Pointer) -> StateBlock {
+        StateBlock {
+            compression_algorithm: CompressionAlgorithm::Lz4,
+            freelist_head: freelist_head,
+            superpage: superpage,
+            sealed: false,
+            event_log: None,
+            purge_method: PurgeMethod::None,
+            generation: 0,
+            history: None,
+            packing_policy: PackingPolicy::Always,
+            raw_metadata: false,
+            metadata_copies: 1,
+            history_copy: None,
+            last_cluster: None,
+            last_cluster_pages: 0,
+            checksum_tree: false,
+            checksum_tree_depth: 0,
+            checksum_tree_root: None,
+            free_clusters: None,
+            indirection: None,
+            dead_pages: None,
+            freelist_head_copy: None,
+        }
+    }
+
     /// Parse a sequence of bytes.
-    fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Result<(), Error> {
-        // Make sure that the checksum of the state block matches the 8 byte field in the start.
-        let expected = LittleEndian::read(&buf);
-        let found = checksum_algorithm.hash(&buf[8..]);
+    ///
+    /// Every pointer is checked against `bounds`. This never panics, regardless of the content and
+    /// length of `buf`.
+    #[deny(clippy::indexing_slicing)]
+    fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm, bounds: cluster::Bounds) -> Result<StateBlock, Error> {
+        let mut reader = codec::Reader::new(buf);
+
+        // Make sure that the checksum of the state block matches the 8 byte field in the start. The
+        // checksum flag picks the algorithm, and is up to the caller.
+        let expected = reader.read_u64()? & !header::CHECKSUM_FLAG;
+        let found = checksum_algorithm.hash(reader.peek(disk::SECTOR_SIZE - 8)?) & !header::CHECKSUM_FLAG;
         if expected != found {
             return Err(Error::ChecksumMismatch {
                 expected: expected,
@@ -70,14 +303,85 @@ impl StateBlock {
             });
         }
 
-        StateBlock {
-            // Load the compression algorithm config field.
-            compression_algorithm: CompressionAlgorithm::try_from(LittleEndian::read(buf[8..]))?,
-            // Load the freelist head pointer.
-            freelist_head: LittleEndian::read(buf[16..]),
-            // Load the superpage pointer.
-            superpage: LittleEndian::read(buf[24..]),
+        // Load the compression algorithm config field.
+        let compression_algorithm = CompressionAlgorithm::try_from(reader.read_u16()?)?;
+        // Load the freelist head pointer.
+        reader.seek(16)?;
+        let freelist_head = reader.read_u64()?;
+        let freelist_head = bounds.check(freelist_head).ok_or(Error::PointerOutOfBounds { cluster: freelist_head })?;
+        // Load the superpage pointer.
+        let superpage = pages::Pointer::decode(reader.read_u64()?).ok_or(Error::InvalidSuperpage)?;
+        if bounds.check(superpage.cluster().into()).is_none() {
+            return Err(Error::PointerOutOfBounds { cluster: superpage.cluster().into() });
         }
+        // Load the seal flag.
+        let sealed = reader.read_u8()? != 0;
+        // Load the event log pointer.
+        reader.seek(40)?;
+        let event_log = read_optional_pointer(&mut reader, bounds)?;
+        // Load the purge method config field.
+        let purge_method = PurgeMethod::try_from(reader.read_u16()?)?;
+        // Load the generation number.
+        reader.seek(56)?;
+        let generation = reader.read_u64()?;
+        // Load the root history pointer.
+        let history = read_optional_pointer(&mut reader, bounds)?;
+        // Load the packing policy.
+        let packing_policy = PackingPolicy::from(reader.read_u16()?);
+        // Load the raw metadata flag.
+        let raw_metadata = reader.read_u8()? != 0;
+        // Load the number of metadata copies. Zero (from older implementations) means one copy.
+        let metadata_copies = match reader.read_u8()? {
+            0 | 1 => 1,
+            2 => 2,
+            _ => return Err(Error::InvalidMetadataCopies),
+        };
+        // Load the root history duplicate pointer.
+        reader.seek(80)?;
+        let history_copy = read_optional_pointer(&mut reader, bounds)?;
+        // Load the last allocated cluster and its fill level.
+        let last_cluster = read_optional_pointer(&mut reader, bounds)?;
+        let last_cluster_pages = reader.read_u16()?;
+        // Load the checksum tree flag.
+        let checksum_tree = reader.read_u8()? != 0;
+        // Load the checksum tree depth.
+        let checksum_tree_depth = reader.read_u8()?;
+        // Load the checksum tree root pointer.
+        reader.seek(104)?;
+        let checksum_tree_root = read_optional_pointer(&mut reader, bounds)?;
+        // Load the free cluster count. It is stored plus one, so zero (from older
+        // implementations) means unrecorded.
+        let free_clusters = reader.read_u64()?.checked_sub(1);
+        // Load the indirection table pointer.
+        let indirection = read_optional_pointer(&mut reader, bounds)?;
+        // Load the dead page list pointer.
+        let dead_pages = read_optional_pointer(&mut reader, bounds)?;
+        // Load the freelist head duplicate pointer.
+        let freelist_head_copy = read_optional_pointer(&mut reader, bounds)?;
+
+        Ok(StateBlock {
+            compression_algorithm: compression_algorithm,
+            freelist_head: freelist_head,
+            superpage: superpage,
+            sealed: sealed,
+            event_log: event_log,
+            purge_method: purge_method,
+            generation: generation,
+            history: history,
+            packing_policy: packing_policy,
+            raw_metadata: raw_metadata,
+            metadata_copies: metadata_copies,
+            history_copy: history_copy,
+            last_cluster: last_cluster,
+            last_cluster_pages: last_cluster_pages,
+            checksum_tree: checksum_tree,
+            checksum_tree_depth: checksum_tree_depth,
+            checksum_tree_root: checksum_tree_root,
+            free_clusters: free_clusters,
+            indirection: indirection,
+            dead_pages: dead_pages,
+            freelist_head_copy: freelist_head_copy,
+        })
     }
 
     /// Encode the state block into a sector-sized buffer.
@@ -85,16 +389,61 @@ impl StateBlock {
         // Create a buffer to hold the data.
         let mut buf = [0; disk::SECTOR_SIZE];
 
-        // Write the compression algorithm.
-        LittleEndian::write(&mut buf[8..], self.compression_algorithm as u16);
-        // Write the freelist head pointer.
-        LittleEndian::write(&mut buf[16..], self.freelist_head);
-        // Write the superpage pointer.
-        LittleEndian::write(&mut buf[24..], self.superpage);
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+
+            // Write the compression algorithm.
+            writer.seek(8);
+            writer.write_u16(self.compression_algorithm as u16);
+            // Write the freelist head pointer.
+            writer.seek(16);
+            writer.write_u64(self.freelist_head.into());
+            // Write the superpage pointer.
+            writer.write_u64(self.superpage.encode());
+            // Write the seal flag.
+            writer.write_u8(self.sealed as u8);
+            // Write the event log pointer.
+            writer.seek(40);
+            writer.write_u64(self.event_log.map_or(0, u64::from));
+            // Write the purge method.
+            writer.write_u16(self.purge_method as u16);
+            // Write the generation number.
+            writer.seek(56);
+            writer.write_u64(self.generation);
+            // Write the root history pointer.
+            writer.write_u64(self.history.map_or(0, u64::from));
+            // Write the packing policy.
+            writer.write_u16(self.packing_policy.into());
+            // Write the raw metadata flag.
+            writer.write_u8(self.raw_metadata as u8);
+            // Write the number of metadata copies.
+            writer.write_u8(self.metadata_copies);
+            // Write the root history duplicate pointer.
+            writer.seek(80);
+            writer.write_u64(self.history_copy.map_or(0, u64::from));
+            // Write the last allocated cluster and its fill level.
+            writer.write_u64(self.last_cluster.map_or(0, u64::from));
+            writer.write_u16(self.last_cluster_pages);
+            // Write the checksum tree flag.
+            writer.write_u8(self.checksum_tree as u8);
+            // Write the checksum tree depth.
+            writer.write_u8(self.checksum_tree_depth);
+            // Write the checksum tree root pointer.
+            writer.seek(104);
+            writer.write_u64(self.checksum_tree_root.map_or(0, u64::from));
+            // Write the free cluster count.
+            writer.write_u64(self.free_clusters.map_or(0, |free| free + 1));
+            // Write the indirection table pointer.
+            writer.write_u64(self.indirection.map_or(0, u64::from));
+            // Write the dead page list pointer.
+            writer.write_u64(self.dead_pages.map_or(0, u64::from));
+            // Write the freelist head duplicate pointer.
+            writer.write_u64(self.freelist_head_copy.map_or(0, u64::from));
+        }
 
-        // Calculate and store the checksum.
-        let cksum = self.checksum_algorithm.hash(&buf[8..]);
-        LittleEndian::write(&mut buf, cksum);
+        // Calculate and store the checksum. The checksum flag is left to the caller.
+        let cksum = checksum_algorithm.hash(&buf[8..]) & !header::CHECKSUM_FLAG;
+        codec::Writer::new(&mut buf).write_u64(cksum);
 
         buf
     }
@@ -117,6 +466,48 @@ mod tests {
 
         block.superpage = 200;
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.sealed = true;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.purge_method = PurgeMethod::Random;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.generation = 0xDEADBEEF;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.packing_policy = PackingPolicy::UpTo(8);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.packing_policy = PackingPolicy::Never;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.raw_metadata = true;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.metadata_copies = 2;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.last_cluster = Some(300);
+        block.last_cluster_pages = 3;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.checksum_tree = true;
+        block.checksum_tree_depth = 2;
+        block.checksum_tree_root = Some(301);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.free_clusters = Some(0);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.indirection = Some(302);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.dead_pages = Some(303);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.freelist_head_copy = Some(304);
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
     }
 
     #[test]
@@ -138,6 +529,66 @@ mod tests {
         sector[24] = 29;
         LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
         assert_eq!(sector, block.encode());
+
+        block.sealed = true;
+        sector[32] = 1;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.purge_method = PurgeMethod::Zero;
+        sector[48] = 1;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.packing_policy = PackingPolicy::UpTo(16);
+        sector[72] = 16;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.raw_metadata = true;
+        sector[74] = 1;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.metadata_copies = 2;
+        sector[75] = 2;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.last_cluster_pages = 5;
+        sector[96] = 5;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.checksum_tree = true;
+        sector[98] = 1;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.checksum_tree_depth = 3;
+        sector[99] = 3;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.free_clusters = Some(41);
+        sector[112] = 42;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.indirection = Some(43);
+        sector[120] = 43;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.dead_pages = Some(44);
+        sector[128] = 44;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
+
+        block.freelist_head_copy = Some(45);
+        sector[136] = 45;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(sector, block.encode());
     }
 
     #[test]
@@ -147,6 +598,15 @@ mod tests {
         assert_eq!(StateBlock::decode(sector), Err(Error::ChecksumMismatch));
     }
 
+    #[test]
+    fn truncated() {
+        let sector = StateBlock::default().encode();
+
+        assert_eq!(StateBlock::decode(&[]), Err(Error::Truncated));
+        assert_eq!(StateBlock::decode(&sector[..20]), Err(Error::Truncated));
+        assert_eq!(StateBlock::decode(&sector[..disk::SECTOR_SIZE - 1]), Err(Error::Truncated));
+    }
+
     #[test]
     fn unknown_invalid_options() {
         let mut sector = StateBlock::default().encode();
@@ -158,4 +618,49 @@ mod tests {
         sector[9] = 0xFF;
         assert_eq!(StateBlock::decode(sector), Err(Error::UnknownChecksumAlgorithm));
     }
+
+    #[test]
+    fn golden() {
+        // A state block as written by format version 1.0 (see `header::format_version()`). If this
+        // fails, the layout has changed, and old images won't open anymore.
+        let mut sector = [0; disk::SECTOR_SIZE];
+        // The checksum.
+        sector[..8].copy_from_slice(&[0x7c, 0x5a, 0x54, 0x39, 0xa7, 0x61, 0xdb, 0x36]);
+        // The compression algorithm (LZ4).
+        sector[8] = 1;
+        // The freelist head (9), and the superpage (page 0 of cluster 10).
+        sector[16] = 9;
+        sector[24..26].copy_from_slice(&[0x0a, 0xa0]);
+        // The event log (11).
+        sector[40] = 11;
+        // The generation (3), the root history (12), and the number of metadata copies (1).
+        sector[56] = 3;
+        sector[64] = 12;
+        sector[75] = 1;
+
+        let block = StateBlock::decode(&sector, header::ChecksumAlgorithm::SeaHash, cluster::Bounds::new(64, 8)).unwrap();
+        assert_eq!(block.compression_algorithm, CompressionAlgorithm::Lz4);
+        assert_eq!(u64::from(block.freelist_head), 9);
+        assert_eq!(block.superpage, pages::Pointer::new(cluster::Pointer::new(10).unwrap(), 0));
+        assert!(!block.sealed);
+        assert_eq!(block.event_log, cluster::Pointer::new(11));
+        assert_eq!(block.purge_method, PurgeMethod::None);
+        assert_eq!(block.generation, 3);
+        assert_eq!(block.history, cluster::Pointer::new(12));
+        assert_eq!(block.packing_policy, PackingPolicy::Always);
+        assert!(!block.raw_metadata);
+        assert_eq!(block.metadata_copies, 1);
+        assert_eq!(block.history_copy, None);
+        assert_eq!(block.last_cluster, None);
+        assert!(!block.checksum_tree);
+        assert_eq!(block.checksum_tree_depth, 0);
+        assert_eq!(block.checksum_tree_root, None);
+        assert_eq!(block.free_clusters, None);
+        assert_eq!(block.indirection, None);
+        assert_eq!(block.dead_pages, None);
+        assert_eq!(block.freelist_head_copy, None);
+
+        // Rewriting the state block must reproduce the image.
+        assert_eq!(&block.encode(header::ChecksumAlgorithm::SeaHash)[..], &sector[..]);
+    }
 }
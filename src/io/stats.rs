@@ -0,0 +1,298 @@
+//! Runtime statistics.
+//!
+//! The page manager keeps statistics on its operations, which can be retrieved through
+//! `Manager::stats()`. They are kept in memory only, and start over when the volume is opened.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The number of exactly represented values in a histogram.
+///
+/// Values below this get their own bucket.
+const LINEAR_BUCKETS: u64 = 16;
+/// The number of bits of sub-buckets per power of two.
+///
+/// Every power of two above `LINEAR_BUCKETS` is divided into `1 << SUB_BUCKET_BITS` buckets, so
+/// the relative error of a recorded value is at most 12.5%.
+const SUB_BUCKET_BITS: u32 = 3;
+/// The total number of buckets.
+const BUCKETS: usize = LINEAR_BUCKETS as usize + (64 - 4) * (1 << SUB_BUCKET_BITS);
+
+/// A latency histogram.
+///
+/// This is a (simplified) HDR histogram: Latencies are recorded in microseconds into log-linear
+/// buckets, such that memory usage is constant, recording is cheap, and percentiles have bounded
+/// relative error over the whole range, which is what's needed for quantifying tail latencies.
+#[derive(Clone)]
+pub struct Histogram {
+    /// The bucket counts.
+    buckets: Box<[u64; BUCKETS]>,
+    /// The number of recorded values.
+    count: u64,
+    /// The sum of the recorded values.
+    sum: u64,
+    /// The largest recorded value.
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: Box::new([0; BUCKETS]),
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+/// Get the bucket of a value.
+fn bucket(value: u64) -> usize {
+    if value < LINEAR_BUCKETS {
+        value as usize
+    } else {
+        // The position of the highest set bit (at least 4).
+        let exponent = 63 - value.leading_zeros();
+        // The bits following the highest set bit.
+        let sub = (value >> (exponent - SUB_BUCKET_BITS)) & ((1 << SUB_BUCKET_BITS) - 1);
+
+        LINEAR_BUCKETS as usize + ((exponent - 4) << SUB_BUCKET_BITS) as usize + sub as usize
+    }
+}
+
+/// Get the smallest value of a bucket.
+fn bucket_start(bucket: usize) -> u64 {
+    if bucket < LINEAR_BUCKETS as usize {
+        bucket as u64
+    } else {
+        let bucket = bucket - LINEAR_BUCKETS as usize;
+        let exponent = (bucket >> SUB_BUCKET_BITS) as u32 + 4;
+        let sub = (bucket & ((1 << SUB_BUCKET_BITS) - 1)) as u64;
+
+        1 << exponent | sub << (exponent - SUB_BUCKET_BITS)
+    }
+}
+
+/// Convert a duration to microseconds.
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1_000_000).saturating_add(duration.subsec_nanos() as u64 / 1000)
+}
+
+impl Histogram {
+    /// Record a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let value = micros(latency);
+
+        self.buckets[bucket(value)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Get the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Get the mean latency.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::new(0, 0)
+        } else {
+            Duration::from_micros(self.sum / self.count)
+        }
+    }
+
+    /// Get the largest recorded latency.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// Get the latency at some percentile (0 to 100).
+    ///
+    /// This is the lower bound of the bucket holding the percentile, so it is at most 12.5%
+    /// smaller than the true value.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        // The number of values at or below the percentile.
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(bucket_start(i));
+            }
+        }
+
+        // The histogram is empty.
+        Duration::new(0, 0)
+    }
+}
+
+/// The number of allocations in the rolling window of the packing statistics.
+const PACKING_WINDOW: usize = 1024;
+
+/// The placement of an allocated page.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Placement {
+    /// The page was packed into the last allocated cluster.
+    Packed,
+    /// The page was put in a new cluster, without trying to pack it.
+    ///
+    /// This is the case when there is no last allocated cluster, when it holds as many pages as
+    /// the packing policy allows, or when it is out of place for the page (e.g. in a zone, or in
+    /// the wrong half of the volume for a metadata copy).
+    NewCluster,
+    /// The page was put in a new cluster, as it didn't fit into the last allocated cluster.
+    DidntFit,
+}
+
+/// Packing and compression statistics.
+#[derive(Clone, Default)]
+pub struct Packing {
+    /// The number of pages allocated.
+    pub pages: u64,
+    /// The number of clusters the pages were put in.
+    pub clusters: u64,
+    /// The number of times a page didn't fit into the last allocated cluster.
+    pub didnt_fit: u64,
+    /// The number of bytes saved by compression, see `.bytes_saved()`.
+    saved: u64,
+    /// The placements of the most recent allocations, oldest first.
+    recent: VecDeque<Placement>,
+}
+
+impl Packing {
+    /// Record the placement of an allocated page.
+    ///
+    /// `before` and `after` are the sizes (in bytes) of the payload of the page's cluster as
+    /// stored (i.e. compressed, if it is), before and after the page was put in it. New clusters
+    /// start out empty. A payload never exceeds a page.
+    pub fn record(&mut self, placement: Placement, before: usize, after: usize) {
+        self.pages += 1;
+        // The page takes up a page uncompressed, but grows the payload by no more than that.
+        self.saved += (pages::PAGE_SIZE + before - after) as u64;
+        match placement {
+            Placement::Packed => (),
+            Placement::NewCluster => self.clusters += 1,
+            Placement::DidntFit => {
+                self.clusters += 1;
+                self.didnt_fit += 1;
+            },
+        }
+
+        if self.recent.len() == PACKING_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(placement);
+    }
+
+    /// Get the average number of pages per cluster.
+    pub fn pages_per_cluster(&self) -> f64 {
+        if self.clusters == 0 {
+            0.0
+        } else {
+            self.pages as f64 / self.clusters as f64
+        }
+    }
+
+    /// Get the number of bytes saved by compression.
+    ///
+    /// This is the size of the allocated pages less the size of their payload as stored. As the
+    /// clusters are of fixed size, it only turns into disk space as far as it lets pages share
+    /// clusters (see `.pages_per_cluster()`).
+    pub fn bytes_saved(&self) -> u64 {
+        self.saved
+    }
+
+    /// Get the ratio of recent allocations which were packed.
+    ///
+    /// This covers the last 1024 allocations, so it reflects the current workload rather than the
+    /// whole history. A low rate means that recompressing the last cluster is mostly wasted work.
+    pub fn recent_pack_rate(&self) -> f64 {
+        if self.recent.is_empty() {
+            0.0
+        } else {
+            let packed = self.recent.iter().filter(|&&x| x == Placement::Packed).count();
+            packed as f64 / self.recent.len() as f64
+        }
+    }
+}
+
+/// The statistics of a page manager.
+#[derive(Clone, Default)]
+pub struct Stats {
+    /// The latencies of page allocations.
+    pub alloc: Histogram,
+    /// The latencies of page reads.
+    pub read: Histogram,
+    /// The latencies of commits.
+    pub commit: Histogram,
+    /// The latencies of cache flushes.
+    pub flush: Histogram,
+    /// The packing and compression statistics.
+    pub packing: Packing,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        // Bucket starts must be increasing and map back to their bucket.
+        for i in 0..BUCKETS {
+            assert_eq!(bucket(bucket_start(i)), i);
+            if i > 0 {
+                assert!(bucket_start(i) > bucket_start(i - 1));
+            }
+        }
+        assert_eq!(bucket(u64::max_value()), BUCKETS - 1);
+    }
+
+    #[test]
+    fn packing() {
+        let mut packing = Packing::default();
+        assert_eq!(packing.pages_per_cluster(), 0.0);
+
+        // A cluster of three pages compressed to 300 bytes, and one uncompressed page.
+        packing.record(Placement::NewCluster, 0, pages::PAGE_SIZE);
+        packing.record(Placement::Packed, pages::PAGE_SIZE, 200);
+        packing.record(Placement::Packed, 200, 300);
+        packing.record(Placement::DidntFit, 0, pages::PAGE_SIZE);
+
+        assert_eq!(packing.pages, 4);
+        assert_eq!(packing.clusters, 2);
+        assert_eq!(packing.didnt_fit, 1);
+        assert_eq!(packing.pages_per_cluster(), 2.0);
+        assert_eq!(packing.bytes_saved(), 3 * pages::PAGE_SIZE as u64 - 300);
+        assert_eq!(packing.recent_pack_rate(), 0.5);
+
+        for _ in 0..PACKING_WINDOW {
+            packing.record(Placement::DidntFit, 0, pages::PAGE_SIZE);
+        }
+        assert_eq!(packing.bytes_saved(), 3 * pages::PAGE_SIZE as u64 - 300);
+        assert_eq!(packing.recent_pack_rate(), 0.0);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(99.0), Duration::new(0, 0));
+
+        for i in 1..101 {
+            histogram.record(Duration::from_micros(i));
+        }
+        histogram.record(Duration::from_millis(50));
+
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.max(), Duration::from_millis(50));
+        assert_eq!(histogram.percentile(10.0), Duration::from_micros(11));
+        // The tail is dominated by the outlier.
+        assert!(histogram.percentile(100.0) > Duration::from_micros(43750));
+        // Percentiles are within 12.5% of the true value.
+        let p50 = histogram.percentile(50.0);
+        assert!(p50 <= Duration::from_micros(51) && p50 >= Duration::from_micros(44));
+    }
+}
@@ -0,0 +1,147 @@
+//! Byte-range storage.
+//!
+//! Not every environment has block devices or files: In the browser, an image might live in a
+//! JavaScript `ArrayBuffer`, and under WASI, it might be provided by the host through some custom
+//! interface. To support these, this module provides a `Disk` over any byte-addressed storage,
+//! which only needs to be able to read and write byte ranges.
+
+use disk::{self, Disk, Sector};
+
+/// Byte-addressed storage.
+///
+/// This is the minimal interface a host must provide to back a disk.
+pub trait Storage {
+    /// The size (in bytes) of the storage.
+    fn len(&self) -> u64;
+    /// Read `buf.len()` bytes starting at byte `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), disk::Error>;
+    /// Write `buf` starting at byte `offset`.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), disk::Error>;
+    /// Make the written data durable.
+    ///
+    /// See `Disk::sync()`.
+    fn sync(&mut self) -> Result<(), disk::Error> {
+        Ok(())
+    }
+    /// Get the geometry of the storage.
+    ///
+    /// See `Disk::geometry()`.
+    fn geometry(&self) -> disk::Geometry {
+        disk::Geometry::default()
+    }
+}
+
+/// In-memory storage.
+impl Storage for Vec<u8> {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), disk::Error> {
+        let offset = offset as usize;
+        buf.copy_from_slice(self.get(offset..offset + buf.len()).ok_or(disk::Error::OutOfBounds)?);
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), disk::Error> {
+        let offset = offset as usize;
+        self.get_mut(offset..offset + buf.len()).ok_or(disk::Error::OutOfBounds)?.copy_from_slice(buf);
+
+        Ok(())
+    }
+}
+
+/// A disk backed by byte-addressed storage.
+pub struct StorageDisk<S> {
+    /// The storage.
+    storage: S,
+}
+
+impl<S: Storage> StorageDisk<S> {
+    /// Create a disk over some storage.
+    ///
+    /// Trailing bytes not filling a whole sector are ignored.
+    pub fn new(storage: S) -> StorageDisk<S> {
+        StorageDisk {
+            storage: storage,
+        }
+    }
+
+    /// Get the inner storage back.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Check that an access of `len` bytes to `sector` is within the disk.
+    ///
+    /// The byte offset of the sector is returned.
+    fn check(&self, sector: Sector, len: usize) -> Result<u64, disk::Error> {
+        if sector >= self.number_of_sectors() {
+            return Err(disk::Error::OutOfBounds);
+        }
+
+        // The sector is within the disk, so this can't overflow.
+        let start = sector as u64 * disk::SECTOR_SIZE as u64;
+        if start + len as u64 > self.storage.len() {
+            Err(disk::Error::OutOfBounds)
+        } else {
+            Ok(start)
+        }
+    }
+}
+
+impl<S: Storage> Disk for StorageDisk<S> {
+    fn number_of_sectors(&self) -> Sector {
+        (self.storage.len() / disk::SECTOR_SIZE as u64) as Sector
+    }
+
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), disk::Error> {
+        let offset = self.check(sector, buffer.len())?;
+        self.storage.write_at(offset, buffer)
+    }
+
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), disk::Error> {
+        let offset = self.check(sector, buffer.len())?;
+        self.storage.read_at(offset, buffer)
+    }
+
+    fn sync(&mut self) -> Result<(), disk::Error> {
+        self.storage.sync()
+    }
+
+    fn geometry(&self) -> disk::Geometry {
+        self.storage.geometry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        let mut disk = StorageDisk::new(vec![0; disk::SECTOR_SIZE * 4 + 100]);
+        assert_eq!(disk.number_of_sectors(), 4);
+
+        disk.write(2, &[1; disk::SECTOR_SIZE]).unwrap();
+        let mut buf = [0; disk::SECTOR_SIZE];
+        disk.read(2, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[1; disk::SECTOR_SIZE][..]);
+
+        let storage = disk.into_inner();
+        assert!(storage[..2 * disk::SECTOR_SIZE].iter().all(|&x| x == 0));
+        assert!(storage[3 * disk::SECTOR_SIZE..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut disk = StorageDisk::new(vec![0; disk::SECTOR_SIZE * 2 + 100]);
+        let mut buf = [0; disk::SECTOR_SIZE];
+
+        assert!(disk.read(2, &mut buf).is_err());
+        assert!(disk.write(usize::max_value(), &buf).is_err());
+        // The buffer mustn't run past the end of the storage.
+        assert!(disk.read(1, &mut [0; disk::SECTOR_SIZE + 101]).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+//! Thin provisioning.
+//!
+//! VM images and loopback files are usually created far smaller than the volume they hold, and
+//! grown as data is written. This module provides a `Disk` over an undersized backing disk, which
+//! presents a logical size of its own, and maps the logical sectors to backing sectors in extents
+//! of fixed size, allocated on their first write. Reading an extent never written yields zeros.
+//! When the backing disk has no extents left, writes to unmapped extents fail with
+//! `disk::Error::BackingExhausted`, while writes to mapped extents keep working.
+//!
+//! The backing disk starts with a header sector, followed by the extent map, followed by the
+//! extents:
+//!
+//! - The header holds an 8 byte checksum of the rest of the sector, an 8 byte magic number, the
+//!   logical size (in sectors, 8 bytes), and the extent size (in sectors, 4 bytes).
+//! - Every sector of the map holds an 8 byte checksum of the rest of the sector, followed by the
+//!   4 byte entries of `ENTRIES_PER_SECTOR` consecutive logical extents. An entry is the backing
+//!   extent plus one, with zero meaning unmapped.
+//!
+//! Backing extents are handed out in order, and never unmapped, as there is no way of telling
+//! that the volume above has stopped using an extent.
+
+use std::cmp;
+
+use disk::{self, Disk, Sector};
+
+/// The magic number of the header.
+const MAGIC: &[u8; 8] = b"TFSTHIN\x01";
+/// The size (in bytes) of the checksum starting every sector of the metadata.
+const CHECKSUM_SIZE: usize = 8;
+/// The number of extent map entries per sector.
+const ENTRIES_PER_SECTOR: usize = (disk::SECTOR_SIZE - CHECKSUM_SIZE) / 4;
+
+quick_error! {
+    /// A thin provisioning error.
+    #[derive(Debug)]
+    pub enum Error {
+        /// A backing disk error.
+        Disk(err: disk::Error) {
+            from()
+            description("Backing disk I/O error")
+            display("Backing disk I/O error: {}", err)
+        }
+        /// The metadata is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated thin provisioning metadata.")
+        }
+        /// The magic number doesn't match.
+        ///
+        /// The backing disk isn't thinly provisioned, or was formatted by an incompatible version.
+        UnknownFormat {
+            description("Unknown thin provisioning format.")
+        }
+        /// The checksum of a metadata sector doesn't match.
+        ChecksumMismatch {
+            /// The sector of the backing disk.
+            sector: Sector,
+            /// The checksum stored in the sector.
+            expected: u64,
+            /// The checksum of the sector.
+            found: u64,
+        } {
+            display("Mismatching checksum in thin provisioning sector {} - expected {:x}, found {:x}.", sector, expected, found)
+            description("Mismatching thin provisioning checksum.")
+        }
+        /// The logical size or the extent size is invalid.
+        ///
+        /// The extent size is zero or doesn't fit into the header, or the logical disk is too large
+        /// to be addressed.
+        InvalidGeometry {
+            description("Invalid thin provisioning geometry.")
+        }
+        /// Two logical extents are mapped to the same backing extent.
+        DuplicateExtent {
+            /// The second logical extent mapped to the backing extent.
+            extent: usize,
+        } {
+            display("Logical extent {} is mapped to a backing extent already in use.", extent)
+            description("Backing extent mapped twice.")
+        }
+        /// An extent map entry points past the end of the backing disk.
+        ExtentOutOfBounds {
+            /// The logical extent.
+            extent: usize,
+        } {
+            display("Logical extent {} is mapped past the end of the backing disk.", extent)
+            description("Extent mapped out of bounds.")
+        }
+        /// The backing disk is too small to hold the extent map.
+        TooSmall {
+            description("Backing disk too small for the extent map.")
+        }
+    }
+}
+
+/// Checksum a metadata sector, i.e. everything following the checksum.
+fn checksum(buf: &[u8]) -> u64 {
+    seahash::hash(&buf[CHECKSUM_SIZE..])
+}
+
+/// Verify the checksum of metadata sector `sector`.
+fn verify(sector: Sector, buf: &[u8]) -> Result<(), Error> {
+    let expected = codec::Reader::new(buf).read_u64()?;
+    let found = checksum(buf);
+    if expected != found {
+        return Err(Error::ChecksumMismatch {
+            sector: sector,
+            expected: expected,
+            found: found,
+        });
+    }
+
+    Ok(())
+}
+
+/// Get the number of extents of a logical disk, and the number of sectors of its extent map.
+///
+/// `Error::InvalidGeometry` is returned if the extent size is zero or the logical disk is too large
+/// to be addressed, and `Error::TooSmall` if the
+/// header and the map don't fit into `backing` sectors.
+fn geometry(logical_sectors: Sector, extent_sectors: Sector, backing: Sector) -> Result<(usize, Sector), Error> {
+    // The byte offsets of the sectors must fit into 64 bits.
+    if extent_sectors == 0 || logical_sectors as u64 > ::std::u64::MAX / disk::SECTOR_SIZE as u64 {
+        return Err(Error::InvalidGeometry);
+    }
+
+    let extents = logical_sectors / extent_sectors + (logical_sectors % extent_sectors != 0) as usize;
+    let map_sectors = extents / ENTRIES_PER_SECTOR + (extents % ENTRIES_PER_SECTOR != 0) as usize;
+    if map_sectors >= backing {
+        return Err(Error::TooSmall);
+    }
+
+    Ok((extents, map_sectors))
+}
+
+/// A thinly provisioned disk.
+pub struct ThinDisk<D> {
+    /// The backing disk.
+    inner: D,
+    /// The logical size (in sectors).
+    logical_sectors: Sector,
+    /// The size (in sectors) of an extent.
+    extent_sectors: Sector,
+    /// The first sector of the first backing extent.
+    data_start: Sector,
+    /// The extent map.
+    ///
+    /// This maps every logical extent to its backing extent plus one, with zero meaning unmapped.
+    map: Vec<u32>,
+    /// The number of backing extents handed out.
+    next: u32,
+    /// The number of backing extents.
+    capacity: u32,
+    /// The sectors of the extent map changed since the last sync.
+    dirty: Vec<bool>,
+}
+
+impl<D: Disk> ThinDisk<D> {
+    /// Create a thin layout of `logical_sectors` sectors on a backing disk.
+    ///
+    /// The extents are `extent_sectors` sectors each. Larger extents keep the map small, while
+    /// smaller ones waste less of the backing disk on partially used extents. Anything stored on
+    /// the backing disk is lost. If `extent_sectors` is zero or too large for the header,
+    /// `Error::InvalidGeometry` is returned.
+    pub fn format(inner: D, logical_sectors: Sector, extent_sectors: Sector) -> Result<ThinDisk<D>, Error> {
+        if extent_sectors > ::std::u32::MAX as Sector {
+            return Err(Error::InvalidGeometry);
+        }
+
+        let (extents, _) = geometry(logical_sectors, extent_sectors, inner.number_of_sectors())?;
+        let mut disk = ThinDisk::new(inner, logical_sectors, extent_sectors, vec![0; extents])?;
+
+        // Write the header.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        {
+            let mut writer = codec::Writer::new(&mut buf);
+            writer.seek(CHECKSUM_SIZE);
+            writer.bytes(MAGIC);
+            writer.write_u64(logical_sectors as u64);
+            writer.write_u32(extent_sectors as u32);
+        }
+        let cksum = checksum(&buf);
+        codec::Writer::new(&mut buf).write_u64(cksum);
+        disk.inner.write(0, &buf)?;
+
+        // Write the empty extent map.
+        for dirty in &mut disk.dirty {
+            *dirty = true;
+        }
+        disk.sync()?;
+
+        Ok(disk)
+    }
+
+    /// Open a thin layout on a backing disk.
+    ///
+    /// This loads the extent map, verifying its checksums, and checks that every mapped extent is
+    /// within the backing disk and mapped only once.
+    pub fn open(mut inner: D) -> Result<ThinDisk<D>, Error> {
+        // Load the header.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        inner.read(0, &mut buf)?;
+        verify(0, &buf)?;
+        let mut reader = codec::Reader::new(&buf);
+        reader.seek(CHECKSUM_SIZE)?;
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(Error::UnknownFormat);
+        }
+        let logical_sectors = reader.read_u64()?;
+        let extent_sectors = reader.read_u32()? as Sector;
+        if logical_sectors > ::std::usize::MAX as u64 {
+            return Err(Error::InvalidGeometry);
+        }
+        let logical_sectors = logical_sectors as Sector;
+
+        // Load the extent map. Its size is checked against the backing disk first, so a corrupt
+        // header can't make us allocate more than the backing disk holds.
+        let (extents, _) = geometry(logical_sectors, extent_sectors, inner.number_of_sectors())?;
+        let mut map = Vec::with_capacity(extents);
+        for sector in 1..1 + (extents + ENTRIES_PER_SECTOR - 1) / ENTRIES_PER_SECTOR {
+            inner.read(sector, &mut buf)?;
+            verify(sector, &buf)?;
+
+            let mut reader = codec::Reader::new(&buf);
+            reader.seek(CHECKSUM_SIZE)?;
+            for _ in 0..cmp::min(ENTRIES_PER_SECTOR, extents - map.len()) {
+                map.push(reader.read_u32()?);
+            }
+        }
+
+        let mut disk = ThinDisk::new(inner, logical_sectors, extent_sectors, map)?;
+        if let Some(extent) = disk.map.iter().position(|&entry| entry > disk.capacity) {
+            return Err(Error::ExtentOutOfBounds { extent: extent });
+        }
+
+        // Every backing extent must be mapped at most once, or writes to one logical extent would
+        // show up in the other.
+        let mut mapped: Vec<(u32, usize)> = disk.map.iter().cloned().enumerate()
+            .filter(|&(_, entry)| entry != 0)
+            .map(|(extent, entry)| (entry, extent))
+            .collect();
+        mapped.sort();
+        if let Some(pair) = mapped.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::DuplicateExtent { extent: pair[1].1 });
+        }
+
+        // The backing extents are handed out in order, so the next one follows the highest mapped.
+        disk.next = mapped.last().map_or(0, |&(entry, _)| entry);
+
+        Ok(disk)
+    }
+
+    /// Create the disk from its parts.
+    ///
+    /// Nothing is written.
+    fn new(inner: D, logical_sectors: Sector, extent_sectors: Sector, map: Vec<u32>) -> Result<ThinDisk<D>, Error> {
+        let backing = inner.number_of_sectors();
+        let (_, map_sectors) = geometry(logical_sectors, extent_sectors, backing)?;
+        let data_start = 1 + map_sectors;
+        // The entries are 32-bit, with zero meaning unmapped.
+        let capacity = cmp::min((backing - data_start) / extent_sectors, ::std::u32::MAX as usize - 1);
+
+        Ok(ThinDisk {
+            inner: inner,
+            logical_sectors: logical_sectors,
+            extent_sectors: extent_sectors,
+            data_start: data_start,
+            map: map,
+            next: 0,
+            capacity: capacity as u32,
+            dirty: vec![false; map_sectors],
+        })
+    }
+
+    /// Get the backing disk back.
+    ///
+    /// Changes of the extent map not synced yet are lost.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Get the number of backing sectors in use.
+    ///
+    /// This counts whole extents, including the parts never written.
+    pub fn allocated_sectors(&self) -> Sector {
+        self.next as Sector * self.extent_sectors
+    }
+
+    /// Get the number of backing sectors available for extents.
+    pub fn backing_sectors(&self) -> Sector {
+        self.capacity as Sector * self.extent_sectors
+    }
+
+    /// Check that an access of `len` bytes to `sector` is within the logical disk.
+    fn check(&self, sector: Sector, len: usize) -> Result<(), disk::Error> {
+        if sector >= self.logical_sectors
+            || sector as u64 * disk::SECTOR_SIZE as u64 + len as u64 > self.logical_sectors as u64 * disk::SECTOR_SIZE as u64 {
+            Err(disk::Error::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get the backing sector of the start of some logical extent, if it is mapped.
+    fn lookup(&self, extent: usize) -> Option<Sector> {
+        match self.map[extent] {
+            0 => None,
+            entry => Some(self.data_start + (entry - 1) as Sector * self.extent_sectors),
+        }
+    }
+
+    /// Map a logical extent to the next backing extent.
+    ///
+    /// The backing extent is zeroed, so the parts never written read as zeros, as they did while
+    /// unmapped. Its first sector is returned. If the backing disk is exhausted,
+    /// `disk::Error::BackingExhausted` is returned.
+    fn map_extent(&mut self, extent: usize) -> Result<Sector, disk::Error> {
+        if self.next >= self.capacity {
+            return Err(disk::Error::BackingExhausted);
+        }
+
+        let start = self.data_start + self.next as Sector * self.extent_sectors;
+        self.inner.write(start, &vec![0; self.extent_sectors * disk::SECTOR_SIZE])?;
+
+        self.next += 1;
+        self.map[extent] = self.next;
+        self.dirty[extent / ENTRIES_PER_SECTOR] = true;
+
+        Ok(start)
+    }
+
+    /// Split an access of `len` bytes to `sector` at the extent boundaries.
+    ///
+    /// This calls `f` with the logical extent, the offset (in sectors) into it, and the range of
+    /// the bytes of the access falling into it.
+    fn split<F>(&mut self, sector: Sector, len: usize, mut f: F) -> Result<(), disk::Error>
+        where F: FnMut(&mut ThinDisk<D>, usize, Sector, ::std::ops::Range<usize>) -> Result<(), disk::Error> {
+        self.check(sector, len)?;
+
+        let mut sector = sector;
+        let mut pos = 0;
+        while pos < len {
+            let extent = sector / self.extent_sectors;
+            let offset = sector % self.extent_sectors;
+            let chunk = cmp::min(len - pos, (self.extent_sectors - offset) * disk::SECTOR_SIZE);
+
+            f(self, extent, offset, pos..pos + chunk)?;
+
+            pos += chunk;
+            sector += self.extent_sectors - offset;
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for ThinDisk<D> {
+    fn number_of_sectors(&self) -> Sector {
+        self.logical_sectors
+    }
+
+    fn write(&mut self, sector: Sector, buffer: &[u8]) -> Result<(), disk::Error> {
+        self.split(sector, buffer.len(), |disk, extent, offset, range| {
+            let start = match disk.lookup(extent) {
+                Some(start) => start,
+                None => disk.map_extent(extent)?,
+            };
+
+            disk.inner.write(start + offset, &buffer[range])
+        })
+    }
+
+    fn read(&mut self, sector: Sector, buffer: &mut [u8]) -> Result<(), disk::Error> {
+        self.split(sector, buffer.len(), |disk, extent, offset, range| {
+            match disk.lookup(extent) {
+                Some(start) => disk.inner.read(start + offset, &mut buffer[range]),
+                // Unmapped extents read as zeros.
+                None => {
+                    for byte in &mut buffer[range] {
+                        *byte = 0;
+                    }
+
+                    Ok(())
+                },
+            }
+        })
+    }
+
+    fn sync(&mut self) -> Result<(), disk::Error> {
+        if self.dirty.iter().any(|&dirty| dirty) {
+            // The extents must reach stable storage before the map pointing to them, so a crash
+            // never maps an extent still holding stale data.
+            self.inner.sync()?;
+
+            let dirty: Vec<usize> = self.dirty.iter().enumerate()
+                .filter(|&(_, &dirty)| dirty)
+                .map(|(i, _)| i)
+                .collect();
+            for i in dirty {
+                // Encode the entries of this sector of the map, and checksum them.
+                let mut buf = [0; disk::SECTOR_SIZE];
+                {
+                    let mut writer = codec::Writer::new(&mut buf);
+                    writer.seek(CHECKSUM_SIZE);
+                    for &entry in self.map.iter().skip(i * ENTRIES_PER_SECTOR).take(ENTRIES_PER_SECTOR) {
+                        writer.write_u32(entry);
+                    }
+                }
+                let cksum = checksum(&buf);
+                codec::Writer::new(&mut buf).write_u64(cksum);
+
+                self.inner.write(1 + i, &buf)?;
+                self.dirty[i] = false;
+            }
+        }
+
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::StorageDisk;
+
+    /// Format a logical disk of 1000 sectors over 64 backing sectors, in extents of 8 sectors.
+    ///
+    /// The header and the map take two sectors, leaving room for 7 extents.
+    fn thin() -> ThinDisk<StorageDisk<Vec<u8>>> {
+        ThinDisk::format(StorageDisk::new(vec![0; 64 * disk::SECTOR_SIZE]), 1000, 8).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut disk = thin();
+        assert_eq!(disk.number_of_sectors(), 1000);
+        assert_eq!(disk.backing_sectors(), 56);
+
+        // Unmapped sectors read as zeros.
+        let mut buf = [1; disk::SECTOR_SIZE];
+        disk.read(900, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        // A write spanning two extents maps both.
+        let data: Vec<u8> = (0..2 * disk::SECTOR_SIZE).map(|x| x as u8).collect();
+        disk.write(903, &data[..disk::SECTOR_SIZE]).unwrap();
+        disk.write(7, &data).unwrap();
+        assert_eq!(disk.allocated_sectors(), 24);
+
+        // The map survives reopening, once synced.
+        disk.sync().unwrap();
+        let mut disk = ThinDisk::open(disk.into_inner()).unwrap();
+        assert_eq!(disk.allocated_sectors(), 24);
+        let mut buf = vec![0; 2 * disk::SECTOR_SIZE];
+        disk.read(7, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        disk.read(902, &mut buf).unwrap();
+        assert!(buf[..disk::SECTOR_SIZE].iter().all(|&x| x == 0));
+        assert_eq!(&buf[disk::SECTOR_SIZE..], &data[..disk::SECTOR_SIZE]);
+
+        assert!(disk.read(1000, &mut buf).is_err());
+        assert!(disk.write(999, &data).is_err());
+    }
+
+    #[test]
+    fn backing_exhausted() {
+        let mut disk = thin();
+        let buf = [1; disk::SECTOR_SIZE];
+
+        for extent in 0..7 {
+            disk.write(extent * 100, &buf).unwrap();
+        }
+        match disk.write(800, &buf) {
+            Err(disk::Error::BackingExhausted) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // The mapped extents can still be written.
+        disk.write(101, &buf).unwrap();
+        assert_eq!(disk.allocated_sectors(), disk.backing_sectors());
+    }
+
+    #[test]
+    fn corrupt_map() {
+        let disk = thin();
+        let mut storage = disk.into_inner().into_inner();
+        assert!(ThinDisk::open(StorageDisk::new(storage.clone())).is_ok());
+
+        // Corrupt an entry of the map.
+        storage[disk::SECTOR_SIZE + CHECKSUM_SIZE] ^= 1;
+        match ThinDisk::open(StorageDisk::new(storage.clone())) {
+            Err(Error::ChecksumMismatch { sector: 1, .. }) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // Corrupt the magic number, keeping the checksum intact.
+        storage[CHECKSUM_SIZE] = b'X';
+        let cksum = checksum(&storage[..disk::SECTOR_SIZE]);
+        codec::Writer::new(&mut storage).write_u64(cksum);
+        match ThinDisk::open(StorageDisk::new(storage)) {
+            Err(Error::UnknownFormat) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    /// Overwrite part of metadata sector `sector`, `offset` bytes past the checksum, and checksum it.
+    fn rewrite(storage: &mut [u8], sector: usize, offset: usize, data: &[u8]) {
+        let buf = &mut storage[sector * disk::SECTOR_SIZE..(sector + 1) * disk::SECTOR_SIZE];
+        buf[CHECKSUM_SIZE + offset..CHECKSUM_SIZE + offset + data.len()].copy_from_slice(data);
+        let cksum = checksum(buf);
+        codec::Writer::new(buf).write_u64(cksum);
+    }
+
+    #[test]
+    fn invalid_map() {
+        let mut disk = thin();
+        disk.write(0, &[1; disk::SECTOR_SIZE]).unwrap();
+        disk.write(100, &[1; disk::SECTOR_SIZE]).unwrap();
+        disk.sync().unwrap();
+        let storage = disk.into_inner().into_inner();
+
+        // Extents of zero sectors.
+        let mut bad = storage.clone();
+        rewrite(&mut bad, 0, 16, &[0; 4]);
+        match ThinDisk::open(StorageDisk::new(bad)) {
+            Err(Error::InvalidGeometry) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A logical size too large to be addressed.
+        let mut bad = storage.clone();
+        rewrite(&mut bad, 0, 8, &[0xFF; 8]);
+        match ThinDisk::open(StorageDisk::new(bad)) {
+            Err(Error::InvalidGeometry) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A logical size whose map doesn't fit into the backing disk.
+        let mut bad = storage.clone();
+        rewrite(&mut bad, 0, 8, &[0, 0, 0, 0, 0, 1, 0, 0]);
+        match ThinDisk::open(StorageDisk::new(bad)) {
+            Err(Error::TooSmall) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A logical extent mapped past the end of the backing disk.
+        let mut bad = storage.clone();
+        rewrite(&mut bad, 1, 4 * 13, &[8, 0, 0, 0]);
+        match ThinDisk::open(StorageDisk::new(bad)) {
+            Err(Error::ExtentOutOfBounds { extent: 13 }) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // Two logical extents mapped to the same backing extent.
+        let mut bad = storage.clone();
+        rewrite(&mut bad, 1, 4 * 20, &[1, 0, 0, 0]);
+        match ThinDisk::open(StorageDisk::new(bad)) {
+            Err(Error::DuplicateExtent { extent: 20 }) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        match ThinDisk::format(StorageDisk::new(storage), 1000, 0) {
+            Err(Error::InvalidGeometry) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Throttling of background I/O.
+//!
+//! Maintenance passes (scrubbing, recompression, rekeying, garbage collection) can run
+//! continuously, but they must not eat up the bandwidth of the disk. To avoid that, their I/O can
+//! be capped in both bytes per second and operations per second, through token buckets.
+
+use std::time::{Duration, Instant};
+use std::thread;
+
+/// A throttle configuration.
+///
+/// `None` means unlimited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Config {
+    /// The maximal number of bytes per second.
+    pub bytes_per_second: Option<u64>,
+    /// The maximal number of I/O operations per second.
+    pub ops_per_second: Option<u64>,
+}
+
+/// A token bucket.
+///
+/// The bucket is refilled with `rate` tokens per second, up to a capacity of one second worth of
+/// tokens (the burst size). Taking tokens from the bucket can make it go into debt, which is then
+/// paid back by waiting.
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    /// The number of tokens added per second.
+    rate: f64,
+    /// The current number of tokens.
+    ///
+    /// This is negative if the bucket is in debt.
+    tokens: f64,
+    /// The last time the bucket was refilled.
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new, full bucket.
+    fn new(rate: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Refill the bucket with the tokens accumulated since last time.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Are there enough tokens to take `n` without going into debt?
+    ///
+    /// Operations bigger than the burst size are admitted when the bucket is full, as they would
+    /// never be otherwise.
+    fn has(&self, n: u64) -> bool {
+        self.tokens >= (n as f64).min(self.rate)
+    }
+
+    /// Take `n` tokens from the bucket.
+    ///
+    /// This returns the time to wait before the operation may proceed.
+    fn take(&mut self, now: Instant, n: u64) -> Duration {
+        self.refill(now);
+
+        // Take the tokens, possibly going into debt.
+        self.tokens -= n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::new(0, 0)
+        } else {
+            // Wait until the debt is paid back.
+            let secs = -self.tokens / self.rate;
+            Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+        }
+    }
+}
+
+/// A throttle.
+///
+/// This combines a bandwidth and an operation rate limit.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    /// The bandwidth bucket, if limited.
+    bytes: Option<TokenBucket>,
+    /// The operation rate bucket, if limited.
+    ops: Option<TokenBucket>,
+}
+
+impl Throttle {
+    /// Create a new throttle from some configuration.
+    pub fn new(config: Config) -> Throttle {
+        let now = Instant::now();
+
+        Throttle {
+            bytes: config.bytes_per_second.map(|rate| TokenBucket::new(rate, now)),
+            ops: config.ops_per_second.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Create a throttle without limits.
+    pub fn unlimited() -> Throttle {
+        Throttle::new(Config::default())
+    }
+
+    /// Account an operation of `bytes` bytes.
+    ///
+    /// This returns the time to wait before issuing the operation.
+    fn delay(&mut self, now: Instant, bytes: u64) -> Duration {
+        let bytes_delay = self.bytes.as_mut().map_or(Duration::new(0, 0), |bucket| bucket.take(now, bytes));
+        let ops_delay = self.ops.as_mut().map_or(Duration::new(0, 0), |bucket| bucket.take(now, 1));
+
+        // Both limits must be respected.
+        bytes_delay.max(ops_delay)
+    }
+
+    /// Account an operation of `bytes` bytes, if it may be issued right away.
+    ///
+    /// If either limit would go into debt, nothing is accounted and `false` is returned, so the
+    /// caller can defer the operation instead of blocking.
+    fn admit_at(&mut self, now: Instant, bytes: u64) -> bool {
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.refill(now);
+        }
+        if let Some(ref mut bucket) = self.ops {
+            bucket.refill(now);
+        }
+
+        if self.bytes.as_ref().map_or(true, |bucket| bucket.has(bytes))
+            && self.ops.as_ref().map_or(true, |bucket| bucket.has(1)) {
+            self.delay(now, bytes);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Account an operation of `bytes` bytes, if it may be issued right away.
+    ///
+    /// This never blocks (see `admit_at()`).
+    pub fn admit(&mut self, bytes: u64) -> bool {
+        self.admit_at(Instant::now(), bytes)
+    }
+
+    /// Account an operation of `bytes` bytes, and block until it may be issued.
+    pub fn wait(&mut self, bytes: u64) {
+        let delay = self.delay(Instant::now(), bytes);
+
+        if delay != Duration::new(0, 0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::unlimited();
+        let now = Instant::now();
+
+        for _ in 0..1000 {
+            assert_eq!(throttle.delay(now, 1 << 30), Duration::new(0, 0));
+        }
+    }
+
+    #[test]
+    fn bandwidth() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(Config {
+            bytes_per_second: Some(1000),
+            ops_per_second: None,
+        });
+
+        // The burst is free.
+        assert_eq!(throttle.delay(now, 1000), Duration::new(0, 0));
+        // Then we go into debt.
+        assert_eq!(throttle.delay(now, 500), Duration::from_millis(500));
+        // After a second, the debt is paid back and we've got half a second of tokens.
+        assert_eq!(throttle.delay(now + Duration::from_secs(1), 500), Duration::new(0, 0));
+    }
+
+    #[test]
+    fn ops() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(Config {
+            bytes_per_second: None,
+            ops_per_second: Some(10),
+        });
+
+        for _ in 0..10 {
+            assert_eq!(throttle.delay(now, 4096), Duration::new(0, 0));
+        }
+        assert_eq!(throttle.delay(now, 4096), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn admit() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(Config {
+            bytes_per_second: Some(1000),
+            ops_per_second: None,
+        });
+
+        assert!(throttle.admit_at(now, 600));
+        // Admitting never goes into debt.
+        assert!(!throttle.admit_at(now, 600));
+        assert!(throttle.admit_at(now, 400));
+        assert!(!throttle.admit_at(now, 1));
+        // Operations bigger than the burst size pass once the bucket is full.
+        assert!(!throttle.admit_at(now + Duration::from_millis(500), 5000));
+        assert!(throttle.admit_at(now + Duration::from_secs(2), 5000));
+        assert!(Throttle::unlimited().admit_at(now, 1 << 30));
+    }
+
+    #[test]
+    fn burst_is_capped() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(Config {
+            bytes_per_second: Some(100),
+            ops_per_second: None,
+        });
+
+        // Idling for a long time doesn't accumulate more than one second of tokens.
+        assert_eq!(throttle.delay(now + Duration::from_secs(100), 100), Duration::new(0, 0));
+        assert!(throttle.delay(now + Duration::from_secs(100), 100) > Duration::new(0, 0));
+    }
+}
@@ -0,0 +1,308 @@
+//! Allocation tracing.
+//!
+//! Layout pathologies (fragmentation, metadata ping-pong, poor packing) are hard to see from
+//! aggregate statistics. For offline analysis, the page manager can record every allocation,
+//! free, read, and write into a compact binary trace, much like `blktrace`. The trace can be
+//! decoded into text (through `Display`) or CSV, and replayed against a fresh volume (see
+//! `bench::replay()`, behind the `bench` feature).
+//!
+//! A trace starts with an 8 byte magic number, followed by the records, each 24 bytes:
+//!
+//! - 8 bytes: The time of the operation, in nanoseconds since the start of the trace.
+//! - 8 bytes: The cluster the operation concerns.
+//! - 4 bytes: The number of bytes of the operation: The cluster size for reads and writes, the
+//!   bytes allocated in the transaction for commits, and zero for allocations and frees.
+//! - 1 byte: The kind of operation.
+//! - 1 byte: The subsystem the operation originates from.
+//! - 2 bytes: Padding.
+
+use std::{fmt, io};
+use std::time::Instant;
+
+/// The magic number starting a trace.
+const MAGIC: &[u8; 8] = b"TFSTRC\0\x01";
+/// The size (in bytes) of a record.
+const RECORD_SIZE: usize = 24;
+
+quick_error! {
+    /// A trace decoding error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The trace is truncated.
+        Truncated {
+            from(codec::Error)
+            description("Truncated trace.")
+        }
+        /// The magic number doesn't match.
+        ///
+        /// The buffer is not a trace, or was written by an incompatible version.
+        UnknownFormat {
+            description("Unknown trace format.")
+        }
+    }
+}
+
+/// The kind of a traced operation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// A cluster was allocated.
+    Alloc = 1,
+    /// A cluster was freed.
+    Free = 2,
+    /// A cluster was read (from the cache or the disk).
+    Read = 3,
+    /// A cluster write was queued.
+    Write = 4,
+    /// The pipeline was committed.
+    Commit = 5,
+    /// An unknown kind, written by a newer implementation.
+    Unknown = 0xFF,
+}
+
+impl From<u8> for Kind {
+    fn from(from: u8) -> Kind {
+        match from {
+            1 => Kind::Alloc,
+            2 => Kind::Free,
+            3 => Kind::Read,
+            4 => Kind::Write,
+            5 => Kind::Commit,
+            _ => Kind::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Kind::Alloc => "alloc",
+            Kind::Free => "free",
+            Kind::Read => "read",
+            Kind::Write => "write",
+            Kind::Commit => "commit",
+            Kind::Unknown => "unknown",
+        })
+    }
+}
+
+/// The subsystem an operation originates from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    /// Data pages.
+    Data = 1,
+    /// The freelist (metaclusters and the freelist head).
+    Freelist = 2,
+    /// The state block.
+    StateBlock = 3,
+    /// The health event log.
+    EventLog = 4,
+    /// The root history.
+    History = 5,
+    /// The checksum tree.
+    ChecksumTree = 6,
+    /// The cluster indirection table.
+    Indirection = 7,
+    /// The dead page list.
+    DeadPages = 8,
+    /// An unknown subsystem, written by a newer implementation.
+    Unknown = 0xFF,
+}
+
+impl From<u8> for Subsystem {
+    fn from(from: u8) -> Subsystem {
+        match from {
+            1 => Subsystem::Data,
+            2 => Subsystem::Freelist,
+            3 => Subsystem::StateBlock,
+            4 => Subsystem::EventLog,
+            5 => Subsystem::History,
+            6 => Subsystem::ChecksumTree,
+            7 => Subsystem::Indirection,
+            8 => Subsystem::DeadPages,
+            _ => Subsystem::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Subsystem::Data => "data",
+            Subsystem::Freelist => "freelist",
+            Subsystem::StateBlock => "state-block",
+            Subsystem::EventLog => "event-log",
+            Subsystem::History => "history",
+            Subsystem::ChecksumTree => "checksum-tree",
+            Subsystem::Indirection => "indirection",
+            Subsystem::DeadPages => "dead-pages",
+            Subsystem::Unknown => "unknown",
+        })
+    }
+}
+
+/// A traced operation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Record {
+    /// The time of the operation, in nanoseconds since the start of the trace.
+    pub time: u64,
+    /// The cluster the operation concerns.
+    ///
+    /// This is zero for operations not concerning a single cluster (e.g. commits).
+    pub cluster: u64,
+    /// The number of bytes of the operation.
+    ///
+    /// This is zero for operations transferring no data (allocations and frees).
+    pub len: u32,
+    /// The kind of the operation.
+    pub kind: Kind,
+    /// The subsystem the operation originates from.
+    pub subsystem: Subsystem,
+}
+
+impl fmt::Display for Record {
+    /// Format the record as a line of text, like `blkparse`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>10}.{:09} {:<6} {:<11} cluster {:#x} ({} bytes)",
+               self.time / 1_000_000_000, self.time % 1_000_000_000, self.kind, self.subsystem,
+               self.cluster, self.len)
+    }
+}
+
+/// A trace recorder.
+///
+/// The records are written to a sink (e.g. a buffered file) as they happen. Tracing must never
+/// make the traced operations fail, so if writing to the sink fails, the recorder stops
+/// recording, and `.is_broken()` tells.
+pub struct Recorder {
+    /// The sink of the trace.
+    sink: Box<io::Write>,
+    /// The start of the trace.
+    start: Instant,
+    /// Did writing to the sink fail?
+    broken: bool,
+}
+
+impl Recorder {
+    /// Start a trace, writing to `sink`.
+    pub fn new(mut sink: Box<io::Write>) -> Recorder {
+        let broken = sink.write_all(MAGIC).is_err();
+
+        Recorder {
+            sink: sink,
+            start: Instant::now(),
+            broken: broken,
+        }
+    }
+
+    /// Record an operation happening now.
+    pub fn record(&mut self, kind: Kind, subsystem: Subsystem, cluster: u64, len: usize) {
+        if self.broken {
+            return;
+        }
+
+        let elapsed = self.start.elapsed();
+        let record = Record {
+            time: elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64,
+            cluster: cluster,
+            len: len as u32,
+            kind: kind,
+            subsystem: subsystem,
+        };
+
+        let mut buf = [0; RECORD_SIZE];
+        encode_record(&record, &mut buf);
+        if self.sink.write_all(&buf).is_err() {
+            self.broken = true;
+        }
+    }
+
+    /// Did writing to the sink fail?
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Stop the trace, flushing the sink.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Encode a record into `buf`.
+fn encode_record(record: &Record, buf: &mut [u8]) {
+    let mut writer = codec::Writer::new(buf);
+    writer.write_u64(record.time);
+    writer.write_u64(record.cluster);
+    writer.write_u32(record.len);
+    writer.write_u8(record.kind as u8);
+    writer.write_u8(record.subsystem as u8);
+}
+
+/// Decode a trace.
+///
+/// A trailing partial record (e.g. from a trace cut short by a crash) is ignored. This never
+/// panics, regardless of the content and length of `buf`.
+#[deny(clippy::indexing_slicing)]
+pub fn decode(buf: &[u8]) -> Result<Vec<Record>, Error> {
+    let mut reader = codec::Reader::new(buf);
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(Error::UnknownFormat);
+    }
+
+    let mut records = Vec::with_capacity((buf.len() - MAGIC.len()) / RECORD_SIZE);
+    while buf.len() - reader.position() >= RECORD_SIZE {
+        let start = reader.position();
+        records.push(Record {
+            time: reader.read_u64()?,
+            cluster: reader.read_u64()?,
+            len: reader.read_u32()?,
+            kind: Kind::from(reader.read_u8()?),
+            subsystem: Subsystem::from(reader.read_u8()?),
+        });
+        // Skip the padding.
+        reader.seek(start + RECORD_SIZE)?;
+    }
+
+    Ok(records)
+}
+
+/// Write records as CSV.
+///
+/// The first line is a header naming the columns.
+pub fn write_csv<W: io::Write>(records: &[Record], mut out: W) -> io::Result<()> {
+    writeln!(out, "time_ns,kind,subsystem,cluster,len")?;
+    for record in records {
+        writeln!(out, "{},{},{},{},{}", record.time, record.kind, record.subsystem, record.cluster, record.len)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = MAGIC.to_vec();
+        let record = Record {
+            time: 1_500_000_000,
+            cluster: 42,
+            len: 510,
+            kind: Kind::Alloc,
+            subsystem: Subsystem::Data,
+        };
+        let mut encoded = [0; RECORD_SIZE];
+        encode_record(&record, &mut encoded);
+        buf.extend_from_slice(&encoded);
+        // A partial record is ignored.
+        buf.extend_from_slice(&encoded[..10]);
+
+        assert_eq!(decode(&buf).unwrap(), vec![record]);
+        assert_eq!(decode(b"TFSTRC\0\x02"), Err(Error::UnknownFormat));
+        assert_eq!(decode(b"TFS"), Err(Error::Truncated));
+
+        let mut csv = Vec::new();
+        write_csv(&[record], &mut csv).unwrap();
+        assert_eq!(csv, b"time_ns,kind,subsystem,cluster,len\n1500000000,alloc,data,42,510\n".to_vec());
+    }
+}
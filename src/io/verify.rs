@@ -0,0 +1,124 @@
+//! Volume verification reports.
+//!
+//! `Manager::verify()` checks every structure it can reach, and rather than failing at the first
+//! problem, it collects the problems into a report. This is intended for health monitoring, where
+//! the whole picture is more useful than the first error.
+
+use std::fmt;
+
+/// The kind of a problem.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Problem {
+    /// The cluster couldn't be read from the disk.
+    Unreadable,
+    /// The checksum of the cluster doesn't match.
+    ChecksumMismatch,
+    /// The cluster couldn't be decoded (e.g. invalid compression or truncation).
+    Undecodable,
+    /// A pointer is out of bounds.
+    PointerOutOfBounds,
+    /// The freelist chain contains a cycle at this metacluster.
+    FreelistCycle,
+    /// The cluster occurs multiple times in the freelist.
+    DoubleFree,
+    /// The cluster is in the freelist, but in use (e.g. by the event log).
+    FreeInUse,
+    /// The free cluster count doesn't match the freelist.
+    ///
+    /// The cluster is the freelist head. Clusters have been lost from (or added to) the
+    /// freelist, without any metacluster being damaged.
+    FreeCountMismatch,
+    /// The reference count of the cluster doesn't match.
+    ///
+    /// Either the dead page list names pages the cluster doesn't hold, or releases every page of
+    /// it without it being freed, or the number of live pages differs from the references to the
+    /// cluster (see `Manager::verify_references()`).
+    ReferenceCountMismatch,
+    /// A reference names a page which was released, or a free cluster.
+    DanglingReference,
+}
+
+/// A problem found during verification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Issue {
+    /// The cluster the problem concerns.
+    pub cluster: u64,
+    /// The problem.
+    pub problem: Problem,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cluster {:x}: {:?}", self.cluster, self.problem)
+    }
+}
+
+/// A verification report.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Report {
+    /// The problems found.
+    pub issues: Vec<Issue>,
+    /// The number of metaclusters in the freelist chain.
+    pub metaclusters: u64,
+    /// The number of free clusters (including metaclusters).
+    pub free_clusters: u64,
+    /// The number of data clusters checked.
+    pub data_clusters: u64,
+}
+
+impl Report {
+    /// Record a problem.
+    pub fn add(&mut self, cluster: u64, problem: Problem) {
+        self.issues.push(Issue {
+            cluster: cluster,
+            problem: problem,
+        });
+    }
+
+    /// Is the volume free of problems?
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Count the problems of some kind.
+    pub fn count(&self, problem: Problem) -> usize {
+        self.issues.iter().filter(|issue| issue.problem == problem).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} free clusters in {} metaclusters, {} data clusters checked.",
+                 self.free_clusters, self.metaclusters, self.data_clusters)?;
+
+        if self.is_clean() {
+            writeln!(f, "No problems found.")
+        } else {
+            writeln!(f, "{} problems found:", self.issues.len())?;
+            for issue in &self.issues {
+                writeln!(f, "- {}", issue)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = Report::default();
+        assert!(report.is_clean());
+
+        report.add(5, Problem::DoubleFree);
+        report.add(7, Problem::DoubleFree);
+        report.add(9, Problem::ChecksumMismatch);
+        assert!(!report.is_clean());
+        assert_eq!(report.count(Problem::DoubleFree), 2);
+        assert_eq!(report.count(Problem::FreeInUse), 0);
+        assert_eq!(report.issues[2].to_string(), "cluster 9: ChecksumMismatch");
+    }
+}
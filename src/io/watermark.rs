@@ -0,0 +1,115 @@
+//! Low-space watermarks.
+//!
+//! Running out of clusters in the middle of a critical operation is nasty, so embedders can
+//! register watermarks on the number of free clusters. When the number of free clusters falls
+//! below a watermark, its callback is called, giving the embedder a chance to clean up (e.g.
+//! delete snapshots or caches) before allocations start failing with `OutOfClusters`.
+
+/// A watermark callback.
+///
+/// It is called with the number of free clusters at the time the watermark was crossed.
+pub type Callback = Box<FnMut(u64)>;
+
+/// A single watermark.
+struct Watermark {
+    /// The number of free clusters below which the callback is called.
+    threshold: u64,
+    /// Is the watermark armed?
+    ///
+    /// The callback is only called when the number of free clusters _crosses_ the threshold, not
+    /// on every allocation below it. After being triggered, the watermark is disarmed, until the
+    /// number of free clusters rises above the threshold again.
+    armed: bool,
+    /// The callback.
+    callback: Callback,
+}
+
+/// A set of watermarks.
+#[derive(Default)]
+pub struct Watermarks {
+    /// The watermarks.
+    watermarks: Vec<Watermark>,
+}
+
+impl Watermarks {
+    /// Add a watermark.
+    ///
+    /// `callback` will be called whenever the number of free clusters falls below `threshold`.
+    pub fn add(&mut self, threshold: u64, callback: Callback) {
+        self.watermarks.push(Watermark {
+            threshold: threshold,
+            armed: true,
+            callback: callback,
+        });
+    }
+
+    /// Remove every watermark.
+    pub fn clear(&mut self) {
+        self.watermarks.clear();
+    }
+
+    /// Are there any watermarks?
+    pub fn is_empty(&self) -> bool {
+        self.watermarks.is_empty()
+    }
+
+    /// Update the watermarks with the current number of free clusters.
+    ///
+    /// This triggers the watermarks which were crossed and rearms the ones which were left.
+    pub fn update(&mut self, free: u64) {
+        for watermark in &mut self.watermarks {
+            if free < watermark.threshold {
+                // We're below the watermark. Trigger it unless it has already been triggered.
+                if watermark.armed {
+                    watermark.armed = false;
+                    (watermark.callback)(free);
+                }
+            } else {
+                // We're above the watermark, so we rearm it.
+                watermark.armed = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn trigger_once() {
+        let triggered = Rc::new(Cell::new(0));
+        let mut watermarks = Watermarks::default();
+
+        let t = triggered.clone();
+        watermarks.add(10, Box::new(move |_| t.set(t.get() + 1)));
+
+        watermarks.update(20);
+        assert_eq!(triggered.get(), 0);
+        watermarks.update(10);
+        assert_eq!(triggered.get(), 0);
+        watermarks.update(9);
+        assert_eq!(triggered.get(), 1);
+        watermarks.update(5);
+        assert_eq!(triggered.get(), 1);
+
+        // Going above the watermark rearms it.
+        watermarks.update(11);
+        watermarks.update(3);
+        assert_eq!(triggered.get(), 2);
+    }
+
+    #[test]
+    fn free_count() {
+        let last = Rc::new(Cell::new(0));
+        let mut watermarks = Watermarks::default();
+
+        let l = last.clone();
+        watermarks.add(100, Box::new(move |free| l.set(free)));
+
+        watermarks.update(42);
+        assert_eq!(last.get(), 42);
+    }
+}
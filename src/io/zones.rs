@@ -0,0 +1,357 @@
+//! Zone-aware allocation.
+//!
+//! Zoned disks (see `disk::Zone`) only accept sequential writes within a zone, so the usual
+//! freelist, which hands out clusters in whatever order they were freed, can't be used on them.
+//! Instead, clusters are appended at the write pointer of an open zone, and freed clusters are
+//! merely counted: A zone is only reusable once all of its clusters are dead and it has been
+//! reset. Zones holding few live clusters are reclaimed by migrating their live clusters
+//! elsewhere, which is up to the layer knowing who points to them (see the allocation note).
+
+use std::collections::HashSet;
+
+use disk::{self, Sector};
+
+quick_error! {
+    /// A zone tracking error.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Error {
+        /// The sector isn't in any zone.
+        OutOfZones {
+            /// The sector.
+            sector: Sector,
+        } {
+            display("Sector {} isn't in any zone.", sector)
+            description("Sector not in any zone.")
+        }
+        /// The zone still has live clusters.
+        ///
+        /// Resetting it would destroy them, so they must be migrated first.
+        ZoneNotEmpty {
+            /// The first sector of the zone.
+            start: Sector,
+            /// The number of live clusters in the zone.
+            live: Sector,
+        } {
+            display("Zone at sector {} still has {} live clusters.", start, live)
+            description("Resetting a zone with live clusters.")
+        }
+        /// A cluster of a zone was freed twice, or without being written.
+        DoubleFree {
+            /// The freed sector.
+            sector: Sector,
+        } {
+            display("Sector {} freed, but not live.", sector)
+            description("Freeing a cluster which isn't live.")
+        }
+        /// The zone report is malformed.
+        ///
+        /// The zone is empty, its write pointer is outside of it, or it overlaps (or precedes) the
+        /// zone reported before it.
+        InvalidZone {
+            /// The first sector of the zone.
+            start: Sector,
+        } {
+            display("Invalid zone at sector {} in the zone report.", start)
+            description("Invalid zone in the zone report.")
+        }
+    }
+}
+
+/// The state of a zone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Nothing has been written to the zone since it was reset.
+    Empty,
+    /// The zone is partially written, and accepts writes at its write pointer.
+    Open,
+    /// The zone is written up to its end.
+    Full,
+}
+
+/// The tracked state of a single zone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Zone {
+    /// The zone as reported by the disk.
+    zone: disk::Zone,
+    /// The number of live clusters in the zone.
+    ///
+    /// This is the number of clusters written, minus the number freed since.
+    live: Sector,
+}
+
+impl Zone {
+    /// Get the state of the zone.
+    fn state(&self) -> State {
+        if self.zone.write_pointer == self.zone.start {
+            State::Empty
+        } else if self.zone.write_pointer >= self.zone.start + self.zone.len {
+            State::Full
+        } else {
+            State::Open
+        }
+    }
+
+    /// Does the zone contain some sector?
+    fn contains(&self, sector: Sector) -> bool {
+        sector >= self.zone.start && sector - self.zone.start < self.zone.len
+    }
+}
+
+/// The zones of a disk.
+///
+/// This tracks the write pointer and the number of live clusters of every zone, and allocates by
+/// appending to a single open zone at a time, so the writes of a flush are sequential.
+#[derive(Clone)]
+pub struct Zones {
+    /// The zones, in ascending order.
+    zones: Vec<Zone>,
+    /// The index of the zone currently appended to, if any.
+    open: Option<usize>,
+    /// The sectors freed since their zone was last reset.
+    ///
+    /// Together with the write pointers, this tells which clusters must be migrated before
+    /// resetting a zone (see `.live_sectors()`).
+    dead: HashSet<Sector>,
+}
+
+impl Zones {
+    /// Start tracking the zones reported by a disk (see `Disk::report_zones()`).
+    ///
+    /// The zones which were already written are assumed to be fully live, as we can't tell what
+    /// was freed before. Partially written zones are resumed, starting with the first one.
+    ///
+    /// The report comes from the disk, so it is checked rather than trusted: `Error::InvalidZone`
+    /// is returned if it is malformed.
+    pub fn new(report: Vec<disk::Zone>) -> Result<Zones, Error> {
+        let mut zones = Vec::with_capacity(report.len());
+        // The end of the previous zone.
+        let mut end = 0;
+        for zone in report {
+            let live = match zone.write_pointer.checked_sub(zone.start) {
+                Some(live) if zone.len != 0 && live <= zone.len && zone.start >= end => live,
+                _ => return Err(Error::InvalidZone { start: zone.start }),
+            };
+            end = zone.start.checked_add(zone.len).ok_or(Error::InvalidZone { start: zone.start })?;
+
+            zones.push(Zone {
+                zone: zone,
+                live: live,
+            });
+        }
+        let open = zones.iter().position(|zone| zone.state() == State::Open);
+
+        Ok(Zones {
+            zones: zones,
+            open: open,
+            dead: HashSet::new(),
+        })
+    }
+
+    /// Allocate a sector.
+    ///
+    /// The sector is the write pointer of the open zone, which is advanced. If the open zone is
+    /// full, the next empty zone is opened. `None` is returned if every zone is full, in which
+    /// case zones must be reclaimed (see `.victim()`).
+    pub fn alloc(&mut self) -> Option<Sector> {
+        // Open an empty zone, if the current one is full (or there is none).
+        if self.open.map_or(true, |open| self.zones[open].state() == State::Full) {
+            self.open = self.zones.iter().position(|zone| zone.state() == State::Empty);
+        }
+
+        let zone = &mut self.zones[self.open?];
+        let sector = zone.zone.write_pointer;
+        zone.zone.write_pointer += 1;
+        zone.live += 1;
+
+        Some(sector)
+    }
+
+    /// Free a sector.
+    ///
+    /// The sector can't be reused until its whole zone is reclaimed, so this merely updates the
+    /// number of live clusters of the zone.
+    pub fn free(&mut self, sector: Sector) -> Result<(), Error> {
+        let index = self.find(sector)?;
+        let zone = &mut self.zones[index];
+        if sector >= zone.zone.write_pointer || !self.dead.insert(sector) {
+            return Err(Error::DoubleFree { sector: sector });
+        }
+        zone.live -= 1;
+
+        Ok(())
+    }
+
+    /// Get the live sectors of the zone starting at `start`.
+    ///
+    /// These are the written sectors which weren't freed since, in ascending order. They must be
+    /// migrated before the zone can be reset.
+    pub fn live_sectors(&self, start: Sector) -> Result<Vec<Sector>, Error> {
+        let zone = &self.zones[self.find(start)?];
+        Ok((zone.zone.start..zone.zone.write_pointer).filter(|sector| !self.dead.contains(sector)).collect())
+    }
+
+    /// Pick the zone to reclaim next.
+    ///
+    /// This is the full zone with the fewest live clusters, as it costs the least to migrate
+    /// (greedy garbage collection). The first sector of the zone and its number of live clusters
+    /// are returned, or `None` if no zone is full.
+    pub fn victim(&self) -> Option<(Sector, Sector)> {
+        self.zones.iter()
+            .filter(|zone| zone.state() == State::Full)
+            .min_by_key(|zone| zone.live)
+            .map(|zone| (zone.zone.start, zone.live))
+    }
+
+    /// Mark the zone starting at `start` as reset.
+    ///
+    /// This must be called after resetting the zone on the disk (see `Disk::reset_zone()`), once
+    /// its live clusters have been migrated (i.e. freed here).
+    pub fn reset(&mut self, start: Sector) -> Result<(), Error> {
+        self.check_empty(start)?;
+
+        let index = self.find(start)?;
+        let zone = &mut self.zones[index];
+        for sector in zone.zone.start..zone.zone.write_pointer {
+            self.dead.remove(&sector);
+        }
+        zone.zone.write_pointer = zone.zone.start;
+
+        Ok(())
+    }
+
+    /// Check that the zone starting at `start` has no live clusters, and can thus be reset.
+    pub fn check_empty(&self, start: Sector) -> Result<(), Error> {
+        let zone = &self.zones[self.find(start)?];
+        if zone.live != 0 {
+            return Err(Error::ZoneNotEmpty {
+                start: zone.zone.start,
+                live: zone.live,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Is some sector in a zone?
+    pub fn contains(&self, sector: Sector) -> bool {
+        self.find(sector).is_ok()
+    }
+
+    /// Get the state of the zone containing some sector.
+    pub fn state(&self, sector: Sector) -> Result<State, Error> {
+        Ok(self.zones[self.find(sector)?].state())
+    }
+
+    /// Get the number of empty zones.
+    ///
+    /// Garbage collection should start before this reaches zero, as migrating the live clusters
+    /// of a zone needs space to migrate them to.
+    pub fn empty_zones(&self) -> usize {
+        self.zones.iter().filter(|zone| zone.state() == State::Empty).count()
+    }
+
+    /// Find the index of the zone containing some sector.
+    fn find(&self, sector: Sector) -> Result<usize, Error> {
+        // The zones are in ascending order, so we search for the last zone starting at or before
+        // the sector.
+        let index = match self.zones.binary_search_by_key(&sector, |zone| zone.zone.start) {
+            Ok(index) => index,
+            Err(0) => return Err(Error::OutOfZones { sector: sector }),
+            Err(index) => index - 1,
+        };
+
+        if self.zones[index].contains(sector) {
+            Ok(index)
+        } else {
+            Err(Error::OutOfZones { sector: sector })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk;
+
+    fn zones(n: usize, len: Sector) -> Zones {
+        Zones::new((0..n).map(|i| disk::Zone {
+            start: i * len,
+            len: len,
+            write_pointer: i * len,
+        }).collect()).unwrap()
+    }
+
+    #[test]
+    fn append() {
+        let mut zones = zones(2, 2);
+
+        assert_eq!(zones.alloc(), Some(0));
+        assert_eq!(zones.state(0).unwrap(), State::Open);
+        assert_eq!(zones.alloc(), Some(1));
+        assert_eq!(zones.state(0).unwrap(), State::Full);
+        assert_eq!(zones.alloc(), Some(2));
+        assert_eq!(zones.alloc(), Some(3));
+        assert_eq!(zones.alloc(), None);
+        assert_eq!(zones.empty_zones(), 0);
+    }
+
+    #[test]
+    fn reclaim() {
+        let mut zones = zones(2, 2);
+        for _ in 0..4 {
+            zones.alloc().unwrap();
+        }
+
+        // The zone with the fewest live clusters is reclaimed first.
+        zones.free(3).unwrap();
+        assert_eq!(zones.victim(), Some((2, 1)));
+        assert_eq!(zones.reset(2), Err(Error::ZoneNotEmpty { start: 2, live: 1 }));
+
+        // Once migrated, the zone can be reset and reused.
+        assert_eq!(zones.live_sectors(2).unwrap(), vec![2]);
+        zones.free(2).unwrap();
+        assert_eq!(zones.free(2), Err(Error::DoubleFree { sector: 2 }));
+        assert_eq!(zones.live_sectors(2).unwrap(), vec![]);
+        zones.reset(2).unwrap();
+        assert_eq!(zones.state(2).unwrap(), State::Empty);
+        assert_eq!(zones.alloc(), Some(2));
+    }
+
+    #[test]
+    fn resume() {
+        let mut zones = Zones::new(vec![
+            disk::Zone { start: 0, len: 4, write_pointer: 4 },
+            disk::Zone { start: 4, len: 4, write_pointer: 6 },
+            disk::Zone { start: 8, len: 4, write_pointer: 8 },
+        ]).unwrap();
+
+        // Partially written zones are appended to first, and written clusters are live.
+        assert_eq!(zones.alloc(), Some(6));
+        assert_eq!(zones.victim(), Some((0, 4)));
+        assert_eq!(zones.state(12), Err(Error::OutOfZones { sector: 12 }));
+    }
+
+    #[test]
+    fn invalid_report() {
+        let invalid = [
+            // The write pointer is before the start.
+            vec![disk::Zone { start: 4, len: 4, write_pointer: 2 }],
+            // The write pointer is past the end.
+            vec![disk::Zone { start: 0, len: 4, write_pointer: 5 }],
+            // The zone is empty.
+            vec![disk::Zone { start: 0, len: 0, write_pointer: 0 }],
+            // The zones overlap.
+            vec![
+                disk::Zone { start: 0, len: 4, write_pointer: 0 },
+                disk::Zone { start: 2, len: 4, write_pointer: 2 },
+            ],
+        ];
+
+        for report in invalid.iter() {
+            match Zones::new(report.clone()) {
+                Err(Error::InvalidZone { .. }) => (),
+                res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+            }
+        }
+    }
+}